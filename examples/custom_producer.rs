@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::Result;
+use peckish::prelude::pipeline::*;
+use peckish::prelude::registry::*;
+use peckish::prelude::*;
+use tracing::info;
+
+/// A trivial custom format: a text file listing every path in the artifact.
+struct ListingProducer {
+    name: String,
+    path: PathBuf,
+    injections: Vec<Injection>,
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for ListingProducer {
+    type Output = file::FileArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<Self::Output> {
+        let memfs = previous.extract().await?;
+        let paths = nyoom::walk_ordered(&*memfs, "/").await?;
+        let listing = paths
+            .iter()
+            .map(|p| format!("{}\n", p.display()))
+            .collect::<String>();
+        tokio::fs::write(&self.path, listing).await?;
+
+        Ok(file::FileArtifact {
+            name: self.name.clone(),
+            paths: vec![self.path.clone()],
+            git_mtimes: false,
+            default_excludes: true,
+            extra_excludes: vec![],
+            placement: file::FilePlacement::AsGiven,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for ListingProducer {
+    async fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+const CONFIG: &str = r#"
+metadata:
+  name: "example"
+  version: "0.0.1-1"
+  description: "example package"
+  author: "me <me@example.com>"
+  arch: "amd64"
+  license: "Apache-2.0"
+
+input:
+  name: "example file artifact"
+  type: "file"
+  paths:
+    - "./examples/data/a"
+
+output:
+  - name: "example listing"
+    type: "plugin"
+    plugin: "listing"
+    path: "listing.txt"
+"#;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let registry = Registry::new().register_producer("listing", |name, options| {
+        Ok(Box::new(ListingProducer {
+            name: name.into(),
+            path: serde_yaml::from_value(options["path"].clone())?,
+            injections: vec![],
+        }))
+    });
+
+    let config = PeckishConfig::parse_with_registry(CONFIG, &registry)?;
+    let out = Pipeline::default().run(config).await?;
+
+    info!("produced {} artifacts", out.len());
+
+    Ok(())
+}
@@ -28,14 +28,13 @@ async fn main() -> Result<()> {
         .package_architecture("amd64")
         .build()?;
 
-    let config = PeckishConfig {
-        input: ConfiguredArtifact::File(file_artifact),
-        output: vec![
+    let config = PeckishConfig::new(
+        ConfiguredArtifact::File(file_artifact),
+        vec![
             ConfiguredProducer::Tarball(tarball_producer),
             ConfiguredProducer::Deb(debian_producer),
         ],
-        chain: false,
-    };
+    );
 
     let pipeline = Pipeline::default();
 
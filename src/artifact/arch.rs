@@ -1,10 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use eyre::Result;
+use eyre::{eyre, Result};
 use flop::tar::TarFloppyDisk;
 use floppy_disk::tokio_fs::TokioFloppyDisk;
 use floppy_disk::FloppyDisk;
 use regex::Regex;
+use serde::Serialize;
 use smoosh::CompressionType;
 use tracing::*;
 
@@ -12,8 +13,11 @@ use crate::fs::MemFS;
 use crate::util;
 use crate::util::config::Injection;
 
-use super::tarball::{TarballArtifact, TarballProducer};
-use super::{get_artifact_size, Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+use super::tarball::{EntryNames, TarballArtifact, TarballProducer};
+use super::{
+    get_artifact_size, input_package_path, Artifact, ArtifactKind, ArtifactProducer, ExtractedDir,
+    PackageInfo, SelfBuilder, SelfValidation,
+};
 
 /// An Arch Linux package. This is a tarball file with a `.pkg.tar` extension
 /// and a `.PKGINFO` file in the root.
@@ -25,6 +29,9 @@ pub struct ArchArtifact {
     pub path: PathBuf,
     /// The .PKGINFO file.
     pub pkginfo: Option<Pkginfo>,
+    /// The detached signature, ex. `foo.pkg.tar.zst.sig`, if the package was
+    /// signed.
+    pub signature: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +45,82 @@ pub struct Pkginfo {
     pub size: u64,
     pub arch: String,
     pub provides: Vec<String>,
+    pub replaces: Vec<String>,
+    pub conflicts: Vec<String>,
+    /// Files, relative to the root, that pacman keeps local changes to on
+    /// upgrade.
+    pub backup: Vec<String>,
+}
+
+impl Pkginfo {
+    /// Parse a `.PKGINFO` file. Keys peckish doesn't know about are ignored.
+    pub fn parse(pkginfo: &str) -> Result<Self> {
+        let mut parsed = Self {
+            pkgname: String::new(),
+            pkgbase: String::new(),
+            pkgver: String::new(),
+            pkgdesc: String::new(),
+            builddate: 0,
+            packager: String::new(),
+            size: 0,
+            arch: String::new(),
+            provides: vec![],
+            replaces: vec![],
+            conflicts: vec![],
+            backup: vec![],
+        };
+
+        for line in pkginfo.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match key.trim() {
+                "pkgname" => parsed.pkgname = value,
+                "pkgbase" => parsed.pkgbase = value,
+                "pkgver" => parsed.pkgver = value,
+                "pkgdesc" => parsed.pkgdesc = value,
+                "builddate" => {
+                    parsed.builddate = value
+                        .parse()
+                        .map_err(|e| eyre!("invalid builddate `{value}`: {e}"))?
+                }
+                "packager" => parsed.packager = value,
+                "size" => {
+                    parsed.size = value
+                        .parse()
+                        .map_err(|e| eyre!("invalid size `{value}`: {e}"))?
+                }
+                "arch" => parsed.arch = value,
+                "provides" => parsed.provides.push(value),
+                "replaces" => parsed.replaces.push(value),
+                "conflicts" => parsed.conflicts.push(value),
+                "backup" => parsed.backup.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl ArchArtifact {
+    /// The package, read as the tarball it is.
+    fn tarball(&self) -> TarballArtifact {
+        TarballArtifact {
+            name: format!("{}-tarball-extractor", self.name),
+            path: self.path.clone(),
+        }
+    }
+
+    /// Read the package's `.PKGINFO` file as-is.
+    pub async fn read_pkginfo(&self) -> Result<String> {
+        let tarball = TarFloppyDisk::open(&self.path).await?;
+        tarball
+            .read_to_string("/.PKGINFO")
+            .await
+            .map_err(|e| eyre!("{} has no .PKGINFO file: {e}", self.path.display()))
+    }
 }
 
 #[async_trait::async_trait]
@@ -46,13 +129,22 @@ impl Artifact for ArchArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Arch
+    }
+
     async fn extract(&self) -> Result<MemFS> {
-        TarballArtifact {
-            name: format!("{}-tarball-extractor", self.name),
-            path: self.path.clone(),
-        }
-        .extract()
-        .await
+        self.tarball().extract().await
+    }
+
+    /// Arch packages are tarballs, so they can be streamed to disk the same
+    /// way.
+    async fn extract_to_dir(&self, dir: &Path) -> Result<ExtractedDir> {
+        self.tarball().extract_to_dir(dir).await
+    }
+
+    async fn extracted_size(&self) -> Result<Option<u64>> {
+        self.tarball().extracted_size().await
     }
 
     fn try_clone(&self) -> Result<Box<dyn Artifact>> {
@@ -60,7 +152,26 @@ impl Artifact for ArchArtifact {
     }
 
     fn paths(&self) -> Option<Vec<PathBuf>> {
-        Some(vec![self.path.clone()])
+        let mut paths = vec![self.path.clone()];
+        if let Some(signature) = &self.signature {
+            paths.push(signature.clone());
+        }
+        Some(paths)
+    }
+
+    /// Artifacts from the config don't have their `.PKGINFO` parsed yet, so
+    /// it's read from the package.
+    async fn package_info(&self) -> Result<Option<PackageInfo>> {
+        let pkginfo = match &self.pkginfo {
+            Some(pkginfo) => pkginfo.clone(),
+            None => Pkginfo::parse(&self.read_pkginfo().await?)?,
+        };
+        Ok(Some(PackageInfo {
+            name: pkginfo.pkgname,
+            version: pkginfo.pkgver,
+            arch: pkginfo.arch,
+            description: pkginfo.pkgdesc,
+        }))
     }
 }
 
@@ -142,6 +253,7 @@ impl SelfBuilder for ArchArtifactBuilder {
             name: self.name.clone(),
             path: self.path.clone(),
             pkginfo: self.pkginfo.clone(),
+            signature: None,
         })
     }
 }
@@ -150,7 +262,7 @@ impl SelfBuilder for ArchArtifactBuilder {
 /// tarball with a `.pkg.tar` extension and a `.PKGINFO` file in the root. The
 /// `.PKGINFO` file is generated from the `package_*` fields on the struct.
 /// The size of the package is calculated from the previous artifact's memfs.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ArchProducer {
     pub name: String,
     pub package_name: String,
@@ -159,7 +271,27 @@ pub struct ArchProducer {
     pub package_author: String,
     pub package_arch: String,
     pub path: PathBuf,
+    #[serde(serialize_with = "crate::util::config::serialize_compression")]
+    pub compression: CompressionType,
     pub injections: Vec<Injection>,
+    /// Keep the input package's `.PKGINFO` instead of generating one from the
+    /// `package_*` fields. Only `size` and `builddate` are updated.
+    pub inherit_metadata: bool,
+    /// Other packages this one provides. The package always provides itself.
+    pub package_provides: Vec<String>,
+    /// Packages this one replaces on `pacman -Syu`.
+    pub package_replaces: Vec<String>,
+    /// Packages that can't be installed alongside this one.
+    pub package_conflicts: Vec<String>,
+    /// Config files that pacman shouldn't overwrite if they were changed,
+    /// ex. `/etc/app.conf`.
+    pub package_backup: Vec<PathBuf>,
+    /// Sign the package with this gpg key, ex. a fingerprint, writing a
+    /// detached `.sig` next to it for signed repos.
+    pub signing_key: Option<String>,
+    /// The gpg home directory with the signing key. Defaults to gpg's own,
+    /// ex. `GNUPGHOME` or `~/.gnupg`.
+    pub gpg_home: Option<PathBuf>,
 }
 
 #[async_trait::async_trait]
@@ -178,8 +310,29 @@ impl ArtifactProducer for ArchProducer {
         let size = get_artifact_size(previous).await?;
         let builddate = util::get_current_time()?;
 
+        if self.inherit_metadata {
+            return self.produce_inherited(previous, size, builddate).await;
+        }
+
+        let mut provides = vec![self.package_name.clone()];
+        for provided in &self.package_provides {
+            if !provides.contains(provided) {
+                provides.push(provided.clone());
+            }
+        }
+        let backup = self
+            .package_backup
+            .iter()
+            .map(|path| {
+                path.strip_prefix("/")
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+
         info!("generating .PKGINFO...");
-        let content = indoc::formatdoc! {r#"
+        let mut content = indoc::formatdoc! {r#"
             # generated by peckish
             pkgname = {name}
             pkgbase = {name}
@@ -189,7 +342,6 @@ impl ArtifactProducer for ArchProducer {
             packager = {author}
             size = {size}
             arch = {arch}
-            provides = {name}
         "#,
             name = self.package_name,
             time = builddate,
@@ -199,44 +351,140 @@ impl ArtifactProducer for ArchProducer {
             version = self.package_ver,
             arch = self.package_arch,
         };
+        // pacman reads arrays as the same key repeated once per value.
+        for (key, values) in [
+            ("provides", &provides),
+            ("replaces", &self.package_replaces),
+            ("conflicts", &self.package_conflicts),
+            ("backup", &backup),
+        ] {
+            for value in values {
+                content.push_str(&format!("{key} = {value}\n"));
+            }
+        }
+
+        let pkginfo = Pkginfo {
+            pkgname: self.package_name.clone(),
+            pkgbase: self.package_name.clone(),
+            pkgver: self.package_ver.clone(),
+            pkgdesc: self.package_desc.clone(),
+            builddate,
+            packager: self.package_author.clone(),
+            size,
+            arch: self.package_arch.clone(),
+            provides,
+            replaces: self.package_replaces.clone(),
+            conflicts: self.package_conflicts.clone(),
+            backup,
+        };
+        self.package(previous, content, pkginfo, vec![]).await
+    }
+}
+
+impl ArchProducer {
+    /// Where the detached signature is written, if the package is signed.
+    pub(crate) fn signature_path(&self) -> Option<PathBuf> {
+        self.signing_key
+            .as_ref()
+            .map(|_| util::gpg::signature_path(&self.path))
+    }
 
+    /// Reuse the input package's `.PKGINFO`. Its `.MTREE` lists the old
+    /// files, so it's dropped rather than carried over.
+    async fn produce_inherited(
+        &self,
+        previous: &dyn Artifact,
+        size: u64,
+        builddate: u64,
+    ) -> Result<ArchArtifact> {
+        let input = ArchArtifactBuilder::new(previous.name())
+            .path(input_package_path(&self.name, previous)?)
+            .build()?;
+
+        info!("updating inherited .PKGINFO...");
+        let content = set_pkginfo_value(&input.read_pkginfo().await?, "builddate", builddate);
+        let content = set_pkginfo_value(&content, "size", size);
+        let pkginfo = Pkginfo::parse(&content)?;
+
+        let mut extra_injections = vec![];
+        if TarFloppyDisk::open(&input.path)
+            .await?
+            .metadata("/.MTREE")
+            .await
+            .is_ok()
+        {
+            extra_injections.push(Injection::Delete {
+                path: PathBuf::from(".MTREE"),
+            });
+        }
+
+        self.package(previous, content, pkginfo, extra_injections)
+            .await
+    }
+
+    async fn package(
+        &self,
+        previous: &dyn Artifact,
+        content: String,
+        pkginfo: Pkginfo,
+        extra_injections: Vec<Injection>,
+    ) -> Result<ArchArtifact> {
         info!("creating package...");
         let mut new_injections = self.injections.clone();
+        new_injections.extend(extra_injections);
         new_injections.push(Injection::Create {
             path: PathBuf::from(".PKGINFO"),
-            content: content.clone().into(),
+            content: content.into(),
         });
 
         if let Some(parent) = self.path.parent() {
             tokio::fs::create_dir_all(parent).await?;
         }
 
-        TarballProducer {
+        let tarball = TarballProducer {
             name: format!("{}-tarball-producer", self.name),
             path: self.path.clone(),
-            compression: CompressionType::Zstd,
+            compression: self.compression,
+            sparse: false,
+            entry_names: EntryNames::Relative,
+            file_mtime: None,
+            dir_mtime: None,
+            rsyncable: false,
+            remap_owner: None,
+            split_size: None,
             injections: new_injections,
         }
         .produce_from(previous)
-        .await
-        .map(|tarball| ArchArtifact {
+        .await?;
+
+        let signature = match &self.signing_key {
+            Some(key) => {
+                Some(util::gpg::detach_sign(self.gpg_home.as_deref(), key, &tarball.path).await?)
+            }
+            None => None,
+        };
+
+        Ok(ArchArtifact {
             name: self.name.clone(),
             path: tarball.path,
-            pkginfo: Some(Pkginfo {
-                pkgname: self.package_name.clone(),
-                pkgbase: self.package_name.clone(),
-                pkgver: self.package_ver.clone(),
-                pkgdesc: self.package_desc.clone(),
-                builddate,
-                packager: self.package_author.clone(),
-                size,
-                arch: self.package_arch.clone(),
-                provides: vec![self.package_name.clone()],
-            }),
+            pkginfo: Some(pkginfo),
+            signature,
         })
     }
 }
 
+/// Replace every `key = ...` line in a `.PKGINFO`, adding one if there are
+/// none.
+fn set_pkginfo_value(pkginfo: &str, key: &str, value: u64) -> String {
+    let mut lines = pkginfo
+        .lines()
+        .filter(|line| line.split_once('=').map(|(k, _)| k.trim()) != Some(key))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    lines.push(format!("{key} = {value}"));
+    lines.join("\n") + "\n"
+}
+
 #[async_trait::async_trait]
 impl SelfValidation for ArchProducer {
     async fn validate(&self) -> Result<()> {
@@ -256,6 +504,20 @@ impl SelfValidation for ArchProducer {
             ));
         }
 
+        if let Some(signature) = self.signature_path() {
+            if TokioFloppyDisk::new(None)
+                .metadata(&signature)
+                .await
+                .is_ok()
+            {
+                return Err(eyre::eyre!(
+                    "cannot produce artifact '{}': signature path already exists: {}",
+                    self.name,
+                    signature.display()
+                ));
+            }
+        }
+
         let mut errors = vec![];
 
         // Validate any package starting with a letter, followed by any letter,
@@ -297,6 +559,18 @@ impl SelfValidation for ArchProducer {
             ));
         }
 
+        for path in &self.package_backup {
+            if path.as_os_str().is_empty() || path == Path::new("/") {
+                errors.push(format!("backup path `{}` is not a file", path.display()));
+            }
+        }
+
+        if let Some(key) = &self.signing_key {
+            if let Err(e) = util::gpg::check_secret_key(self.gpg_home.as_deref(), key).await {
+                errors.push(e.to_string());
+            }
+        }
+
         if !errors.is_empty() {
             Err(eyre::eyre!(
                 "Arch producer is invalid:\n{}",
@@ -316,7 +590,15 @@ pub struct ArchProducerBuilder {
     package_author: String,
     package_arch: String,
     path: PathBuf,
+    compression: CompressionType,
     injections: Vec<Injection>,
+    inherit_metadata: bool,
+    package_provides: Vec<String>,
+    package_replaces: Vec<String>,
+    package_conflicts: Vec<String>,
+    package_backup: Vec<PathBuf>,
+    signing_key: Option<String>,
+    gpg_home: Option<PathBuf>,
 }
 
 #[allow(unused)]
@@ -351,10 +633,50 @@ impl ArchProducerBuilder {
         self
     }
 
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub fn inject(mut self, injection: Injection) -> Self {
         self.injections.push(injection);
         self
     }
+
+    pub fn inherit_metadata(mut self, inherit_metadata: bool) -> Self {
+        self.inherit_metadata = inherit_metadata;
+        self
+    }
+
+    pub fn provides<S: Into<String>>(mut self, package: S) -> Self {
+        self.package_provides.push(package.into());
+        self
+    }
+
+    pub fn replaces<S: Into<String>>(mut self, package: S) -> Self {
+        self.package_replaces.push(package.into());
+        self
+    }
+
+    pub fn conflicts<S: Into<String>>(mut self, package: S) -> Self {
+        self.package_conflicts.push(package.into());
+        self
+    }
+
+    pub fn backup<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.package_backup.push(path.into());
+        self
+    }
+
+    pub fn signing_key<S: Into<String>>(mut self, key: S) -> Self {
+        self.signing_key = Some(key.into());
+        self
+    }
+
+    pub fn gpg_home<P: Into<PathBuf>>(mut self, gpg_home: P) -> Self {
+        self.gpg_home = Some(gpg_home.into());
+        self
+    }
 }
 
 impl SelfBuilder for ArchProducerBuilder {
@@ -369,7 +691,15 @@ impl SelfBuilder for ArchProducerBuilder {
             package_author: String::new(),
             package_arch: String::new(),
             path: PathBuf::new(),
+            compression: CompressionType::Zstd,
             injections: vec![],
+            inherit_metadata: false,
+            package_provides: vec![],
+            package_replaces: vec![],
+            package_conflicts: vec![],
+            package_backup: vec![],
+            signing_key: None,
+            gpg_home: None,
         }
     }
 
@@ -382,7 +712,237 @@ impl SelfBuilder for ArchProducerBuilder {
             package_author: self.package_author.clone(),
             package_arch: self.package_arch.clone(),
             path: self.path.clone(),
+            compression: self.compression,
             injections: self.injections.clone(),
+            inherit_metadata: self.inherit_metadata,
+            package_provides: self.package_provides.clone(),
+            package_replaces: self.package_replaces.clone(),
+            package_conflicts: self.package_conflicts.clone(),
+            package_backup: self.package_backup.clone(),
+            signing_key: self.signing_key.clone(),
+            gpg_home: self.gpg_home.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::EmptyArtifact;
+    use crate::fs::TempDir;
+    use crate::util::with_source_date_epoch;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_pkginfo_repeats_array_keys() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let arch = ArchProducerBuilder::new("arrays")
+            .path(tmp.path_view().join("arrays.pkg.tar"))
+            .package_name("arrays")
+            .package_ver("0.1.0-1")
+            .package_desc("arrays")
+            .package_author("peckish <peckish@example.com>")
+            .package_arch("x86_64")
+            .provides("arrays-bin")
+            .provides("libarrays.so=1-64")
+            .conflicts("arrays-git")
+            .backup("/etc/arrays.conf")
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let pkginfo = arch.read_pkginfo().await?;
+        let provides = pkginfo
+            .lines()
+            .filter(|line| line.starts_with("provides = "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "provides = arrays",
+                "provides = arrays-bin",
+                "provides = libarrays.so=1-64"
+            ],
+            provides
+        );
+        assert!(pkginfo.lines().any(|line| line == "conflicts = arrays-git"));
+        assert!(pkginfo
+            .lines()
+            .any(|line| line == "backup = etc/arrays.conf"));
+
+        let parsed = Pkginfo::parse(&pkginfo)?;
+        assert_eq!(vec!["etc/arrays.conf"], parsed.backup);
+        assert!(parsed.replaces.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_builddate_follows_source_date_epoch() -> Result<()> {
+        with_source_date_epoch(1_700_000_000, async {
+            let tmp = TempDir::new().await?;
+            let arch = ArchProducerBuilder::new("builddate")
+                .path(tmp.path_view().join("builddate.pkg.tar"))
+                .package_name("builddate")
+                .package_ver("0.1.0-1")
+                .package_desc("builddate")
+                .package_author("peckish <peckish@example.com>")
+                .package_arch("x86_64")
+                .build()?
+                .produce_from(&EmptyArtifact::new("empty"))
+                .await?;
+
+            let pkginfo = Pkginfo::parse(&arch.read_pkginfo().await?)?;
+            assert_eq!(1_700_000_000, pkginfo.builddate);
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Run gpg against a throwaway keyring, so tests never touch the user's.
+    async fn gpg(home: &Path, args: &[&str]) -> Result<std::process::Output> {
+        Ok(tokio::process::Command::new("gpg")
+            .env("GNUPGHOME", home)
+            .args(["--batch", "--pinentry-mode", "loopback", "--passphrase", ""])
+            .args(args)
+            .output()
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn test_signed_package_has_a_valid_detached_signature() -> Result<()> {
+        let home = TempDir::new().await?;
+        tokio::fs::set_permissions(
+            home.path_view(),
+            std::os::unix::fs::PermissionsExt::from_mode(0o700),
+        )
+        .await?;
+        let Ok(output) = gpg(
+            &home.path_view(),
+            &[
+                "--quick-gen-key",
+                "peckish-test@example.com",
+                "ed25519",
+                "sign",
+                "never",
+            ],
+        )
+        .await
+        else {
+            warn!("gpg is not available, skipping");
+            return Ok(());
+        };
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let tmp = TempDir::new().await?;
+        let producer = ArchProducerBuilder::new("signed")
+            .path(tmp.path_view().join("signed.pkg.tar.zst"))
+            .package_name("signed")
+            .package_ver("0.1.0-1")
+            .package_desc("signed")
+            .package_author("peckish <peckish@example.com>")
+            .package_arch("any")
+            .signing_key("peckish-test@example.com")
+            .gpg_home(home.path_view())
+            .build()?;
+        producer.validate().await?;
+        let arch = producer.produce_from(&EmptyArtifact::new("empty")).await?;
+
+        let signature = tmp.path_view().join("signed.pkg.tar.zst.sig");
+        assert_eq!(arch.signature.as_ref(), Some(&signature));
+        assert_eq!(
+            Some(vec![arch.path.clone(), signature.clone()]),
+            arch.paths()
+        );
+        let signature = signature.to_string_lossy().to_string();
+        let verify = gpg(
+            &home.path_view(),
+            &["--verify", &signature, &arch.path.to_string_lossy()],
+        )
+        .await?;
+        assert!(verify.status.success());
+
+        // The signature is for this package only.
+        let other = tmp.path_view().join("other");
+        tokio::fs::write(&other, b"not the package").await?;
+        let verify = gpg(
+            &home.path_view(),
+            &["--verify", &signature, &other.to_string_lossy()],
+        )
+        .await?;
+        assert!(!verify.status.success());
+
+        let unknown = ArchProducer {
+            path: tmp.path_view().join("unknown.pkg.tar.zst"),
+            signing_key: Some("nobody@example.com".into()),
+            ..producer
+        };
+        assert!(unknown.validate().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inherited_metadata_survives_a_round_trip() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let original = ArchProducerBuilder::new("original")
+            .path(tmp.path_view().join("original.pkg.tar"))
+            .package_name("original")
+            .package_ver("1.2.3-1")
+            .package_desc("the original package")
+            .package_author("peckish <peckish@example.com>")
+            .package_arch("x86_64")
+            .provides("original-bin")
+            .backup("/etc/original.conf")
+            .inject(Injection::Create {
+                path: "/usr/bin/tool".into(),
+                content: b"tool".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        // Inputs from the config don't have their .PKGINFO parsed yet.
+        let input = ArchArtifactBuilder::new("input")
+            .path(&original.path)
+            .build()?;
+        let patched = ArchProducerBuilder::new("patched")
+            .path(tmp.path_view().join("patched.pkg.tar"))
+            .package_name("from-config")
+            .package_ver("9.9.9-1")
+            .inherit_metadata(true)
+            .inject(Injection::Create {
+                path: "/etc/patched".into(),
+                content: b"patched".to_vec(),
+            })
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        let pkginfo = Pkginfo::parse(&patched.read_pkginfo().await?)?;
+        assert_eq!("original", pkginfo.pkgname);
+        assert_eq!("1.2.3-1", pkginfo.pkgver);
+        assert_eq!("the original package", pkginfo.pkgdesc);
+        assert_eq!("peckish <peckish@example.com>", pkginfo.packager);
+        assert_eq!("x86_64", pkginfo.arch);
+        assert_eq!(vec!["original", "original-bin"], pkginfo.provides);
+        assert_eq!(vec!["etc/original.conf"], pkginfo.backup);
+
+        let memfs = patched.extract().await?;
+        assert_eq!(b"tool".to_vec(), memfs.fs().read("/usr/bin/tool").await?);
+        assert_eq!(b"patched".to_vec(), memfs.fs().read("/etc/patched").await?);
+        assert_eq!(memfs.size().await?, pkginfo.size);
+
+        Ok(())
+    }
+}
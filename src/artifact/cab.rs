@@ -0,0 +1,834 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{Datelike, TimeZone, Timelike, Utc};
+use eyre::{eyre, Result};
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
+use floppy_disk::tokio_fs::TokioFloppyDisk;
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::fs::MemFS;
+use crate::util::config::Injection;
+
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation};
+
+const SIGNATURE: &[u8] = b"MSCF";
+const HEADER_SIZE: usize = 36;
+const FOLDER_SIZE: usize = 8;
+const FILE_HEADER_SIZE: usize = 16;
+const DATA_HEADER_SIZE: usize = 8;
+/// Every data block holds at most 32KiB of uncompressed data.
+const BLOCK_SIZE: usize = 32768;
+/// cabextract and Windows both refuse names longer than this.
+const MAX_NAME: usize = 256;
+/// The largest folder that offsets within it can address.
+const MAX_FOLDER_SIZE: u64 = 0x7fff8000;
+
+const FLAG_PREV_CABINET: u16 = 0x0001;
+const FLAG_NEXT_CABINET: u16 = 0x0002;
+const FLAG_RESERVE_PRESENT: u16 = 0x0004;
+
+const ATTR_READONLY: u16 = 0x01;
+const ATTR_ARCHIVE: u16 = 0x20;
+const ATTR_EXEC: u16 = 0x40;
+const ATTR_NAME_IS_UTF: u16 = 0x80;
+
+/// A Microsoft cabinet (`.cab`) archive. Cabinets have no directories or
+/// symlinks, and only keep whether a file is executable or read-only, so
+/// files are read back as 0o644, 0o755, or 0o444.
+#[derive(Debug, Clone)]
+pub struct CabArtifact {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl Artifact for CabArtifact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Cab
+    }
+
+    async fn extract(&self) -> Result<MemFS> {
+        let cabinet = tokio::fs::read(&self.path).await?;
+        let files =
+            read_cab(&cabinet).map_err(|e| eyre!("cannot read {}: {e}", self.path.display()))?;
+
+        let memfs = MemFS::new();
+        let fs = memfs.fs();
+        for file in files {
+            debug!("extracting {}", file.path.display());
+            if let Some(parent) = file.path.parent() {
+                fs.create_dir_all(parent).await?;
+            }
+            fs.write(&file.path, &file.data).await?;
+
+            let mode = if file.attribs & ATTR_READONLY != 0 {
+                0o444
+            } else if file.attribs & ATTR_EXEC != 0 {
+                0o755
+            } else {
+                0o644
+            };
+            fs.set_permissions(&file.path, MemPermissions::from_mode(mode))
+                .await?;
+            memfs.set_mtime(&file.path, file.mtime);
+        }
+
+        Ok(memfs)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        Some(vec![self.path.clone()])
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for CabArtifact {
+    async fn validate(&self) -> Result<()> {
+        if !self.path.is_file() {
+            return Err(eyre!("path is not a file: {:?}", self.path));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct CabArtifactBuilder {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[allow(unused)]
+impl CabArtifactBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl SelfBuilder for CabArtifactBuilder {
+    type Output = CabArtifact;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(CabArtifact {
+            name: self.name.clone(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// How a cabinet folder's data is compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CabCompression {
+    None,
+    /// Deflate, in 32KiB blocks. Every version of Windows can read it.
+    #[default]
+    Mszip,
+}
+
+impl CabCompression {
+    fn type_code(self) -> u16 {
+        match self {
+            CabCompression::None => 0,
+            CabCompression::Mszip => 1,
+        }
+    }
+}
+
+/// Produces a Microsoft cabinet archive, as a first step towards MSIs.
+///
+/// Cabinets compress files in folders rather than one by one, so files under
+/// `uncompressed` are stored in their own uncompressed folder, ex. for media
+/// that's already compressed. Empty directories and symlinks can't be
+/// stored, and are left out.
+#[derive(Debug, Clone, Serialize)]
+pub struct CabProducer {
+    pub name: String,
+    pub path: PathBuf,
+    pub compression: CabCompression,
+    /// Files at or under these paths are stored without compression.
+    pub uncompressed: Vec<PathBuf>,
+    pub injections: Vec<Injection>,
+}
+
+impl CabProducer {
+    fn compression_for(&self, path: &Path) -> CabCompression {
+        if self
+            .uncompressed
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            CabCompression::None
+        } else {
+            self.compression
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for CabProducer {
+    type Output = CabArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<CabArtifact> {
+        info!("producing {}", self.path.display());
+        let mut memfs = previous.extract().await?;
+        self.inject(&mut memfs).await?;
+
+        let fs = memfs.fs();
+        let mut writer = CabWriter::default();
+        for path in nyoom::walk_ordered(fs, "/").await? {
+            let metadata = fs.symlink_metadata(&path).await?;
+            if metadata.is_symlink() {
+                warn!(
+                    "skipping symlink {}, since cabinets can't store symlinks",
+                    path.display()
+                );
+                continue;
+            } else if !metadata.is_file() {
+                continue;
+            }
+
+            let mode = metadata.permissions().mode();
+            let mut attribs = ATTR_ARCHIVE;
+            if mode & 0o111 != 0 {
+                attribs |= ATTR_EXEC;
+            }
+            if mode & 0o222 == 0 {
+                attribs |= ATTR_READONLY;
+            }
+
+            writer.add(
+                &path,
+                fs.read(&path).await?,
+                memfs.mtime(&path).unwrap_or(0),
+                attribs,
+                self.compression_for(&path),
+            )?;
+        }
+
+        tokio::fs::write(&self.path, writer.write()?).await?;
+
+        Ok(CabArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for CabProducer {
+    async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        for path in &self.uncompressed {
+            if !path.is_absolute() {
+                errors.push(format!(
+                    "uncompressed path `{}` must be absolute",
+                    path.display()
+                ));
+            }
+        }
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if TokioFloppyDisk::new(None)
+            .metadata(&self.path)
+            .await
+            .is_ok()
+        {
+            errors.push(format!("path already exists: {}", self.path.display()));
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!(
+                "cannot produce artifact '{}':\n{}",
+                self.name,
+                errors.join("\n")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct CabProducerBuilder {
+    name: String,
+    path: PathBuf,
+    compression: CabCompression,
+    uncompressed: Vec<PathBuf>,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl CabProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn compression(mut self, compression: CabCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn uncompressed<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.uncompressed.push(path.into());
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for CabProducerBuilder {
+    type Output = CabProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+            compression: CabCompression::default(),
+            uncompressed: vec![],
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(CabProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            compression: self.compression,
+            uncompressed: self.uncompressed.clone(),
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+/// A file, as it's stored in a cabinet.
+struct CabFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    mtime: u64,
+    attribs: u16,
+}
+
+/// Files grouped into one folder per compression type.
+#[derive(Default)]
+struct CabWriter {
+    folders: Vec<(CabCompression, Vec<CabFile>)>,
+}
+
+impl CabWriter {
+    fn add(
+        &mut self,
+        path: &Path,
+        data: Vec<u8>,
+        mtime: u64,
+        attribs: u16,
+        compression: CabCompression,
+    ) -> Result<()> {
+        if data.len() > u32::MAX as usize {
+            return Err(eyre!("{} is too large for a cabinet", path.display()));
+        }
+
+        let file = CabFile {
+            path: path.to_path_buf(),
+            data,
+            mtime,
+            attribs,
+        };
+        match self.folders.iter_mut().find(|(c, _)| *c == compression) {
+            Some((_, files)) => files.push(file),
+            None => self.folders.push((compression, vec![file])),
+        }
+
+        Ok(())
+    }
+
+    fn write(self) -> Result<Vec<u8>> {
+        let file_count: usize = self.folders.iter().map(|(_, files)| files.len()).sum();
+        if file_count > u16::MAX as usize {
+            return Err(eyre!(
+                "a cabinet can hold at most {} files, but there are {file_count}",
+                u16::MAX
+            ));
+        }
+
+        let mut file_headers = vec![];
+        let mut data_blocks = vec![];
+        for (index, (compression, files)) in self.folders.iter().enumerate() {
+            let mut folder_data = vec![];
+            for file in files {
+                let relative = file.path.strip_prefix("/").unwrap_or(&file.path);
+                let mut name = relative.as_os_str().as_bytes().to_vec();
+                for byte in name.iter_mut() {
+                    if *byte == b'/' {
+                        *byte = b'\\';
+                    }
+                }
+                if name.len() >= MAX_NAME {
+                    return Err(eyre!(
+                        "{} is too long for a cabinet, names are at most {} bytes",
+                        file.path.display(),
+                        MAX_NAME - 1
+                    ));
+                }
+
+                let mut attribs = file.attribs;
+                if !name.is_ascii() {
+                    attribs |= ATTR_NAME_IS_UTF;
+                }
+                let (date, time) = dos_datetime(file.mtime);
+
+                file_headers.extend((file.data.len() as u32).to_le_bytes());
+                file_headers.extend((folder_data.len() as u32).to_le_bytes());
+                file_headers.extend((index as u16).to_le_bytes());
+                file_headers.extend(date.to_le_bytes());
+                file_headers.extend(time.to_le_bytes());
+                file_headers.extend(attribs.to_le_bytes());
+                file_headers.extend(name);
+                file_headers.push(0);
+
+                folder_data.extend_from_slice(&file.data);
+                if folder_data.len() as u64 > MAX_FOLDER_SIZE {
+                    return Err(eyre!(
+                        "cabinet folders can hold at most {MAX_FOLDER_SIZE} bytes"
+                    ));
+                }
+            }
+
+            let blocks = folder_data
+                .chunks(BLOCK_SIZE)
+                .map(|chunk| data_block(chunk, *compression))
+                .collect::<Vec<_>>();
+            data_blocks.push((*compression, blocks));
+        }
+
+        let files_offset = HEADER_SIZE + FOLDER_SIZE * self.folders.len();
+        let mut data_offset = files_offset + file_headers.len();
+        let mut folders = vec![];
+        for (compression, blocks) in &data_blocks {
+            folders.extend((data_offset as u32).to_le_bytes());
+            folders.extend((blocks.len() as u16).to_le_bytes());
+            folders.extend(compression.type_code().to_le_bytes());
+            data_offset += blocks.iter().map(Vec::len).sum::<usize>();
+        }
+        let size = data_offset;
+        if size > u32::MAX as usize {
+            return Err(eyre!("cabinet would be larger than 4GiB"));
+        }
+
+        let mut cabinet = Vec::with_capacity(size);
+        cabinet.extend(SIGNATURE);
+        cabinet.extend(0u32.to_le_bytes());
+        cabinet.extend((size as u32).to_le_bytes());
+        cabinet.extend(0u32.to_le_bytes());
+        cabinet.extend((files_offset as u32).to_le_bytes());
+        cabinet.extend(0u32.to_le_bytes());
+        // Version 1.3
+        cabinet.extend([3, 1]);
+        cabinet.extend((self.folders.len() as u16).to_le_bytes());
+        cabinet.extend((file_count as u16).to_le_bytes());
+        // No flags, set ID, or cabinet index, since it's never split.
+        cabinet.extend([0; 6]);
+        cabinet.extend(folders);
+        cabinet.extend(file_headers);
+        for (_, blocks) in data_blocks {
+            for block in blocks {
+                cabinet.extend(block);
+            }
+        }
+
+        Ok(cabinet)
+    }
+}
+
+/// A CFDATA block for up to [`BLOCK_SIZE`] bytes of a folder.
+fn data_block(chunk: &[u8], compression: CabCompression) -> Vec<u8> {
+    let data = match compression {
+        CabCompression::None => chunk.to_vec(),
+        // Each block is a complete deflate stream. Readers keep the previous
+        // block around as history, but nothing requires using it.
+        CabCompression::Mszip => {
+            let mut data = b"CK".to_vec();
+            data.extend(miniz_oxide::deflate::compress_to_vec(chunk, 6));
+            data
+        }
+    };
+
+    let mut sizes = vec![];
+    sizes.extend((data.len() as u16).to_le_bytes());
+    sizes.extend((chunk.len() as u16).to_le_bytes());
+
+    let mut block = checksum(&sizes, checksum(&data, 0)).to_le_bytes().to_vec();
+    block.extend(sizes);
+    block.extend(data);
+    block
+}
+
+/// The checksum of a CFDATA block, which covers its data and then its sizes.
+fn checksum(data: &[u8], seed: u32) -> u32 {
+    let mut csum = seed;
+    let mut words = data.chunks_exact(4);
+    for word in &mut words {
+        csum ^= u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    // Leftover bytes are taken big-endian, unlike the words before them.
+    let rest = words
+        .remainder()
+        .iter()
+        .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+
+    csum ^ rest
+}
+
+/// Cabinets store times as MS-DOS dates, which start at 1980 and only have
+/// two second precision. Times are written as UTC.
+fn dos_datetime(secs: u64) -> (u16, u16) {
+    let Some(time) = Utc.timestamp_opt(secs as i64, 0).single() else {
+        return (0x21, 0);
+    };
+    if time.year() < 1980 {
+        return (0x21, 0);
+    }
+    let year = (time.year() - 1980).min(127) as u16;
+
+    (
+        (year << 9) | ((time.month() as u16) << 5) | time.day() as u16,
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2),
+    )
+}
+
+fn parse_dos_datetime(date: u16, time: u16) -> u64 {
+    Utc.with_ymd_and_hms(
+        (date >> 9) as i32 + 1980,
+        ((date >> 5) & 0xf) as u32,
+        (date & 0x1f) as u32,
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3f) as u32,
+        (time & 0x1f) as u32 * 2,
+    )
+    .single()
+    .map(|time| time.timestamp().max(0) as u64)
+    .unwrap_or(0)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| eyre!("cabinet is truncated"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| eyre!("cabinet is truncated"))
+}
+
+/// Decompress every data block of a folder, which starts at `offset`.
+fn read_folder(
+    cabinet: &[u8],
+    mut offset: usize,
+    blocks: u16,
+    compression: u16,
+    data_reserve: usize,
+) -> Result<Vec<u8>> {
+    let mut folder = vec![];
+    for _ in 0..blocks {
+        let csum = read_u32(cabinet, offset)?;
+        let compressed_size = read_u16(cabinet, offset + 4)? as usize;
+        let size = read_u16(cabinet, offset + 6)? as usize;
+        let start = offset + DATA_HEADER_SIZE + data_reserve;
+        let data = cabinet
+            .get(start..start + compressed_size)
+            .ok_or_else(|| eyre!("cabinet is truncated"))?;
+        if csum != 0 && csum != checksum(&cabinet[offset + 4..offset + 8], checksum(data, 0)) {
+            return Err(eyre!("data block at offset {offset} has a bad checksum"));
+        }
+        offset = start + compressed_size;
+
+        match compression & 0xf {
+            0 => folder.extend_from_slice(data),
+            1 => {
+                let deflated = data
+                    .strip_prefix(b"CK")
+                    .ok_or_else(|| eyre!("mszip block at offset {offset} has no CK signature"))?;
+                // Blocks can refer back into the ones before them, so they're
+                // inflated in place after the rest of the folder.
+                let out_pos = folder.len();
+                folder.resize(out_pos + size, 0);
+                let (status, _, written) = decompress(
+                    &mut DecompressorOxide::new(),
+                    deflated,
+                    &mut folder,
+                    out_pos,
+                    TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+                );
+                if status != TINFLStatus::Done || written != size {
+                    return Err(eyre!("mszip block is corrupt ({status:?})"));
+                }
+            }
+            2 => return Err(eyre!("quantum compression is not supported")),
+            3 => return Err(eyre!("lzx compression is not supported")),
+            other => return Err(eyre!("unknown compression type {other}")),
+        }
+    }
+
+    Ok(folder)
+}
+
+fn read_cab(cabinet: &[u8]) -> Result<Vec<CabFile>> {
+    if !cabinet.starts_with(SIGNATURE) {
+        return Err(eyre!("not a cabinet"));
+    }
+    let files_offset = read_u32(cabinet, 16)? as usize;
+    let folder_count = read_u16(cabinet, 26)? as usize;
+    let file_count = read_u16(cabinet, 28)? as usize;
+    let flags = read_u16(cabinet, 30)?;
+    if flags & (FLAG_PREV_CABINET | FLAG_NEXT_CABINET) != 0 {
+        return Err(eyre!(
+            "cabinets split across several files are not supported"
+        ));
+    }
+
+    let (mut offset, folder_reserve, data_reserve) = if flags & FLAG_RESERVE_PRESENT != 0 {
+        let header_reserve = read_u16(cabinet, HEADER_SIZE)? as usize;
+        let folder_reserve = *cabinet
+            .get(HEADER_SIZE + 2)
+            .ok_or_else(|| eyre!("cabinet is truncated"))? as usize;
+        let data_reserve = *cabinet
+            .get(HEADER_SIZE + 3)
+            .ok_or_else(|| eyre!("cabinet is truncated"))? as usize;
+        (
+            HEADER_SIZE + 4 + header_reserve,
+            folder_reserve,
+            data_reserve,
+        )
+    } else {
+        (HEADER_SIZE, 0, 0)
+    };
+
+    let mut folders = vec![];
+    for _ in 0..folder_count {
+        folders.push(read_folder(
+            cabinet,
+            read_u32(cabinet, offset)? as usize,
+            read_u16(cabinet, offset + 4)?,
+            read_u16(cabinet, offset + 6)?,
+            data_reserve,
+        )?);
+        offset += FOLDER_SIZE + folder_reserve;
+    }
+
+    let mut files = vec![];
+    let mut offset = files_offset;
+    for _ in 0..file_count {
+        let size = read_u32(cabinet, offset)? as usize;
+        let folder_offset = read_u32(cabinet, offset + 4)? as usize;
+        let folder = read_u16(cabinet, offset + 8)? as usize;
+        let date = read_u16(cabinet, offset + 10)?;
+        let time = read_u16(cabinet, offset + 12)?;
+        let attribs = read_u16(cabinet, offset + 14)?;
+
+        let name_start = offset + FILE_HEADER_SIZE;
+        let name_len = cabinet
+            .get(name_start..)
+            .and_then(|rest| rest.iter().position(|byte| *byte == 0))
+            .ok_or_else(|| eyre!("cabinet is truncated"))?;
+        let name = cabinet[name_start..name_start + name_len]
+            .iter()
+            .map(|byte| if *byte == b'\\' { b'/' } else { *byte })
+            .collect::<Vec<_>>();
+        offset = name_start + name_len + 1;
+
+        let relative = Path::new(OsStr::from_bytes(&name));
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(eyre!("{} is not a relative path", relative.display()));
+        }
+
+        let data = folders
+            .get(folder)
+            .and_then(|data| data.get(folder_offset..folder_offset + size))
+            .ok_or_else(|| eyre!("{} is out of bounds", relative.display()))?;
+        files.push(CabFile {
+            path: Path::new("/").join(relative),
+            data: data.to_vec(),
+            mtime: parse_dos_datetime(date, time),
+            attribs,
+        });
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::artifact::memory::MemoryArtifact;
+    use crate::fs::TempDir;
+    use crate::util::run_if_installed;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    async fn produce(memfs: MemFS, path: &Path) -> Result<CabArtifact> {
+        CabProducerBuilder::new("cab")
+            .path(path)
+            .uncompressed("/usr/share/media")
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_files_survive_a_round_trip() -> Result<()> {
+        // Big enough to span several data blocks.
+        let big = (0..200_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let memfs = MemFS::new();
+        let fs = memfs.fs();
+        fs.create_dir_all("/usr/bin").await?;
+        fs.write("/usr/bin/app", b"#!/bin/sh\necho hi\n").await?;
+        fs.set_permissions("/usr/bin/app", MemPermissions::from_mode(0o755))
+            .await?;
+        fs.create_dir_all("/usr/share/media").await?;
+        fs.write("/usr/share/media/big.bin", &big).await?;
+        fs.create_dir_all("/etc").await?;
+        fs.write("/etc/app.conf", b"key = value").await?;
+        memfs.set_mtime("/etc/app.conf", 1_700_000_000);
+
+        let tmp = TempDir::new().await?;
+        let cab = produce(memfs, &tmp.path_view().join("app.cab")).await?;
+
+        // One compressed folder, and one for the uncompressed media.
+        let cabinet = tokio::fs::read(&cab.path).await?;
+        assert_eq!(2, read_u16(&cabinet, 26)?);
+        assert_eq!(3, read_u16(&cabinet, 28)?);
+
+        let extracted = cab.extract().await?;
+        let fs = extracted.fs();
+        assert_eq!(
+            b"#!/bin/sh\necho hi\n".to_vec(),
+            fs.read("/usr/bin/app").await?
+        );
+        assert_eq!(big, fs.read("/usr/share/media/big.bin").await?);
+        assert_eq!(b"key = value".to_vec(), fs.read("/etc/app.conf").await?);
+        assert_eq!(
+            0o755,
+            fs.metadata("/usr/bin/app").await?.permissions().mode() & 0o777
+        );
+        assert_eq!(Some(1_700_000_000), extracted.mtime("/etc/app.conf"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_external_tools_extract_the_cabinet() -> Result<()> {
+        let big = (0..200_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect::<Vec<_>>();
+        let memfs = MemFS::new();
+        let fs = memfs.fs();
+        fs.create_dir_all("/usr/bin").await?;
+        fs.write("/usr/bin/app", b"#!/bin/sh\necho hi\n").await?;
+        fs.create_dir_all("/usr/share/media").await?;
+        fs.write("/usr/share/media/big.bin", &big).await?;
+
+        let tmp = TempDir::new().await?;
+        let cab = produce(memfs, &tmp.path_view().join("app.cab")).await?;
+
+        // So a bug shared by the writer and `read_cab` can't go unnoticed.
+        for (tool, args) in [
+            ("cabextract", vec!["-q", "-d", "{dir}", "{cab}"]),
+            ("gcab", vec!["-x", "-C", "{dir}", "{cab}"]),
+        ] {
+            let dir = tmp.path_view().join(tool);
+            tokio::fs::create_dir_all(&dir).await?;
+            let args = args.into_iter().map(|arg| match arg {
+                "{dir}" => dir.clone().into_os_string(),
+                "{cab}" => cab.path.clone().into_os_string(),
+                arg => arg.into(),
+            });
+            if run_if_installed(tool, args).await?.is_none() {
+                continue;
+            }
+
+            assert_eq!(
+                b"#!/bin/sh\necho hi\n".to_vec(),
+                tokio::fs::read(dir.join("usr/bin/app")).await?,
+                "{tool} extracted the wrong data"
+            );
+            assert_eq!(
+                big,
+                tokio::fs::read(dir.join("usr/share/media/big.bin")).await?,
+                "{tool} extracted the wrong data"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_blocks_are_rejected() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().write("/README", b"hello from peckish!").await?;
+
+        let tmp = TempDir::new().await?;
+        let cab = produce(memfs, &tmp.path_view().join("readme.cab")).await?;
+
+        let mut cabinet = tokio::fs::read(&cab.path).await?;
+        let last = cabinet.len() - 1;
+        cabinet[last] ^= 0xff;
+        tokio::fs::write(&cab.path, cabinet).await?;
+
+        let err = cab.extract().await.unwrap_err().to_string();
+        assert!(err.contains("bad checksum"), "{err}");
+
+        Ok(())
+    }
+}
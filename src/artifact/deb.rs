@@ -1,24 +1,39 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use disk_drive::DiskDrive;
-use eyre::Result;
-use flop::ar::ArFloppyDisk;
-use flop::tar::TarFloppyDisk;
+use eyre::{eyre, Result};
 use floppy_disk::mem::MemOpenOptions;
 use floppy_disk::tokio_fs::TokioFloppyDisk;
-use floppy_disk::{FloppyDisk, FloppyMetadata, FloppyOpenOptions};
+use floppy_disk::{FloppyDirEntry, FloppyDisk, FloppyMetadata, FloppyOpenOptions, FloppyReadDir};
+use itertools::Itertools;
 use regex::Regex;
+use serde::Serialize;
 use smoosh::CompressionType;
-use tokio::io::AsyncReadExt;
 use tracing::*;
 
-use crate::artifact::get_artifact_size;
-use crate::artifact::memory::EmptyArtifact;
-use crate::artifact::tarball::{TarballProducer, TarballProducerBuilder};
-use crate::fs::{MemFS, TempDir};
+use crate::artifact::memory::{EmptyArtifact, MemoryArtifact};
+use crate::artifact::tarball::{
+    EntryNames, TarballArtifact, TarballProducer, TarballProducerBuilder,
+};
+use crate::fs::{MemFS, MergePolicy, TempDir};
+use crate::util::changelog::{render_debian, ChangelogEntry};
 use crate::util::config::Injection;
+use crate::util::debuginfo::split_debug_info;
+use crate::util::{compress, get_current_time, md5_digest, DIGEST_BUFFER_SIZE};
 
-use super::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+use super::{
+    check_compatibility, input_package_path, Artifact, ArtifactKind, ArtifactProducer, KeptOwners,
+    PackageInfo, SelfBuilder, SelfValidation,
+};
+
+/// The current .deb format version.
+pub const DEB_FORMAT_VERSION: &str = "2.0";
+
+/// Top-level directories that packages are expected to install into.
+/// Everything else, ex. `/home` or `/tmp`, belongs to users or the admin.
+const FHS_DIRECTORIES: &[&str] = &[
+    "bin", "boot", "etc", "lib", "lib32", "lib64", "libx32", "opt", "sbin", "srv", "usr", "var",
+];
 
 /// A Debian package. This is a **non-compressed** ar archive.
 #[derive(Debug, Clone)]
@@ -28,6 +43,8 @@ pub struct DebArtifact {
     pub control: Option<ControlFile>,
     pub postinst: Option<String>,
     pub prerm: Option<String>,
+    /// The companion `-dbg` package, if debug symbols were split out.
+    pub debug_package: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +55,8 @@ pub struct ControlFile {
     pub priority: String,
     pub architecture: String,
     pub depends: String,
+    pub pre_depends: String,
+    pub breaks: String,
     pub suggests: String,
     pub conflicts: String,
     pub replaces: String,
@@ -46,36 +65,165 @@ pub struct ControlFile {
     pub description: String,
 }
 
+impl ControlFile {
+    /// Parse a `control` file. Fields peckish doesn't know about are ignored,
+    /// and continuation lines are folded into the field they belong to.
+    pub fn parse(control: &str) -> Result<Self> {
+        let mut fields: HashMap<String, String> = HashMap::new();
+        let mut last_field = None;
+        for line in control.lines() {
+            if line.starts_with([' ', '\t']) {
+                if let Some(value) = last_field.as_ref().and_then(|f| fields.get_mut(f)) {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+            } else if let Some((field, value)) = line.split_once(':') {
+                let field = field.trim().to_lowercase();
+                fields.insert(field.clone(), value.trim().to_string());
+                last_field = Some(field);
+            }
+        }
+
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let installed_size = match fields.get("installed-size") {
+            Some(size) => size
+                .parse()
+                .map_err(|e| eyre!("invalid Installed-Size `{size}`: {e}"))?,
+            None => 0,
+        };
+
+        Ok(Self {
+            package: field("package"),
+            version: field("version"),
+            section: field("section"),
+            priority: field("priority"),
+            architecture: field("architecture"),
+            depends: field("depends"),
+            pre_depends: field("pre-depends"),
+            breaks: field("breaks"),
+            suggests: field("suggests"),
+            conflicts: field("conflicts"),
+            replaces: field("replaces"),
+            installed_size,
+            maintainer: field("maintainer"),
+            description: field("description"),
+        })
+    }
+}
+
+impl DebArtifact {
+    /// Unpack the control archive, with the `control` file, maintainer
+    /// scripts, and md5sums.
+    pub async fn control_fs(&self) -> Result<MemFS> {
+        let members = read_deb(&self.path).await?;
+        let (name, data) = match members_named(&members, "control.tar").as_slice() {
+            [control] => *control,
+            [] => {
+                return Err(eyre!(
+                    "{} does not contain a control.tar member",
+                    self.path.display()
+                ))
+            }
+            _ => {
+                return Err(eyre!(
+                    "{} contains more than one control archive",
+                    self.path.display()
+                ))
+            }
+        };
+
+        let tmp = TempDir::new().await?;
+        let control_tar_path = tmp.path_view().join(name);
+        tokio::fs::write(&control_tar_path, data).await?;
+
+        TarballArtifact {
+            name: format!("{} {name}", self.name),
+            path: control_tar_path,
+        }
+        .extract()
+        .await
+    }
+
+    /// Read the package's `control` file.
+    pub async fn read_control(&self) -> Result<ControlFile> {
+        let control_fs = self.control_fs().await?;
+        let control = control_fs
+            .fs()
+            .read_to_string("/control")
+            .await
+            .map_err(|e| eyre!("{} has no control file: {e}", self.path.display()))?;
+        ControlFile::parse(&control)
+    }
+
+    /// Read the package's `conffiles` list, one entry per line. Packages
+    /// without the file have no conffiles.
+    pub async fn read_conffiles(&self) -> Result<Vec<String>> {
+        let control_fs = self.control_fs().await?;
+        Ok(match control_fs.fs().read_to_string("/conffiles").await {
+            Ok(conffiles) => conffiles
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(_) => vec![],
+        })
+    }
+}
+
+/// The path a `conffiles` entry is for. Newer dpkg allows flags before the
+/// path, ex. `remove-on-upgrade /etc/old.conf`.
+fn conffile_path(entry: &str) -> &str {
+    entry.split_whitespace().last().unwrap_or(entry)
+}
+
+/// Whether a `conffiles` entry is for a file that dpkg removes on upgrade.
+/// Those aren't shipped in the package, so they aren't expected to exist.
+fn is_removed_on_upgrade(entry: &str) -> bool {
+    let mut words = entry.split_whitespace().rev().skip(1);
+    words.any(|flag| flag == "remove-on-upgrade")
+}
+
 #[async_trait::async_trait]
 impl Artifact for DebArtifact {
     fn name(&self) -> &str {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Deb
+    }
+
     async fn extract(&self) -> Result<MemFS> {
-        let fs = MemFS::new();
         let tmp = TempDir::new().await?;
-        let host = TokioFloppyDisk::new(Some(tmp.path_view()));
-        let deb = ArFloppyDisk::open(&self.path).await?;
-
-        let control_tar = deb
-            .find_in_dir("/", "control.tar")
-            .await?
-            .expect("control.tar was not present in deb!?");
-
-        let data_tar = deb
-            .find_in_dir("/", "data.tar")
-            .await?
-            .expect("data.tar was not present in deb!?");
-
-        DiskDrive::copy_from_src(&deb, &host, "/").await?;
-        DiskDrive::copy_from_src(&deb, &host, &control_tar).await?;
-        DiskDrive::copy_from_src(&deb, &host, &data_tar).await?;
+        let members = read_deb(&self.path).await?;
+        let data_members = members_named(&members, "data.tar");
+        if data_members.is_empty() {
+            return Err(eyre!(
+                "{} does not contain a data.tar member",
+                self.path.display()
+            ));
+        }
 
-        let data = TarFloppyDisk::open(tmp.path_view().join(&data_tar)).await?;
-        DiskDrive::copy_between(&data, &*fs).await?;
+        // Large debs may split their data across several members, so every
+        // one of them is unpacked, in order.
+        let memfs = MemFS::new();
+        for (i, (name, data)) in data_members.into_iter().enumerate() {
+            // Write the member out in one go, since the tar reader needs a
+            // real path and has to see the whole file.
+            let data_tar_path = tmp.path_view().join(format!("{i}-{name}"));
+            tokio::fs::write(&data_tar_path, data).await?;
+
+            let member_fs = TarballArtifact {
+                name: format!("{} {name}", self.name),
+                path: data_tar_path,
+            }
+            .extract()
+            .await?;
+            memfs.merge(&member_fs, MergePolicy::Overwrite).await?;
+        }
 
-        Ok(fs)
+        Ok(memfs)
     }
 
     fn try_clone(&self) -> Result<Box<dyn Artifact>> {
@@ -83,7 +231,26 @@ impl Artifact for DebArtifact {
     }
 
     fn paths(&self) -> Option<Vec<PathBuf>> {
-        Some(vec![self.path.clone()])
+        let mut paths = vec![self.path.clone()];
+        if let Some(debug_package) = &self.debug_package {
+            paths.push(debug_package.clone());
+        }
+        Some(paths)
+    }
+
+    /// Artifacts from the config don't have their control file parsed yet,
+    /// so it's read from the package.
+    async fn package_info(&self) -> Result<Option<PackageInfo>> {
+        let control = match &self.control {
+            Some(control) => control.clone(),
+            None => self.read_control().await?,
+        };
+        Ok(Some(PackageInfo {
+            name: control.package,
+            version: control.version,
+            arch: control.architecture,
+            description: control.description,
+        }))
     }
 }
 
@@ -108,31 +275,35 @@ impl SelfValidation for DebArtifact {
         // /debian-binary
         // /control.tar.gz
         // /data.tar.gz
-        let deb = ArFloppyDisk::open(&self.path).await?;
+        let members = read_deb(&self.path).await?;
 
-        if deb.find_in_dir("/", "debian-binary").await?.is_none() {
+        if members_named(&members, "debian-binary").is_empty() {
             errors.push(format!(
                 "deb artifact does not contain debian-binary: {:#?}",
                 self.path
             ));
         }
 
-        if deb.find_in_dir("/", "control.tar").await?.is_none() {
-            errors.push(format!(
+        match members_named(&members, "control.tar").as_slice() {
+            [] => errors.push(format!(
                 "deb artifact does not contain control.tar: {:#?}",
                 self.path
-            ));
+            )),
+            [_] => {}
+            control => errors.push(format!(
+                "deb artifact contains more than one control archive ({}): {:#?}",
+                control.iter().map(|(name, _)| name.as_str()).join(", "),
+                self.path
+            )),
         }
 
-        if deb.find_in_dir("/", "data.tar").await?.is_none() {
+        if members_named(&members, "data.tar").is_empty() {
             errors.push(format!(
                 "deb artifact does not contain data.tar: {:#?}",
                 self.path
             ));
         }
 
-        deb.close().await?;
-
         if !errors.is_empty() {
             return Err(eyre::eyre!(
                 "Debian artifact is invalid:\n{}",
@@ -150,6 +321,7 @@ pub struct DebArtifactBuilder {
     control: Option<ControlFile>,
     postinst: Option<String>,
     prerm: Option<String>,
+    debug_package: Option<PathBuf>,
 }
 
 #[allow(unused)]
@@ -173,6 +345,11 @@ impl DebArtifactBuilder {
         self.prerm = Some(prerm.into());
         self
     }
+
+    pub fn debug_package<P: Into<PathBuf>>(mut self, debug_package: P) -> Self {
+        self.debug_package = Some(debug_package.into());
+        self
+    }
 }
 
 impl SelfBuilder for DebArtifactBuilder {
@@ -185,6 +362,7 @@ impl SelfBuilder for DebArtifactBuilder {
             control: None,
             postinst: None,
             prerm: None,
+            debug_package: None,
         }
     }
 
@@ -195,17 +373,30 @@ impl SelfBuilder for DebArtifactBuilder {
             control: self.control.clone(),
             postinst: self.postinst.clone(),
             prerm: self.prerm.clone(),
+            debug_package: self.debug_package.clone(),
         })
     }
 }
 
 /// A Debian package producer. This is ultimately an ar archive.
 /// TODO: Support all control file features
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DebProducer {
     pub name: String,
     pub path: PathBuf,
+    #[serde(serialize_with = "crate::util::config::serialize_compression")]
     pub compression: CompressionType,
+    /// Overrides `compression` for `control.tar`. It's tiny, so leaving it
+    /// uncompressed costs little and speeds up `dpkg --info`.
+    #[serde(serialize_with = "crate::util::config::serialize_optional_compression")]
+    pub control_compression: Option<CompressionType>,
+    /// Overrides `compression` for `data.tar`.
+    #[serde(serialize_with = "crate::util::config::serialize_optional_compression")]
+    pub data_compression: Option<CompressionType>,
+    /// Compress `data.tar` like `gzip --rsyncable`, so that mirrors can
+    /// sync new versions of the package as deltas. Needs gzip data
+    /// compression.
+    pub rsyncable: bool,
     pub prerm: Option<PathBuf>,
     pub postinst: Option<PathBuf>,
     pub injections: Vec<Injection>,
@@ -214,16 +405,82 @@ pub struct DebProducer {
     pub package_architecture: String,
     pub package_version: String,
     pub package_depends: String,
+    /// Packages that have to be fully installed before this one is even
+    /// unpacked, not just configured before it is.
+    pub package_pre_depends: String,
+    /// Versions of other packages that this one breaks. Unlike conflicts,
+    /// both can be unpacked at once, so they can be upgraded together.
+    pub package_breaks: String,
     pub package_description: String,
+    /// Split debug info out of ELF binaries into a companion `-dbg` package.
+    pub split_debug: bool,
+    /// The .deb format version written to `debian-binary`. Only 2.x is
+    /// understood by dpkg.
+    pub format_version: String,
+    /// Installed as `/usr/share/doc/<package>/changelog.Debian.gz`.
+    pub changelog: Option<Vec<ChangelogEntry>>,
+    /// Keep the input deb's control archive, ex. its control file and
+    /// maintainer scripts, instead of generating one from the `package_*`
+    /// fields. Only the md5sums and `Installed-Size` are updated.
+    pub inherit_metadata: bool,
+    /// Config files that dpkg shouldn't overwrite if they were changed,
+    /// ex. `/etc/app.conf`. If the input is a deb, its conffiles are kept
+    /// too.
+    pub package_conffiles: Vec<PathBuf>,
 }
 
 impl DebProducer {
-    fn tar_file_extension(&self) -> String {
-        match self.compression {
+    fn control_compression(&self) -> CompressionType {
+        self.control_compression.unwrap_or(self.compression)
+    }
+
+    fn data_compression(&self) -> CompressionType {
+        self.data_compression.unwrap_or(self.compression)
+    }
+
+    fn tar_file_extension(compression: CompressionType) -> String {
+        match compression {
             CompressionType::None => "".into(),
             ct => format!(".{}", ct.file_extension()),
         }
     }
+
+    /// `foo.deb` -> `foo-dbg.deb`
+    pub(crate) fn debug_package_path(&self) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.package_name.clone());
+        self.path.with_file_name(format!("{stem}-dbg.deb"))
+    }
+
+    async fn produce_debug_package(&self, debug_fs: MemFS) -> Result<DebArtifact> {
+        info!("packaging debug symbols...");
+        let producer = DebProducer {
+            name: format!("{} (debug symbols)", self.name),
+            path: self.debug_package_path(),
+            prerm: None,
+            postinst: None,
+            injections: vec![],
+            package_name: format!("{}-dbg", self.package_name),
+            package_depends: format!("{} (= {})", self.package_name, self.package_version),
+            package_pre_depends: "".into(),
+            package_breaks: "".into(),
+            package_description: format!("debug symbols for {}", self.package_name),
+            split_debug: false,
+            inherit_metadata: false,
+            package_conffiles: vec![],
+            ..self.clone()
+        };
+
+        producer
+            .produce_from(&MemoryArtifact {
+                name: producer.name.clone(),
+                fs: debug_fs,
+            })
+            .await
+    }
 }
 
 #[async_trait::async_trait]
@@ -238,53 +495,227 @@ impl ArtifactProducer for DebProducer {
         &self.injections
     }
 
+    async fn can_produce_from(&self, memfs: &MemFS) -> Result<Vec<String>> {
+        let mut warnings = vec![];
+        let mut entries = memfs.fs().read_dir("/").await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let is_fhs = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| FHS_DIRECTORIES.contains(&name));
+            if !is_fhs {
+                warnings.push(format!(
+                    "{} is outside of the FHS, and dpkg may clobber files users put there",
+                    path.display()
+                ));
+            }
+        }
+
+        Ok(warnings)
+    }
+
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<Self::Output> {
+        let input_conffiles = input_conffiles(previous).await?;
+        if !self.inherit_metadata {
+            return self.produce(previous, None, &input_conffiles).await;
+        }
+
+        let input = DebArtifactBuilder::new(previous.name())
+            .path(input_package_path(&self.name, previous)?)
+            .build()?;
+        let control = input.read_control().await?;
+        if control.package.is_empty() || control.version.is_empty() {
+            return Err(eyre!(
+                "cannot inherit metadata from {}: its control file has no Package or Version",
+                input.path.display()
+            ));
+        }
+
+        // The inherited names are still needed for ex. the changelog path and
+        // the debug package.
+        DebProducer {
+            package_name: control.package,
+            package_maintainer: control.maintainer,
+            package_architecture: control.architecture,
+            package_version: control.version,
+            package_depends: control.depends,
+            package_pre_depends: control.pre_depends,
+            package_breaks: control.breaks,
+            package_description: control.description,
+            prerm: None,
+            postinst: None,
+            inherit_metadata: false,
+            ..self.clone()
+        }
+        .produce(previous, Some(input.control_fs().await?), &input_conffiles)
+        .await
+    }
+}
+
+/// The conffiles of the deb an artifact was read from, so that repackaging
+/// it keeps them. Other artifacts have none.
+async fn input_conffiles(previous: &dyn Artifact) -> Result<Vec<String>> {
+    if previous.kind() != ArtifactKind::Deb {
+        return Ok(vec![]);
+    }
+    let Some(path) = previous.paths().and_then(|paths| paths.into_iter().next()) else {
+        return Ok(vec![]);
+    };
+
+    DebArtifactBuilder::new(previous.name())
+        .path(path)
+        .build()?
+        .read_conffiles()
+        .await
+}
+
+impl DebProducer {
+    /// The input's conffiles, followed by the configured ones. Inherited
+    /// conffiles that are no longer in the package, ex. because an injection
+    /// deleted them, are dropped, since dpkg refuses to install a package
+    /// with a missing conffile. `remove-on-upgrade` entries are kept, since
+    /// their files are meant to be missing.
+    async fn merge_conffiles(&self, memfs: &MemFS, inherited: &[String]) -> Result<Vec<String>> {
+        let mut conffiles: Vec<String> = vec![];
+        let is_listed = |conffiles: &[String], path: &str| {
+            conffiles.iter().any(|entry| conffile_path(entry) == path)
+        };
+
+        for entry in inherited {
+            let path = conffile_path(entry);
+            if !is_removed_on_upgrade(entry) && (*memfs).metadata(path).await.is_err() {
+                warn!("dropping conffile {path}, since it's no longer in the package");
+            } else if !is_listed(&conffiles, path) {
+                conffiles.push(entry.clone());
+            }
+        }
+
+        for path in &self.package_conffiles {
+            if (*memfs).metadata(path).await.is_err() {
+                return Err(eyre!("conffile {} is not in the package", path.display()));
+            }
+            let path = path.to_string_lossy();
+            if !is_listed(&conffiles, &path) {
+                conffiles.push(path.to_string());
+            }
+        }
+
+        Ok(conffiles)
+    }
+
+    /// Build the package. If `inherited_control` is set, it's used as the
+    /// base of the control archive instead of an empty one.
+    async fn produce(
+        &self,
+        previous: &dyn Artifact,
+        inherited_control: Option<MemFS>,
+        inherited_conffiles: &[String],
+    ) -> Result<DebArtifact> {
         let tmp = TempDir::new().await?;
+        let mut memfs = previous.extract().await?;
+        let owners = KeptOwners::new(previous, &memfs, &self.injections).await?;
+        self.inject(&mut memfs).await?;
+        check_compatibility(self, &memfs).await?;
+        // data.tar is written with the memfs's owners, so files from the host
+        // mustn't keep the building user's.
+        owners.apply(&memfs).await?;
+
+        if let Some(changelog) = &self.changelog {
+            debug!("writing changelog...");
+            let doc_dir = Path::new("/usr/share/doc").join(&self.package_name);
+            let changelog = render_debian(&self.package_name, changelog)?;
+            (*memfs).create_dir_all(&doc_dir).await?;
+            (*memfs)
+                .write(
+                    doc_dir.join("changelog.Debian.gz"),
+                    compress(changelog.as_bytes(), CompressionType::Gzip).await?,
+                )
+                .await?;
+        }
+
+        let debug_fs = if self.split_debug {
+            info!("splitting debug symbols...");
+            split_debug_info(&memfs).await?
+        } else {
+            None
+        };
+
+        let data = MemoryArtifact {
+            name: "data".into(),
+            fs: memfs.clone(),
+        };
+
         // Create data.tar from previous artifact in tmp using TarballProducer
         info!("packaging data files...");
         debug!("producing data.tar from previous artifact...");
-        let data_tar = tmp
-            .path_view()
-            .join(format!("data.tar{}", self.tar_file_extension()));
+        let data_tar = tmp.path_view().join(format!(
+            "data.tar{}",
+            Self::tar_file_extension(self.data_compression())
+        ));
         let _tar_artifact = TarballProducer {
             name: "data.tar.gz".to_string(),
             path: data_tar.clone(),
-            compression: self.compression,
-            injections: self.injections.clone(),
+            compression: self.data_compression(),
+            sparse: false,
+            entry_names: EntryNames::DotRelative,
+            file_mtime: None,
+            dir_mtime: None,
+            rsyncable: self.rsyncable,
+            remap_owner: None,
+            split_size: None,
+            injections: vec![],
         }
-        .produce_from(previous)
+        .produce_from(&data)
         .await?;
 
         // Create control.tar from control file in tmp
         info!("packaging metadata files...");
         debug!("producing control.tar...");
-        let control_tar = tmp
-            .path_view()
-            .join(format!("control.tar{}", self.tar_file_extension()));
+        let control_tar = tmp.path_view().join(format!(
+            "control.tar{}",
+            Self::tar_file_extension(self.control_compression())
+        ));
 
         // Write control file to control.tar
-        let installed_size = get_artifact_size(previous).await?;
-        let control_data = indoc::formatdoc! {r#"
-            Package: {name}
-            Maintainer: {maintainer}
-            Architecture: {architecture}
-            Version: {version}
-            Depends: {depends}
-            Description: {description}
-            Installed-Size: {installed_size}
-        "#,
-            name = self.package_name,
-            maintainer = self.package_maintainer,
-            architecture = self.package_architecture,
-            version = self.package_version,
-            depends = self.package_depends,
-            description = self.package_description,
-            installed_size = installed_size,
+        let installed_size = memfs.size().await?;
+        let relations = [
+            ("Pre-Depends", &self.package_pre_depends),
+            ("Breaks", &self.package_breaks),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(field, value)| format!("\n{field}: {value}"))
+        .collect::<String>();
+        let control_data = match &inherited_control {
+            Some(control_fs) => with_installed_size(
+                &control_fs.fs().read_to_string("/control").await?,
+                installed_size,
+            ),
+            None => indoc::formatdoc! {r#"
+                Package: {name}
+                Maintainer: {maintainer}
+                Architecture: {architecture}
+                Version: {version}
+                Depends: {depends}{relations}
+                Description: {description}
+                Installed-Size: {installed_size}
+            "#,
+                name = self.package_name,
+                maintainer = self.package_maintainer,
+                architecture = self.package_architecture,
+                version = self.package_version,
+                depends = self.package_depends,
+                description = self.package_description,
+                installed_size = installed_size,
+            },
         };
+        let control = ControlFile::parse(&control_data)?;
 
         let control_tar_builder = TarballProducerBuilder::new("control.tar.gz")
             .path(control_tar.clone())
-            .compression(self.compression)
+            .compression(self.control_compression())
+            .entry_names(EntryNames::DotRelative)
             .inject(Injection::Create {
                 path: "/control".into(),
                 content: control_data.into_bytes(),
@@ -293,7 +724,7 @@ impl ArtifactProducer for DebProducer {
         // Write self.prerm and self.postinst into control.tar if they exist
         let control_tar_builder = if let Some(prerm) = &self.prerm {
             debug!("wrote prerm file {:?} to control.tar", self.prerm);
-            control_tar_builder.inject(Injection::Copy {
+            control_tar_builder.inject(Injection::HostFile {
                 src: prerm.clone(),
                 dest: "/prerm".into(),
             })
@@ -302,7 +733,7 @@ impl ArtifactProducer for DebProducer {
         };
         let control_tar_builder = if let Some(postinst) = &self.postinst {
             debug!("wrote postinst file {:?} to control.tar", self.postinst);
-            control_tar_builder.inject(Injection::Copy {
+            control_tar_builder.inject(Injection::HostFile {
                 src: postinst.clone(),
                 dest: "/postinst".into(),
             })
@@ -311,33 +742,7 @@ impl ArtifactProducer for DebProducer {
         };
 
         info!("computing checksums...");
-        // Compute the md5sums of every file in the memfs
-        let mut md5sums = vec![];
-        let mut memfs = previous.extract().await?;
-        self.inject(&mut memfs).await?;
-        let paths = nyoom::walk(&*memfs, "/").await?;
-        for path in paths {
-            if (*memfs).metadata(&path).await?.is_file() {
-                let mut file = MemOpenOptions::new()
-                    .read(true)
-                    .open(&*memfs, &path)
-                    .await?;
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf).await?;
-                let md5sum = md5::compute(buf);
-                let md5sum = format!("{:x}", md5sum);
-                debug!("md5sum of {}: {}", path.display(), md5sum);
-                md5sums.push((path, md5sum));
-            }
-        }
-
-        // Write formatted md5sums to control.tar as /md5sums
-        let md5sums = md5sums
-            .into_iter()
-            .map(|(path, md5sum)| format!("{}  {}", md5sum, path.to_string_lossy()))
-            .collect::<Vec<_>>()
-            .join("\n");
-
+        let md5sums = md5sums(&memfs).await?;
         debug!("computed md5sums:\n{}", md5sums);
 
         let control_tar_builder = control_tar_builder.inject(Injection::Create {
@@ -346,64 +751,202 @@ impl ArtifactProducer for DebProducer {
         });
         debug!("wrote md5sums to control.tar");
 
+        // An inherited control archive has its own `/conffiles`, which would
+        // otherwise still list the conffiles that were dropped.
+        let conffiles = self.merge_conffiles(&memfs, inherited_conffiles).await?;
+        let inherits_conffiles = match &inherited_control {
+            Some(control_fs) => control_fs.fs().metadata("/conffiles").await.is_ok(),
+            None => false,
+        };
+        let control_tar_builder = if conffiles.is_empty() && inherits_conffiles {
+            debug!("removed inherited conffiles from control.tar");
+            control_tar_builder.inject(Injection::Delete {
+                path: "/conffiles".into(),
+            })
+        } else if conffiles.is_empty() {
+            control_tar_builder
+        } else {
+            debug!("wrote {} conffiles to control.tar", conffiles.len());
+            control_tar_builder.inject(Injection::Create {
+                path: "/conffiles".into(),
+                content: (conffiles.join("\n") + "\n").into_bytes(),
+            })
+        };
+
         // Finish control.tar
-        control_tar_builder
-            .build()?
-            .produce_from(&EmptyArtifact::new("control.tar"))
-            .await?;
+        let control_tar_producer = control_tar_builder.build()?;
+        match &inherited_control {
+            Some(control_fs) => {
+                control_tar_producer
+                    .produce_from(&MemoryArtifact {
+                        name: "control.tar".into(),
+                        fs: control_fs.clone(),
+                    })
+                    .await?
+            }
+            None => {
+                control_tar_producer
+                    .produce_from(&EmptyArtifact::new("control.tar"))
+                    .await?
+            }
+        };
         debug!("finished control.tar");
 
         // Create .deb ar archive from debian-binary, control.tar, and data.tar
         info!("building final .deb...");
 
-        let host = TokioFloppyDisk::new(Some(tmp.path_view()));
-        let debfs = ArFloppyDisk::open(&self.path).await?;
-        debug!("write debian-binary");
-        debfs.write(Path::new("/debian-binary"), b"2.0\n").await?;
-        debug!("write control tar");
-        DiskDrive::copy_from_src(&host, &debfs, control_tar.file_name().unwrap()).await?;
-        debug!("write data tar");
-        DiskDrive::copy_from_src(&host, &debfs, data_tar.file_name().unwrap()).await?;
+        let members = vec![
+            (
+                "debian-binary".to_string(),
+                format!("{}\n", self.format_version).into_bytes(),
+            ),
+            (
+                control_tar
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string(),
+                tokio::fs::read(&control_tar).await?,
+            ),
+            (
+                data_tar.file_name().unwrap().to_string_lossy().to_string(),
+                tokio::fs::read(&data_tar).await?,
+            ),
+        ];
+        write_deb(&self.path, &members).await?;
 
         debug!("done!");
 
         let prerm = if let Some(prerm) = &self.prerm {
             Some(tokio::fs::read_to_string(prerm).await?)
+        } else if let Some(control_fs) = &inherited_control {
+            control_fs.fs().read_to_string("/prerm").await.ok()
         } else {
             None
         };
 
         let postinst = if let Some(postinst) = &self.postinst {
             Some(tokio::fs::read_to_string(postinst).await?)
+        } else if let Some(control_fs) = &inherited_control {
+            control_fs.fs().read_to_string("/postinst").await.ok()
         } else {
             None
         };
 
-        debfs.close().await?;
+        let debug_package = if let Some(debug_fs) = debug_fs {
+            Some(self.produce_debug_package(debug_fs).await?.path)
+        } else {
+            None
+        };
 
         Ok(DebArtifact {
             name: self.name.clone(),
             path: self.path.clone(),
-            control: Some(ControlFile {
-                package: self.package_name.clone(),
-                maintainer: self.package_maintainer.clone(),
-                architecture: self.package_architecture.clone(),
-                version: self.package_version.clone(),
-                depends: self.package_depends.clone(),
-                description: self.package_description.clone(),
-                section: "".into(),
-                priority: "".into(),
-                suggests: "".into(),
-                conflicts: "".into(),
-                replaces: "".into(),
-                installed_size,
-            }),
+            control: Some(control),
             prerm,
             postinst,
+            debug_package,
         })
     }
 }
 
+/// Replace a control file's `Installed-Size`, adding it if it's missing.
+fn with_installed_size(control: &str, installed_size: u64) -> String {
+    let mut lines = control
+        .lines()
+        .filter(|line| !line.to_lowercase().starts_with("installed-size:"))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    lines.push(format!("Installed-Size: {installed_size}"));
+    lines.join("\n") + "\n"
+}
+
+/// Render the md5sums of every file in the memfs, one `md5  path` line per
+/// file. Lines are sorted by path so that the output doesn't depend on the
+/// order the memfs is walked in.
+async fn md5sums(memfs: &MemFS) -> Result<String> {
+    let mut md5sums = vec![];
+    let paths = nyoom::walk(memfs.fs(), "/").await?;
+    for path in paths {
+        if memfs.fs().metadata(&path).await?.is_file() {
+            let mut file = MemOpenOptions::new()
+                .read(true)
+                .open(memfs.fs(), &path)
+                .await?;
+            let md5sum = md5_digest(&mut file, DIGEST_BUFFER_SIZE).await?;
+            debug!("md5sum of {}: {}", path.display(), md5sum);
+            md5sums.push((path, md5sum));
+        }
+    }
+    md5sums.sort();
+
+    Ok(md5sums
+        .into_iter()
+        .map(|(path, md5sum)| format!("{}  {}", md5sum, path.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Read every member of a .deb, in archive order. Members are read directly
+/// instead of through flop, since flop only keeps one member per name.
+async fn read_deb(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let deb = tokio::fs::read(path).await?;
+    let mut archive = ar::Archive::new(deb.as_slice());
+    let mut members = vec![];
+    while let Some(entry) = archive.next_entry() {
+        let mut entry =
+            entry.map_err(|e| eyre!("{} is not a valid ar archive: {e}", path.display()))?;
+        let name = String::from_utf8_lossy(entry.header().identifier())
+            .trim_end_matches('/')
+            .to_string();
+        let mut data = vec![];
+        std::io::Read::read_to_end(&mut entry, &mut data)?;
+        members.push((name, data));
+    }
+
+    Ok(members)
+}
+
+/// The members that are exactly `name`, or `name` plus an extension, ex.
+/// `data.tar.xz` for `data.tar`. Unrelated members that happen to share a
+/// prefix, like `data.tarball`, aren't matched.
+fn members_named<'a>(members: &'a [(String, Vec<u8>)], name: &str) -> Vec<&'a (String, Vec<u8>)> {
+    members
+        .iter()
+        .filter(|(member, _)| {
+            member == name
+                || member
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+        .collect()
+}
+
+/// Write the members of a .deb into an ar archive. flop takes member metadata
+/// from the memfs, so the ar is written directly instead, with fixed owners
+/// and modes, and mtimes from `SOURCE_DATE_EPOCH` when it's set, so that the
+/// output is reproducible.
+async fn write_deb(path: &Path, members: &[(String, Vec<u8>)]) -> Result<()> {
+    let mtime = get_current_time()?;
+    let mut buffer = vec![];
+    {
+        let mut archive = ar::Builder::new(&mut buffer);
+        for (name, data) in members {
+            debug!("write {name}");
+            let mut header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+            header.set_mtime(mtime);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mode(0o100644);
+            archive.append(&header, data.as_slice())?;
+        }
+    }
+
+    tokio::fs::write(path, buffer).await?;
+
+    Ok(())
+}
+
 #[async_trait::async_trait]
 impl SelfValidation for DebProducer {
     async fn validate(&self) -> Result<()> {
@@ -423,6 +966,19 @@ impl SelfValidation for DebProducer {
             ))?;
         }
 
+        if self.split_debug
+            && TokioFloppyDisk::new(None)
+                .metadata(self.debug_package_path())
+                .await
+                .is_ok()
+        {
+            return Err(eyre::eyre!(
+                "cannot produce artifact '{}': debug package path already exists: {}",
+                self.name,
+                self.debug_package_path().display()
+            ))?;
+        }
+
         let package_name_regex = Regex::new(r"^[a-z0-9][a-z0-9+-\.]+$")?;
         let package_maintainer_regex = Regex::new(r"^[^<]+( <[^>]+>)?$")?;
         let package_version_regex = Regex::new(r"^[a-z0-9][a-z0-9+._-]*(-\d+)$")?;
@@ -454,6 +1010,40 @@ impl SelfValidation for DebProducer {
             errors.push("package description must not be empty".to_string());
         }
 
+        for (field, value, alternatives) in [
+            ("depends", &self.package_depends, true),
+            ("pre-depends", &self.package_pre_depends, true),
+            ("breaks", &self.package_breaks, false),
+        ] {
+            errors.extend(relation_errors(field, value, alternatives)?);
+        }
+
+        if self.rsyncable && self.data_compression() != CompressionType::Gzip {
+            errors.push("rsyncable needs gzip data compression".to_string());
+        }
+
+        if !Regex::new(r"^2\.\d+$")?.is_match(&self.format_version) {
+            errors.push(format!(
+                "format version {} is invalid, must be 2.x",
+                self.format_version
+            ));
+        }
+
+        for entry in self.changelog.iter().flatten() {
+            if let Err(e) = entry.timestamp() {
+                errors.push(e.to_string());
+            }
+        }
+
+        for path in &self.package_conffiles {
+            if !path.is_absolute() || path == Path::new("/") {
+                errors.push(format!(
+                    "conffile `{}` must be an absolute path to a file",
+                    path.display()
+                ));
+            }
+        }
+
         // validate architecture against all known debian architectures
         let valid_architectures = vec![
             "amd64", "arm64", "armel", "armhf", "i386", "mips", "mips64el", "mipsel", "ppc64el",
@@ -478,10 +1068,46 @@ impl SelfValidation for DebProducer {
     }
 }
 
+/// Check a relation field like `Depends` against dpkg's syntax: a comma
+/// separated list of packages, each with an optional version restriction,
+/// ex. `libc6 (>= 2.34), zlib1g`. Fields that allow alternatives can also
+/// separate packages with `|`.
+fn relation_errors(field: &str, value: &str, alternatives: bool) -> Result<Vec<String>> {
+    let relation_regex = Regex::new(
+        r"^[a-z0-9][a-z0-9+.-]+(:[a-z0-9-]+)?(\s*\((<<|<=|=|>=|>>)\s*[A-Za-z0-9.+~:-]+\))?(\s*\[[^\]]+\])?$",
+    )?;
+
+    let mut errors = vec![];
+    if value.trim().is_empty() {
+        return Ok(errors);
+    }
+    for relation in value.split(',').map(str::trim) {
+        let packages = relation.split('|').map(str::trim).collect::<Vec<_>>();
+        if packages.len() > 1 && !alternatives {
+            errors.push(format!(
+                "{field} relation `{relation}` is invalid, {field} can't have alternatives"
+            ));
+            continue;
+        }
+        for package in packages {
+            if !relation_regex.is_match(package) {
+                errors.push(format!(
+                    "{field} relation `{package}` is invalid, must look like `package (>= 1.0)`"
+                ));
+            }
+        }
+    }
+
+    Ok(errors)
+}
+
 pub struct DebProducerBuilder {
     name: String,
     path: PathBuf,
     compression: CompressionType,
+    control_compression: Option<CompressionType>,
+    data_compression: Option<CompressionType>,
+    rsyncable: bool,
     prerm: Option<PathBuf>,
     postinst: Option<PathBuf>,
     injections: Vec<Injection>,
@@ -490,7 +1116,14 @@ pub struct DebProducerBuilder {
     package_architecture: String,
     package_version: String,
     package_depends: String,
+    package_pre_depends: String,
+    package_breaks: String,
     package_description: String,
+    split_debug: bool,
+    format_version: String,
+    changelog: Option<Vec<ChangelogEntry>>,
+    inherit_metadata: bool,
+    package_conffiles: Vec<PathBuf>,
 }
 
 #[allow(unused)]
@@ -505,6 +1138,21 @@ impl DebProducerBuilder {
         self
     }
 
+    pub fn control_compression(mut self, ct: CompressionType) -> Self {
+        self.control_compression = Some(ct);
+        self
+    }
+
+    pub fn data_compression(mut self, ct: CompressionType) -> Self {
+        self.data_compression = Some(ct);
+        self
+    }
+
+    pub fn rsyncable(mut self, rsyncable: bool) -> Self {
+        self.rsyncable = rsyncable;
+        self
+    }
+
     pub fn prerm<P: Into<PathBuf>>(mut self, prerm: P) -> Self {
         self.prerm = Some(prerm.into());
         self
@@ -545,10 +1193,45 @@ impl DebProducerBuilder {
         self
     }
 
+    pub fn package_pre_depends<S: Into<String>>(mut self, package_pre_depends: S) -> Self {
+        self.package_pre_depends = package_pre_depends.into();
+        self
+    }
+
+    pub fn package_breaks<S: Into<String>>(mut self, package_breaks: S) -> Self {
+        self.package_breaks = package_breaks.into();
+        self
+    }
+
     pub fn package_description<S: Into<String>>(mut self, package_description: S) -> Self {
         self.package_description = package_description.into();
         self
     }
+
+    pub fn split_debug(mut self, split_debug: bool) -> Self {
+        self.split_debug = split_debug;
+        self
+    }
+
+    pub fn format_version<S: Into<String>>(mut self, format_version: S) -> Self {
+        self.format_version = format_version.into();
+        self
+    }
+
+    pub fn changelog_entry(mut self, entry: ChangelogEntry) -> Self {
+        self.changelog.get_or_insert_with(Vec::new).push(entry);
+        self
+    }
+
+    pub fn inherit_metadata(mut self, inherit_metadata: bool) -> Self {
+        self.inherit_metadata = inherit_metadata;
+        self
+    }
+
+    pub fn conffile<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.package_conffiles.push(path.into());
+        self
+    }
 }
 
 impl SelfBuilder for DebProducerBuilder {
@@ -559,6 +1242,9 @@ impl SelfBuilder for DebProducerBuilder {
             name: name.into(),
             path: PathBuf::from("package.deb"),
             compression: CompressionType::None,
+            control_compression: None,
+            data_compression: None,
+            rsyncable: false,
             prerm: None,
             postinst: None,
             injections: vec![],
@@ -567,7 +1253,14 @@ impl SelfBuilder for DebProducerBuilder {
             package_architecture: "".into(),
             package_version: "".into(),
             package_depends: "".into(),
+            package_pre_depends: "".into(),
+            package_breaks: "".into(),
             package_description: "".into(),
+            split_debug: false,
+            format_version: DEB_FORMAT_VERSION.into(),
+            changelog: None,
+            inherit_metadata: false,
+            package_conffiles: vec![],
         }
     }
 
@@ -576,6 +1269,9 @@ impl SelfBuilder for DebProducerBuilder {
             name: self.name.clone(),
             path: self.path.clone(),
             compression: self.compression,
+            control_compression: self.control_compression,
+            data_compression: self.data_compression,
+            rsyncable: self.rsyncable,
             prerm: self.prerm.clone(),
             postinst: self.postinst.clone(),
             injections: self.injections.clone(),
@@ -584,7 +1280,695 @@ impl SelfBuilder for DebProducerBuilder {
             package_architecture: self.package_architecture.clone(),
             package_version: self.package_version.clone(),
             package_depends: self.package_depends.clone(),
+            package_pre_depends: self.package_pre_depends.clone(),
+            package_breaks: self.package_breaks.clone(),
             package_description: self.package_description.clone(),
+            split_debug: self.split_debug,
+            format_version: self.format_version.clone(),
+            changelog: self.changelog.clone(),
+            inherit_metadata: self.inherit_metadata,
+            package_conffiles: self.package_conffiles.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+    use floppy_disk::{FloppyDiskUnixExt, FloppyUnixMetadata};
+    use object::Object;
+
+    use crate::artifact::IncompatibleInput;
+    use crate::fs::test_utils::Fixture;
+    use crate::util::with_source_date_epoch;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_chown_injection_sets_owners_in_data_tar() -> Result<()> {
+        // Host files are owned by whoever builds the package, ex. a CI user.
+        let tmp = TempDir::new().await?;
+        let host_file = tmp.path_view().join("host");
+        tokio::fs::write(&host_file, b"hi").await?;
+
+        let artifact = DebProducerBuilder::new("owners")
+            .path(tmp.path_view().join("owners.deb"))
+            .package_name("owners")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("root-owned files")
+            .inject(Injection::HostFile {
+                src: host_file.clone(),
+                dest: "/usr/bin/app".into(),
+            })
+            .inject(Injection::HostFile {
+                src: host_file.clone(),
+                dest: "/var/www/html/index.html".into(),
+            })
+            .inject(Injection::Chown {
+                path: "/usr/bin/app".into(),
+                uid: 7,
+                gid: 7,
+                recursive: false,
+            })
+            .inject(Injection::HostFile {
+                src: host_file.clone(),
+                dest: "/usr/bin/other".into(),
+            })
+            .inject(Injection::Chown {
+                path: "/var/www".into(),
+                uid: 33,
+                gid: 33,
+                recursive: true,
+            })
+            .build()?
+            .produce_from(&crate::artifact::file::FileArtifactBuilder::new("nothing").build()?)
+            .await?;
+
+        let extracted = artifact.extract().await?;
+        for (path, owner) in [
+            ("/usr", 0),
+            ("/usr/bin", 0),
+            ("/usr/bin/app", 7),
+            ("/usr/bin/other", 0),
+            ("/var/www", 33),
+            ("/var/www/html", 33),
+            ("/var/www/html/index.html", 33),
+        ] {
+            let metadata = extracted.fs().metadata(path).await?;
+            assert_eq!((owner, owner), (metadata.uid()?, metadata.gid()?), "{path}");
+        }
+
+        Ok(())
+    }
+
+    fn conffiles_producer(name: &str, path: PathBuf) -> DebProducerBuilder {
+        DebProducerBuilder::new(name)
+            .path(path)
+            .package_name("app")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("an app with config")
+    }
+
+    #[tokio::test]
+    async fn test_conffiles_survive_repackaging() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let original = conffiles_producer("original", tmp.path_view().join("original.deb"))
+            .inject(Injection::Create {
+                path: "/etc/app.conf".into(),
+                content: b"key = value".to_vec(),
+            })
+            .conffile("/etc/app.conf")
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+        assert_eq!(vec!["/etc/app.conf"], original.read_conffiles().await?);
+
+        let repackaged = conffiles_producer("repackaged", tmp.path_view().join("repackaged.deb"))
+            .inject(Injection::Create {
+                path: "/etc/app.d/extra.conf".into(),
+                content: b"more = values".to_vec(),
+            })
+            .conffile("/etc/app.d/extra.conf")
+            .conffile("/etc/app.conf")
+            .build()?
+            .produce_from(&original)
+            .await?;
+        assert_eq!(
+            vec!["/etc/app.conf", "/etc/app.d/extra.conf"],
+            repackaged.read_conffiles().await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleted_conffiles_are_dropped() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let original = conffiles_producer("original", tmp.path_view().join("original.deb"))
+            .inject(Injection::Create {
+                path: "/etc/app.conf".into(),
+                content: b"key = value".to_vec(),
+            })
+            .conffile("/etc/app.conf")
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let repackaged = conffiles_producer("repackaged", tmp.path_view().join("repackaged.deb"))
+            .inject(Injection::Delete {
+                path: "/etc/app.conf".into(),
+            })
+            .build()?
+            .produce_from(&original)
+            .await?;
+        assert!(repackaged.read_conffiles().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleted_conffiles_are_dropped_from_inherited_metadata() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let original = conffiles_producer("original", tmp.path_view().join("original.deb"))
+            .inject(Injection::Create {
+                path: "/etc/app.conf".into(),
+                content: b"key = value".to_vec(),
+            })
+            .conffile("/etc/app.conf")
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let repackaged = conffiles_producer("repackaged", tmp.path_view().join("repackaged.deb"))
+            .inherit_metadata(true)
+            .inject(Injection::Delete {
+                path: "/etc/app.conf".into(),
+            })
+            .build()?
+            .produce_from(&original)
+            .await?;
+        assert!(repackaged.read_conffiles().await?.is_empty());
+        assert!(repackaged
+            .control_fs()
+            .await?
+            .fs()
+            .metadata("/conffiles")
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_removed_on_upgrade_conffiles_are_kept() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/etc").await?;
+        memfs.fs().write("/etc/app.conf", b"key = value").await?;
+        let producer = conffiles_producer("repackaged", "repackaged.deb".into()).build()?;
+
+        let inherited = vec![
+            "/etc/app.conf".to_string(),
+            "remove-on-upgrade /etc/old.conf".to_string(),
+            "/etc/gone.conf".to_string(),
+        ];
+        assert_eq!(
+            vec!["/etc/app.conf", "remove-on-upgrade /etc/old.conf"],
+            producer.merge_conffiles(&memfs, &inherited).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_files_in_home_are_warned_about() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().write("/usr/bin/hello", b"hi").await?;
+        memfs.fs().create_dir_all("/home/user").await?;
+        memfs.fs().write("/home/user/.bashrc", b"hi").await?;
+
+        let warnings = DebProducerBuilder::new("home")
+            .build()?
+            .can_produce_from(&memfs)
+            .await?;
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].starts_with("/home is outside of the FHS"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_injected_files_are_checked_in_strict_mode() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().write("/usr/bin/hello", b"hi").await?;
+
+        let tmp = TempDir::new().await?;
+        let producer = DebProducerBuilder::new("home")
+            .path(tmp.path_view().join("home.deb"))
+            .package_name("home")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("injects into /home")
+            .inject(Injection::Create {
+                path: "/home/user/.bashrc".into(),
+                content: b"hi".to_vec(),
+            })
+            .build()?;
+        let input = MemoryArtifact {
+            name: "home".into(),
+            fs: memfs,
+        };
+
+        let err = crate::artifact::with_strict_compatibility(true, producer.produce_from(&input))
+            .await
+            .unwrap_err();
+        let IncompatibleInput(warnings) = err.downcast_ref::<IncompatibleInput>().unwrap();
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].starts_with("/home is outside of the FHS"));
+        assert!(!tmp.path_view().join("home.deb").exists());
+
+        // Without strict mode, it's only a warning.
+        producer.produce_from(&input).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_debug_produces_debug_package() -> Result<()> {
+        let fixture = Fixture::new("hello-debug").await;
+        let original = tokio::fs::read(fixture.path_view()).await?;
+        let tmp = TempDir::new().await?;
+
+        let producer = DebProducerBuilder::new("hello")
+            .path(tmp.path_view().join("hello.deb"))
+            .package_name("hello")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("hello from peckish!")
+            .inject(Injection::HostFile {
+                src: fixture.path_view(),
+                dest: "/usr/bin/hello".into(),
+            })
+            .split_debug(true)
+            .build()?;
+        producer.validate().await?;
+
+        let artifact = producer.produce_from(&EmptyArtifact::new("empty")).await?;
+        let debug_package = artifact.debug_package.clone().unwrap();
+        assert_eq!(debug_package, tmp.path_view().join("hello-dbg.deb"));
+        assert_eq!(artifact.paths().unwrap().len(), 2);
+
+        let main = artifact.extract().await?;
+        let stripped = (*main).read("/usr/bin/hello").await?;
+        let stripped = object::File::parse(stripped.as_slice())?;
+        assert!(stripped.section_by_name(".debug_info").is_none());
+        assert!(stripped.section_by_name(".text").is_some());
+
+        let debug = DebArtifactBuilder::new("hello-dbg")
+            .path(debug_package)
+            .build()?
+            .extract()
+            .await?;
+        let mut debug_files = vec![];
+        for path in nyoom::walk_ordered(&*debug, "/").await? {
+            if (*debug).metadata(&path).await?.is_file() {
+                debug_files.push(path);
+            }
+        }
+        assert_eq!(debug_files.len(), 1);
+        assert!(debug_files[0].starts_with("/usr/lib/debug/.build-id"));
+        assert!(debug_files[0].to_string_lossy().ends_with(".debug"));
+        assert_eq!((*debug).read(&debug_files[0]).await?, original);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ar_mtimes_follow_source_date_epoch() -> Result<()> {
+        with_source_date_epoch(1_600_000_000, async {
+            let tmp = TempDir::new().await?;
+            let path = tmp.path_view().join("mtimes.deb");
+            DebProducerBuilder::new("mtimes")
+                .path(&path)
+                .package_name("mtimes")
+                .package_maintainer("peckish <peckish@example.com>")
+                .package_architecture("amd64")
+                .package_version("0.1.0-1")
+                .package_description("mtimes")
+                .build()?
+                .produce_from(&EmptyArtifact::new("empty"))
+                .await?;
+
+            let deb = tokio::fs::read(&path).await?;
+            let mut archive = ar::Archive::new(deb.as_slice());
+            while let Some(entry) = archive.next_entry() {
+                assert_eq!(1_600_000_000, entry?.header().mtime());
+            }
+
+            Ok(())
         })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_deb_ar_headers_are_reproducible() -> Result<()> {
+        with_source_date_epoch(1_700_000_000, async {
+            let tmp = TempDir::new().await?;
+
+            let mut headers = vec![];
+            for name in ["first.deb", "second.deb"] {
+                let path = tmp.path_view().join(name);
+                DebProducerBuilder::new("reproducible")
+                    .path(&path)
+                    .package_name("reproducible")
+                    .package_maintainer("peckish <peckish@example.com>")
+                    .package_architecture("amd64")
+                    .package_version("0.1.0-1")
+                    .package_description("reproducible peckish!")
+                    .inject(Injection::Create {
+                        path: "/etc/reproducible".into(),
+                        content: b"hi".to_vec(),
+                    })
+                    .build()?
+                    .produce_from(&EmptyArtifact::new("empty"))
+                    .await?;
+
+                let deb = tokio::fs::read(&path).await?;
+                let mut archive = ar::Archive::new(deb.as_slice());
+                let mut members = vec![];
+                while let Some(entry) = archive.next_entry() {
+                    let entry = entry?;
+                    let header = entry.header();
+                    assert_eq!(header.mtime(), 1_700_000_000);
+                    assert_eq!(header.uid(), 0);
+                    assert_eq!(header.gid(), 0);
+                    members.push((
+                        header.identifier().to_vec(),
+                        header.mtime(),
+                        header.uid(),
+                        header.gid(),
+                        header.mode(),
+                        header.size(),
+                    ));
+                }
+                headers.push(members);
+            }
+
+            assert_eq!(headers[0].len(), 3);
+            assert_eq!(headers[0][0].0, b"debian-binary");
+            assert_eq!(headers[0], headers[1]);
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_md5sums_are_sorted_by_path() -> Result<()> {
+        let memfs = MemFS::new();
+        for dir in ["/usr/bin", "/etc", "/opt/z"] {
+            memfs.fs().create_dir_all(dir).await?;
+        }
+        for path in ["/usr/bin/tool", "/opt/z/file", "/etc/b", "/etc/a"] {
+            memfs.fs().write(path, path.as_bytes()).await?;
+        }
+
+        let md5sums = md5sums(&memfs).await?;
+        let paths = md5sums
+            .lines()
+            .map(|line| line.split_once("  ").unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/etc/a", "/etc/b", "/opt/z/file", "/usr/bin/tool"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_xz_deb_members_are_selected_exactly() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("xz.deb");
+        DebProducerBuilder::new("xz")
+            .path(&path)
+            .compression_type(CompressionType::Xz)
+            .package_name("xz")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("xz members")
+            .inject(Injection::Create {
+                path: "/etc/first".into(),
+                content: b"first".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let mut members = read_deb(&path).await?;
+        let names = members.iter().map(|(name, _)| name.as_str()).collect_vec();
+        assert_eq!(names, ["debian-binary", "control.tar.xz", "data.tar.xz"]);
+
+        // Split the data across a second member.
+        let second = MemFS::new();
+        second.fs().create_dir_all("/etc").await?;
+        second.fs().write("/etc/second", b"second").await?;
+        let second_tar = tmp.path_view().join("second.tar.xz");
+        TarballProducerBuilder::new("second")
+            .path(&second_tar)
+            .compression(CompressionType::Xz)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "second".into(),
+                fs: second,
+            })
+            .await?;
+        members.push(("data.tar.xz".into(), tokio::fs::read(&second_tar).await?));
+        let split = tmp.path_view().join("split.deb");
+        write_deb(&split, &members).await?;
+
+        let deb = DebArtifactBuilder::new("split").path(&split).build()?;
+        deb.validate().await?;
+        let memfs = deb.extract().await?;
+        assert_eq!(memfs.fs().read("/etc/first").await?, b"first");
+        assert_eq!(memfs.fs().read("/etc/second").await?, b"second");
+
+        // With two control archives, there's no telling which one is right.
+        members.insert(2, ("control.tar.gz".into(), members[1].1.clone()));
+        let ambiguous = tmp.path_view().join("ambiguous.deb");
+        write_deb(&ambiguous, &members).await?;
+        let err = DebArtifactBuilder::new("ambiguous")
+            .path(&ambiguous)
+            .build()?
+            .validate()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("more than one control archive (control.tar.xz, control.tar.gz)"));
+
+        // A deb missing its data is an error, not a panic.
+        members.retain(|(name, _)| !name.starts_with("data.tar"));
+        let no_data = tmp.path_view().join("no-data.deb");
+        write_deb(&no_data, &members).await?;
+        let deb = DebArtifactBuilder::new("no-data").path(&no_data).build()?;
+        assert!(deb.extract().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_format_version_is_rejected() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let producer = DebProducerBuilder::new("bad-format")
+            .path(tmp.path_view().join("bad.deb"))
+            .package_name("bad-format")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("bad format")
+            .format_version("3.0")
+            .build()?;
+
+        assert!(producer.validate().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pre_depends_and_breaks_are_written_to_control() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let artifact = DebProducerBuilder::new("relations")
+            .path(tmp.path_view().join("relations.deb"))
+            .package_name("relations")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_depends("libc6 (>= 2.34) | musl")
+            .package_pre_depends("dpkg (>= 1.17.14)")
+            .package_breaks("old-relations (<< 0.1.0), other:amd64")
+            .package_description("relations")
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let control_fs = artifact.control_fs().await?;
+        let control = control_fs.fs().read_to_string("/control").await?;
+        assert!(control.contains("\nPre-Depends: dpkg (>= 1.17.14)\n"));
+        assert!(control.contains("\nBreaks: old-relations (<< 0.1.0), other:amd64\n"));
+
+        let control = ControlFile::parse(&control)?;
+        assert_eq!("dpkg (>= 1.17.14)", control.pre_depends);
+        assert_eq!("old-relations (<< 0.1.0), other:amd64", control.breaks);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_relations_are_rejected() -> Result<()> {
+        let producer = DebProducerBuilder::new("bad-relations")
+            .package_name("bad-relations")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("bad relations")
+            .package_pre_depends("dpkg (~> 1.17)")
+            .package_breaks("one | other")
+            .build()?;
+
+        let err = producer.validate().await.unwrap_err().to_string();
+        assert!(err.contains("pre-depends relation `dpkg (~> 1.17)` is invalid"));
+        assert!(err.contains("breaks can't have alternatives"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_changelog_is_installed() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let artifact = DebProducerBuilder::new("changelog")
+            .path(tmp.path_view().join("changelog.deb"))
+            .package_name("changelog-test")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("a changelog")
+            .changelog_entry(ChangelogEntry {
+                version: "0.1.0-1".into(),
+                date: "2024-01-15".into(),
+                author: "peckish <peckish@example.com>".into(),
+                notes: vec!["initial release".into()],
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let memfs = artifact.extract().await?;
+        let compressed = (*memfs)
+            .read("/usr/share/doc/changelog-test/changelog.Debian.gz")
+            .await?;
+        let mut changelog = vec![];
+        smoosh::recompress(
+            &mut compressed.as_slice(),
+            &mut changelog,
+            CompressionType::None,
+        )
+        .await?;
+
+        assert_eq!(
+            String::from_utf8(changelog)?,
+            indoc::indoc! {"
+                changelog-test (0.1.0-1) unstable; urgency=medium
+
+                  * initial release
+
+                 -- peckish <peckish@example.com>  Mon, 15 Jan 2024 00:00:00 +0000
+
+            "}
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inherited_metadata_survives_a_round_trip() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let postinst = tmp.path_view().join("postinst");
+        tokio::fs::write(&postinst, "#!/bin/sh\necho installed\n").await?;
+
+        let files = MemFS::new();
+        files.fs().create_dir_all("/usr/bin").await?;
+        files.fs().write("/usr/bin/tool", b"tool").await?;
+        let original = DebProducerBuilder::new("original")
+            .path(tmp.path_view().join("original.deb"))
+            .package_name("original")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("1.2.3-1")
+            .package_depends("libc6")
+            .package_description("the original package")
+            .postinst(&postinst)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "files".into(),
+                fs: files,
+            })
+            .await?;
+
+        // Inputs from the config don't have their control file parsed yet.
+        let input = DebArtifactBuilder::new("input")
+            .path(&original.path)
+            .build()?;
+        let patched = DebProducerBuilder::new("patched")
+            .path(tmp.path_view().join("patched.deb"))
+            .package_name("from-config")
+            .package_version("9.9.9-1")
+            .inherit_metadata(true)
+            .inject(Injection::Create {
+                path: "/etc/patched".into(),
+                content: b"patched".to_vec(),
+            })
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        let control = patched.read_control().await?;
+        assert_eq!("original", control.package);
+        assert_eq!("1.2.3-1", control.version);
+        assert_eq!("libc6", control.depends);
+        assert_eq!("peckish <peckish@example.com>", control.maintainer);
+        assert_eq!("the original package", control.description);
+        assert_eq!(
+            Some("#!/bin/sh\necho installed\n".to_string()),
+            patched.postinst
+        );
+
+        let memfs = patched.extract().await?;
+        assert_eq!(b"tool".to_vec(), memfs.fs().read("/usr/bin/tool").await?);
+        assert_eq!(b"patched".to_vec(), memfs.fs().read("/etc/patched").await?);
+        assert_eq!(memfs.size().await?, control.installed_size);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_control_and_data_can_be_compressed_separately() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let artifact = DebProducerBuilder::new("mixed")
+            .path(tmp.path_view().join("mixed.deb"))
+            .compression_type(CompressionType::Gzip)
+            .control_compression(CompressionType::None)
+            .data_compression(CompressionType::Xz)
+            .package_name("mixed")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("mixed compression")
+            .inject(Injection::Create {
+                path: "/etc/config".into(),
+                content: b"a=b".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let members = read_deb(&artifact.path).await?;
+        let names = members.iter().map(|(name, _)| name.as_str()).collect_vec();
+        assert_eq!(names, ["debian-binary", "control.tar", "data.tar.xz"]);
+
+        assert_eq!("mixed", artifact.read_control().await?.package);
+        let memfs = artifact.extract().await?;
+        assert_eq!(b"a=b".to_vec(), memfs.fs().read("/etc/config").await?);
+
+        Ok(())
     }
 }
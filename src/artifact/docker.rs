@@ -1,13 +1,14 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use bollard::image::CreateImageOptions;
 use bollard::Docker;
 use disk_drive::DiskDrive;
 use eyre::Result;
-use floppy_disk::mem::MemOpenOptions;
+use floppy_disk::mem::{MemFloppyDisk, MemOpenOptions};
 use floppy_disk::tokio_fs::TokioFloppyDisk;
-use floppy_disk::{FloppyDisk, FloppyOpenOptions};
+use floppy_disk::{FloppyDisk, FloppyMetadata, FloppyOpenOptions, FloppyUnixPermissions};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use smoosh::CompressionType;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -16,24 +17,49 @@ use tokio_util::codec::{BytesCodec, FramedRead};
 use tracing::*;
 
 use crate::artifact::memory::MemoryArtifact;
-use crate::fs::{MemFS, TempDir};
+use crate::fs::{MemFS, MergePolicy, OwnerRemap, PathFilter, TempDir};
 use crate::util::config::Injection;
 
-use super::tarball::{TarballArtifact, TarballProducer};
-use super::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+use super::tarball::{EntryNames, TarballArtifact, TarballProducer};
+use super::{
+    check_compatibility, Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation,
+};
 
 /// A Docker image.
 ///
 /// ## Caveats
 ///
-/// - Will currently always attempt to pull the provided image if needed
 /// - Will only unpack the first set of layers in a Docker image
 ///
+/// For multi-arch images, `platform` (ex. `linux/arm64`) selects which
+/// architecture is pulled. Otherwise, the daemon picks its own platform.
+/// `pull_policy` controls whether the image is pulled at all.
+///
 /// TODO: Preserve image config
 #[derive(Debug, Clone)]
 pub struct DockerArtifact {
     pub name: String,
     pub image: String,
+    pub platform: Option<String>,
+    pub pull_policy: PullPolicy,
+    /// Only extract these paths, and everything under them. Everything is
+    /// extracted if this is empty.
+    pub include_paths: Vec<PathBuf>,
+    /// Skip these paths, and everything under them, ex. package caches.
+    pub exclude_paths: Vec<PathBuf>,
+}
+
+/// When a Docker image is pulled from its registry before being extracted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PullPolicy {
+    /// Always pull, even if the image already exists locally.
+    #[default]
+    Always,
+    /// Only pull if the image doesn't exist locally.
+    IfNotPresent,
+    /// Never pull. It's an error for the image to not exist locally.
+    Never,
 }
 
 #[async_trait::async_trait]
@@ -42,24 +68,47 @@ impl Artifact for DockerArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Docker
+    }
+
     async fn extract(&self) -> Result<MemFS> {
         let docker = Docker::connect_with_local_defaults()?;
         let (image, tag) = split_image_name_into_repo_and_tag(&self.image);
 
-        info!("attempting to pull {}...", self.image);
-        // Attempt to download the image
-        let mut pull = docker.create_image(
-            Some(CreateImageOptions {
-                from_image: image,
-                tag,
-                ..Default::default()
-            }),
-            None,
-            None,
-        );
-        while let Some(info) = pull.next().await {
-            let info = info?;
-            info!("pulling {:?}: {:?}", image, info.progress);
+        let should_pull = match self.pull_policy {
+            PullPolicy::Always => true,
+            PullPolicy::IfNotPresent => docker.inspect_image(&self.image).await.is_err(),
+            PullPolicy::Never => {
+                if docker.inspect_image(&self.image).await.is_err() {
+                    return Err(eyre::eyre!(
+                        "docker image {} does not exist locally, and pull_policy is never",
+                        self.image
+                    ));
+                }
+                false
+            }
+        };
+
+        if should_pull {
+            info!("attempting to pull {}...", self.image);
+            // Attempt to download the image
+            let mut pull = docker.create_image(
+                Some(CreateImageOptions {
+                    from_image: image,
+                    tag,
+                    platform: self.platform.as_deref().unwrap_or_default(),
+                    ..Default::default()
+                }),
+                None,
+                None,
+            );
+            while let Some(info) = pull.next().await {
+                let info = info?;
+                info!("pulling {:?}: {:?}", image, info.progress);
+            }
+        } else {
+            info!("using local copy of {}", self.image);
         }
 
         // Export image to a TAR file
@@ -69,15 +118,12 @@ impl Artifact for DockerArtifact {
         let mut export = docker.export_image(&self.image);
         let export_name = format!("{}.tar", self.name.replace(['/', ':', ' '], "_"));
         let export_path = image_tar_export.path_view().join(&export_name);
-        tokio::fs::create_dir_all(export_path.parent().unwrap())
-            .await
-            .unwrap();
-        let mut file = tokio::fs::File::create(&export_path).await.unwrap();
+        let mut file = tokio::fs::File::create(&export_path).await?;
         while let Some(chunk) = export.next().await {
-            let chunk = chunk.unwrap();
-            file.write_all(&chunk).await.unwrap();
-            file.sync_all().await.unwrap();
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
         }
+        file.sync_all().await?;
 
         // Docker exports a tarball of tarballs of layers
 
@@ -103,38 +149,32 @@ impl Artifact for DockerArtifact {
         let manifest: serde_json::Value = serde_json::from_str(&buf)?;
         let layers: Vec<&str> = manifest
             .as_array()
-            .unwrap()
-            .first()
-            .unwrap()
-            .get("Layers")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|v| v.as_str().unwrap())
-            .collect();
+            .and_then(|images| images.first())
+            .and_then(|image| image.get("Layers"))
+            .and_then(|layers| layers.as_array())
+            .and_then(|layers| layers.iter().map(|v| v.as_str()).collect())
+            .ok_or_else(|| eyre::eyre!("docker image {} has an invalid manifest", self.image))?;
 
         info!("extracting docker layers into memfs...");
-        let fs = MemFS::new();
         let host = TokioFloppyDisk::new(Some(image_tar_export.path_view()));
         host.create_dir("/").await?;
 
         info!("copying base tarball contents to host...");
         DiskDrive::copy_between(basic_tar_fs, &host).await?;
 
-        for layer in layers {
-            debug!("copying layer: {layer}");
-            let layer_path = image_tar_export.path_view().join(layer);
-            let layer_memfs = TarballArtifact {
-                name: self.name.clone(),
-                path: layer_path,
-            }
-            .extract()
-            .await?;
-            DiskDrive::copy_between(&*layer_memfs, &*fs).await?;
-        }
-
-        Ok(fs)
+        let layers = layers
+            .iter()
+            .map(|layer| image_tar_export.path_view().join(layer))
+            .collect::<Vec<_>>();
+        extract_layers(
+            &self.name,
+            &layers,
+            &PathFilter {
+                include: self.include_paths.clone(),
+                exclude: self.exclude_paths.clone(),
+            },
+        )
+        .await
     }
 
     fn try_clone(&self) -> Result<Box<dyn Artifact>> {
@@ -146,9 +186,51 @@ impl Artifact for DockerArtifact {
     }
 }
 
+/// Apply an image's layer tarballs in order, only reading in the paths that
+/// `filter` keeps.
+async fn extract_layers(name: &str, layers: &[PathBuf], filter: &PathFilter) -> Result<MemFS> {
+    let fs = MemFS::new();
+    for layer in layers {
+        debug!("copying layer: {}", layer.display());
+        let layer_memfs = TarballArtifact {
+            name: name.to_string(),
+            path: layer.clone(),
+        }
+        .extract_filtered(filter)
+        .await?;
+        fs.apply_layer(layer_memfs).await?;
+    }
+
+    Ok(fs)
+}
+
 #[async_trait::async_trait]
 impl SelfValidation for DockerArtifact {
     async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        if let Some(platform) = &self.platform {
+            let platform_regex = Regex::new(r"^[a-z0-9]+/[a-z0-9_]+(/[a-z0-9]+)?$").unwrap();
+            if !platform_regex.is_match(platform) {
+                errors.push(format!(
+                    "platform {platform} is invalid, must be os/arch[/variant]"
+                ));
+            }
+        }
+
+        for path in self.include_paths.iter().chain(&self.exclude_paths) {
+            if !path.is_absolute() {
+                errors.push(format!("path filter {} must be absolute", path.display()));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre::eyre!(
+                "Docker artifact is invalid:\n{}",
+                errors.join("\n")
+            ));
+        }
+
         Ok(())
     }
 }
@@ -156,6 +238,10 @@ impl SelfValidation for DockerArtifact {
 pub struct DockerArtifactBuilder {
     pub name: String,
     pub image: String,
+    pub platform: Option<String>,
+    pub pull_policy: PullPolicy,
+    pub include_paths: Vec<PathBuf>,
+    pub exclude_paths: Vec<PathBuf>,
 }
 
 #[allow(unused)]
@@ -164,6 +250,26 @@ impl DockerArtifactBuilder {
         self.image = image.into();
         self
     }
+
+    pub fn platform<S: Into<String>>(mut self, platform: S) -> Self {
+        self.platform = Some(platform.into());
+        self
+    }
+
+    pub fn pull_policy(mut self, pull_policy: PullPolicy) -> Self {
+        self.pull_policy = pull_policy;
+        self
+    }
+
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    pub fn exclude_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.exclude_paths.push(path.into());
+        self
+    }
 }
 
 impl SelfBuilder for DockerArtifactBuilder {
@@ -173,6 +279,10 @@ impl SelfBuilder for DockerArtifactBuilder {
         Self {
             name: name.into(),
             image: "".into(),
+            platform: None,
+            pull_policy: PullPolicy::default(),
+            include_paths: vec![],
+            exclude_paths: vec![],
         }
     }
 
@@ -180,6 +290,10 @@ impl SelfBuilder for DockerArtifactBuilder {
         Ok(DockerArtifact {
             name: self.name.clone(),
             image: self.image.clone(),
+            platform: self.platform.clone(),
+            pull_policy: self.pull_policy,
+            include_paths: self.include_paths.clone(),
+            exclude_paths: self.exclude_paths.clone(),
         })
     }
 }
@@ -191,12 +305,17 @@ impl SelfBuilder for DockerArtifactBuilder {
 ///
 /// - Will currently always attempt to pull the base image
 /// - Does not support changes other than setting the `CMD`
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DockerProducer {
     pub name: String,
     pub image: String,
     pub base_image: Option<String>,
+    /// How paths in both the base image and the previous artifact are
+    /// handled. Only used with `base_image`.
+    pub merge_policy: MergePolicy,
     pub cmd: Option<Vec<String>>,
+    /// Give every file in the image this owner, after the injections.
+    pub remap_owner: Option<OwnerRemap>,
     pub injections: Vec<Injection>,
 }
 
@@ -212,6 +331,34 @@ impl ArtifactProducer for DockerProducer {
         &self.injections
     }
 
+    async fn can_produce_from(&self, memfs: &MemFS) -> Result<Vec<String>> {
+        // The base image usually has the entrypoint.
+        if self.base_image.is_some() {
+            return Ok(vec![]);
+        }
+
+        let fs = memfs.fs();
+        if let Some(program) = self.cmd.as_ref().and_then(|cmd| cmd.first()) {
+            // Relative commands are looked up in the image's $PATH.
+            if program.starts_with('/') && !is_executable(fs, program).await {
+                return Ok(vec![format!(
+                    "entrypoint {program} is not an executable file in the image"
+                )]);
+            }
+            return Ok(vec![]);
+        }
+
+        for path in nyoom::walk_ordered(fs, "/").await? {
+            if is_executable(fs, &path).await {
+                return Ok(vec![]);
+            }
+        }
+
+        Ok(vec![
+            "image has no executable files, so there's nothing for it to run".into(),
+        ])
+    }
+
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<DockerArtifact> {
         // Produce a tarball artifact from the previous artifact
         let tmp = TempDir::new().await?;
@@ -224,27 +371,35 @@ impl ArtifactProducer for DockerProducer {
             // layers directly.
 
             let merged_fs = {
-                let out = MemFS::new();
-
                 let base_fs = DockerArtifact {
                     name: self.name.clone(),
                     image: base_image.clone(),
+                    platform: None,
+                    pull_policy: PullPolicy::default(),
+                    include_paths: vec![],
+                    exclude_paths: vec![],
                 }
                 .extract()
                 .await?;
 
                 let added_fs = previous.extract().await?;
 
-                DiskDrive::copy_between(&*base_fs, &*out).await?;
-                DiskDrive::copy_between(&*added_fs, &*out).await?;
+                base_fs.merge(&added_fs, self.merge_policy).await?;
 
-                out
+                base_fs
             };
 
             TarballProducer {
                 name: self.name.clone(),
                 path: tarball_path.clone(),
                 compression: CompressionType::None,
+                sparse: false,
+                entry_names: EntryNames::Relative,
+                file_mtime: None,
+                dir_mtime: None,
+                rsyncable: false,
+                remap_owner: self.remap_owner,
+                split_size: None,
                 injections: self.injections.clone(),
             }
             .produce_from(&MemoryArtifact {
@@ -253,14 +408,29 @@ impl ArtifactProducer for DockerProducer {
             })
             .await?
         } else {
-            // Otherwise, we can just import the tarball directly into Docker
+            // Otherwise, we can just import the tarball directly into Docker,
+            // once the injected files are checked for something to run.
+            let mut memfs = previous.extract().await?;
+            self.inject(&mut memfs).await?;
+            check_compatibility(self, &memfs).await?;
+
             TarballProducer {
                 name: self.name.clone(),
                 path: tarball_path.clone(),
                 compression: CompressionType::None,
-                injections: self.injections.clone(),
+                sparse: false,
+                entry_names: EntryNames::Relative,
+                file_mtime: None,
+                dir_mtime: None,
+                rsyncable: false,
+                remap_owner: self.remap_owner,
+                split_size: None,
+                injections: vec![],
             }
-            .produce_from(previous)
+            .produce_from_owned(Box::new(MemoryArtifact {
+                name: self.name.clone(),
+                fs: memfs,
+            }))
             .await?
         };
 
@@ -314,6 +484,10 @@ impl ArtifactProducer for DockerProducer {
         Ok(DockerArtifact {
             name: self.name.clone(),
             image: self.image.clone(),
+            platform: None,
+            pull_policy: PullPolicy::default(),
+            include_paths: vec![],
+            exclude_paths: vec![],
         })
     }
 }
@@ -321,29 +495,24 @@ impl ArtifactProducer for DockerProducer {
 #[async_trait::async_trait]
 impl SelfValidation for DockerProducer {
     async fn validate(&self) -> Result<()> {
-        TokioFloppyDisk::new(None)
-            .metadata("/var/run/docker.sock")
-            .await
-            .map(|_| ())?;
-
-        let docker_image_name_with_tag_and_repo_regex =
-            Regex::new(r"^(?P<repo>[a-z0-9]+(?:[._-][a-z0-9]+)*/)?(?P<name>[a-z0-9]+(?:[._-][a-z0-9]+)*):(?P<tag>[a-z0-9]+(?:[._-][a-z0-9]+)*)$")
-                .unwrap();
-
         let mut errors = vec![];
 
-        if !docker_image_name_with_tag_and_repo_regex.is_match(&self.image) {
+        if !is_valid_reference(&self.image) {
             errors.push(format!(
-                "Docker image name with tag is invalid: {}, must match {docker_image_name_with_tag_and_repo_regex}",
+                "Docker image name is invalid: {}, must be {REFERENCE_FORMAT}",
+                self.image
+            ));
+        } else if self.image.contains('@') {
+            errors.push(format!(
+                "Docker image name can't have a digest: {}, since the image is tagged, and its digest isn't known until it's built",
                 self.image
             ));
         }
 
         if let Some(base_image) = &self.base_image {
-            if !docker_image_name_with_tag_and_repo_regex.is_match(base_image) {
+            if !is_valid_reference(base_image) {
                 errors.push(format!(
-                    "Docker base image name with tag is invalid: {}, must match {docker_image_name_with_tag_and_repo_regex}",
-                    base_image
+                    "Docker base image name is invalid: {base_image}, must be {REFERENCE_FORMAT}"
                 ));
             }
         }
@@ -355,15 +524,50 @@ impl SelfValidation for DockerProducer {
             ));
         }
 
+        TokioFloppyDisk::new(None)
+            .metadata("/var/run/docker.sock")
+            .await
+            .map(|_| ())?;
+
         Ok(())
     }
 }
 
+const REFERENCE_FORMAT: &str = "[registry[:port]/]path[:tag][@digest]";
+
+/// The image reference grammar from the distribution spec: an optional
+/// registry host and port, a `/`-separated path of lowercase components,
+/// then an optional tag and digest.
+const REFERENCE_REGEX: &str = concat!(
+    r"^(?:(?P<registry>",
+    r"(?:[a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9])",
+    r"(?:\.(?:[a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9]))*",
+    r"|\[[a-fA-F0-9:]+\]",
+    r")(?::[0-9]+)?/)?",
+    r"(?P<path>[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*",
+    r"(?:/[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*)*)",
+    r"(?::(?P<tag>[\w][\w.-]{0,127}))?",
+    r"(?:@(?P<digest>[A-Za-z][A-Za-z0-9]*(?:[-_+.][A-Za-z][A-Za-z0-9]*)*:[0-9a-fA-F]{32,}))?$",
+);
+
+fn is_valid_reference(image: &str) -> bool {
+    Regex::new(REFERENCE_REGEX).unwrap().is_match(image)
+}
+
+async fn is_executable<P: AsRef<Path> + Send>(fs: &MemFloppyDisk, path: P) -> bool {
+    match fs.metadata(path).await {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
 pub struct DockerProducerBuilder {
     name: String,
     image: String,
     base_image: Option<String>,
+    merge_policy: MergePolicy,
     entrypoint: Option<Vec<String>>,
+    remap_owner: Option<OwnerRemap>,
     injections: Vec<Injection>,
 }
 
@@ -379,11 +583,21 @@ impl DockerProducerBuilder {
         self
     }
 
+    pub fn merge_policy(mut self, merge_policy: MergePolicy) -> Self {
+        self.merge_policy = merge_policy;
+        self
+    }
+
     pub fn entrypoint(mut self, entrypoint: Vec<String>) -> Self {
         self.entrypoint = Some(entrypoint);
         self
     }
 
+    pub fn remap_owner(mut self, remap_owner: OwnerRemap) -> Self {
+        self.remap_owner = Some(remap_owner);
+        self
+    }
+
     pub fn inject(mut self, injection: Injection) -> Self {
         self.injections.push(injection);
         self
@@ -398,7 +612,9 @@ impl SelfBuilder for DockerProducerBuilder {
             name: name.into(),
             image: "".into(),
             base_image: None,
+            merge_policy: MergePolicy::default(),
             entrypoint: None,
+            remap_owner: None,
             injections: vec![],
         }
     }
@@ -408,22 +624,36 @@ impl SelfBuilder for DockerProducerBuilder {
             name: self.name.clone(),
             image: self.image.clone(),
             base_image: self.base_image.clone(),
+            merge_policy: self.merge_policy,
             cmd: self.entrypoint.clone(),
+            remap_owner: self.remap_owner,
             injections: self.injections.clone(),
         })
     }
 }
 
+/// Split an image reference into the repository and the tag or digest that
+/// Docker's API takes separately, ex. `localhost:5000/app:dev` into
+/// `localhost:5000/app` and `dev`. The tag is after the last `:` following the
+/// last `/`, since a registry can have a port. A digest wins over a tag, and
+/// references without either are `latest`.
 fn split_image_name_into_repo_and_tag(name: &str) -> (&str, &str) {
-    if let Some((image, tag)) = name.split_once(':') {
-        (image, tag)
-    } else {
-        (name, "latest")
+    if let Some((name, digest)) = name.split_once('@') {
+        return (split_image_name_into_repo_and_tag(name).0, digest);
+    }
+
+    let path_start = name.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match name[path_start..].rfind(':') {
+        Some(i) => (&name[..path_start + i], &name[path_start + i + 1..]),
+        None => (name, "latest"),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::artifact::tarball::TarballProducerBuilder;
+    use crate::fs::test_utils::Fixture;
+
     use super::*;
 
     use eyre::Result;
@@ -439,6 +669,10 @@ mod tests {
         let artifact = DockerArtifact {
             name: "alpine-artifact".into(),
             image: "alpine:latest".to_string(),
+            platform: None,
+            pull_policy: PullPolicy::default(),
+            include_paths: vec![],
+            exclude_paths: vec![],
         };
         {
             let fs = artifact.extract().await?;
@@ -454,7 +688,9 @@ mod tests {
             name: "docker image producer".into(),
             image: new_image.clone(),
             base_image: None,
+            merge_policy: MergePolicy::default(),
             cmd: None,
+            remap_owner: None,
             injections: vec![],
         };
 
@@ -468,4 +704,266 @@ mod tests {
 
         Ok(())
     }
+
+    async fn docker_available() -> bool {
+        match Docker::connect_with_local_defaults() {
+            Ok(docker) => docker.ping().await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_docker_artifact_pulls_requested_platform() -> Result<()> {
+        if !docker_available().await {
+            warn!("docker is not available, skipping");
+            return Ok(());
+        }
+
+        let artifact = DockerArtifact {
+            name: "alpine-arm64-artifact".into(),
+            image: "alpine:3.19".to_string(),
+            platform: Some("linux/arm64".into()),
+            pull_policy: PullPolicy::default(),
+            include_paths: vec![],
+            exclude_paths: vec![],
+        };
+        artifact.validate().await?;
+
+        let fs = artifact.extract().await?;
+        assert!(MemOpenOptions::new()
+            .read(true)
+            .open(&*fs, "/lib/ld-musl-aarch64.so.1")
+            .await
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_never_pull_policy_does_not_pull() -> Result<()> {
+        if !docker_available().await {
+            warn!("docker is not available, skipping");
+            return Ok(());
+        }
+
+        // An image that doesn't exist anywhere: pulling it would fail with a
+        // registry error rather than the local-only one.
+        let artifact = DockerArtifact {
+            name: "missing-artifact".into(),
+            image: "peckish-dev/does-not-exist:never".to_string(),
+            platform: None,
+            pull_policy: PullPolicy::Never,
+            include_paths: vec![],
+            exclude_paths: vec![],
+        };
+
+        let err = artifact.extract().await.unwrap_err();
+        assert!(err.to_string().contains("does not exist locally"));
+
+        Ok(())
+    }
+
+    /// The hello world image's only layer, a layer on top of it with a
+    /// package cache, and one that removes the hello world binary.
+    async fn fixture_layers(tmp: &TempDir) -> Result<Vec<PathBuf>> {
+        let fixture = Fixture::new("oci.tar").await;
+        let oci = TarballArtifact {
+            name: "oci".into(),
+            path: fixture.path_view(),
+        }
+        .extract()
+        .await?;
+        let base = tmp.path_view().join("base.tar");
+        tokio::fs::write(
+            &base,
+            oci.fs()
+                .read("/blobs/sha256/acd6bfea9f03eb9e0cb55ffa8193e14764d8da054c91552a09ed42ae6798b5e5")
+                .await?,
+        )
+        .await?;
+
+        let mut layers = vec![base];
+        for (name, files) in [
+            (
+                "cache",
+                vec![
+                    "/usr/local/bin/tool",
+                    "/var/cache/apk/APKINDEX",
+                    "/etc/motd",
+                ],
+            ),
+            ("removal", vec!["/usr/local/bin/.wh.podman_hello_world"]),
+        ] {
+            let memfs = MemFS::new();
+            for file in files {
+                memfs
+                    .fs()
+                    .create_dir_all(Path::new(file).parent().unwrap())
+                    .await?;
+                memfs.fs().write(file, file.as_bytes()).await?;
+            }
+            let path = tmp.path_view().join(format!("{name}.tar"));
+            TarballProducerBuilder::new(name)
+                .path(&path)
+                .build()?
+                .produce_from(&MemoryArtifact {
+                    name: name.into(),
+                    fs: memfs,
+                })
+                .await?;
+            layers.push(path);
+        }
+
+        Ok(layers)
+    }
+
+    #[tokio::test]
+    async fn test_only_included_paths_are_extracted() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let layers = fixture_layers(&tmp).await?;
+        let filter = PathFilter {
+            include: vec!["/usr/local/bin".into()],
+            exclude: vec![],
+        };
+
+        let fs = extract_layers("hello", &layers[..2], &filter).await?;
+        assert!(fs
+            .fs()
+            .metadata("/usr/local/bin/podman_hello_world")
+            .await?
+            .is_file());
+        assert!(fs.fs().metadata("/usr/local/bin/tool").await?.is_file());
+        assert!(fs.fs().metadata("/var").await.is_err());
+        assert!(fs.fs().metadata("/etc").await.is_err());
+
+        // Whiteouts for included paths still apply.
+        let fs = extract_layers("hello", &layers, &filter).await?;
+        assert!(fs
+            .fs()
+            .metadata("/usr/local/bin/podman_hello_world")
+            .await
+            .is_err());
+        assert!(fs.fs().metadata("/usr/local/bin/tool").await?.is_file());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_excluded_paths_are_not_extracted() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let layers = fixture_layers(&tmp).await?;
+        let filter = PathFilter {
+            include: vec![],
+            exclude: vec!["/var/cache".into()],
+        };
+
+        let fs = extract_layers("hello", &layers[..2], &filter).await?;
+        assert!(fs
+            .fs()
+            .metadata("/usr/local/bin/podman_hello_world")
+            .await?
+            .is_file());
+        assert!(fs.fs().metadata("/etc/motd").await?.is_file());
+        assert!(fs.fs().metadata("/var/cache").await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_references_with_registries_are_valid() {
+        assert!(is_valid_reference("ghcr.io/org/app:1.0"));
+        assert!(is_valid_reference("localhost:5000/app:dev"));
+        assert!(is_valid_reference("alpine"));
+        assert!(is_valid_reference(&format!(
+            "alpine@sha256:{}",
+            "a".repeat(64)
+        )));
+    }
+
+    #[test]
+    fn test_image_names_split_after_the_registry_port() {
+        for (name, repo, tag) in [
+            ("alpine", "alpine", "latest"),
+            ("alpine:3.19", "alpine", "3.19"),
+            ("localhost:5000/app", "localhost:5000/app", "latest"),
+            ("localhost:5000/app:dev", "localhost:5000/app", "dev"),
+            ("ghcr.io/org/app:1.0", "ghcr.io/org/app", "1.0"),
+            ("alpine@sha256:abc", "alpine", "sha256:abc"),
+            (
+                "localhost:5000/app:dev@sha256:abc",
+                "localhost:5000/app",
+                "sha256:abc",
+            ),
+        ] {
+            assert_eq!(
+                (repo, tag),
+                split_image_name_into_repo_and_tag(name),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_producer_images_cant_have_digests() -> Result<()> {
+        let err = DockerProducerBuilder::new("digest")
+            .image(format!("localhost:5000/app@sha256:{}", "a".repeat(64)))
+            .build()?
+            .validate()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("can't have a digest"), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_producer_tags_images_on_registries_with_ports() -> Result<()> {
+        if !docker_available().await {
+            warn!("docker is not available, skipping");
+            return Ok(());
+        }
+
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/etc").await?;
+        memfs.fs().write("/etc/motd", b"hi").await?;
+        let image = "localhost:5000/peckish-dev/ported:dev";
+        let producer = DockerProducerBuilder::new("ported").image(image).build()?;
+        producer.validate().await?;
+        producer
+            .produce_from(&MemoryArtifact {
+                name: "motd".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let docker = Docker::connect_with_local_defaults()?;
+        let inspected = docker.inspect_image(image).await?;
+        assert!(inspected
+            .repo_tags
+            .unwrap_or_default()
+            .contains(&image.to_string()));
+        docker.remove_image(image, None, None).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_references_are_rejected() {
+        assert!(!is_valid_reference("ghcr.io/Org/app:1.0"));
+        assert!(!is_valid_reference("ghcr.io/org/app:bad tag"));
+        assert!(!is_valid_reference("app:"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_platform_is_rejected() {
+        let artifact = DockerArtifact {
+            name: "alpine-artifact".into(),
+            image: "alpine:latest".to_string(),
+            platform: Some("arm64".into()),
+            pull_policy: PullPolicy::default(),
+            include_paths: vec![],
+            exclude_paths: vec![],
+        };
+        assert!(artifact.validate().await.is_err());
+    }
 }
@@ -0,0 +1,409 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use floppy_disk::prelude::*;
+use serde::Serialize;
+use tracing::*;
+
+use crate::fs::MemFS;
+use crate::util::config::Injection;
+
+use super::file::{FileArtifact, FilePlacement};
+use super::{Artifact, ArtifactKind, ArtifactProducer, KeptOwners, SelfBuilder, SelfValidation};
+
+/// A Docker build context: a directory with a `Dockerfile` and a `context/`
+/// directory that it copies into the image.
+#[derive(Debug, Clone)]
+pub struct DockerfileArtifact {
+    pub name: String,
+    /// The directory holding the `Dockerfile` and `context/`.
+    pub path: PathBuf,
+}
+
+impl DockerfileArtifact {
+    pub fn dockerfile_path(&self) -> PathBuf {
+        self.path.join("Dockerfile")
+    }
+
+    pub fn context_path(&self) -> PathBuf {
+        self.path.join("context")
+    }
+}
+
+#[async_trait::async_trait]
+impl Artifact for DockerfileArtifact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Dockerfile
+    }
+
+    /// Only the context is extracted. Whatever the `Dockerfile` would add on
+    /// top of it, like the base image, needs a docker build.
+    async fn extract(&self) -> Result<MemFS> {
+        FileArtifact {
+            name: self.name.clone(),
+            paths: vec![self.context_path()],
+            git_mtimes: false,
+            default_excludes: false,
+            extra_excludes: vec![],
+            placement: FilePlacement::Root,
+        }
+        .extract()
+        .await
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        Some(vec![self.dockerfile_path(), self.context_path()])
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for DockerfileArtifact {
+    async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        if !self.dockerfile_path().is_file() {
+            errors.push(format!("no Dockerfile in {}", self.path.display()));
+        }
+
+        if !self.context_path().is_dir() {
+            errors.push(format!("no context/ directory in {}", self.path.display()));
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!(
+                "Dockerfile artifact not valid:\n{}",
+                errors.join("\n")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct DockerfileArtifactBuilder {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[allow(unused)]
+impl DockerfileArtifactBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl SelfBuilder for DockerfileArtifactBuilder {
+    type Output = DockerfileArtifact;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(DockerfileArtifact {
+            name: self.name.clone(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// Writes a Docker build context instead of importing an image, so that it
+/// can be built later, or committed to a repo, without a running daemon.
+#[derive(Debug, Clone, Serialize)]
+pub struct DockerfileProducer {
+    pub name: String,
+    /// The directory to write the `Dockerfile` and `context/` to.
+    pub path: PathBuf,
+    /// The image to build on top of. Defaults to `scratch`.
+    pub base_image: Option<String>,
+    pub cmd: Option<Vec<String>>,
+    pub injections: Vec<Injection>,
+}
+
+impl DockerfileProducer {
+    async fn dockerfile(&self, memfs: &MemFS, owners: &KeptOwners) -> Result<String> {
+        let mut dockerfile = indoc::formatdoc! {"
+            # generated by peckish
+            FROM {base_image}
+            COPY context/ /
+        ",
+            base_image = self.base_image.as_deref().unwrap_or("scratch"),
+        };
+        for line in chown_copies(memfs, owners).await? {
+            dockerfile.push_str(&line);
+        }
+        if let Some(cmd) = &self.cmd {
+            // The exec form, so that the command isn't run through a shell,
+            // which a scratch image doesn't have.
+            dockerfile.push_str(&format!("CMD {}\n", serde_json::to_string(cmd)?));
+        }
+
+        Ok(dockerfile)
+    }
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for DockerfileProducer {
+    type Output = DockerfileArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<DockerfileArtifact> {
+        let mut memfs = previous.extract().await?;
+        let owners = KeptOwners::new(previous, &memfs, &self.injections).await?;
+        self.inject(&mut memfs).await?;
+
+        let artifact = DockerfileArtifact {
+            name: self.name.clone(),
+            path: self.path.clone(),
+        };
+        info!(
+            "writing build context to {}",
+            artifact.context_path().display()
+        );
+        // `COPY` makes everything root-owned unless told otherwise, so owners
+        // on the host don't matter; the Dockerfile sets them instead.
+        memfs
+            .write_contents_to_host(&artifact.context_path())
+            .await?;
+        tokio::fs::write(
+            artifact.dockerfile_path(),
+            self.dockerfile(&memfs, &owners).await?,
+        )
+        .await?;
+
+        Ok(artifact)
+    }
+}
+
+/// `COPY --chown` lines that give paths their owners back, after everything
+/// was copied as root. Only paths owned differently from their parent are
+/// copied again, since copying a directory also copies everything under it
+/// with the same owner. They're in walk order, so that deeper paths are
+/// copied after, and win over, their parents.
+async fn chown_copies(memfs: &MemFS, owners: &KeptOwners) -> Result<Vec<String>> {
+    let fs = memfs.fs();
+    let mut lines = vec![];
+    for path in nyoom::walk_ordered(fs, "/").await? {
+        let Some(parent) = path.parent() else {
+            continue;
+        };
+        let owner = |path: &Path| {
+            let path = path.to_path_buf();
+            async move {
+                if path == Path::new("/") {
+                    return Ok((0, 0));
+                }
+                let metadata = fs.symlink_metadata(&path).await?;
+                Ok::<_, eyre::Report>(owners.owner(&path, metadata.uid()?, metadata.gid()?))
+            }
+        };
+        let (uid, gid) = owner(&path).await?;
+        if (uid, gid) == owner(parent).await? {
+            continue;
+        }
+
+        let relative = path.strip_prefix("/")?.to_string_lossy();
+        lines.push(format!(
+            "COPY --chown={uid}:{gid} {}\n",
+            serde_json::to_string(&[format!("context/{relative}"), format!("/{relative}")])?
+        ));
+    }
+
+    Ok(lines)
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for DockerfileProducer {
+    async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        if self.path.join("Dockerfile").exists() || self.path.join("context").exists() {
+            errors.push(format!(
+                "{} already has a Dockerfile or context/",
+                self.path.display()
+            ));
+        }
+
+        if self.cmd.as_ref().is_some_and(|cmd| cmd.is_empty()) {
+            errors.push("cmd is empty".to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!(
+                "Dockerfile producer is invalid:\n{}",
+                errors.join("\n")
+            ));
+        }
+
+        tokio::fs::create_dir_all(&self.path).await?;
+
+        Ok(())
+    }
+}
+
+pub struct DockerfileProducerBuilder {
+    name: String,
+    path: PathBuf,
+    base_image: Option<String>,
+    cmd: Option<Vec<String>>,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl DockerfileProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn base_image<S: Into<String>>(mut self, base_image: S) -> Self {
+        self.base_image = Some(base_image.into());
+        self
+    }
+
+    pub fn cmd(mut self, cmd: Vec<String>) -> Self {
+        self.cmd = Some(cmd);
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for DockerfileProducerBuilder {
+    type Output = DockerfileProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+            base_image: None,
+            cmd: None,
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(DockerfileProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            base_image: self.base_image.clone(),
+            cmd: self.cmd.clone(),
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+    use floppy_disk::FloppyDisk;
+
+    use crate::artifact::memory::EmptyArtifact;
+    use crate::fs::TempDir;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_build_context_copies_and_runs_the_memfs() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let producer = DockerfileProducerBuilder::new("context")
+            .path(tmp.path_view().join("build"))
+            .base_image("debian:bookworm-slim")
+            .cmd(vec!["/usr/bin/app".into(), "--serve".into()])
+            .inject(Injection::Create {
+                path: "/usr/bin/app".into(),
+                content: b"#!/bin/sh\necho hi\n".to_vec(),
+            })
+            .build()?;
+        producer.validate().await?;
+        let artifact = producer.produce_from(&EmptyArtifact::new("empty")).await?;
+
+        let dockerfile = tokio::fs::read_to_string(artifact.dockerfile_path()).await?;
+        let lines = dockerfile.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"FROM debian:bookworm-slim"));
+        assert!(lines.contains(&"COPY context/ /"));
+        assert!(lines.contains(&r#"CMD ["/usr/bin/app","--serve"]"#));
+        assert!(!dockerfile.contains("--chown"));
+
+        let memfs = artifact.extract().await?;
+        assert_eq!(
+            b"#!/bin/sh\necho hi\n".to_vec(),
+            memfs.fs().read("/usr/bin/app").await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_owners_are_copied_with_chown() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let artifact = DockerfileProducerBuilder::new("owners")
+            .path(tmp.path_view().join("build"))
+            .inject(Injection::Create {
+                path: "/var/www/index.html".into(),
+                content: b"hi".to_vec(),
+            })
+            .inject(Injection::Create {
+                path: "/var/www/private/key".into(),
+                content: b"secret".to_vec(),
+            })
+            .inject(Injection::Chown {
+                path: "/var/www".into(),
+                uid: 33,
+                gid: 33,
+                recursive: true,
+            })
+            .inject(Injection::Chown {
+                path: "/var/www/private/key".into(),
+                uid: 0,
+                gid: 0,
+                recursive: false,
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let dockerfile = tokio::fs::read_to_string(artifact.dockerfile_path()).await?;
+        let copies = dockerfile
+            .lines()
+            .filter(|line| line.starts_with("COPY"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "COPY context/ /",
+                r#"COPY --chown=33:33 ["context/var/www","/var/www"]"#,
+                r#"COPY --chown=0:0 ["context/var/www/private/key","/var/www/private/key"]"#,
+            ],
+            copies
+        );
+
+        Ok(())
+    }
+}
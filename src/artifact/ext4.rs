@@ -1,16 +1,20 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use disk_drive::DiskDrive;
 use eyre::Result;
 use flail::ext::facade::ExtFacadeFloppyDisk;
+use flail::ext::messages::{ExtError, ExtEtMessage};
+use flail::ext::{ExtFilesystem, ExtFilesystemOpenFlags};
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
 use floppy_disk::tokio_fs::TokioFloppyDisk;
-use floppy_disk::FloppyDisk;
+use serde::Serialize;
 use tracing::*;
 
-use crate::fs::MemFS;
+use crate::fs::{MemFS, TempDir};
 use crate::util::config::Injection;
 
-use super::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation};
 
 #[derive(Debug, Clone)]
 pub struct Ext4Artifact {
@@ -24,13 +28,53 @@ impl Artifact for Ext4Artifact {
         &self.name
     }
 
-    async fn extract(&self) -> Result<MemFS> {
-        let fs = MemFS::new();
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Ext4
+    }
 
-        let floppy_disk = ExtFacadeFloppyDisk::new(&self.path)?;
-        DiskDrive::copy_between(&floppy_disk, &*fs).await?;
+    async fn extract(&self) -> Result<MemFS> {
+        let memfs = MemFS::new();
+        let fs = memfs.fs();
+
+        // flail can only open images read-write: opening one creates a
+        // missing `lost+found`, and closing it writes the bitmaps back. Reading
+        // from a copy leaves the image itself untouched.
+        let tmp = TempDir::new().await?;
+        let image = tmp.path_view().join("image.ext4");
+        tokio::fs::copy(&self.path, &image).await?;
+
+        let disk = ExtFacadeFloppyDisk::new(&image)?;
+        let listing = open_image(&image)?;
+
+        let mut dirs = vec![PathBuf::from("/")];
+        while let Some(dir) = dirs.pop() {
+            for name in list_dir(&listing, &dir)? {
+                let path = dir.join(name);
+                debug!("extracting {}", path.display());
+                let metadata = disk.symlink_metadata(&path).await?;
+
+                if metadata.is_symlink() {
+                    fs.symlink(disk.read_link(&path).await?, path).await?;
+                    continue;
+                } else if metadata.is_dir() {
+                    fs.create_dir_all(&path).await?;
+                    dirs.push(path.clone());
+                } else if metadata.is_file() {
+                    fs.write(&path, disk.read(&path).await?).await?;
+                } else {
+                    continue;
+                }
+
+                fs.chown(&path, metadata.uid()?, metadata.gid()?).await?;
+                fs.set_permissions(
+                    &path,
+                    MemPermissions::from_mode(metadata.permissions().mode()),
+                )
+                .await?;
+            }
+        }
 
-        Ok(fs)
+        Ok(memfs)
     }
 
     fn try_clone(&self) -> Result<Box<dyn Artifact>> {
@@ -42,6 +86,34 @@ impl Artifact for Ext4Artifact {
     }
 }
 
+fn open_image(path: &Path) -> Result<ExtFilesystem> {
+    ExtFilesystem::open(
+        path,
+        None,
+        Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+    )
+}
+
+/// The names of the entries in a directory of the image, minus `.`, `..`, and
+/// the root's `lost+found`. The facade's `read_dir` can't be used for this,
+/// since it hands back the first entry over and over, so `DiskDrive` can't
+/// walk an ext4 image either.
+fn list_dir(fs: &ExtFilesystem, dir: &Path) -> Result<Vec<String>> {
+    let mut names = vec![];
+    fs.iterate_dir(dir, |entry, _offset, _blocksize, _buf, _priv_data| {
+        // SAFETY: e2fs hands us a valid entry for the duration of the callback.
+        let entry = unsafe { *entry };
+        let len = (entry.name_len & 0xff) as usize;
+        let name: Vec<u8> = entry.name[..len].iter().map(|c| *c as u8).collect();
+        names.push(String::from_utf8_lossy(&name).to_string());
+        Ok(0)
+    })?;
+
+    let is_root = dir == Path::new("/");
+    names.retain(|name| name != "." && name != ".." && !(is_root && name == "lost+found"));
+    Ok(names)
+}
+
 #[async_trait::async_trait]
 impl SelfValidation for Ext4Artifact {
     async fn validate(&self) -> Result<()> {
@@ -90,13 +162,132 @@ impl SelfBuilder for Ext4ArtifactBuilder {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The block size flail formats images with.
+const BLOCK_SIZE: u64 = 1_024;
+/// flail creates one inode for every this many bytes of the image.
+const BYTES_PER_INODE: u64 = 8_192;
+/// How big the inodes flail creates are.
+const INODE_SIZE: u64 = 256;
+/// The slack added on top of the computed size if none is configured.
+pub const DEFAULT_SIZE_SLACK: u64 = 1_024 * 1_024;
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Ext4Producer {
     pub name: String,
     pub path: PathBuf,
+    /// Extra bytes to leave free in the image, on top of what its contents
+    /// need.
+    pub size_slack: u64,
+    /// An existing image to copy the memfs into, instead of creating a fresh
+    /// one. The output keeps the template's size, label, reserved blocks,
+    /// and contents, so `size_slack` is unused.
+    pub template: Option<PathBuf>,
     pub injections: Vec<Injection>,
 }
 
+impl Ext4Producer {
+    /// Write the memfs into a new image of `size` bytes at `self.path`.
+    async fn fill_image(&self, memfs: &MemFS, size: u64) -> Result<()> {
+        drop(ExtFacadeFloppyDisk::create(&self.path, size)?);
+        create_directories(memfs, &self.path).await?;
+        let output = ExtFacadeFloppyDisk::new(&self.path)?;
+        DiskDrive::copy_between(memfs.fs(), &output).await?;
+
+        Ok(())
+    }
+
+    /// Write the memfs into a copy of the template image at `self.path`.
+    /// Paths in both are overwritten with the memfs' version. The copy is
+    /// made next to `self.path` and only moved there once it's complete, so
+    /// that a failure doesn't leave a partial image behind.
+    async fn overlay_template(&self, memfs: &MemFS, template: &Path) -> Result<()> {
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let overlaid: Result<()> = async {
+            tokio::fs::copy(template, &partial).await?;
+            create_directories(memfs, &partial).await?;
+            let output = ExtFacadeFloppyDisk::new(&partial)?;
+            DiskDrive::copy_between(memfs.fs(), &output).await?;
+            drop(output);
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = overlaid {
+            if let Err(e) = tokio::fs::remove_file(&partial).await {
+                warn!("could not remove {}: {e}", partial.display());
+            }
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+/// Whether filling an image failed because it ran out of blocks, inodes, or
+/// room in a directory, rather than for any other reason. flail wraps
+/// libext2fs errors in io errors that can't be downcast back, so they're
+/// recognized by their messages.
+fn is_out_of_space(e: &eyre::Report) -> bool {
+    let messages = [
+        ExtError::ENOSPC.to_string(),
+        ExtError::EFBIG.to_string(),
+        ExtEtMessage::TooSmall.to_string(),
+        ExtEtMessage::BlockAllocFail.to_string(),
+        ExtEtMessage::InodeAllocFail.to_string(),
+        ExtEtMessage::DirNoSpace.to_string(),
+        ExtEtMessage::DirNoSpaceForCsum.to_string(),
+        ExtEtMessage::ExtentNoSpace.to_string(),
+        ExtEtMessage::EANoSpace.to_string(),
+        ExtEtMessage::InlineDataNoSpace.to_string(),
+    ];
+
+    e.chain().any(|e| {
+        let no_space = e
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.raw_os_error() == Some(28));
+        let message = e.to_string();
+        no_space || messages.iter().any(|m| message.contains(m.as_str()))
+    })
+}
+
+/// A tight estimate of how big an image has to be to hold the memfs: every
+/// file and symlink rounded up to whole blocks, a block for every
+/// directory, and enough room for the inode table and the groups' metadata.
+/// Since flail sizes the inode table from the image size, the image is also
+/// made big enough to have an inode for every path.
+pub async fn image_size(memfs: &MemFS, slack: u64) -> Result<u64> {
+    let fs = memfs.fs();
+    let mut blocks = 0u64;
+    let mut inodes = 0u64;
+
+    for path in nyoom::walk_ordered(fs, "/").await? {
+        let metadata = fs.symlink_metadata(&path).await?;
+        inodes += 1;
+        blocks += if metadata.is_file() || metadata.is_symlink() {
+            // Large files need extent blocks on top of their data.
+            let data = metadata.len().div_ceil(BLOCK_SIZE);
+            data + data / 256
+        } else {
+            1
+        };
+    }
+
+    let content = blocks * BLOCK_SIZE;
+    // The inode table is sized from the whole image, so it grows along with
+    // everything else: 1/32 of the image with the defaults.
+    let with_inode_table = content + content * INODE_SIZE / BYTES_PER_INODE;
+    // Superblocks, group descriptors, bitmaps, the root dir, and
+    // `lost+found`.
+    let overhead = 16 * BLOCK_SIZE + with_inode_table / 64;
+    let size = (with_inode_table + overhead).max((inodes + 16) * BYTES_PER_INODE);
+
+    Ok(size + slack)
+}
+
 #[async_trait::async_trait]
 impl ArtifactProducer for Ext4Producer {
     type Output = Ext4Artifact;
@@ -113,12 +304,36 @@ impl ArtifactProducer for Ext4Producer {
         info!("producing {}", self.path.display());
         let mut memfs = previous.extract().await?;
         self.inject(&mut memfs).await?;
-        // we add 2M to the end *just* in case of space memes
-        let size = memfs.size().await? + (1_024 * 1_024 * 1_024 * 2);
 
-        let output = ExtFacadeFloppyDisk::create(&self.path, size)?;
+        if let Some(template) = &self.template {
+            debug!("copying into template {}", template.display());
+            self.overlay_template(&memfs, template).await?;
+            return Ok(Ext4Artifact {
+                name: self.path.to_string_lossy().to_string(),
+                path: self.path.clone(),
+            });
+        }
 
-        DiskDrive::copy_between(&*memfs, &output).await?;
+        let size = image_size(&memfs, self.size_slack).await?;
+        debug!("creating {} byte image", size);
+        match self.fill_image(&memfs, size).await {
+            // The estimate can come up short, ex. for directories with a lot
+            // of entries, so give it one more go with plenty of room before
+            // giving up.
+            Err(e) if is_out_of_space(&e) => {
+                warn!(
+                    "{} bytes wasn't enough for {}, retrying with {} bytes: {e}",
+                    size,
+                    self.path.display(),
+                    size * 2
+                );
+                if let Err(e) = tokio::fs::remove_file(&self.path).await {
+                    warn!("could not remove {}: {e}", self.path.display());
+                }
+                self.fill_image(&memfs, size * 2).await?;
+            }
+            result => result?,
+        }
 
         Ok(Ext4Artifact {
             name: self.path.to_string_lossy().to_string(),
@@ -137,22 +352,68 @@ impl SelfValidation for Ext4Producer {
         if TokioFloppyDisk::new(None)
             .metadata(&self.path)
             .await
-            .is_err()
+            .is_ok()
         {
-            Ok(())
-        } else {
-            Err(eyre::eyre!(
+            return Err(eyre::eyre!(
                 "cannot produce artifact '{}': path already exists: {}",
                 self.name,
                 self.path.display()
-            ))?
+            ));
+        }
+
+        if let Some(template) = &self.template {
+            if !template.is_file() {
+                return Err(eyre::eyre!(
+                    "cannot produce artifact '{}': template is not a file: {}",
+                    self.name,
+                    template.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates every directory in the memfs on the ext4 image ahead of copying,
+/// parents first.
+///
+/// The ext facade's `create_dir_all` only creates the *parents* of the path
+/// it's given, so `DiskDrive` would otherwise go on to set the mode and owner
+/// of a directory that doesn't exist yet. The directories are made through a
+/// handle of their own, which is closed before the facade opens the image;
+/// `DiskDrive` then finds them already there and applies the right ownership
+/// and permissions to them.
+async fn create_directories(memfs: &MemFS, image: &Path) -> Result<()> {
+    let mut dirs = vec![];
+    for path in nyoom::walk_ordered(memfs.fs(), "/").await? {
+        if path != Path::new("/") && memfs.fs().symlink_metadata(&path).await?.is_dir() {
+            dirs.push(path);
+        }
+    }
+
+    let fs = open_image(image)?;
+    for dir in dirs {
+        // Templates can already have some of them.
+        if fs.find_inode(&dir).is_ok() {
+            continue;
         }
+        let parent = dir.parent().unwrap_or(Path::new("/"));
+        let name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        fs.mkdir(parent, name)?;
     }
+
+    Ok(())
 }
 
 pub struct Ext4ProducerBuilder {
     name: String,
     path: PathBuf,
+    size_slack: u64,
+    template: Option<PathBuf>,
     injections: Vec<Injection>,
 }
 
@@ -163,6 +424,16 @@ impl Ext4ProducerBuilder {
         self
     }
 
+    pub fn size_slack(mut self, size_slack: u64) -> Self {
+        self.size_slack = size_slack;
+        self
+    }
+
+    pub fn template<P: Into<PathBuf>>(mut self, template: P) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
     pub fn inject(mut self, injection: Injection) -> Self {
         self.injections.push(injection);
         self
@@ -176,6 +447,8 @@ impl SelfBuilder for Ext4ProducerBuilder {
         Self {
             name: name.into(),
             path: PathBuf::from(""),
+            size_slack: DEFAULT_SIZE_SLACK,
+            template: None,
             injections: vec![],
         }
     }
@@ -184,7 +457,265 @@ impl SelfBuilder for Ext4ProducerBuilder {
         Ok(Ext4Producer {
             name: self.name.clone(),
             path: self.path.clone(),
+            size_slack: self.size_slack,
+            template: self.template.clone(),
             injections: self.injections.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::MemoryArtifact;
+    use crate::artifact::tarball::TarballProducerBuilder;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[test]
+    fn test_only_running_out_of_space_is_retried() {
+        let wrapped = |e| eyre::Report::new(std::io::Error::new(std::io::ErrorKind::Other, e));
+        assert!(is_out_of_space(&wrapped(eyre::Report::new(
+            ExtEtMessage::BlockAllocFail
+        ))));
+        assert!(is_out_of_space(&eyre::Report::new(ExtError::ENOSPC)));
+        assert!(is_out_of_space(&eyre::Report::new(
+            std::io::Error::from_raw_os_error(28)
+        )));
+
+        assert!(!is_out_of_space(&wrapped(eyre::Report::new(
+            ExtError::ENOENT
+        ))));
+        assert!(!is_out_of_space(&eyre::eyre!("permission denied")));
+    }
+
+    #[tokio::test]
+    async fn test_ownership_and_modes_survive_roundtrip() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/usr/bin").await?;
+        (*memfs).write("/usr/bin/hello", b"hello").await?;
+        (*memfs)
+            .set_permissions("/usr/bin/hello", MemPermissions::from_mode(0o100750))
+            .await?;
+        (*memfs).chown("/usr/bin/hello", 1000, 1001).await?;
+
+        let artifact = Ext4ProducerBuilder::new("ext4")
+            .path(tmp.path_view().join("image.ext4"))
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let extracted = artifact.extract().await?;
+        let metadata = (*extracted).metadata("/usr/bin/hello").await?;
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(metadata.uid()?, 1000);
+        assert_eq!(metadata.gid()?, 1001);
+        assert_eq!((*extracted).read("/usr/bin/hello").await?, b"hello");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ext4_and_tarball_extract_the_same_paths() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/etc/peckish").await?;
+        memfs.fs().write("/etc/peckish/config", b"config").await?;
+        memfs.fs().write("/etc/hostname", b"peckish").await?;
+        let input = MemoryArtifact {
+            name: "memory".into(),
+            fs: memfs,
+        };
+
+        let ext4 = Ext4ProducerBuilder::new("ext4")
+            .path(tmp.path_view().join("image.ext4"))
+            .build()?
+            .produce_from(&input)
+            .await?
+            .extract()
+            .await?;
+        let tarball = TarballProducerBuilder::new("tarball")
+            .path(tmp.path_view().join("image.tar"))
+            .build()?
+            .produce_from(&input)
+            .await?
+            .extract()
+            .await?;
+
+        assert_eq!(
+            nyoom::walk_ordered(ext4.fs(), "/").await?,
+            nyoom::walk_ordered(tarball.fs(), "/").await?
+        );
+        assert_eq!(ext4.fs().read("/etc/hostname").await?, b"peckish");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extracting_leaves_the_image_alone() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/srv/lost+found").await?;
+        memfs.fs().write("/srv/lost+found/kept", b"kept").await?;
+
+        let artifact = Ext4ProducerBuilder::new("ext4")
+            .path(tmp.path_view().join("image.ext4"))
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let before = crate::util::sha256_digest(&artifact.path).await?;
+        let extracted = artifact.extract().await?;
+        assert_eq!(before, crate::util::sha256_digest(&artifact.path).await?);
+
+        // Only the root's `lost+found` belongs to the filesystem.
+        assert!(extracted.fs().metadata("/lost+found").await.is_err());
+        assert_eq!(extracted.fs().read("/srv/lost+found/kept").await?, b"kept");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_image_is_sized_to_its_contents() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/share/data").await?;
+        for i in 0..4 {
+            memfs
+                .fs()
+                .write(format!("/usr/share/data/{i}"), vec![i as u8; 64 * 1_024])
+                .await?;
+        }
+        let content = 4 * 64 * 1_024;
+
+        let artifact = Ext4ProducerBuilder::new("ext4")
+            .path(tmp.path_view().join("image.ext4"))
+            .size_slack(0)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let size = tokio::fs::metadata(&artifact.path).await?.len();
+        assert!(
+            size < content + content / 4,
+            "{size} byte image for {content} bytes of content"
+        );
+        let extracted = artifact.extract().await?;
+        assert_eq!(
+            vec![3u8; 64 * 1_024],
+            extracted.fs().read("/usr/share/data/3").await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memfs_is_overlaid_into_template() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let template = MemFS::new();
+        template.fs().create_dir_all("/etc").await?;
+        template.fs().write("/etc/template", b"template").await?;
+        template.fs().write("/etc/hostname", b"golden").await?;
+        let template = Ext4ProducerBuilder::new("template")
+            .path(tmp.path_view().join("template.ext4"))
+            .size_slack(4 * 1_024 * 1_024)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "template".into(),
+                fs: template,
+            })
+            .await?;
+
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/etc").await?;
+        memfs.fs().write("/etc/hostname", b"peckish").await?;
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().write("/usr/bin/new", b"new").await?;
+        let producer = Ext4ProducerBuilder::new("ext4")
+            .path(tmp.path_view().join("image.ext4"))
+            .template(&template.path)
+            .build()?;
+        producer.validate().await?;
+        let artifact = producer
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        assert_eq!(
+            tokio::fs::metadata(&template.path).await?.len(),
+            tokio::fs::metadata(&artifact.path).await?.len()
+        );
+        let extracted = artifact.extract().await?;
+        assert_eq!(extracted.fs().read("/etc/template").await?, b"template");
+        assert_eq!(extracted.fs().read("/etc/hostname").await?, b"peckish");
+        assert_eq!(extracted.fs().read("/usr/bin/new").await?, b"new");
+
+        // The template itself is left alone.
+        let template = template.extract().await?;
+        assert_eq!(template.fs().read("/etc/hostname").await?, b"golden");
+        assert!(template.fs().metadata("/usr/bin/new").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_overlay_leaves_no_image() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let template = MemFS::new();
+        template.fs().write("/template", b"template").await?;
+        let template = Ext4ProducerBuilder::new("template")
+            .path(tmp.path_view().join("template.ext4"))
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "template".into(),
+                fs: template,
+            })
+            .await?;
+
+        // Far too big for the template.
+        let memfs = MemFS::new();
+        memfs
+            .fs()
+            .write("/big", vec![1u8; 16 * 1_024 * 1_024])
+            .await?;
+        let path = tmp.path_view().join("image.ext4");
+        let producer = Ext4ProducerBuilder::new("ext4")
+            .path(&path)
+            .template(&template.path)
+            .build()?;
+        assert!(producer
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await
+            .is_err());
+
+        assert!(tokio::fs::metadata(&path).await.is_err());
+        assert!(
+            tokio::fs::metadata(tmp.path_view().join("image.ext4.partial"))
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
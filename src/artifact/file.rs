@@ -1,20 +1,177 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use disk_drive::DiskDrive;
-use eyre::Result;
+use eyre::{eyre, Result};
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
 use floppy_disk::tokio_fs::TokioFloppyDisk;
+use serde::Serialize;
 use tracing::*;
 
-use crate::fs::MemFS;
+use crate::fs::{IntendedOwner, MemFS};
 use crate::util::config::Injection;
+use crate::util::{get_current_time, git, sha256_digest};
 
-use super::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+use super::{
+    exceeds_extracted_size, Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation,
+};
 
 /// A path or set of paths on the filesystem.
 #[derive(Debug, Clone)]
 pub struct FileArtifact {
     pub name: String,
     pub paths: Vec<PathBuf>,
+    /// Set each file's mtime to the time of the last git commit that touched
+    /// it. Files that aren't tracked by git get the current time instead.
+    pub git_mtimes: bool,
+    /// Leave out [`DEFAULT_EXCLUDES`] when copying directories.
+    pub default_excludes: bool,
+    /// More file or directory names to leave out when copying directories.
+    pub extra_excludes: Vec<String>,
+    /// Where in the memfs each path ends up.
+    pub placement: FilePlacement,
+}
+
+/// Where a [`FileArtifact`]'s paths end up in the memfs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FilePlacement {
+    /// At the path as it was given, so `usr/bin/foo` ends up at
+    /// `/usr/bin/foo`. The contents of a directory end up under its full
+    /// host path, ex. `./dir` ends up at `/dir/<current dir>/dir`.
+    #[default]
+    AsGiven,
+    /// Directly under `/`. A file ends up at `/<file name>`, and a
+    /// directory's contents end up in `/`.
+    Root,
+    /// At the path as it was given, under this prefix, so `usr/bin/foo` and
+    /// `/usr/bin/foo` with the prefix `/opt/app` both end up at
+    /// `/opt/app/usr/bin/foo`. A directory's contents end up there too.
+    Prefix(PathBuf),
+}
+
+impl FilePlacement {
+    /// Where `path` ends up, or `None` if it ends up at the path as given.
+    fn target(&self, path: &Path) -> Option<PathBuf> {
+        match self {
+            FilePlacement::AsGiven => None,
+            FilePlacement::Root if path.is_dir() => Some(PathBuf::from("/")),
+            FilePlacement::Root => Some(Path::new("/").join(path.file_name()?)),
+            FilePlacement::Prefix(prefix) => {
+                let relative = path
+                    .components()
+                    .filter(|c| matches!(c, Component::Normal(_)))
+                    .collect::<PathBuf>();
+                Some(Path::new("/").join(prefix).join(relative))
+            }
+        }
+    }
+}
+
+/// VCS and build-artifact directories that almost never belong in a package.
+pub const DEFAULT_EXCLUDES: &[&str] = &[".git", ".hg", ".svn", "target", "node_modules"];
+
+/// The names to leave out of copied directories: [`DEFAULT_EXCLUDES`], if
+/// `default_excludes` is set, and `extra_excludes`.
+pub(crate) fn excluded_names(default_excludes: bool, extra_excludes: &[String]) -> Vec<String> {
+    let defaults = if default_excludes {
+        DEFAULT_EXCLUDES
+    } else {
+        &[]
+    };
+    defaults
+        .iter()
+        .map(|name| name.to_string())
+        .chain(extra_excludes.iter().cloned())
+        .collect()
+}
+
+/// Copy the contents of the host directory `src` to `dest` in the memfs,
+/// leaving out entries with an excluded name. Excluded entries are skipped
+/// before they're read, and excluded directories aren't walked at all. Only
+/// names *inside* `src` are matched, so a path like `./target/release` is
+/// still copied as asked.
+pub(crate) async fn copy_host_dir(
+    fs: &MemFS,
+    src: &Path,
+    dest: &Path,
+    excludes: &[String],
+) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let host = TokioFloppyDisk::new(None);
+    let mut dirs = vec![(src.to_path_buf(), dest.to_path_buf())];
+    while let Some((src, dest)) = dirs.pop() {
+        let metadata = tokio::fs::metadata(&src).await?;
+        fs.create_dir_all(&dest).await?;
+        fs.set_permissions(&dest, MemPermissions::from_mode(metadata.mode()))
+            .await?;
+        fs.chown(&dest, metadata.uid(), metadata.gid()).await?;
+
+        let mut entries = tokio::fs::read_dir(&src).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let src = entry.path();
+            if excludes.iter().any(|excluded| name == excluded.as_str()) {
+                debug!("excluding {}", src.display());
+                continue;
+            }
+
+            let dest = dest.join(&name);
+            let file_type = entry.file_type().await?;
+            if file_type.is_symlink() {
+                fs.symlink(tokio::fs::read_link(&src).await?, dest).await?;
+            } else if file_type.is_dir() {
+                dirs.push((src, dest));
+            } else if file_type.is_file() {
+                DiskDrive::copy_from_src_to_dest(&host, &**fs, &src, &dest).await?;
+            } else {
+                warn!(
+                    "skipping {}, which is not a file, directory, or symlink",
+                    src.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl FileArtifact {
+    /// Apply git mtimes to `src`, which was copied to `root`.
+    async fn finish_copy(&self, fs: &MemFS, src: &Path, root: &Path) -> Result<()> {
+        if self.git_mtimes {
+            self.set_git_mtimes(fs, src, root).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_git_mtimes(&self, fs: &MemFS, src: &Path, root: &Path) -> Result<()> {
+        let now = get_current_time()?;
+        // Commit times are keyed relative to the directory git runs in.
+        let (dir, file) = if src.is_dir() {
+            (src, None)
+        } else {
+            (src.parent().unwrap_or(Path::new("/")), src.file_name())
+        };
+        let times = git::last_commit_times(dir).await?.unwrap_or_default();
+        for path in nyoom::walk_ordered(&**fs, root).await? {
+            if !fs.symlink_metadata(&path).await?.is_file() {
+                continue;
+            }
+
+            let relative = match (file, path.strip_prefix(root)) {
+                (Some(file), _) => PathBuf::from(file),
+                (None, Ok(relative)) => relative.to_path_buf(),
+                (None, Err(_)) => continue,
+            };
+            let mtime = times.get(&relative).copied().unwrap_or(now);
+            debug!("git mtime of {}: {mtime}", dir.join(&relative).display());
+            fs.set_mtime(path, mtime);
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -23,10 +180,15 @@ impl Artifact for FileArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::File
+    }
+
     async fn extract(&self) -> Result<MemFS> {
         let fs = MemFS::new();
         let host = TokioFloppyDisk::new(None);
         debug!("copying {} paths to memfs!", self.paths.len());
+        let excludes = excluded_names(self.default_excludes, &self.extra_excludes);
         let pwd = std::env::current_dir()?;
         for path in &self.paths {
             let full_src_path = if !path.starts_with("/") {
@@ -34,8 +196,38 @@ impl Artifact for FileArtifact {
             } else {
                 path.to_path_buf()
             };
-            debug!("copy {} -> {}", full_src_path.display(), path.display());
-            DiskDrive::copy_from_src_to_dest(&host, &*fs, full_src_path, path).await?;
+
+            let Some(target) = self.placement.target(path) else {
+                debug!("copy {} -> {}", full_src_path.display(), path.display());
+                // Directories are copied to `path` with their full host path
+                // appended.
+                let mut root = Path::new("/").join(path);
+                if full_src_path.is_dir() {
+                    root.push(full_src_path.strip_prefix("/")?);
+                    copy_host_dir(&fs, &full_src_path, &root, &excludes).await?;
+                } else {
+                    DiskDrive::copy_from_src_to_dest(&host, &*fs, &full_src_path, path).await?;
+                }
+                self.finish_copy(&fs, &full_src_path, &root).await?;
+                continue;
+            };
+
+            // Copy into a scratch memfs first, so that the directory's
+            // contents, rather than its host path, are what get moved.
+            debug!("copy {} -> {}", full_src_path.display(), target.display());
+            let staged = MemFS::new();
+            let staged_root = if full_src_path.is_dir() {
+                let root = Path::new("/").join(full_src_path.strip_prefix("/")?);
+                copy_host_dir(&staged, &full_src_path, &root, &excludes).await?;
+                root
+            } else {
+                let name = Path::new("/").join(full_src_path.file_name().unwrap_or_default());
+                DiskDrive::copy_from_src_to_dest(&host, &*staged, &full_src_path, &name).await?;
+                name
+            };
+            self.finish_copy(&staged, &full_src_path, &staged_root)
+                .await?;
+            fs.copy_tree_from(&staged, &staged_root, &target).await?;
         }
         Ok(fs)
     }
@@ -76,14 +268,38 @@ impl SelfValidation for FileArtifact {
 pub struct FileArtifactBuilder {
     name: String,
     paths: Vec<PathBuf>,
+    git_mtimes: bool,
+    default_excludes: bool,
+    extra_excludes: Vec<String>,
+    placement: FilePlacement,
 }
 
 #[allow(unused)]
 impl FileArtifactBuilder {
-    pub fn add_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+    pub fn add_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
         self.paths.push(path.into());
         self
     }
+
+    pub fn git_mtimes(mut self, git_mtimes: bool) -> Self {
+        self.git_mtimes = git_mtimes;
+        self
+    }
+
+    pub fn default_excludes(mut self, default_excludes: bool) -> Self {
+        self.default_excludes = default_excludes;
+        self
+    }
+
+    pub fn exclude<S: Into<String>>(mut self, name: S) -> Self {
+        self.extra_excludes.push(name.into());
+        self
+    }
+
+    pub fn placement(mut self, placement: FilePlacement) -> Self {
+        self.placement = placement;
+        self
+    }
 }
 
 impl SelfBuilder for FileArtifactBuilder {
@@ -93,6 +309,10 @@ impl SelfBuilder for FileArtifactBuilder {
         Self {
             name: name.into(),
             paths: vec![],
+            git_mtimes: false,
+            default_excludes: true,
+            extra_excludes: vec![],
+            placement: FilePlacement::AsGiven,
         }
     }
 
@@ -100,19 +320,166 @@ impl SelfBuilder for FileArtifactBuilder {
         Ok(FileArtifact {
             name: self.name.clone(),
             paths: self.paths.clone(),
+            git_mtimes: self.git_mtimes,
+            default_excludes: self.default_excludes,
+            extra_excludes: self.extra_excludes.clone(),
+            placement: self.placement.clone(),
         })
     }
 }
 
 /// Produces a set of files at the given path on the filesystem.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FileProducer {
     pub name: String,
     pub path: PathBuf,
     pub preserve_empty_directories: Option<bool>,
+    /// Extract straight to `path`, instead of into memory, when the input's
+    /// contents add up to more than this many bytes once extracted. Only
+    /// inputs that know their [`Artifact::extracted_size`], like tarballs,
+    /// are spilled. Injections are applied in memory, so producers with
+    /// injections always extract into memory.
+    pub spill_threshold: Option<u64>,
+    /// Also write a [`CHECKSUMS_FILE`] into `path`, so that the output can be
+    /// checked with `sha256sum -c` from inside it.
+    pub checksums: bool,
     pub injections: Vec<Injection>,
 }
 
+/// The name of the checksums file that [`FileProducer::checksums`] writes.
+pub const CHECKSUMS_FILE: &str = "CHECKSUMS.txt";
+
+impl FileProducer {
+    async fn produce_on_disk(&self, previous: &dyn Artifact) -> Result<FileArtifact> {
+        tokio::fs::create_dir_all(&self.path).await?;
+        let extracted = previous.extract_to_dir(&self.path).await?;
+        self.record_owners(&extracted.unowned).await?;
+
+        // Only what the input extracted is part of the output, not whatever
+        // was already in the directory, ex. an earlier run's checksums.
+        let output_root = tokio::fs::canonicalize(&self.path).await?;
+        let paths = extracted
+            .paths
+            .iter()
+            .map(|p| output_root.join(p.strip_prefix("/").unwrap_or(p)))
+            .collect::<Vec<_>>();
+        let paths = self.prune_empty_directories(paths).await?;
+        if self.checksums {
+            self.write_checksums(&output_root, &paths).await?;
+        }
+
+        Ok(FileArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            paths,
+            git_mtimes: false,
+            default_excludes: true,
+            extra_excludes: vec![],
+            placement: FilePlacement::AsGiven,
+        })
+    }
+
+    /// Where the owners that couldn't be set are written, next to the output
+    /// directory rather than in it.
+    pub fn owners_manifest_path(&self) -> PathBuf {
+        let dir = self.path.components().collect::<PathBuf>();
+        PathBuf::from(format!("{}.owners", dir.display()))
+    }
+
+    /// Remove the exported directories that ended up without any files in
+    /// them, deepest first, unless [`FileProducer::preserve_empty_directories`]
+    /// is set. Returns the paths that are left.
+    async fn prune_empty_directories(&self, paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        if self.preserve_empty_directories.unwrap_or(false) {
+            return Ok(paths);
+        }
+
+        let mut removed = std::collections::HashSet::new();
+        for path in paths.iter().rev() {
+            if !tokio::fs::symlink_metadata(path).await?.is_dir() {
+                continue;
+            }
+            if tokio::fs::read_dir(path)
+                .await?
+                .next_entry()
+                .await?
+                .is_none()
+            {
+                debug!("removing empty directory {}", path.display());
+                tokio::fs::remove_dir(path).await?;
+                removed.insert(path.clone());
+            }
+        }
+
+        Ok(paths
+            .into_iter()
+            .filter(|path| !removed.contains(path))
+            .collect())
+    }
+
+    /// Write a `<sha256>  <path>` line for every exported file, with paths
+    /// relative to the output directory, the way `sha256sum` does. The
+    /// checksums file itself isn't one of the artifact's paths, and one left
+    /// over from an earlier run is replaced.
+    async fn write_checksums(&self, output_root: &Path, paths: &[PathBuf]) -> Result<()> {
+        let checksums = output_root.join(CHECKSUMS_FILE);
+        let mut content = String::new();
+        for path in paths {
+            if *path == checksums {
+                return Err(eyre!(
+                    "producer '{}': cannot write {CHECKSUMS_FILE}, the input already has one",
+                    self.name
+                ));
+            }
+            if !tokio::fs::symlink_metadata(path).await?.is_file() {
+                continue;
+            }
+
+            let relative = path.strip_prefix(output_root)?;
+            content.push_str(&format!(
+                "{}  {}\n",
+                sha256_digest(path).await?,
+                relative.display()
+            ));
+        }
+
+        debug!("writing {}", checksums.display());
+        tokio::fs::write(&checksums, content).await?;
+        Ok(())
+    }
+
+    /// Warn about paths whose owners couldn't be set, and write their
+    /// intended owners out, one `<path> uid=<uid> gid=<gid>` line each, so
+    /// that they can be fixed up by something that does run as root.
+    async fn record_owners(&self, unowned: &[IntendedOwner]) -> Result<()> {
+        if unowned.is_empty() {
+            return Ok(());
+        }
+
+        let manifest = self.owners_manifest_path();
+        let content = unowned
+            .iter()
+            .map(|owner| {
+                format!(
+                    "{} uid={} gid={}\n",
+                    owner.path.display(),
+                    owner.uid,
+                    owner.gid
+                )
+            })
+            .collect::<String>();
+        tokio::fs::write(&manifest, content).await?;
+        warn!(
+            "could not set the owners of {} paths in {}, since that needs root; \
+             their intended owners were written to {}",
+            unowned.len(),
+            self.path.display(),
+            manifest.display()
+        );
+
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl ArtifactProducer for FileProducer {
     type Output = FileArtifact;
@@ -126,6 +493,15 @@ impl ArtifactProducer for FileProducer {
     }
 
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<FileArtifact> {
+        if let Some(threshold) = self.spill_threshold {
+            if !self.injections.is_empty() {
+                debug!("not extracting to disk, since there are injections to apply");
+            } else if exceeds_extracted_size(previous, threshold).await? {
+                info!("input is larger than {threshold} bytes, extracting straight to disk");
+                return self.produce_on_disk(previous).await;
+            }
+        }
+
         let mut memfs = previous.extract().await?;
         debug!("injecting memfs");
         self.inject(&mut memfs).await?;
@@ -134,26 +510,28 @@ impl ArtifactProducer for FileProducer {
             tokio::fs::create_dir_all(parent).await?;
         }
 
-        let out_disk = TokioFloppyDisk::new(Some(self.path.clone()));
-        DiskDrive::copy_between(&*memfs, &out_disk).await?;
+        let unowned = memfs.write_to_host(&self.path).await?;
+        self.record_owners(&unowned).await?;
+        // Report paths rooted at the absolute output directory, so that they
+        // stay meaningful regardless of the current directory.
+        let output_root = tokio::fs::canonicalize(&self.path).await?;
         let output_paths = nyoom::walk_ordered(&*memfs, "/").await?;
         let paths = output_paths
             .iter()
-            .map(|p| {
-                let p = if p.starts_with("/") {
-                    Path::join(&PathBuf::from("./"), p.strip_prefix("/").unwrap())
-                } else if !p.starts_with("./") {
-                    Path::join(&PathBuf::from("./"), p)
-                } else {
-                    p.to_path_buf()
-                };
-                Path::join(&self.path, p)
-            })
-            .collect();
+            .map(|p| output_root.join(p.strip_prefix("/").unwrap_or(p)))
+            .collect::<Vec<_>>();
+        let paths = self.prune_empty_directories(paths).await?;
+        if self.checksums {
+            self.write_checksums(&output_root, &paths).await?;
+        }
 
         Ok(FileArtifact {
             name: self.path.to_string_lossy().to_string(),
             paths,
+            git_mtimes: false,
+            default_excludes: true,
+            extra_excludes: vec![],
+            placement: FilePlacement::AsGiven,
         })
     }
 }
@@ -171,6 +549,8 @@ pub struct FileProducerBuilder {
     name: String,
     path: PathBuf,
     preserve_empty_directories: Option<bool>,
+    spill_threshold: Option<u64>,
+    checksums: bool,
     injections: Vec<Injection>,
 }
 
@@ -186,6 +566,16 @@ impl FileProducerBuilder {
         self
     }
 
+    pub fn spill_threshold(mut self, spill_threshold: u64) -> Self {
+        self.spill_threshold = Some(spill_threshold);
+        self
+    }
+
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
     pub fn inject(mut self, injection: Injection) -> Self {
         self.injections.push(injection);
         self
@@ -200,6 +590,8 @@ impl SelfBuilder for FileProducerBuilder {
             name: name.into(),
             path: PathBuf::from("/"),
             preserve_empty_directories: None,
+            spill_threshold: None,
+            checksums: false,
             injections: vec![],
         }
     }
@@ -209,7 +601,446 @@ impl SelfBuilder for FileProducerBuilder {
             name: self.name.clone(),
             path: self.path.clone(),
             preserve_empty_directories: self.preserve_empty_directories,
+            spill_threshold: self.spill_threshold,
+            checksums: self.checksums,
             injections: self.injections.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::tarball::TarballArtifact;
+    use crate::artifact::tarball::TarballProducerBuilder;
+    use crate::artifact::ExtractedDir;
+    use crate::fs::TempDir;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_unset_owners_are_written_next_to_the_output() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let producer = FileProducerBuilder::new("owners")
+            .path(tmp.path_view().join("out/"))
+            .build()?;
+        tokio::fs::create_dir_all(&producer.path).await?;
+
+        producer
+            .record_owners(&[IntendedOwner {
+                path: "/usr/bin/app".into(),
+                uid: 0,
+                gid: 0,
+            }])
+            .await?;
+
+        let manifest = producer.owners_manifest_path();
+        assert_eq!(tmp.path_view().join("out.owners"), manifest);
+        assert_eq!(
+            "/usr/bin/app uid=0 gid=0\n",
+            tokio::fs::read_to_string(&manifest).await?
+        );
+
+        Ok(())
+    }
+
+    async fn extract_with(placement: FilePlacement, paths: &[&str]) -> Result<MemFS> {
+        paths
+            .iter()
+            .fold(FileArtifactBuilder::new("placed"), |builder, path| {
+                builder.add_path(path)
+            })
+            .placement(placement)
+            .build()?
+            .extract()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_as_given_placement_keeps_relative_paths() -> Result<()> {
+        let memfs = extract_with(FilePlacement::AsGiven, &["Cargo.toml", "src/lib.rs"]).await?;
+        assert!(memfs.fs().metadata("/Cargo.toml").await?.is_file());
+        assert!(memfs.fs().metadata("/src/lib.rs").await?.is_file());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_root_placement_puts_paths_at_root() -> Result<()> {
+        let memfs = extract_with(FilePlacement::Root, &["Cargo.toml", "./docs"]).await?;
+        assert!(memfs.fs().metadata("/Cargo.toml").await?.is_file());
+        assert!(memfs.fs().metadata("/iso.md").await?.is_file());
+        assert!(memfs.fs().metadata("/docs").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefix_placement_puts_paths_under_prefix() -> Result<()> {
+        let memfs = extract_with(
+            FilePlacement::Prefix("/opt/peckish".into()),
+            &["Cargo.toml", "./docs"],
+        )
+        .await?;
+        assert_eq!(
+            tokio::fs::read("Cargo.toml").await?,
+            memfs.fs().read("/opt/peckish/Cargo.toml").await?
+        );
+        assert!(memfs
+            .fs()
+            .metadata("/opt/peckish/docs/iso.md")
+            .await?
+            .is_file());
+        assert!(memfs.fs().metadata("/Cargo.toml").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reported_paths_are_canonical() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let file_artifact = FileArtifactBuilder::new("Cargo.toml")
+            .add_path("Cargo.toml")
+            .build()?;
+
+        let file_producer = FileProducerBuilder::new("test-file-producer")
+            .path(tmp.path_view().join("./out"))
+            .build()?;
+
+        let output = file_producer.produce_from(&file_artifact).await?;
+
+        assert!(!output.paths.is_empty());
+        for path in &output.paths {
+            assert!(path.exists());
+            assert_eq!(path, &path.canonicalize()?);
+        }
+        assert!(output
+            .paths
+            .contains(&tmp.path_view().canonicalize()?.join("out/Cargo.toml")));
+
+        Ok(())
+    }
+
+    /// A tarball that refuses to be extracted into memory.
+    struct DiskOnlyTarball(TarballArtifact);
+
+    #[async_trait::async_trait]
+    impl Artifact for DiskOnlyTarball {
+        fn name(&self) -> &str {
+            self.0.name()
+        }
+
+        async fn extract(&self) -> Result<MemFS> {
+            Err(eyre::eyre!("extracted into memory"))
+        }
+
+        async fn extract_to_dir(&self, dir: &Path) -> Result<ExtractedDir> {
+            self.0.extract_to_dir(dir).await
+        }
+
+        async fn extracted_size(&self) -> Result<Option<u64>> {
+            self.0.extracted_size().await
+        }
+
+        fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+            Err(eyre::eyre!("disk-only tarballs can't be cloned"))
+        }
+
+        fn paths(&self) -> Option<Vec<PathBuf>> {
+            self.0.paths()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SelfValidation for DiskOnlyTarball {
+        async fn validate(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_inputs_are_extracted_to_disk() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tarball = TarballProducerBuilder::new("large tarball")
+            .path(tmp.path_view().join("large.tar.gz"))
+            .compression(smoosh::CompressionType::Gzip)
+            .inject(Injection::Create {
+                path: "/usr/share/large/data".into(),
+                content: vec![7; 64 * 1024],
+            })
+            .build()?
+            .produce_from(&FileArtifactBuilder::new("nothing").build()?)
+            .await?;
+        let input = DiskOnlyTarball(tarball);
+
+        // The compressed tarball is well under the threshold, but its
+        // contents aren't.
+        let threshold = 16 * 1024;
+        assert!(input.stored_size().await?.unwrap() < threshold);
+        let producer = FileProducerBuilder::new("spilled")
+            .path(tmp.path_view().join("out"))
+            .spill_threshold(threshold)
+            .build()?;
+        let output = producer.produce_from(&input).await?;
+
+        let data = tmp
+            .path_view()
+            .canonicalize()?
+            .join("out/usr/share/large/data");
+        assert_eq!(tokio::fs::read(&data).await?, vec![7; 64 * 1024]);
+        assert!(output.paths.contains(&data));
+
+        // Under the threshold, the input is extracted into memory as usual.
+        let producer = FileProducerBuilder::new("in memory")
+            .path(tmp.path_view().join("out-2"))
+            .spill_threshold(u64::MAX)
+            .build()?;
+        assert!(producer.produce_from(&input).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_directories_are_removed_unless_preserved() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tarball = TarballProducerBuilder::new("tarball")
+            .path(tmp.path_view().join("input.tar"))
+            .inject(Injection::Create {
+                path: "/usr/share/full/data".into(),
+                content: vec![7; 64 * 1024],
+            })
+            .inject(Injection::Create {
+                path: "/usr/share/empty/data".into(),
+                content: vec![],
+            })
+            .inject(Injection::Delete {
+                path: "/usr/share/empty/data".into(),
+            })
+            .build()?
+            .produce_from(&FileArtifactBuilder::new("nothing").build()?)
+            .await?;
+
+        // Both in memory and spilled to disk.
+        for (out, threshold) in [("memory", u64::MAX), ("disk", 1024)] {
+            let removed = tmp.path_view().join(format!("{out}-removed"));
+            FileProducerBuilder::new("removed")
+                .path(&removed)
+                .spill_threshold(threshold)
+                .build()?
+                .produce_from(&tarball)
+                .await?;
+            assert!(removed.join("usr/share/full/data").exists());
+            assert!(!removed.join("usr/share/empty").exists());
+
+            let preserved = tmp.path_view().join(format!("{out}-preserved"));
+            FileProducerBuilder::new("preserved")
+                .path(&preserved)
+                .spill_threshold(threshold)
+                .preserve_empty_directories(true)
+                .build()?
+                .produce_from(&tarball)
+                .await?;
+            assert!(preserved.join("usr/share/empty").is_dir());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vcs_directories_are_excluded_by_default() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tree = tmp.path_view().join("tree");
+        tokio::fs::create_dir_all(tree.join(".git/objects")).await?;
+        tokio::fs::create_dir_all(tree.join("src")).await?;
+        tokio::fs::write(tree.join(".git/HEAD"), b"ref: refs/heads/main").await?;
+        tokio::fs::write(tree.join("src/main.rs"), b"fn main() {}").await?;
+        tokio::fs::write(tree.join("notes.txt"), b"notes").await?;
+
+        let memfs = FileArtifactBuilder::new("tree")
+            .add_path(&tree)
+            .exclude("notes.txt")
+            .build()?
+            .extract()
+            .await?;
+        let copied = Path::new("/").join(&tree).join(tree.strip_prefix("/")?);
+        assert!(memfs
+            .fs()
+            .metadata(copied.join("src/main.rs"))
+            .await
+            .is_ok());
+        assert!(memfs.fs().metadata(copied.join(".git")).await.is_err());
+        assert!(memfs.fs().metadata(copied.join("notes.txt")).await.is_err());
+
+        let memfs = FileArtifactBuilder::new("tree")
+            .add_path(&tree)
+            .default_excludes(false)
+            .build()?
+            .extract()
+            .await?;
+        assert!(memfs.fs().metadata(copied.join(".git/HEAD")).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_git_mtimes_are_used() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let repo = tmp.path_view().join("repo");
+        tokio::fs::create_dir_all(&repo).await?;
+        tokio::fs::write(repo.join("committed"), b"committed").await?;
+
+        let git = |args: &[&str]| {
+            std::process::Command::new("git")
+                .args(args)
+                .current_dir(&repo)
+                .env("GIT_AUTHOR_DATE", "2020-01-01T00:00:00Z")
+                .env("GIT_COMMITTER_DATE", "2020-01-01T00:00:00Z")
+                .output()
+        };
+        git(&["init", "-q"])?;
+        git(&["add", "committed"])?;
+        git(&[
+            "-c",
+            "user.name=peckish",
+            "-c",
+            "user.email=peckish@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "add committed",
+        ])?;
+        tokio::fs::write(repo.join("untracked"), b"untracked").await?;
+
+        let before = get_current_time()?;
+        let tarball = TarballProducerBuilder::new("git mtimes")
+            .path(tmp.path_view().join("git-mtimes.tar"))
+            .build()?
+            .produce_from(
+                &FileArtifactBuilder::new("repo")
+                    .add_path(&repo)
+                    .git_mtimes(true)
+                    .build()?,
+            )
+            .await?;
+
+        let data = tokio::fs::read(&tarball.path).await?;
+        let mut archive = tokio_tar_up2date::Archive::new(data.as_slice());
+        let mut entries = archive.entries()?;
+        let mut mtimes = std::collections::HashMap::new();
+        while let Some(entry) = futures_util::TryStreamExt::try_next(&mut entries).await? {
+            let name = entry.path()?.file_name().map(|name| name.to_owned());
+            if let Some(name) = name {
+                mtimes.insert(name, entry.header().mtime()?);
+            }
+        }
+
+        assert_eq!(mtimes[std::ffi::OsStr::new("committed")], 1_577_836_800);
+        assert!(mtimes[std::ffi::OsStr::new("untracked")] >= before);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checksums_match_the_exported_files() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let out = tmp.path_view().join("out");
+        let output = FileProducerBuilder::new("checksums")
+            .path(&out)
+            .checksums(true)
+            .inject(Injection::Create {
+                path: "/usr/bin/app".into(),
+                content: b"#!/bin/sh\necho hi\n".to_vec(),
+            })
+            .inject(Injection::Create {
+                path: "/etc/app/app.conf".into(),
+                content: b"verbose = true\n".to_vec(),
+            })
+            .inject(Injection::Symlink {
+                src: "/usr/bin/app".into(),
+                dest: "/usr/bin/alias".into(),
+                relative: None,
+            })
+            .build()?
+            .produce_from(&crate::artifact::memory::EmptyArtifact::new("empty"))
+            .await?;
+
+        let checksums = tokio::fs::read_to_string(out.join(CHECKSUMS_FILE)).await?;
+        let mut listed = vec![];
+        for line in checksums.lines() {
+            let (sha256, path) = line.split_once("  ").unwrap();
+            assert_eq!(sha256_digest(&out.join(path)).await?, sha256);
+            listed.push(path);
+        }
+        // Only files are listed, and the checksums file isn't one of them.
+        assert_eq!(vec!["etc/app/app.conf", "usr/bin/app"], listed);
+        assert!(!output
+            .paths
+            .iter()
+            .any(|path| path.ends_with(CHECKSUMS_FILE)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checksums_only_list_the_input_across_runs() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tarball = TarballProducerBuilder::new("large tarball")
+            .path(tmp.path_view().join("large.tar.gz"))
+            .compression(smoosh::CompressionType::Gzip)
+            .inject(Injection::Create {
+                path: "/usr/share/large/data".into(),
+                content: vec![7; 64 * 1024],
+            })
+            .inject(Injection::Create {
+                path: "/etc/app.conf".into(),
+                content: b"verbose = true\n".to_vec(),
+            })
+            .build()?
+            .produce_from(&FileArtifactBuilder::new("nothing").build()?)
+            .await?;
+
+        let spilled: Box<dyn Artifact> = Box::new(DiskOnlyTarball(tarball.clone()));
+        let in_memory: Box<dyn Artifact> = Box::new(tarball);
+        for (name, input, threshold) in [
+            ("spilled", spilled, 16 * 1024),
+            ("in-memory", in_memory, u64::MAX),
+        ] {
+            let out = tmp.path_view().join(name);
+            tokio::fs::create_dir_all(&out).await?;
+            tokio::fs::write(out.join("unrelated.txt"), b"not from the input").await?;
+
+            // The second run finds the first one's checksums file.
+            for run in 0..2 {
+                let output = FileProducerBuilder::new(name)
+                    .path(&out)
+                    .spill_threshold(threshold)
+                    .checksums(true)
+                    .build()?
+                    .produce_from(input.as_ref())
+                    .await?;
+
+                let checksums = tokio::fs::read_to_string(out.join(CHECKSUMS_FILE)).await?;
+                let listed = checksums
+                    .lines()
+                    .map(|line| line.split_once("  ").unwrap().1)
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    vec!["etc/app.conf", "usr/share/large/data"],
+                    listed,
+                    "{name}, run {run}"
+                );
+                assert!(!output.paths.iter().any(|path| {
+                    path.ends_with(CHECKSUMS_FILE) || path.ends_with("unrelated.txt")
+                }));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1550 @@
+use std::collections::{HashMap, HashSet};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use eyre::{eyre, Result};
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
+use floppy_disk::tokio_fs::TokioFloppyDisk;
+use regex::Regex;
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::*;
+
+use crate::fs::MemFS;
+use crate::util::config::Injection;
+use crate::util::get_current_time;
+
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation};
+
+const SECTOR_SIZE: usize = 2048;
+/// The first 16 sectors are reserved for the system, ex. for boot code.
+const SYSTEM_AREA_SECTORS: u32 = 16;
+const STANDARD_ID: &[u8] = b"CD001";
+const VOLUME_DESCRIPTOR_PRIMARY: u8 = 1;
+const VOLUME_DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const VOLUME_DESCRIPTOR_TERMINATOR: u8 = 255;
+/// UCS-2 level 3, the escape sequence every Joliet writer uses.
+const JOLIET_ESCAPE: &[u8] = b"%/E";
+/// A directory record's length is a single byte.
+const MAX_RECORD_SIZE: usize = 255;
+const RECORD_HEADER_SIZE: usize = 33;
+const FLAG_DIRECTORY: u8 = 0x02;
+const SUSP_CE_SIZE: usize = 28;
+/// Joliet only allows 64 UCS-2 characters per name.
+const JOLIET_MAX_NAME: usize = 64;
+
+const RRIP_ID: &str = "RRIP_1991A";
+const RRIP_DESCRIPTOR: &str =
+    "THE ROCK RIDGE INTERCHANGE PROTOCOL PROVIDES SUPPORT FOR POSIX FILE SYSTEM SEMANTICS";
+const RRIP_SOURCE: &str = "PLEASE CONTACT DISC PUBLISHER FOR SPECIFICATION SOURCE.  SEE PUBLISHER IDENTIFIER IN PRIMARY VOLUME DESCRIPTOR FOR CONTACT INFORMATION.";
+
+/// An ISO 9660 image. Rock Ridge names, modes, owners, and symlinks are read
+/// when the image has them; otherwise Joliet names are used if present.
+#[derive(Debug, Clone)]
+pub struct IsoArtifact {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl Artifact for IsoArtifact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Iso
+    }
+
+    async fn extract(&self) -> Result<MemFS> {
+        let image = tokio::fs::read(&self.path).await?;
+        let memfs = MemFS::new();
+        read_iso(&image, &memfs)
+            .await
+            .map_err(|e| eyre!("cannot read {}: {e}", self.path.display()))?;
+        Ok(memfs)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        Some(vec![self.path.clone()])
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for IsoArtifact {
+    async fn validate(&self) -> Result<()> {
+        if !self.path.is_file() {
+            return Err(eyre!("path is not a file: {:?}", self.path));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct IsoArtifactBuilder {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[allow(unused)]
+impl IsoArtifactBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl SelfBuilder for IsoArtifactBuilder {
+    type Output = IsoArtifact;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(IsoArtifact {
+            name: self.name.clone(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// Produces an ISO 9660 image.
+///
+/// Plain ISO 9660 names are uppercase and short, so the real names are also
+/// recorded with Rock Ridge, which also keeps modes, owners, and symlinks,
+/// and with Joliet, which is what Windows reads. Symlinks are left out of
+/// images without Rock Ridge.
+#[derive(Debug, Clone, Serialize)]
+pub struct IsoProducer {
+    pub name: String,
+    pub path: PathBuf,
+    /// Up to 32 of `A-Z`, `0-9`, and `_`.
+    pub volume_label: String,
+    pub rock_ridge: bool,
+    pub joliet: bool,
+    pub injections: Vec<Injection>,
+}
+
+impl IsoProducer {
+    /// A valid volume label made from ex. a package name.
+    pub(crate) fn volume_label_for(name: &str) -> String {
+        let label = d_characters(name, 32);
+        if label.is_empty() {
+            "CDROM".into()
+        } else {
+            label
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for IsoProducer {
+    type Output = IsoArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<IsoArtifact> {
+        info!("producing {}", self.path.display());
+        let mut memfs = previous.extract().await?;
+        self.inject(&mut memfs).await?;
+
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&self.path).await?);
+        IsoWriter::new(&memfs, self.rock_ridge, self.joliet)
+            .await?
+            .write(&memfs, &self.volume_label, &mut file)
+            .await?;
+        file.flush().await?;
+
+        Ok(IsoArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for IsoProducer {
+    async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        let volume_label_regex = Regex::new(r"^[A-Z0-9_]{1,32}$")?;
+        if !volume_label_regex.is_match(&self.volume_label) {
+            errors.push(format!(
+                "volume label `{}` is invalid, must match {volume_label_regex}",
+                self.volume_label
+            ));
+        }
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if TokioFloppyDisk::new(None)
+            .metadata(&self.path)
+            .await
+            .is_ok()
+        {
+            errors.push(format!("path already exists: {}", self.path.display()));
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!(
+                "cannot produce artifact '{}':\n{}",
+                self.name,
+                errors.join("\n")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct IsoProducerBuilder {
+    name: String,
+    path: PathBuf,
+    volume_label: String,
+    rock_ridge: bool,
+    joliet: bool,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl IsoProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn volume_label<S: Into<String>>(mut self, volume_label: S) -> Self {
+        self.volume_label = volume_label.into();
+        self
+    }
+
+    pub fn rock_ridge(mut self, rock_ridge: bool) -> Self {
+        self.rock_ridge = rock_ridge;
+        self
+    }
+
+    pub fn joliet(mut self, joliet: bool) -> Self {
+        self.joliet = joliet;
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for IsoProducerBuilder {
+    type Output = IsoProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+            volume_label: "CDROM".into(),
+            rock_ridge: true,
+            joliet: true,
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(IsoProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            volume_label: self.volume_label.clone(),
+            rock_ridge: self.rock_ridge,
+            joliet: self.joliet,
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+/// A file, directory, or symlink from the memfs.
+struct Node {
+    name: Vec<u8>,
+    parent: usize,
+    /// Unsorted; each tree sorts them by its own identifiers.
+    children: Vec<usize>,
+    kind: NodeKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+}
+
+enum NodeKind {
+    Dir,
+    /// The file's path in the memfs and its size. Its data is only read when
+    /// it's written out.
+    File(PathBuf, u32),
+    Symlink(PathBuf),
+}
+
+/// A directory hierarchy as one volume descriptor sees it. The primary
+/// descriptor and Joliet share files, but name and sort them differently.
+struct Tree {
+    rock_ridge: bool,
+    /// Each node's identifier. Unused for nodes that aren't in the tree.
+    ids: Vec<Vec<u8>>,
+    /// Each directory's children, sorted by identifier.
+    children: Vec<Vec<usize>>,
+    /// Directories in path table order.
+    dirs: Vec<usize>,
+    dir_lba: Vec<u32>,
+    dir_size: Vec<u32>,
+}
+
+/// A directory record, before its extent and continuation area are known.
+struct RecordPlan {
+    node: usize,
+    id: Vec<u8>,
+    inline_su: Vec<u8>,
+    continuation: Option<Vec<u8>>,
+}
+
+impl RecordPlan {
+    fn len(&self) -> usize {
+        let len = RECORD_HEADER_SIZE
+            + self.id.len()
+            + (self.id.len() + 1) % 2
+            + self.inline_su.len()
+            + self.continuation.as_ref().map_or(0, |_| SUSP_CE_SIZE);
+        len + len % 2
+    }
+}
+
+struct IsoWriter {
+    nodes: Vec<Node>,
+    primary: Tree,
+    joliet: Option<Tree>,
+    file_lba: Vec<u32>,
+    /// The (sector, offset) of each record's continuation area, by directory
+    /// and record index.
+    continuations: HashMap<(usize, usize), (u32, u32)>,
+    now: u64,
+}
+
+impl IsoWriter {
+    async fn new(memfs: &MemFS, rock_ridge: bool, joliet: bool) -> Result<Self> {
+        let fs = memfs.fs();
+        let now = get_current_time()?;
+        let root_metadata = fs.metadata("/").await?;
+        let mut nodes = vec![Node {
+            name: vec![],
+            parent: 0,
+            children: vec![],
+            kind: NodeKind::Dir,
+            mode: 0o040000 | (root_metadata.permissions().mode() & 0o7777),
+            uid: 0,
+            gid: 0,
+            mtime: memfs.mtime("/").unwrap_or(now),
+        }];
+
+        let mut indices = HashMap::from([(PathBuf::from("/"), 0)]);
+        for path in nyoom::walk_ordered(fs, "/").await? {
+            let metadata = fs.symlink_metadata(&path).await?;
+            let kind = if metadata.is_symlink() {
+                if !rock_ridge {
+                    warn!(
+                        "skipping symlink {}, since symlinks need rock ridge",
+                        path.display()
+                    );
+                    continue;
+                }
+                NodeKind::Symlink(fs.read_link(&path).await?)
+            } else if metadata.is_dir() {
+                NodeKind::Dir
+            } else if metadata.is_file() {
+                let size = metadata
+                    .len()
+                    .try_into()
+                    .map_err(|_| eyre!("{} is too large for an iso 9660 image", path.display()))?;
+                NodeKind::File(path.clone(), size)
+            } else {
+                continue;
+            };
+
+            let file_type = match kind {
+                NodeKind::Dir => 0o040000,
+                NodeKind::File(..) => 0o100000,
+                NodeKind::Symlink(_) => 0o120000,
+            };
+            let Some(&parent) = path.parent().and_then(|parent| indices.get(parent)) else {
+                continue;
+            };
+            let index = nodes.len();
+            nodes.push(Node {
+                name: path
+                    .file_name()
+                    .map(|name| name.as_bytes().to_vec())
+                    .unwrap_or_default(),
+                parent,
+                children: vec![],
+                kind,
+                mode: file_type | (metadata.permissions().mode() & 0o7777),
+                uid: metadata.uid()?,
+                gid: metadata.gid()?,
+                mtime: memfs.mtime(&path).unwrap_or(now),
+            });
+            nodes[parent].children.push(index);
+            indices.insert(path, index);
+        }
+
+        let primary = Tree::new(&nodes, rock_ridge, primary_identifier);
+        let joliet = joliet.then(|| Tree::new(&nodes, false, joliet_identifier));
+
+        Ok(Self {
+            file_lba: vec![0; nodes.len()],
+            nodes,
+            primary,
+            joliet,
+            continuations: HashMap::new(),
+            now,
+        })
+    }
+
+    /// Write the image out. Everything up to the first file's data is laid
+    /// out in memory, since records point at extents further on, then files
+    /// are read from the memfs and written one at a time.
+    async fn write<W: AsyncWrite + Unpin>(
+        mut self,
+        memfs: &MemFS,
+        volume_label: &str,
+        out: &mut W,
+    ) -> Result<()> {
+        // Lay everything out first, since records point at their extents.
+        let descriptors = 2 + self.joliet.is_some() as u32;
+        let mut next = SYSTEM_AREA_SECTORS + descriptors;
+
+        let primary_path_table = path_table(&self.primary, &self.nodes, false);
+        let primary_l = next;
+        let primary_m = primary_l + sectors(primary_path_table.len());
+        next = primary_m + sectors(primary_path_table.len());
+
+        let joliet_path_table = self
+            .joliet
+            .as_ref()
+            .map(|tree| path_table(tree, &self.nodes, false));
+        let joliet_l = next;
+        let joliet_m = joliet_l + joliet_path_table.as_ref().map_or(0, |t| sectors(t.len()));
+        next = joliet_m + joliet_path_table.as_ref().map_or(0, |t| sectors(t.len()));
+
+        let primary_plans = self.plan_records(&self.primary)?;
+        next = self.primary.place(&primary_plans, next);
+
+        // Continuation areas are packed together after the directories,
+        // without crossing sector boundaries.
+        let mut offset = 0;
+        for (&dir, plans) in self.primary.dirs.iter().zip(&primary_plans) {
+            for (i, plan) in plans.iter().enumerate() {
+                if let Some(continuation) = &plan.continuation {
+                    if offset + continuation.len() > SECTOR_SIZE {
+                        next += 1;
+                        offset = 0;
+                    }
+                    self.continuations.insert((dir, i), (next, offset as u32));
+                    offset += continuation.len();
+                }
+            }
+        }
+        if offset > 0 {
+            next += 1;
+        }
+
+        let joliet_plans = match &self.joliet {
+            Some(tree) => self.plan_records(tree)?,
+            None => vec![],
+        };
+        if let Some(tree) = &mut self.joliet {
+            next = tree.place(&joliet_plans, next);
+        }
+
+        let metadata_sectors = next;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let NodeKind::File(_, size) = &node.kind {
+                if *size > 0 {
+                    self.file_lba[i] = next;
+                    next += sectors(*size as usize);
+                }
+            }
+        }
+
+        let mut image = vec![0; metadata_sectors as usize * SECTOR_SIZE];
+        let total_sectors = next;
+
+        let root = self.root_record(&self.primary);
+        write_sector(
+            &mut image,
+            SYSTEM_AREA_SECTORS,
+            &self.volume_descriptor(
+                VOLUME_DESCRIPTOR_PRIMARY,
+                volume_label,
+                total_sectors,
+                primary_path_table.len(),
+                primary_l,
+                primary_m,
+                &root,
+            ),
+        );
+        if let (Some(tree), Some(table)) = (&self.joliet, &joliet_path_table) {
+            let root = self.root_record(tree);
+            write_sector(
+                &mut image,
+                SYSTEM_AREA_SECTORS + 1,
+                &self.volume_descriptor(
+                    VOLUME_DESCRIPTOR_SUPPLEMENTARY,
+                    volume_label,
+                    total_sectors,
+                    table.len(),
+                    joliet_l,
+                    joliet_m,
+                    &root,
+                ),
+            );
+        }
+        let mut terminator = vec![VOLUME_DESCRIPTOR_TERMINATOR];
+        terminator.extend_from_slice(STANDARD_ID);
+        terminator.push(1);
+        write_sector(
+            &mut image,
+            SYSTEM_AREA_SECTORS + descriptors - 1,
+            &terminator,
+        );
+
+        // The tables' extents are only known now, so they're rebuilt.
+        write_sector(
+            &mut image,
+            primary_l,
+            &path_table(&self.primary, &self.nodes, false),
+        );
+        write_sector(
+            &mut image,
+            primary_m,
+            &path_table(&self.primary, &self.nodes, true),
+        );
+        if let Some(tree) = &self.joliet {
+            write_sector(&mut image, joliet_l, &path_table(tree, &self.nodes, false));
+            write_sector(&mut image, joliet_m, &path_table(tree, &self.nodes, true));
+        }
+
+        self.write_directories(&mut image, &self.primary, &primary_plans);
+        if let Some(tree) = &self.joliet {
+            self.write_directories(&mut image, tree, &joliet_plans);
+        }
+
+        for (&dir, plans) in self.primary.dirs.iter().zip(&primary_plans) {
+            for (i, plan) in plans.iter().enumerate() {
+                if let Some(continuation) = &plan.continuation {
+                    let (sector, offset) = self.continuations[&(dir, i)];
+                    let start = sector as usize * SECTOR_SIZE + offset as usize;
+                    image[start..start + continuation.len()].copy_from_slice(continuation);
+                }
+            }
+        }
+
+        out.write_all(&image).await?;
+        drop(image);
+
+        // Files were given extents in node order, so they're written in the
+        // same order, each padded out to a whole sector.
+        for node in &self.nodes {
+            let NodeKind::File(path, size) = &node.kind else {
+                continue;
+            };
+            if *size == 0 {
+                continue;
+            }
+            let data = memfs.fs().read(path).await?;
+            if data.len() != *size as usize {
+                return Err(eyre!(
+                    "{} changed size while writing the image",
+                    path.display()
+                ));
+            }
+            out.write_all(&data).await?;
+            let padding = sectors(data.len()) as usize * SECTOR_SIZE - data.len();
+            out.write_all(&vec![0; padding]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The records of every directory in the tree, in path table order.
+    fn plan_records(&self, tree: &Tree) -> Result<Vec<Vec<RecordPlan>>> {
+        let mut plans = vec![];
+        for &dir in &tree.dirs {
+            let mut records = vec![];
+
+            let mut dot_su = vec![];
+            if tree.rock_ridge {
+                if dir == 0 {
+                    dot_su.push(susp_sp());
+                }
+                dot_su.push(self.rrip_px(dir));
+                if dir == 0 {
+                    dot_su.push(susp_er());
+                }
+            }
+            records.push(plan(dir, vec![0], dot_su)?);
+            records.push(plan(self.nodes[dir].parent, vec![1], vec![])?);
+
+            for &child in &tree.children[dir] {
+                let mut su = vec![];
+                if tree.rock_ridge {
+                    su.push(self.rrip_px(child));
+                    su.push(rrip_tf(self.nodes[child].mtime));
+                    su.extend(rrip_nm(&self.nodes[child].name));
+                    if let NodeKind::Symlink(target) = &self.nodes[child].kind {
+                        su.extend(rrip_sl(target)?);
+                    }
+                }
+                records.push(plan(child, tree.ids[child].clone(), su)?);
+            }
+
+            plans.push(records);
+        }
+
+        Ok(plans)
+    }
+
+    fn rrip_px(&self, node: usize) -> Vec<u8> {
+        let node_ref = &self.nodes[node];
+        let links = match node_ref.kind {
+            NodeKind::Dir => 2,
+            _ => 1,
+        };
+        let mut px = vec![b'P', b'X', 44, 1];
+        px.extend(both_u32(node_ref.mode));
+        px.extend(both_u32(links));
+        px.extend(both_u32(node_ref.uid));
+        px.extend(both_u32(node_ref.gid));
+        px.extend(both_u32(node as u32 + 1));
+        px
+    }
+
+    /// The extent and size of what a record points at.
+    fn extent(&self, tree: &Tree, node: usize) -> (u32, u32) {
+        match &self.nodes[node].kind {
+            NodeKind::Dir => (tree.dir_lba[node], tree.dir_size[node]),
+            NodeKind::File(_, size) => (self.file_lba[node], *size),
+            NodeKind::Symlink(_) => (0, 0),
+        }
+    }
+
+    fn record(&self, tree: &Tree, plan: &RecordPlan, continuation: Option<(u32, u32)>) -> Vec<u8> {
+        let (lba, size) = self.extent(tree, plan.node);
+        let node = &self.nodes[plan.node];
+        let mut record = vec![plan.len() as u8, 0];
+        record.extend(both_u32(lba));
+        record.extend(both_u32(size));
+        record.extend(record_date(node.mtime));
+        record.push(match node.kind {
+            NodeKind::Dir => FLAG_DIRECTORY,
+            _ => 0,
+        });
+        record.extend([0, 0]);
+        record.extend(both_u16(1));
+        record.push(plan.id.len() as u8);
+        record.extend(&plan.id);
+        if plan.id.len().is_multiple_of(2) {
+            record.push(0);
+        }
+        record.extend(&plan.inline_su);
+        if let (Some(data), Some((sector, offset))) = (&plan.continuation, continuation) {
+            record.extend([b'C', b'E', SUSP_CE_SIZE as u8, 1]);
+            record.extend(both_u32(sector));
+            record.extend(both_u32(offset));
+            record.extend(both_u32(data.len() as u32));
+        }
+        record.resize(plan.len(), 0);
+        record
+    }
+
+    /// The root's `.` record without any system use entries, as stored in
+    /// the volume descriptor.
+    fn root_record(&self, tree: &Tree) -> Vec<u8> {
+        self.record(
+            tree,
+            &RecordPlan {
+                node: 0,
+                id: vec![0],
+                inline_su: vec![],
+                continuation: None,
+            },
+            None,
+        )
+    }
+
+    fn write_directories(&self, image: &mut [u8], tree: &Tree, plans: &[Vec<RecordPlan>]) {
+        for (&dir, records) in tree.dirs.iter().zip(plans) {
+            let mut sector = tree.dir_lba[dir];
+            let mut offset = 0;
+            for (i, plan) in records.iter().enumerate() {
+                if offset + plan.len() > SECTOR_SIZE {
+                    sector += 1;
+                    offset = 0;
+                }
+                let continuation = self.continuations.get(&(dir, i)).copied();
+                let record = self.record(tree, plan, continuation);
+                let start = sector as usize * SECTOR_SIZE + offset;
+                image[start..start + record.len()].copy_from_slice(&record);
+                offset += record.len();
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn volume_descriptor(
+        &self,
+        kind: u8,
+        volume_label: &str,
+        total_sectors: u32,
+        path_table_size: usize,
+        path_table_l: u32,
+        path_table_m: u32,
+        root: &[u8],
+    ) -> Vec<u8> {
+        let joliet = kind == VOLUME_DESCRIPTOR_SUPPLEMENTARY;
+        let text = |s: &str, len: usize| {
+            if joliet {
+                let mut out = s
+                    .encode_utf16()
+                    .flat_map(u16::to_be_bytes)
+                    .collect::<Vec<_>>();
+                out.truncate(len);
+                while out.len() < len {
+                    out.extend([0, b' ']);
+                }
+                out.truncate(len);
+                out
+            } else {
+                let mut out = s.as_bytes().to_vec();
+                out.resize(len, b' ');
+                out.truncate(len);
+                out
+            }
+        };
+
+        let mut vd = vec![0; SECTOR_SIZE];
+        vd[0] = kind;
+        vd[1..6].copy_from_slice(STANDARD_ID);
+        vd[6] = 1;
+        vd[8..40].copy_from_slice(&text("LINUX", 32));
+        vd[40..72].copy_from_slice(&text(volume_label, 32));
+        vd[80..88].copy_from_slice(&both_u32(total_sectors));
+        if joliet {
+            vd[88..91].copy_from_slice(JOLIET_ESCAPE);
+        }
+        vd[120..124].copy_from_slice(&both_u16(1));
+        vd[124..128].copy_from_slice(&both_u16(1));
+        vd[128..132].copy_from_slice(&both_u16(SECTOR_SIZE as u16));
+        vd[132..140].copy_from_slice(&both_u32(path_table_size as u32));
+        vd[140..144].copy_from_slice(&path_table_l.to_le_bytes());
+        vd[148..152].copy_from_slice(&path_table_m.to_be_bytes());
+        vd[156..156 + root.len()].copy_from_slice(root);
+        vd[190..318].copy_from_slice(&text("", 128));
+        vd[318..446].copy_from_slice(&text("", 128));
+        vd[446..574].copy_from_slice(&text("", 128));
+        vd[574..702].copy_from_slice(&text("PECKISH", 128));
+        vd[702..813].copy_from_slice(&text("", 111));
+        let date = volume_date(Some(self.now));
+        vd[813..830].copy_from_slice(&date);
+        vd[830..847].copy_from_slice(&date);
+        vd[847..864].copy_from_slice(&volume_date(None));
+        vd[864..881].copy_from_slice(&date);
+        vd[881] = 1;
+        vd
+    }
+}
+
+impl Tree {
+    fn new(nodes: &[Node], rock_ridge: bool, identifier: fn(&[u8], bool) -> Vec<u8>) -> Self {
+        let mut ids = vec![vec![]; nodes.len()];
+        let mut children = vec![vec![]; nodes.len()];
+        for (dir, node) in nodes.iter().enumerate() {
+            if !matches!(node.kind, NodeKind::Dir) {
+                continue;
+            }
+
+            let mut taken = HashSet::new();
+            for &child in &node.children {
+                let is_dir = matches!(nodes[child].kind, NodeKind::Dir);
+                let id = unique_identifier(identifier(&nodes[child].name, is_dir), &taken);
+                taken.insert(id.clone());
+                ids[child] = id;
+            }
+
+            let mut sorted = node.children.clone();
+            sorted.sort_by(|a, b| ids[*a].cmp(&ids[*b]));
+            children[dir] = sorted;
+        }
+
+        // Path table order is breadth-first, with each directory's children
+        // in identifier order.
+        let mut dirs = vec![0];
+        let mut i = 0;
+        while i < dirs.len() {
+            for &child in &children[dirs[i]] {
+                if matches!(nodes[child].kind, NodeKind::Dir) {
+                    dirs.push(child);
+                }
+            }
+            i += 1;
+        }
+
+        Self {
+            rock_ridge,
+            ids,
+            children,
+            dirs,
+            dir_lba: vec![0; nodes.len()],
+            dir_size: vec![0; nodes.len()],
+        }
+    }
+
+    /// Assign each directory its extent, starting at `next`. Returns the
+    /// first sector after them.
+    fn place(&mut self, plans: &[Vec<RecordPlan>], mut next: u32) -> u32 {
+        for (&dir, records) in self.dirs.iter().zip(plans) {
+            let mut used_sectors = 1;
+            let mut offset = 0;
+            for plan in records {
+                if offset + plan.len() > SECTOR_SIZE {
+                    used_sectors += 1;
+                    offset = 0;
+                }
+                offset += plan.len();
+            }
+            self.dir_lba[dir] = next;
+            self.dir_size[dir] = used_sectors * SECTOR_SIZE as u32;
+            next += used_sectors;
+        }
+        next
+    }
+}
+
+/// Split a record's system use entries between the record itself and a
+/// continuation area, keeping them in order.
+fn plan(node: usize, id: Vec<u8>, entries: Vec<Vec<u8>>) -> Result<RecordPlan> {
+    let mut plan = RecordPlan {
+        node,
+        id,
+        inline_su: vec![],
+        continuation: None,
+    };
+    let room = MAX_RECORD_SIZE - 1 - (RECORD_HEADER_SIZE + plan.id.len() + (plan.id.len() + 1) % 2);
+    let total = entries.iter().map(Vec::len).sum::<usize>();
+    if total <= room {
+        plan.inline_su = entries.concat();
+        return Ok(plan);
+    }
+
+    let mut continuation = vec![];
+    for entry in entries {
+        if continuation.is_empty() && plan.inline_su.len() + entry.len() + SUSP_CE_SIZE <= room {
+            plan.inline_su.extend(entry);
+        } else {
+            continuation.extend(entry);
+        }
+    }
+    if continuation.len() > SECTOR_SIZE {
+        return Err(eyre!("rock ridge entries are too large for one sector"));
+    }
+    plan.continuation = Some(continuation);
+    Ok(plan)
+}
+
+fn susp_sp() -> Vec<u8> {
+    vec![b'S', b'P', 7, 1, 0xbe, 0xef, 0]
+}
+
+fn susp_er() -> Vec<u8> {
+    let mut er = vec![
+        b'E',
+        b'R',
+        (8 + RRIP_ID.len() + RRIP_DESCRIPTOR.len() + RRIP_SOURCE.len()) as u8,
+        1,
+        RRIP_ID.len() as u8,
+        RRIP_DESCRIPTOR.len() as u8,
+        RRIP_SOURCE.len() as u8,
+        1,
+    ];
+    er.extend(RRIP_ID.as_bytes());
+    er.extend(RRIP_DESCRIPTOR.as_bytes());
+    er.extend(RRIP_SOURCE.as_bytes());
+    er
+}
+
+fn rrip_tf(mtime: u64) -> Vec<u8> {
+    // Only the modification time is recorded.
+    let mut tf = vec![b'T', b'F', 12, 1, 0x02];
+    tf.extend(record_date(mtime));
+    tf
+}
+
+/// `NM` entries for a name, split with the continue flag where an entry
+/// would be too long.
+fn rrip_nm(name: &[u8]) -> Vec<Vec<u8>> {
+    let chunks = name.chunks(250).collect::<Vec<_>>();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let flags = (i + 1 < chunks.len()) as u8;
+            let mut nm = vec![b'N', b'M', 5 + chunk.len() as u8, 1, flags];
+            nm.extend(*chunk);
+            nm
+        })
+        .collect()
+}
+
+/// `SL` entries for a symlink target, split between components where an
+/// entry would be too long.
+fn rrip_sl(target: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut components = vec![];
+    for component in target.components() {
+        let (flags, content) = match component {
+            Component::RootDir => (0x08, vec![]),
+            Component::CurDir => (0x02, vec![]),
+            Component::ParentDir => (0x04, vec![]),
+            Component::Normal(name) => (0, name.as_bytes().to_vec()),
+            Component::Prefix(_) => continue,
+        };
+        if content.len() > 248 {
+            return Err(eyre!(
+                "symlink target {} has a component that's too long",
+                target.display()
+            ));
+        }
+        let mut record = vec![flags, content.len() as u8];
+        record.extend(content);
+        components.push(record);
+    }
+
+    let mut entries: Vec<Vec<u8>> = vec![];
+    let mut current = vec![];
+    for component in components {
+        if 5 + current.len() + component.len() > MAX_RECORD_SIZE - SUSP_CE_SIZE {
+            entries.push(std::mem::take(&mut current));
+        }
+        current.extend(component);
+    }
+    entries.push(current);
+
+    let count = entries.len();
+    Ok(entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, components)| {
+            let flags = (i + 1 < count) as u8;
+            let mut sl = vec![b'S', b'L', 5 + components.len() as u8, 1, flags];
+            sl.extend(components);
+            sl
+        })
+        .collect())
+}
+
+/// Uppercase `A-Z`, `0-9`, and `_`, with anything else replaced by `_`.
+fn d_characters(s: &str, max: usize) -> String {
+    s.chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9' | '_') => c,
+            _ => '_',
+        })
+        .take(max)
+        .collect()
+}
+
+/// A plain ISO 9660 identifier: `NAME.EXT;1` for files, `NAME` for
+/// directories, short enough for level 2.
+fn primary_identifier(name: &[u8], is_dir: bool) -> Vec<u8> {
+    let name = String::from_utf8_lossy(name);
+    if is_dir {
+        return d_characters(&name, 30).into_bytes();
+    }
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (name.as_ref(), ""),
+    };
+    format!("{}.{};1", d_characters(stem, 24), d_characters(ext, 3)).into_bytes()
+}
+
+/// A Joliet identifier: the name in UCS-2, with `;1` after files.
+fn joliet_identifier(name: &[u8], is_dir: bool) -> Vec<u8> {
+    let name = String::from_utf8_lossy(name);
+    let mut units = name
+        .chars()
+        .map(|c| match c {
+            '*' | '/' | ':' | ';' | '?' | '\\' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .encode_utf16()
+        .collect::<Vec<_>>();
+    let max = if is_dir {
+        JOLIET_MAX_NAME
+    } else {
+        JOLIET_MAX_NAME - 2
+    };
+    units.truncate(max);
+    if !is_dir {
+        units.extend(";1".encode_utf16());
+    }
+    units.into_iter().flat_map(u16::to_be_bytes).collect()
+}
+
+/// Make an identifier unique among its siblings by replacing the end of its
+/// name with `~N`. Identifiers are either ASCII or UCS-2, which is told
+/// apart by whether the identifier has NUL bytes.
+fn unique_identifier(id: Vec<u8>, taken: &HashSet<Vec<u8>>) -> Vec<u8> {
+    if !taken.contains(&id) {
+        return id;
+    }
+
+    let ucs2 = id.chunks(2).all(|unit| unit.len() == 2 && unit[0] == 0);
+    let chars: Vec<Vec<u8>> = if ucs2 {
+        id.chunks(2).map(|unit| unit.to_vec()).collect()
+    } else {
+        id.iter().map(|byte| vec![*byte]).collect()
+    };
+    let encode = |s: &str| -> Vec<Vec<u8>> {
+        if ucs2 {
+            s.encode_utf16()
+                .map(|unit| unit.to_be_bytes().to_vec())
+                .collect()
+        } else {
+            s.bytes().map(|byte| vec![byte]).collect()
+        }
+    };
+
+    // Keep any `.EXT;1` suffix, and shorten the name before it.
+    let dot = encode(".")[0].clone();
+    let semicolon = encode(";")[0].clone();
+    let suffix_start = chars
+        .iter()
+        .rposition(|c| *c == dot)
+        .or_else(|| chars.iter().rposition(|c| *c == semicolon))
+        .unwrap_or(chars.len());
+    let (name, suffix) = chars.split_at(suffix_start);
+
+    for n in 1.. {
+        let marker = encode(&format!("~{n}"));
+        let keep = name.len().saturating_sub(marker.len());
+        let candidate = name[..keep]
+            .iter()
+            .chain(&marker)
+            .chain(suffix)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        if !taken.contains(&candidate) {
+            return candidate;
+        }
+    }
+    unreachable!()
+}
+
+fn path_table(tree: &Tree, nodes: &[Node], big_endian: bool) -> Vec<u8> {
+    let numbers = tree
+        .dirs
+        .iter()
+        .enumerate()
+        .map(|(i, dir)| (*dir, i as u16 + 1))
+        .collect::<HashMap<_, _>>();
+
+    let mut table = vec![];
+    for &dir in &tree.dirs {
+        let id = if dir == 0 {
+            vec![0]
+        } else {
+            tree.ids[dir].clone()
+        };
+        let parent = numbers[&nodes[dir].parent];
+        table.push(id.len() as u8);
+        table.push(0);
+        if big_endian {
+            table.extend(tree.dir_lba[dir].to_be_bytes());
+            table.extend(parent.to_be_bytes());
+        } else {
+            table.extend(tree.dir_lba[dir].to_le_bytes());
+            table.extend(parent.to_le_bytes());
+        }
+        table.extend(&id);
+        if id.len() % 2 == 1 {
+            table.push(0);
+        }
+    }
+    table
+}
+
+fn sectors(len: usize) -> u32 {
+    len.div_ceil(SECTOR_SIZE) as u32
+}
+
+fn write_sector(image: &mut [u8], sector: u32, data: &[u8]) {
+    let start = sector as usize * SECTOR_SIZE;
+    image[start..start + data.len()].copy_from_slice(data);
+}
+
+fn both_u16(n: u16) -> [u8; 4] {
+    let [a, b] = n.to_le_bytes();
+    [a, b, b, a]
+}
+
+fn both_u32(n: u32) -> [u8; 8] {
+    let [a, b, c, d] = n.to_le_bytes();
+    [a, b, c, d, d, c, b, a]
+}
+
+fn timestamp(secs: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs as i64, 0).unwrap_or_default()
+}
+
+/// The 7-byte date used in directory records, in UTC.
+fn record_date(secs: u64) -> [u8; 7] {
+    let t = timestamp(secs);
+    [
+        (t.year() - 1900).clamp(0, 255) as u8,
+        t.month() as u8,
+        t.day() as u8,
+        t.hour() as u8,
+        t.minute() as u8,
+        t.second() as u8,
+        0,
+    ]
+}
+
+/// The 17-byte date used in volume descriptors, in UTC. `None` is the
+/// all-zeroes "not specified" date.
+fn volume_date(secs: Option<u64>) -> [u8; 17] {
+    let mut date = [b'0'; 17];
+    date[16] = 0;
+    if let Some(secs) = secs {
+        date[..16].copy_from_slice(
+            timestamp(secs)
+                .format("%Y%m%d%H%M%S00")
+                .to_string()
+                .as_bytes(),
+        );
+    }
+    date
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| eyre!("image is truncated"))
+}
+
+/// What a record's Rock Ridge entries say about it.
+#[derive(Default)]
+struct RockRidge {
+    name: Option<Vec<u8>>,
+    mode: Option<u32>,
+    uid: u32,
+    gid: u32,
+    symlink: Option<PathBuf>,
+    mtime: Option<u64>,
+}
+
+fn parse_rock_ridge(image: &[u8], su: &[u8]) -> Result<RockRidge> {
+    let mut rr = RockRidge::default();
+    let mut areas = vec![su.to_vec()];
+    let mut symlink: Option<PathBuf> = None;
+    // Guard against continuation areas that point at each other.
+    let mut followed = 0;
+
+    while let Some(area) = areas.pop() {
+        let mut pos = 0;
+        while pos + 4 <= area.len() {
+            let signature = &area[pos..pos + 2];
+            let len = area[pos + 2] as usize;
+            if len < 4 || pos + len > area.len() {
+                break;
+            }
+            let entry = &area[pos..pos + len];
+            match signature {
+                b"PX" if len >= 36 => {
+                    rr.mode = Some(read_u32(entry, 4)?);
+                    rr.uid = read_u32(entry, 20)?;
+                    rr.gid = read_u32(entry, 28)?;
+                }
+                b"NM" if len >= 5 => {
+                    let flags = entry[4];
+                    // `.` and `..` flags don't name anything.
+                    if flags & 0x06 == 0 {
+                        rr.name.get_or_insert_with(Vec::new).extend(&entry[5..]);
+                    }
+                }
+                b"SL" if len >= 5 => {
+                    let target = symlink.get_or_insert_with(PathBuf::new);
+                    let mut c = 5;
+                    while c + 2 <= entry.len() {
+                        let flags = entry[c];
+                        let clen = entry[c + 1] as usize;
+                        let content = entry.get(c + 2..c + 2 + clen).unwrap_or_default();
+                        match flags & 0x0e {
+                            0x08 => target.push("/"),
+                            0x04 => target.push(".."),
+                            0x02 => target.push("."),
+                            _ => target.push(std::ffi::OsString::from_vec(content.to_vec())),
+                        }
+                        c += 2 + clen;
+                    }
+                }
+                b"TF" if len >= 5 => {
+                    let flags = entry[4];
+                    let size = if flags & 0x80 != 0 { 17 } else { 7 };
+                    // Creation comes first, if it's recorded.
+                    let offset = 5 + if flags & 0x01 != 0 { size } else { 0 };
+                    if flags & 0x02 != 0 && size == 7 {
+                        if let Some(date) = entry.get(offset..offset + 7) {
+                            rr.mtime = Some(parse_record_date(date));
+                        }
+                    }
+                }
+                b"CE" if len >= 28 && followed < 64 => {
+                    followed += 1;
+                    let start =
+                        read_u32(entry, 4)? as usize * SECTOR_SIZE + read_u32(entry, 12)? as usize;
+                    let length = read_u32(entry, 20)? as usize;
+                    let continuation = image
+                        .get(start..start + length)
+                        .ok_or_else(|| eyre!("continuation area is out of bounds"))?;
+                    areas.push(continuation.to_vec());
+                }
+                b"ST" => break,
+                _ => {}
+            }
+            pos += len;
+        }
+    }
+
+    rr.symlink = symlink;
+    Ok(rr)
+}
+
+fn parse_record_date(date: &[u8]) -> u64 {
+    let offset = Duration::minutes(date[6] as i8 as i64 * 15);
+    Utc.with_ymd_and_hms(
+        1900 + date[0] as i32,
+        date[1] as u32,
+        date[2] as u32,
+        date[3] as u32,
+        date[4] as u32,
+        date[5] as u32,
+    )
+    .single()
+    .map(|t| (t - offset).timestamp().max(0) as u64)
+    .unwrap_or(0)
+}
+
+/// Read an ISO 9660 image into the memfs.
+async fn read_iso(image: &[u8], memfs: &MemFS) -> Result<()> {
+    let fs = memfs.fs();
+
+    let mut primary = None;
+    let mut joliet = None;
+    let mut sector = SYSTEM_AREA_SECTORS as usize;
+    loop {
+        let vd = image
+            .get(sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE)
+            .ok_or_else(|| eyre!("image has no volume descriptor terminator"))?;
+        if &vd[1..6] != STANDARD_ID {
+            return Err(eyre!("not an iso 9660 image"));
+        }
+        match vd[0] {
+            VOLUME_DESCRIPTOR_PRIMARY => primary = Some(vd),
+            VOLUME_DESCRIPTOR_SUPPLEMENTARY if vd[88..90] == *b"%/" => joliet = Some(vd),
+            VOLUME_DESCRIPTOR_TERMINATOR => break,
+            _ => {}
+        }
+        sector += 1;
+    }
+    let primary = primary.ok_or_else(|| eyre!("image has no primary volume descriptor"))?;
+
+    // Rock Ridge images mark the root's `.` record with an SP entry.
+    let root_extent = |vd: &[u8]| -> Result<(usize, usize)> {
+        Ok((
+            read_u32(vd, 156 + 2)? as usize,
+            read_u32(vd, 156 + 10)? as usize,
+        ))
+    };
+    let (root_lba, _) = root_extent(primary)?;
+    let dot = image
+        .get(root_lba * SECTOR_SIZE..)
+        .ok_or_else(|| eyre!("root directory is out of bounds"))?;
+    let dot_su = &dot[RECORD_HEADER_SIZE + 1..dot[0] as usize];
+    let rock_ridge = dot_su.starts_with(b"SP") && dot_su.get(4..6) == Some(&[0xbe, 0xef]);
+
+    let (vd, is_joliet) = match joliet {
+        Some(joliet) if !rock_ridge => (joliet, true),
+        _ => (primary, false),
+    };
+    debug!("reading iso, rock ridge: {rock_ridge}, joliet: {is_joliet}");
+
+    let mut dirs = vec![(root_extent(vd)?, PathBuf::from("/"))];
+    while let Some(((lba, size), dir)) = dirs.pop() {
+        let extent = image
+            .get(lba * SECTOR_SIZE..lba * SECTOR_SIZE + size)
+            .ok_or_else(|| eyre!("directory {} is out of bounds", dir.display()))?;
+
+        let mut pos = 0;
+        while pos < extent.len() {
+            let len = extent[pos] as usize;
+            if len == 0 {
+                // Records don't cross sectors, so the rest is padding.
+                pos = (pos / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                continue;
+            }
+            let record = extent
+                .get(pos..pos + len)
+                .ok_or_else(|| eyre!("directory {} is truncated", dir.display()))?;
+            pos += len;
+
+            let id_len = record[32] as usize;
+            let id = &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + id_len];
+            let su_start = RECORD_HEADER_SIZE + id_len + (id_len + 1) % 2;
+            let rr = if rock_ridge {
+                parse_rock_ridge(image, record.get(su_start..).unwrap_or_default())?
+            } else {
+                RockRidge::default()
+            };
+
+            if id == [0] || id == [1] {
+                if id == [0] && dir == Path::new("/") {
+                    if let Some(mode) = rr.mode {
+                        fs.set_permissions("/", MemPermissions::from_mode(mode & 0o7777))
+                            .await?;
+                    }
+                }
+                continue;
+            }
+
+            let name = match rr.name {
+                Some(name) => name,
+                None if is_joliet => {
+                    let units = id
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                        .collect::<Vec<_>>();
+                    let name = String::from_utf16_lossy(&units);
+                    name.strip_suffix(";1").unwrap_or(&name).as_bytes().to_vec()
+                }
+                None => {
+                    let name = String::from_utf8_lossy(id).to_lowercase();
+                    let name = name.split(';').next().unwrap_or_default();
+                    name.strip_suffix('.').unwrap_or(name).as_bytes().to_vec()
+                }
+            };
+            let path = dir.join(std::ffi::OsStr::from_bytes(&name));
+            debug!("extracting {}", path.display());
+
+            let data_lba = read_u32(record, 2)? as usize;
+            let data_size = read_u32(record, 10)? as usize;
+            let is_dir = record[25] & FLAG_DIRECTORY != 0;
+
+            if let Some(target) = rr.symlink {
+                fs.symlink(target, path.clone()).await?;
+                continue;
+            } else if is_dir {
+                fs.create_dir_all(&path).await?;
+                dirs.push(((data_lba, data_size), path.clone()));
+            } else {
+                let data = image
+                    .get(data_lba * SECTOR_SIZE..data_lba * SECTOR_SIZE + data_size)
+                    .ok_or_else(|| eyre!("{} is out of bounds", path.display()))?;
+                fs.write(&path, data).await?;
+            }
+
+            if let Some(mode) = rr.mode {
+                fs.chown(&path, rr.uid, rr.gid).await?;
+                fs.set_permissions(&path, MemPermissions::from_mode(mode & 0o7777))
+                    .await?;
+            }
+            memfs.set_mtime(
+                &path,
+                rr.mtime
+                    .unwrap_or_else(|| parse_record_date(&record[18..25])),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::MemoryArtifact;
+    use crate::fs::TempDir;
+    use crate::util::run_if_installed;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    async fn memfs_with_long_names() -> Result<MemFS> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/share/doc/peckish").await?;
+        memfs
+            .fs()
+            .write(
+                "/usr/share/doc/peckish/a-file-name-much-longer-than-iso-9660-allows.txt",
+                b"hello from an iso",
+            )
+            .await?;
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().write("/usr/bin/peckish", b"#!/bin/sh\n").await?;
+        memfs
+            .fs()
+            .set_permissions("/usr/bin/peckish", MemPermissions::from_mode(0o755))
+            .await?;
+        Ok(memfs)
+    }
+
+    #[tokio::test]
+    async fn test_rock_ridge_keeps_long_names_and_modes() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = memfs_with_long_names().await?;
+        memfs
+            .fs()
+            .symlink("../share/doc/peckish", "/usr/bin/docs")
+            .await?;
+        memfs.set_mtime("/usr/bin/peckish", 1_600_000_000);
+
+        let artifact = IsoProducerBuilder::new("iso")
+            .path(tmp.path_view().join("test.iso"))
+            .volume_label("PECKISH_TEST")
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let image = tokio::fs::read(&artifact.path).await?;
+        assert_eq!(&image[16 * SECTOR_SIZE + 1..16 * SECTOR_SIZE + 6], b"CD001");
+        assert_eq!(
+            &image[16 * SECTOR_SIZE + 40..16 * SECTOR_SIZE + 52],
+            b"PECKISH_TEST"
+        );
+
+        let extracted = artifact.extract().await?;
+        assert_eq!(
+            b"hello from an iso".to_vec(),
+            extracted
+                .fs()
+                .read("/usr/share/doc/peckish/a-file-name-much-longer-than-iso-9660-allows.txt")
+                .await?
+        );
+        let peckish = extracted.fs().metadata("/usr/bin/peckish").await?;
+        assert_eq!(0o755, peckish.permissions().mode() & 0o777);
+        assert_eq!(Some(1_600_000_000), extracted.mtime("/usr/bin/peckish"));
+        assert_eq!(
+            PathBuf::from("../share/doc/peckish"),
+            extracted.fs().read_link("/usr/bin/docs").await?
+        );
+
+        Ok(())
+    }
+
+    /// The image's paths, as listed by `isoinfo`, or by `xorriso` if that's
+    /// installed instead, so the image isn't only read back by `read_iso`.
+    async fn list_externally(image: &Path, joliet: bool) -> Result<Option<String>> {
+        let image = image.to_string_lossy().to_string();
+        let names = if joliet { "-J" } else { "-R" };
+        if let Some(listing) =
+            run_if_installed("isoinfo", [names, "-f", "-i", image.as_str()]).await?
+        {
+            return Ok(Some(listing));
+        }
+        // xorriso prefers Rock Ridge names, so it can only check those.
+        if joliet {
+            return Ok(None);
+        }
+        run_if_installed("xorriso", ["-indev", image.as_str(), "-find", "/"]).await
+    }
+
+    #[tokio::test]
+    async fn test_external_tools_read_the_image() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        for joliet in [false, true] {
+            let path = tmp.path_view().join(format!("external-{joliet}.iso"));
+            IsoProducerBuilder::new("iso")
+                .path(path.clone())
+                .rock_ridge(!joliet)
+                .joliet(joliet)
+                .build()?
+                .produce_from(&MemoryArtifact {
+                    name: "memory".into(),
+                    fs: memfs_with_long_names().await?,
+                })
+                .await?;
+
+            let Some(listing) = list_externally(&path, joliet).await? else {
+                continue;
+            };
+            for expected in [
+                "/usr/share/doc/peckish/a-file-name-much-longer-than-iso-9660-allows.txt",
+                "/usr/bin/peckish",
+            ] {
+                assert!(
+                    listing.contains(expected),
+                    "{expected} is missing from:\n{listing}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_joliet_names_are_read_without_rock_ridge() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let artifact = IsoProducerBuilder::new("iso")
+            .path(tmp.path_view().join("joliet.iso"))
+            .rock_ridge(false)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs_with_long_names().await?,
+            })
+            .await?;
+
+        let extracted = artifact.extract().await?;
+        assert_eq!(
+            b"hello from an iso".to_vec(),
+            extracted
+                .fs()
+                .read("/usr/share/doc/peckish/a-file-name-much-longer-than-iso-9660-allows.txt")
+                .await?
+        );
+
+        Ok(())
+    }
+}
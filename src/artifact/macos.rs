@@ -0,0 +1,1108 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
+use floppy_disk::tokio_fs::TokioFloppyDisk;
+use regex::Regex;
+use serde::Serialize;
+use smoosh::CompressionType;
+use tracing::*;
+
+use crate::fs::MemFS;
+use crate::util::config::Injection;
+use crate::util::{compress, decompress};
+
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation};
+
+const XAR_MAGIC: &[u8] = b"xar!";
+const XAR_HEADER_SIZE: u16 = 28;
+const XAR_VERSION: u16 = 1;
+const XAR_CHECKSUM_MD5: u32 = 2;
+const MD5_SIZE: u64 = 16;
+
+const CPIO_MAGIC: &[u8] = b"070707";
+const CPIO_HEADER_SIZE: usize = 76;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const BOM_MAGIC: &[u8] = b"BOMStore";
+/// Blocks start after a header padded out to this many bytes, like `mkbom`
+/// writes it.
+const BOM_HEADER_SIZE: usize = 512;
+/// The block size of the `Paths` and `HLIndex` trees.
+const BOM_TREE_BLOCK_SIZE: u32 = 4_096;
+/// The block size of the `VIndex` and `Size64` trees.
+const BOM_SMALL_TREE_BLOCK_SIZE: u32 = 128;
+/// How many paths fit in one leaf of the `Paths` tree: its block size, minus
+/// the leaf's header, in 8-byte entries.
+const BOM_PATHS_PER_LEAF: usize = (BOM_TREE_BLOCK_SIZE as usize - 12) / 8;
+
+/// A macOS flat component package: a xar archive holding a `Bom`, a
+/// `PackageInfo`, a gzipped cpio `Payload`, and optionally a gzipped cpio of
+/// `Scripts`.
+#[derive(Debug, Clone)]
+pub struct MacosPkgArtifact {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl Artifact for MacosPkgArtifact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::MacosPkg
+    }
+
+    async fn extract(&self) -> Result<MemFS> {
+        let data = tokio::fs::read(&self.path).await?;
+        let payload = read_xar(&data)
+            .await?
+            .into_iter()
+            .find(|(name, _)| name == "Payload")
+            .map(|(_, payload)| payload)
+            .ok_or_else(|| eyre!("{} has no Payload", self.path.display()))?;
+        if payload.starts_with(b"pbzx") {
+            return Err(eyre!(
+                "{} has a pbzx payload, which is not supported",
+                self.path.display()
+            ));
+        }
+
+        let mut cpio = vec![];
+        decompress(&mut payload.as_slice(), &mut cpio).await?;
+
+        let memfs = MemFS::new();
+        read_cpio(&cpio, &memfs).await?;
+        Ok(memfs)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        Some(vec![self.path.clone()])
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for MacosPkgArtifact {
+    async fn validate(&self) -> Result<()> {
+        if !self.path.is_file() {
+            return Err(eyre!("path is not a file: {:?}", self.path));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MacosPkgArtifactBuilder {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[allow(unused)]
+impl MacosPkgArtifactBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl SelfBuilder for MacosPkgArtifactBuilder {
+    type Output = MacosPkgArtifact;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(MacosPkgArtifact {
+            name: self.name.clone(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// Produces a macOS flat component package. The `Bom` lists the same paths,
+/// modes, and owners as the payload, in the format `mkbom` writes.
+#[derive(Debug, Clone, Serialize)]
+pub struct MacosPkgProducer {
+    pub name: String,
+    pub path: PathBuf,
+    /// The package identifier, ex. `com.example.peckish`.
+    pub identifier: String,
+    pub version: String,
+    /// Where the payload is installed. Paths in the artifact are relative to
+    /// this.
+    pub install_location: PathBuf,
+    /// Host paths to the `preinstall` and `postinstall` scripts.
+    pub preinstall: Option<PathBuf>,
+    pub postinstall: Option<PathBuf>,
+    pub injections: Vec<Injection>,
+}
+
+impl MacosPkgProducer {
+    async fn package_info(&self, memfs: &MemFS) -> Result<String> {
+        let mut files = 0u64;
+        let mut size = 0u64;
+        for path in nyoom::walk_ordered(memfs.fs(), "/").await? {
+            files += 1;
+            size += memfs.fs().symlink_metadata(&path).await?.len();
+        }
+
+        let mut scripts = String::new();
+        for (name, script) in self.scripts() {
+            if script.is_some() {
+                scripts.push_str(&format!("        <{name} file=\"./{name}\"/>\n"));
+            }
+        }
+
+        Ok(format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<pkg-info format-version="2" identifier="{}" version="{}" install-location="{}" auth="root" generator-version="peckish">
+    <payload numberOfFiles="{files}" installKBytes="{}"/>
+    <scripts>
+{scripts}    </scripts>
+</pkg-info>
+"#,
+            xml_escape(&self.identifier),
+            xml_escape(&self.version),
+            xml_escape(&self.install_location.to_string_lossy()),
+            size.div_ceil(1024),
+        ))
+    }
+
+    fn scripts(&self) -> [(&'static str, Option<&PathBuf>); 2] {
+        [
+            ("preinstall", self.preinstall.as_ref()),
+            ("postinstall", self.postinstall.as_ref()),
+        ]
+    }
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for MacosPkgProducer {
+    type Output = MacosPkgArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<MacosPkgArtifact> {
+        info!("producing {}", self.path.display());
+        let mut memfs = previous.extract().await?;
+        self.inject(&mut memfs).await?;
+
+        let mut files = vec![
+            ("Bom", write_bom(&memfs).await?),
+            ("PackageInfo", self.package_info(&memfs).await?.into_bytes()),
+            (
+                "Payload",
+                compress(&write_cpio(&memfs).await?, CompressionType::Gzip).await?,
+            ),
+        ];
+
+        if self.preinstall.is_some() || self.postinstall.is_some() {
+            let scripts = MemFS::new();
+            for (name, script) in self.scripts() {
+                if let Some(script) = script {
+                    let path = Path::new("/").join(name);
+                    scripts
+                        .fs()
+                        .write(&path, tokio::fs::read(script).await?)
+                        .await?;
+                    scripts
+                        .fs()
+                        .set_permissions(&path, MemPermissions::from_mode(0o755))
+                        .await?;
+                }
+            }
+            files.push((
+                "Scripts",
+                compress(&write_cpio(&scripts).await?, CompressionType::Gzip).await?,
+            ));
+        }
+
+        tokio::fs::write(&self.path, write_xar(&files).await?).await?;
+
+        Ok(MacosPkgArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for MacosPkgProducer {
+    async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        if self.identifier.is_empty() {
+            errors.push("identifier must not be empty".to_string());
+        }
+
+        for (name, script) in self.scripts() {
+            if let Some(script) = script {
+                if !script.is_file() {
+                    errors.push(format!("{name} script is not a file: {script:?}"));
+                }
+            }
+        }
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if TokioFloppyDisk::new(None)
+            .metadata(&self.path)
+            .await
+            .is_ok()
+        {
+            errors.push(format!("path already exists: {}", self.path.display()));
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!(
+                "cannot produce artifact '{}':\n{}",
+                self.name,
+                errors.join("\n")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MacosPkgProducerBuilder {
+    name: String,
+    path: PathBuf,
+    identifier: String,
+    version: String,
+    install_location: PathBuf,
+    preinstall: Option<PathBuf>,
+    postinstall: Option<PathBuf>,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl MacosPkgProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn identifier<S: Into<String>>(mut self, identifier: S) -> Self {
+        self.identifier = identifier.into();
+        self
+    }
+
+    pub fn version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    pub fn install_location<P: Into<PathBuf>>(mut self, install_location: P) -> Self {
+        self.install_location = install_location.into();
+        self
+    }
+
+    pub fn preinstall<P: Into<PathBuf>>(mut self, preinstall: P) -> Self {
+        self.preinstall = Some(preinstall.into());
+        self
+    }
+
+    pub fn postinstall<P: Into<PathBuf>>(mut self, postinstall: P) -> Self {
+        self.postinstall = Some(postinstall.into());
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for MacosPkgProducerBuilder {
+    type Output = MacosPkgProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+            identifier: String::new(),
+            version: String::new(),
+            install_location: PathBuf::from("/"),
+            preinstall: None,
+            postinstall: None,
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(MacosPkgProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            identifier: self.identifier.clone(),
+            version: self.version.clone(),
+            install_location: self.install_location.clone(),
+            preinstall: self.preinstall.clone(),
+            postinstall: self.postinstall.clone(),
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+/// Write a xar archive of the given files, stored uncompressed. The heap
+/// starts with the md5 of the compressed table of contents.
+async fn write_xar(files: &[(&str, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut toc = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar>
+ <toc>
+  <checksum style="md5">
+   <offset>0</offset>
+   <size>16</size>
+  </checksum>
+  <creation-time>1970-01-01T00:00:00</creation-time>
+"#,
+    );
+    let mut offset = MD5_SIZE;
+    for (id, (name, data)) in files.iter().enumerate() {
+        let checksum = md5_hex(data);
+        toc.push_str(&format!(
+            r#"  <file id="{}">
+   <data>
+    <length>{}</length>
+    <offset>{offset}</offset>
+    <size>{}</size>
+    <encoding style="application/octet-stream"/>
+    <archived-checksum style="md5">{checksum}</archived-checksum>
+    <extracted-checksum style="md5">{checksum}</extracted-checksum>
+   </data>
+   <name>{}</name>
+   <type>file</type>
+   <mode>0644</mode>
+  </file>
+"#,
+            id + 1,
+            data.len(),
+            data.len(),
+            xml_escape(name),
+        ));
+        offset += data.len() as u64;
+    }
+    toc.push_str(" </toc>\n</xar>\n");
+
+    let compressed_toc = compress(toc.as_bytes(), CompressionType::Zlib).await?;
+
+    let mut out = vec![];
+    out.extend_from_slice(XAR_MAGIC);
+    out.extend_from_slice(&XAR_HEADER_SIZE.to_be_bytes());
+    out.extend_from_slice(&XAR_VERSION.to_be_bytes());
+    out.extend_from_slice(&(compressed_toc.len() as u64).to_be_bytes());
+    out.extend_from_slice(&(toc.len() as u64).to_be_bytes());
+    out.extend_from_slice(&XAR_CHECKSUM_MD5.to_be_bytes());
+    out.extend_from_slice(&compressed_toc);
+    out.extend_from_slice(md5::compute(&compressed_toc).as_ref());
+    for (_, data) in files {
+        out.extend_from_slice(data);
+    }
+
+    Ok(out)
+}
+
+/// Read the top-level files out of a xar archive. Only uncompressed
+/// (`application/octet-stream`) file data is supported, which is what flat
+/// packages use.
+async fn read_xar(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    if data.len() < XAR_HEADER_SIZE as usize || &data[..4] != XAR_MAGIC {
+        return Err(eyre!("not a xar archive"));
+    }
+
+    let header_size = u16::from_be_bytes([data[4], data[5]]) as usize;
+    let toc_size = u64::from_be_bytes(data[8..16].try_into()?) as usize;
+    let heap = header_size + toc_size;
+    let compressed_toc = data
+        .get(header_size..heap)
+        .ok_or_else(|| eyre!("xar table of contents is truncated"))?;
+
+    let mut toc = vec![];
+    decompress(&mut &compressed_toc[..], &mut toc).await?;
+    let toc = String::from_utf8(toc)?;
+
+    let file_re = Regex::new(r"(?s)<file id=.*?</file>")?;
+    let field_re = |tag: &str| Regex::new(&format!("<{tag}>([^<]*)</{tag}>"));
+    let (name_re, offset_re, length_re) =
+        (field_re("name")?, field_re("offset")?, field_re("length")?);
+    let field = |file: &str, re: &Regex| -> Option<String> {
+        re.captures(file).map(|captures| captures[1].to_string())
+    };
+
+    let mut files = vec![];
+    for file in file_re.find_iter(&toc).map(|m| m.as_str()) {
+        let (Some(name), Some(offset), Some(length)) = (
+            field(file, &name_re),
+            field(file, &offset_re),
+            field(file, &length_re),
+        ) else {
+            continue;
+        };
+
+        let start = heap + offset.parse::<usize>()?;
+        let end = start + length.parse::<usize>()?;
+        let content = data
+            .get(start..end)
+            .ok_or_else(|| eyre!("xar file {name} is truncated"))?;
+        files.push((name, content.to_vec()));
+    }
+
+    Ok(files)
+}
+
+/// Write the memfs as an odc (`070707`) cpio archive, with `./`-relative
+/// names, as macOS expects.
+async fn write_cpio(memfs: &MemFS) -> Result<Vec<u8>> {
+    let fs = memfs.fs();
+    let mut out = vec![];
+    for (ino, path) in nyoom::walk_ordered(fs, "/").await?.iter().enumerate() {
+        let metadata = fs.symlink_metadata(path).await?;
+        let (file_type, data) = if metadata.is_symlink() {
+            let link = fs.read_link(path).await?;
+            (0o120000, link.as_os_str().as_bytes().to_vec())
+        } else if metadata.is_dir() {
+            (0o040000, vec![])
+        } else {
+            (0o100000, fs.read(path).await?)
+        };
+
+        let name = Path::new(".").join(path.strip_prefix("/").unwrap_or(path));
+        write_cpio_entry(
+            &mut out,
+            name.as_os_str().as_bytes(),
+            ino as u64 + 1,
+            file_type | (metadata.permissions().mode() & 0o7777),
+            metadata.uid()?,
+            metadata.gid()?,
+            memfs.mtime(path).unwrap_or(0),
+            &data,
+        )
+        .map_err(|e| eyre!("cannot add {} to the payload: {e}", path.display()))?;
+    }
+    write_cpio_entry(&mut out, CPIO_TRAILER.as_bytes(), 0, 0, 0, 0, 0, &[])?;
+
+    Ok(out)
+}
+
+/// `value` as a zero-padded octal cpio header field `width` digits wide, or
+/// an error if it doesn't fit.
+fn cpio_field(name: &str, value: u64, width: u32) -> Result<String> {
+    if value >= 8u64.pow(width) {
+        return Err(eyre!(
+            "{name} {value} is too large for an odc cpio header, which has {width} octal digits for it"
+        ));
+    }
+
+    Ok(format!("{value:0width$o}", width = width as usize))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_cpio_entry(
+    out: &mut Vec<u8>,
+    name: &[u8],
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    data: &[u8],
+) -> Result<()> {
+    // Inode numbers only have to be unique among hard links, which are never
+    // written, so huge payloads can wrap them around.
+    let ino = ino % 8u64.pow(6);
+    let header = [
+        cpio_field("device", 0, 6)?,
+        cpio_field("inode", ino, 6)?,
+        cpio_field("mode", mode as u64, 6)?,
+        cpio_field("uid", uid as u64, 6)?,
+        cpio_field("gid", gid as u64, 6)?,
+        cpio_field("link count", 1, 6)?,
+        cpio_field("rdev", 0, 6)?,
+        cpio_field("mtime", mtime, 11)?,
+        cpio_field("name size", name.len() as u64 + 1, 6)?,
+        cpio_field("file size", data.len() as u64, 11)?,
+    ]
+    .concat();
+
+    out.extend_from_slice(CPIO_MAGIC);
+    out.extend_from_slice(header.as_bytes());
+    out.extend_from_slice(name);
+    out.push(0);
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+/// The `cksum` CRC of the data, which is what a `Bom` records for files and
+/// symlinks.
+fn posix_cksum(data: &[u8]) -> u32 {
+    fn update(crc: u32, byte: u8) -> u32 {
+        let mut crc = crc ^ ((byte as u32) << 24);
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    }
+
+    let mut crc = data.iter().fold(0, |crc, byte| update(crc, *byte));
+    // The length goes in too, least significant byte first, without the
+    // trailing zero bytes.
+    let mut len = data.len() as u64;
+    while len != 0 {
+        crc = update(crc, len as u8);
+        len >>= 8;
+    }
+    !crc
+}
+
+/// A `BOMStore` file being put together: numbered blocks of data, plus named
+/// variables pointing at some of them. Block 0 is always the null block.
+#[derive(Default)]
+struct BomStore {
+    blocks: Vec<Vec<u8>>,
+    vars: Vec<(&'static str, u32)>,
+}
+
+impl BomStore {
+    fn add_block(&mut self, data: Vec<u8>) -> u32 {
+        self.blocks.push(data);
+        self.blocks.len() as u32
+    }
+
+    fn set_block(&mut self, index: u32, data: Vec<u8>) {
+        self.blocks[index as usize - 1] = data;
+    }
+
+    fn add_var(&mut self, name: &'static str, data: Vec<u8>) {
+        let index = self.add_block(data);
+        self.vars.push((name, index));
+    }
+
+    /// A B-tree node of `(index0, index1)` entries. Nodes take up a whole
+    /// block of the tree.
+    fn tree_node(
+        is_leaf: bool,
+        entries: &[(u32, u32)],
+        forward: u32,
+        backward: u32,
+        block_size: u32,
+    ) -> Vec<u8> {
+        let mut node = vec![];
+        node.extend_from_slice(&(is_leaf as u16).to_be_bytes());
+        node.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+        node.extend_from_slice(&forward.to_be_bytes());
+        node.extend_from_slice(&backward.to_be_bytes());
+        for (index0, index1) in entries {
+            node.extend_from_slice(&index0.to_be_bytes());
+            node.extend_from_slice(&index1.to_be_bytes());
+        }
+        node.resize(node.len().max(block_size as usize), 0);
+        node
+    }
+
+    /// A `tree` header pointing at its root node.
+    fn tree(root: u32, block_size: u32, path_count: u32) -> Vec<u8> {
+        let mut tree = b"tree".to_vec();
+        tree.extend_from_slice(&1u32.to_be_bytes());
+        tree.extend_from_slice(&root.to_be_bytes());
+        tree.extend_from_slice(&block_size.to_be_bytes());
+        tree.extend_from_slice(&path_count.to_be_bytes());
+        tree.push(0);
+        tree
+    }
+
+    /// A tree without anything in it, for the indexes peckish has nothing to
+    /// put in.
+    fn add_empty_tree(&mut self, block_size: u32) -> u32 {
+        let root = self.add_block(Self::tree_node(true, &[], 0, 0, block_size));
+        self.add_block(Self::tree(root, block_size, 0))
+    }
+
+    /// The header, then the blocks, then the variables, then the table of
+    /// where each block is.
+    fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut out = vec![0; BOM_HEADER_SIZE];
+        let mut pointers = vec![(0u32, 0u32)];
+        for block in &self.blocks {
+            pointers.push((out.len().try_into()?, block.len().try_into()?));
+            out.extend_from_slice(block);
+        }
+
+        let vars_offset: u32 = out.len().try_into()?;
+        out.extend_from_slice(&(self.vars.len() as u32).to_be_bytes());
+        for (name, index) in &self.vars {
+            out.extend_from_slice(&index.to_be_bytes());
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+        }
+        let vars_length = out.len() as u32 - vars_offset;
+
+        let index_offset: u32 = out.len().try_into()?;
+        out.extend_from_slice(&(pointers.len() as u32).to_be_bytes());
+        for (address, length) in &pointers {
+            out.extend_from_slice(&address.to_be_bytes());
+            out.extend_from_slice(&length.to_be_bytes());
+        }
+        // An empty free list.
+        out.extend_from_slice(&0u32.to_be_bytes());
+        let index_length = out.len() as u32 - index_offset;
+
+        let mut header = BOM_MAGIC.to_vec();
+        for field in [
+            1,
+            self.blocks.len() as u32,
+            index_offset,
+            index_length,
+            vars_offset,
+            vars_length,
+        ] {
+            header.extend_from_slice(&field.to_be_bytes());
+        }
+        out[..header.len()].copy_from_slice(&header);
+
+        Ok(out)
+    }
+}
+
+/// Write a `Bom` listing every path in the memfs, the way `mkbom` does: a
+/// `Paths` tree of each path's name, parent, type, mode, owner, mtime, size,
+/// and checksum, and empty `HLIndex`, `VIndex`, and `Size64` indexes. Paths
+/// are named relative to the install location, starting from `.`.
+async fn write_bom(memfs: &MemFS) -> Result<Vec<u8>> {
+    let fs = memfs.fs();
+    let mut bom = BomStore::default();
+
+    let mut paths = vec![PathBuf::from("/")];
+    paths.extend(
+        nyoom::walk_ordered(fs, "/")
+            .await?
+            .into_iter()
+            .filter(|path| path != Path::new("/")),
+    );
+
+    // Path ids start at 1, for `.`, whose parent is 0.
+    let mut ids = std::collections::HashMap::new();
+    let mut entries = vec![];
+    for (id, path) in paths.iter().enumerate() {
+        let id = id as u32 + 1;
+        ids.insert(path.clone(), id);
+
+        let metadata = fs.symlink_metadata(path).await?;
+        let (kind, data) = if metadata.is_symlink() {
+            let link = fs.read_link(path).await?;
+            (3u8, link.as_os_str().as_bytes().to_vec())
+        } else if metadata.is_dir() {
+            (2, vec![])
+        } else {
+            (1, fs.read(path).await?)
+        };
+        let size: u32 = data
+            .len()
+            .try_into()
+            .map_err(|_| eyre!("{} is too large for a Bom", path.display()))?;
+        let mtime: u32 = memfs
+            .mtime(path)
+            .unwrap_or(0)
+            .try_into()
+            .map_err(|_| eyre!("the mtime of {} is too large for a Bom", path.display()))?;
+        let file_type = match kind {
+            1 => 0o100000,
+            2 => 0o040000,
+            _ => 0o120000,
+        };
+
+        let mut info = vec![kind, 1];
+        // The architecture, which is only meaningful for executables.
+        info.extend_from_slice(&3u16.to_be_bytes());
+        info.extend_from_slice(
+            &((file_type | (metadata.permissions().mode() & 0o7777)) as u16).to_be_bytes(),
+        );
+        info.extend_from_slice(&metadata.uid()?.to_be_bytes());
+        info.extend_from_slice(&metadata.gid()?.to_be_bytes());
+        info.extend_from_slice(&mtime.to_be_bytes());
+        info.extend_from_slice(&size.to_be_bytes());
+        info.push(1);
+        let checksum = if kind == 2 { 0 } else { posix_cksum(&data) };
+        info.extend_from_slice(&checksum.to_be_bytes());
+        if kind == 3 {
+            info.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+            info.extend_from_slice(&data);
+            info.push(0);
+        } else {
+            info.extend_from_slice(&0u32.to_be_bytes());
+        }
+        let info = bom.add_block(info);
+
+        let mut path_info = id.to_be_bytes().to_vec();
+        path_info.extend_from_slice(&info.to_be_bytes());
+        let path_info = bom.add_block(path_info);
+
+        let (parent, name) = match path.parent() {
+            Some(parent) => (
+                *ids.get(parent)
+                    .ok_or_else(|| eyre!("{} was walked before its parent", path.display()))?,
+                path.file_name().unwrap_or_default().as_bytes(),
+            ),
+            None => (0, b".".as_slice()),
+        };
+        let mut file = parent.to_be_bytes().to_vec();
+        file.extend_from_slice(name);
+        file.push(0);
+        let file = bom.add_block(file);
+
+        entries.push((path_info, file));
+    }
+
+    // Leaves are chained together, so their blocks are set aside first.
+    let chunks = entries.chunks(BOM_PATHS_PER_LEAF).collect::<Vec<_>>();
+    if chunks.len() > BOM_PATHS_PER_LEAF {
+        return Err(eyre!("too many paths for a Bom: {}", entries.len()));
+    }
+    let leaves = chunks
+        .iter()
+        .map(|_| bom.add_block(vec![]))
+        .collect::<Vec<_>>();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let forward = leaves.get(i + 1).copied().unwrap_or(0);
+        let backward = if i == 0 { 0 } else { leaves[i - 1] };
+        bom.set_block(
+            leaves[i],
+            BomStore::tree_node(true, chunk, forward, backward, BOM_TREE_BLOCK_SIZE),
+        );
+    }
+    let root = if leaves.len() == 1 {
+        leaves[0]
+    } else {
+        // Each leaf is keyed by the last path in it.
+        let keys = leaves
+            .iter()
+            .zip(&chunks)
+            .map(|(leaf, chunk)| (*leaf, chunk.last().map(|(_, file)| *file).unwrap_or(0)))
+            .collect::<Vec<_>>();
+        bom.add_block(BomStore::tree_node(false, &keys, 0, 0, BOM_TREE_BLOCK_SIZE))
+    };
+
+    let mut info = 1u32.to_be_bytes().to_vec();
+    info.extend_from_slice(&(paths.len() as u32).to_be_bytes());
+    info.extend_from_slice(&0u32.to_be_bytes());
+    bom.add_var("BomInfo", info);
+    bom.add_var(
+        "Paths",
+        BomStore::tree(root, BOM_TREE_BLOCK_SIZE, paths.len() as u32),
+    );
+    let hl_index = bom.add_empty_tree(BOM_TREE_BLOCK_SIZE);
+    bom.vars.push(("HLIndex", hl_index));
+    let v_tree = bom.add_empty_tree(BOM_SMALL_TREE_BLOCK_SIZE);
+    let mut v_index = 1u32.to_be_bytes().to_vec();
+    v_index.extend_from_slice(&v_tree.to_be_bytes());
+    v_index.extend_from_slice(&0u32.to_be_bytes());
+    v_index.push(0);
+    bom.add_var("VIndex", v_index);
+    let size_64 = bom.add_empty_tree(BOM_SMALL_TREE_BLOCK_SIZE);
+    bom.vars.push(("Size64", size_64));
+
+    bom.into_bytes()
+}
+
+/// Read an odc cpio archive into the memfs.
+async fn read_cpio(data: &[u8], memfs: &MemFS) -> Result<()> {
+    let fs = memfs.fs();
+    let octal = |field: &[u8]| -> Result<u64> {
+        u64::from_str_radix(std::str::from_utf8(field)?, 8)
+            .map_err(|e| eyre!("invalid cpio header field: {e}"))
+    };
+
+    let mut pos = 0;
+    loop {
+        let header = data
+            .get(pos..pos + CPIO_HEADER_SIZE)
+            .ok_or_else(|| eyre!("cpio archive is truncated"))?;
+        if &header[..6] != CPIO_MAGIC {
+            return Err(eyre!("not an odc cpio archive"));
+        }
+
+        let mode = octal(&header[18..24])? as u32;
+        let uid = octal(&header[24..30])? as u32;
+        let gid = octal(&header[30..36])? as u32;
+        let name_size = octal(&header[59..65])? as usize;
+        let file_size = octal(&header[65..76])? as usize;
+        pos += CPIO_HEADER_SIZE;
+
+        let name = data
+            .get(pos..pos + name_size)
+            .ok_or_else(|| eyre!("cpio archive is truncated"))?;
+        let name = std::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name))?;
+        pos += name_size;
+        let content = data
+            .get(pos..pos + file_size)
+            .ok_or_else(|| eyre!("cpio archive is truncated"))?;
+        pos += file_size;
+
+        if name == CPIO_TRAILER {
+            break;
+        }
+
+        let path = Path::new("/").join(name.trim_start_matches('.').trim_start_matches('/'));
+        if path == Path::new("/") {
+            continue;
+        }
+
+        debug!("extracting {}", path.display());
+        if let Some(parent) = path.parent() {
+            fs.create_dir_all(parent).await?;
+        }
+
+        match mode & 0o170000 {
+            0o040000 => fs.create_dir_all(&path).await?,
+            0o120000 => {
+                let link = PathBuf::from(std::ffi::OsStr::from_bytes(content));
+                fs.symlink(link, path).await?;
+                continue;
+            }
+            0o100000 => fs.write(&path, content).await?,
+            _ => continue,
+        }
+
+        fs.chown(&path, uid, gid).await?;
+        fs.set_permissions(&path, MemPermissions::from_mode(mode & 0o7777))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::MemoryArtifact;
+    use crate::fs::TempDir;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_package_has_flat_package_structure() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let postinstall = tmp.path_view().join("postinstall");
+        tokio::fs::write(&postinstall, b"#!/bin/sh\necho installed\n").await?;
+
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/local/bin").await?;
+        memfs.fs().write("/usr/local/bin/hello", b"hello").await?;
+        memfs
+            .fs()
+            .set_permissions("/usr/local/bin/hello", MemPermissions::from_mode(0o755))
+            .await?;
+
+        let artifact = MacosPkgProducerBuilder::new("pkg")
+            .path(tmp.path_view().join("hello.pkg"))
+            .identifier("com.example.hello")
+            .version("1.0.0")
+            .postinstall(&postinstall)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let data = tokio::fs::read(&artifact.path).await?;
+        assert_eq!(&data[..4], b"xar!");
+        let files = read_xar(&data).await?;
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["Bom", "PackageInfo", "Payload", "Scripts"]);
+
+        let package_info = String::from_utf8(files[1].1.clone())?;
+        assert!(package_info.contains(r#"identifier="com.example.hello""#));
+        assert!(package_info.contains(r#"version="1.0.0""#));
+        assert!(package_info.contains(r#"<postinstall file="./postinstall"/>"#));
+
+        let mut scripts = vec![];
+        smoosh::recompress(
+            &mut files[3].1.as_slice(),
+            &mut scripts,
+            CompressionType::None,
+        )
+        .await?;
+        let scripts_fs = MemFS::new();
+        read_cpio(&scripts, &scripts_fs).await?;
+        let script = scripts_fs.fs().metadata("/postinstall").await?;
+        assert_eq!(script.permissions().mode() & 0o777, 0o755);
+
+        let extracted = artifact.extract().await?;
+        assert_eq!(extracted.fs().read("/usr/local/bin/hello").await?, b"hello");
+        let hello = extracted.fs().metadata("/usr/local/bin/hello").await?;
+        assert_eq!(hello.permissions().mode() & 0o777, 0o755);
+
+        Ok(())
+    }
+
+    fn be_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Every path in a `Bom`, with its mode, found by following the `Paths`
+    /// tree's leaves, like `lsbom` does.
+    fn read_bom_paths(bom: &[u8]) -> Vec<(String, u16)> {
+        assert_eq!(&bom[..8], BOM_MAGIC);
+        let index_offset = be_u32(bom, 16) as usize;
+        let vars_offset = be_u32(bom, 24) as usize;
+        let block = |index: u32| {
+            let pointer = index_offset + 4 + index as usize * 8;
+            let address = be_u32(bom, pointer) as usize;
+            &bom[address..address + be_u32(bom, pointer + 4) as usize]
+        };
+
+        let mut vars = std::collections::HashMap::new();
+        let mut offset = vars_offset + 4;
+        for _ in 0..be_u32(bom, vars_offset) {
+            let index = be_u32(bom, offset);
+            let length = bom[offset + 4] as usize;
+            let name = String::from_utf8(bom[offset + 5..offset + 5 + length].to_vec()).unwrap();
+            vars.insert(name, index);
+            offset += 5 + length;
+        }
+        for var in ["BomInfo", "Paths", "HLIndex", "VIndex", "Size64"] {
+            assert!(vars.contains_key(var), "missing {var}");
+        }
+
+        let tree = block(vars["Paths"]);
+        assert_eq!(&tree[..4], b"tree");
+        let mut node = be_u32(tree, 8);
+        while u16::from_be_bytes(block(node)[..2].try_into().unwrap()) == 0 {
+            node = be_u32(block(node), 12);
+        }
+
+        let mut names = std::collections::HashMap::new();
+        let mut paths = vec![];
+        while node != 0 {
+            let leaf = block(node);
+            let count = u16::from_be_bytes(leaf[2..4].try_into().unwrap()) as usize;
+            for entry in 0..count {
+                let path_info = block(be_u32(leaf, 12 + entry * 8));
+                let file = block(be_u32(leaf, 16 + entry * 8));
+                let info = block(be_u32(path_info, 4));
+                let name = String::from_utf8(file[4..file.len() - 1].to_vec()).unwrap();
+                let path = match names.get(&be_u32(file, 0)) {
+                    Some(parent) => format!("{parent}/{name}"),
+                    None => name,
+                };
+                names.insert(be_u32(path_info, 0), path.clone());
+                paths.push((path, u16::from_be_bytes(info[4..6].try_into().unwrap())));
+            }
+            node = be_u32(leaf, 4);
+        }
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_bom_lists_every_path() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/local/bin").await?;
+        memfs.fs().write("/usr/local/bin/hello", b"hello").await?;
+        memfs
+            .fs()
+            .set_permissions("/usr/local/bin/hello", MemPermissions::from_mode(0o755))
+            .await?;
+        memfs.fs().symlink("hello", "/usr/local/bin/hi").await?;
+        for i in 0..BOM_PATHS_PER_LEAF {
+            memfs.fs().write(format!("/file-{i}"), b"").await?;
+        }
+
+        let bom = write_bom(&memfs).await?;
+        let paths = read_bom_paths(&bom);
+        assert_eq!(paths.len(), BOM_PATHS_PER_LEAF + 6);
+        let file_type = |path: &str| {
+            paths
+                .iter()
+                .find(|(name, _)| name == path)
+                .map(|(_, mode)| mode & 0o170000)
+        };
+        assert_eq!(paths[0].0, ".");
+        assert_eq!(file_type("."), Some(0o040000));
+        assert!(paths.contains(&("./usr/local/bin/hello".to_string(), 0o100755)));
+        assert_eq!(file_type("./usr/local/bin/hi"), Some(0o120000));
+        assert_eq!(file_type("./file-0"), Some(0o100000));
+        assert_eq!(
+            file_type(&format!("./file-{}", BOM_PATHS_PER_LEAF - 1)),
+            Some(0o100000)
+        );
+
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("Bom");
+        tokio::fs::write(&path, &bom).await?;
+        match tokio::process::Command::new("lsbom")
+            .arg(&path)
+            .output()
+            .await
+        {
+            Ok(output) => {
+                assert!(output.status.success());
+                let listing = String::from_utf8(output.stdout)?;
+                assert!(listing.contains("./usr/local/bin/hello\t100755"));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("lsbom is not installed, so the Bom can't be checked with it");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_cpio_fields_are_rejected() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().write("/hello", b"hello").await?;
+        memfs.fs().chown("/hello", 1 << 20, 0).await?;
+
+        let err = write_cpio(&memfs).await.unwrap_err();
+        assert!(err.to_string().contains("uid 1048576 is too large"));
+
+        let mut out = vec![];
+        assert!(write_cpio_entry(&mut out, b"big", 1, 0o100644, 0, 0, 1 << 33, &[]).is_err());
+        assert!(write_cpio_entry(&mut out, b"ok", 1 << 20, 0o100644, 0, 0, 0, &[]).is_ok());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::path::PathBuf;
+
+use eyre::{eyre, Result};
+use floppy_disk::prelude::*;
+use floppy_disk::tokio_fs::TokioFloppyDisk;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::artifact::file::{FileArtifact, FilePlacement};
+use crate::util::config::Injection;
+use crate::util::sha256_digest_bytes;
+
+use super::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+
+/// The format a manifest is written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ManifestFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+/// What kind of filesystem entry a [`ManifestEntry`] describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ManifestEntryType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A single path in a manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub entry_type: ManifestEntryType,
+    pub size: u64,
+    pub mode: u32,
+    /// Only present for files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Produces a machine-readable packing list of the previous artifact: every
+/// path in it, with its type, size, mode, and (for files) sha256. The output
+/// is a single file, so it can be chained like any other file artifact.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestProducer {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: ManifestFormat,
+    pub injections: Vec<Injection>,
+}
+
+impl ManifestProducer {
+    /// Every path in the memfs, in walk order. The root is left out.
+    pub async fn entries(fs: &MemFloppyDisk) -> Result<Vec<ManifestEntry>> {
+        let mut entries = vec![];
+        for path in nyoom::walk_ordered(fs, "/").await? {
+            if path == std::path::Path::new("/") {
+                continue;
+            }
+
+            let metadata = fs.symlink_metadata(&path).await?;
+            let (entry_type, sha256) = if metadata.is_symlink() {
+                (ManifestEntryType::Symlink, None)
+            } else if metadata.is_dir() {
+                (ManifestEntryType::Directory, None)
+            } else {
+                let data = fs.read(&path).await?;
+                (ManifestEntryType::File, Some(sha256_digest_bytes(&data)))
+            };
+
+            entries.push(ManifestEntry {
+                path,
+                entry_type,
+                size: metadata.len(),
+                mode: metadata.permissions().mode(),
+                sha256,
+            });
+        }
+
+        Ok(entries)
+    }
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for ManifestProducer {
+    type Output = FileArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<FileArtifact> {
+        info!("producing manifest {}", self.path.display());
+        let mut memfs = previous.extract().await?;
+        self.inject(&mut memfs).await?;
+
+        let entries = Self::entries(&memfs).await?;
+        debug!("manifest has {} entries", entries.len());
+        let manifest = match self.format {
+            ManifestFormat::Json => serde_json::to_string_pretty(&entries)?,
+            ManifestFormat::Yaml => serde_yaml::to_string(&entries)?,
+        };
+        tokio::fs::write(&self.path, manifest).await?;
+
+        Ok(FileArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            paths: vec![self.path.clone()],
+            git_mtimes: false,
+            default_excludes: true,
+            extra_excludes: vec![],
+            placement: FilePlacement::AsGiven,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for ManifestProducer {
+    async fn validate(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if TokioFloppyDisk::new(None)
+            .metadata(&self.path)
+            .await
+            .is_ok()
+        {
+            return Err(eyre!(
+                "cannot produce artifact '{}': path already exists: {}",
+                self.name,
+                self.path.display()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ManifestProducerBuilder {
+    name: String,
+    path: PathBuf,
+    format: ManifestFormat,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl ManifestProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn format(mut self, format: ManifestFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for ManifestProducerBuilder {
+    type Output = ManifestProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from("manifest.json"),
+            format: ManifestFormat::default(),
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(ManifestProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            format: self.format,
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::EmptyArtifact;
+    use crate::fs::TempDir;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_manifest_lists_every_path() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("manifest.json");
+
+        ManifestProducerBuilder::new("manifest")
+            .path(&path)
+            .inject(Injection::Create {
+                path: "/usr/bin/hello".into(),
+                content: b"hello".to_vec(),
+            })
+            .inject(Injection::Symlink {
+                src: "/usr/bin/hello".into(),
+                dest: "/usr/bin/hi".into(),
+                relative: None,
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let entries: Vec<ManifestEntry> =
+            serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?;
+        // /usr, /usr/bin, /usr/bin/hello, /usr/bin/hi
+        assert_eq!(entries.len(), 4);
+
+        let hello = entries
+            .iter()
+            .find(|entry| entry.path == PathBuf::from("/usr/bin/hello"))
+            .unwrap();
+        assert_eq!(hello.entry_type, ManifestEntryType::File);
+        assert_eq!(hello.size, 5);
+        assert_eq!(
+            hello.sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+
+        Ok(())
+    }
+}
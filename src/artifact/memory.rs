@@ -1,10 +1,15 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use disk_drive::DiskDrive;
 use eyre::Result;
+use futures_util::stream::BoxStream;
+use tokio::sync::OnceCell;
 
-use super::{Artifact, ArtifactProducer, SelfValidation};
-use crate::fs::MemFS;
+use super::{
+    memfs_entries, Artifact, ArtifactKind, ArtifactProducer, Entry, ExtractedDir, PackageInfo,
+    SelfValidation,
+};
+use crate::fs::{MemFS, MergePolicy};
 use crate::util::config::Injection;
 
 #[derive(Debug, Clone)]
@@ -19,11 +24,18 @@ impl Artifact for MemoryArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Memory
+    }
+
     async fn extract(&self) -> Result<MemFS> {
-        // TODO: Consider making .extract() take ownership and consume
         Ok(self.fs.clone())
     }
 
+    async fn into_memfs(self: Box<Self>) -> Result<MemFS> {
+        Ok(self.fs)
+    }
+
     fn try_clone(&self) -> Result<Box<dyn Artifact>> {
         Ok(Box::new(self.clone()))
     }
@@ -59,8 +71,18 @@ impl ArtifactProducer for MemoryProducer {
 
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<Self::Output> {
         let prev = previous.extract().await?;
-        let fs = MemFS::new();
+        let mut fs = MemFS::new();
         DiskDrive::copy_between(&*prev, &*fs).await?;
+        self.inject(&mut fs).await?;
+        Ok(MemoryArtifact {
+            name: self.name.clone(),
+            fs,
+        })
+    }
+
+    async fn produce_from_owned(&self, previous: Box<dyn Artifact>) -> Result<Self::Output> {
+        let mut fs = previous.into_memfs().await?;
+        self.inject(&mut fs).await?;
         Ok(MemoryArtifact {
             name: self.name.clone(),
             fs,
@@ -93,6 +115,10 @@ impl Artifact for EmptyArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Memory
+    }
+
     async fn extract(&self) -> Result<MemFS> {
         Ok(MemFS::new())
     }
@@ -112,3 +138,165 @@ impl SelfValidation for EmptyArtifact {
         Ok(())
     }
 }
+
+/// Wraps an artifact so that it's only extracted once, no matter how many
+/// producers read from it. Producers modify the memfs they're given, so every
+/// call to [`Artifact::extract`] gets its own copy of the cached contents.
+pub struct CachedArtifact {
+    inner: Box<dyn Artifact>,
+    fs: OnceCell<MemFS>,
+}
+
+impl CachedArtifact {
+    pub fn new(inner: Box<dyn Artifact>) -> Self {
+        Self {
+            inner,
+            fs: OnceCell::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Artifact for CachedArtifact {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        self.inner.kind()
+    }
+
+    async fn extract(&self) -> Result<MemFS> {
+        let cached = self.fs.get_or_try_init(|| self.inner.extract()).await?;
+        let copy = MemFS::new();
+        copy.merge(cached, MergePolicy::Overwrite).await?;
+        Ok(copy)
+    }
+
+    async fn extract_to_dir(&self, dir: &Path) -> Result<ExtractedDir> {
+        // Don't pull the whole artifact into memory just to write it out, in
+        // case the inner artifact can stream to disk.
+        match self.fs.get() {
+            Some(cached) => ExtractedDir::write_memfs(cached, dir).await,
+            None => self.inner.extract_to_dir(dir).await,
+        }
+    }
+
+    async fn stream_entries(&self) -> Result<BoxStream<'_, Result<Entry>>> {
+        // Streaming only reads, so the cached copy can be shared.
+        match self.fs.get() {
+            Some(cached) => Ok(memfs_entries(cached.clone())),
+            None => self.inner.stream_entries().await,
+        }
+    }
+
+    async fn into_memfs(self: Box<Self>) -> Result<MemFS> {
+        // Nothing else can read the cached copy anymore, so it can be handed
+        // over as-is.
+        match self.fs.into_inner() {
+            Some(cached) => Ok(cached),
+            None => self.inner.into_memfs().await,
+        }
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(Self {
+            inner: self.inner.try_clone()?,
+            fs: self.fs.clone(),
+        }))
+    }
+
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        self.inner.paths()
+    }
+
+    async fn package_info(&self) -> Result<Option<PackageInfo>> {
+        self.inner.package_info().await
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for CachedArtifact {
+    async fn validate(&self) -> Result<()> {
+        self.inner.validate().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use floppy_disk::FloppyDisk;
+
+    use crate::fs::test_utils::CountingArtifact;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_owned_memory_artifact_is_handed_off_without_copying() -> Result<()> {
+        let fs = MemFS::new();
+        let producer = MemoryProducer {
+            name: "owned".into(),
+            injections: vec![],
+        };
+        let artifact = producer
+            .produce_from_owned(Box::new(MemoryArtifact {
+                name: "previous".into(),
+                fs: fs.clone(),
+            }))
+            .await?;
+
+        // A copy wouldn't see writes made to the original after the fact.
+        fs.fs().write("/after", b"hi").await?;
+        assert_eq!(b"hi", artifact.fs.fs().read("/after").await?.as_slice());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_owned_memory_artifact_gets_injections() -> Result<()> {
+        let producer = MemoryProducer {
+            name: "owned".into(),
+            injections: vec![Injection::Create {
+                path: "/injected".into(),
+                content: b"hi".to_vec(),
+            }],
+        };
+        let artifact = producer
+            .produce_from_owned(Box::new(MemoryArtifact {
+                name: "previous".into(),
+                fs: MemFS::new(),
+            }))
+            .await?;
+        assert_eq!(b"hi", artifact.fs.fs().read("/injected").await?.as_slice());
+
+        let artifact = producer
+            .produce_from(&MemoryArtifact {
+                name: "previous".into(),
+                fs: MemFS::new(),
+            })
+            .await?;
+        assert_eq!(b"hi", artifact.fs.fs().read("/injected").await?.as_slice());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_owned_cached_artifact_is_not_extracted_again() -> Result<()> {
+        let counting = CountingArtifact::default();
+        let cached = CachedArtifact::new(Box::new(counting.clone()));
+        let producer = MemoryProducer {
+            name: "owned".into(),
+            injections: vec![],
+        };
+
+        producer.produce_from(&cached).await?;
+        producer.produce_from_owned(Box::new(cached)).await?;
+        assert_eq!(1, counting.extracts());
+
+        Ok(())
+    }
+}
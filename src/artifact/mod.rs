@@ -1,19 +1,33 @@
-use std::path::PathBuf;
-
-use eyre::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use floppy_disk::prelude::*;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use thiserror::Error;
 use tracing::*;
 
-use crate::fs::MemFS;
+use crate::fs::{IntendedOwner, MemFS};
 use crate::util::config::Injection;
 
 pub mod arch;
+pub mod cab;
 pub mod deb;
 pub mod docker;
+pub mod dockerfile;
 pub mod ext4;
 pub mod file;
+pub mod iso;
+pub mod macos;
+pub mod manifest;
 pub(crate) mod memory;
 pub mod oci;
+pub mod registry;
+pub mod remote;
 pub mod rpm;
+pub mod sbom;
+pub mod script;
 pub mod tarball;
 
 /// An artifact is (usually) the result of some build process.
@@ -21,10 +35,59 @@ pub mod tarball;
 pub trait Artifact: Send + Sync + SelfValidation {
     fn name(&self) -> &str;
 
+    /// Which format this artifact is, for code that only has a
+    /// `dyn Artifact` to go on. Artifacts from outside of peckish are
+    /// [`ArtifactKind::Custom`] unless they say otherwise.
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Custom
+    }
+
     /// Extract this artifact into a virtual filesystem. Used for manipulating
     /// the artifact's contents.
     async fn extract(&self) -> Result<MemFS>;
 
+    /// Extract only the given paths, and everything under them, into a
+    /// virtual filesystem. Their parent directories are extracted as well.
+    ///
+    /// By default, this extracts everything and throws away the rest, so
+    /// formats that can skip over unwanted files should override it.
+    async fn extract_paths(&self, paths: &[PathBuf]) -> Result<MemFS> {
+        let memfs = self.extract().await?;
+        memfs.retain_paths(paths).await?;
+        Ok(memfs)
+    }
+
+    /// Extract this artifact onto the host filesystem, under `dir`. Used
+    /// instead of [`Artifact::extract`] for artifacts too large to hold in
+    /// memory. Returns the paths that were extracted, and the owners that
+    /// couldn't be set.
+    ///
+    /// By default, this extracts into memory and copies the result out, so
+    /// formats that can stream their contents to disk should override it.
+    async fn extract_to_dir(&self, dir: &Path) -> Result<ExtractedDir> {
+        ExtractedDir::write_memfs(&self.extract().await?, dir).await
+    }
+
+    /// Stream this artifact's entries one at a time, in the order the format
+    /// stores them, which may put children before their parents. The root
+    /// directory isn't included. Each file entry holds the file's whole
+    /// contents.
+    ///
+    /// By default, this extracts into memory and streams the result in walk
+    /// order, so formats that can read their entries incrementally should
+    /// override it.
+    async fn stream_entries(&self) -> Result<BoxStream<'_, Result<Entry>>> {
+        Ok(memfs_entries(self.extract().await?))
+    }
+
+    /// Turn this artifact into a virtual filesystem, consuming it. Artifacts
+    /// that already hold a memfs can hand it over without copying it, so
+    /// callers that own an artifact should prefer this over
+    /// [`Artifact::extract`]. By default, this just extracts the artifact.
+    async fn into_memfs(self: Box<Self>) -> Result<MemFS> {
+        self.extract().await
+    }
+
     /// We can't require `Clone` bounds because then it's not object-safe.
     fn try_clone(&self) -> Result<Box<dyn Artifact>>;
 
@@ -32,6 +95,185 @@ pub trait Artifact: Send + Sync + SelfValidation {
     /// value is optional, as ex. Docker artifacts don't "exist" on the
     /// filesystem, but are instead pulled from the daemon.
     fn paths(&self) -> Option<Vec<PathBuf>>;
+
+    /// How many bytes this artifact takes up on disk, ex. a compressed
+    /// tarball's file size, without extracting it. `None` if the artifact
+    /// isn't stored as files, like a Docker image. For the size of the
+    /// contents, use [`get_artifact_size`].
+    async fn stored_size(&self) -> Result<Option<u64>> {
+        let Some(paths) = self.paths() else {
+            return Ok(None);
+        };
+
+        let mut size = 0;
+        for path in paths {
+            let metadata = tokio::fs::metadata(&path).await?;
+            if !metadata.is_file() {
+                return Ok(None);
+            }
+            size += metadata.len();
+        }
+
+        Ok(Some(size))
+    }
+
+    /// How many bytes this artifact's contents take up once extracted, if
+    /// that can be worked out without holding them in memory, ex. by
+    /// streaming through a tarball. `None` by default, which is also what
+    /// artifacts that can't be extracted straight to disk should return.
+    async fn extracted_size(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// The package metadata of this artifact, in a format-independent shape.
+    /// Artifacts that aren't packages, like tarballs and files, don't have
+    /// any.
+    async fn package_info(&self) -> Result<Option<PackageInfo>> {
+        Ok(None)
+    }
+}
+
+/// The format of an artifact, see [`Artifact::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArtifactKind {
+    Arch,
+    Cab,
+    Deb,
+    Docker,
+    Dockerfile,
+    Ext4,
+    File,
+    Iso,
+    MacosPkg,
+    /// Held in memory, ex. an empty input or the output of a memory producer.
+    Memory,
+    Oci,
+    Rpm,
+    ScriptInstaller,
+    Tarball,
+    /// An artifact type defined outside of peckish.
+    Custom,
+}
+
+impl std::fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ArtifactKind::Arch => "arch",
+            ArtifactKind::Cab => "cab",
+            ArtifactKind::Deb => "deb",
+            ArtifactKind::Docker => "docker",
+            ArtifactKind::Dockerfile => "dockerfile",
+            ArtifactKind::Ext4 => "ext4",
+            ArtifactKind::File => "file",
+            ArtifactKind::Iso => "iso",
+            ArtifactKind::MacosPkg => "macos_pkg",
+            ArtifactKind::Memory => "memory",
+            ArtifactKind::Oci => "oci",
+            ArtifactKind::Rpm => "rpm",
+            ArtifactKind::ScriptInstaller => "script_installer",
+            ArtifactKind::Tarball => "tarball",
+            ArtifactKind::Custom => "custom",
+        })
+    }
+}
+
+/// Package metadata that every package format has, however it stores it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    pub description: String,
+}
+
+/// What [`Artifact::extract_to_dir`] wrote.
+#[derive(Debug, Default)]
+pub struct ExtractedDir {
+    /// The extracted paths as they're named in the artifact, ex.
+    /// `/usr/bin/app`, starting with `/`, and with parents before their
+    /// children. Anything that was already in the directory isn't included.
+    pub paths: Vec<PathBuf>,
+    /// The owners that couldn't be set, see [`MemFS::write_to_host`].
+    pub unowned: Vec<IntendedOwner>,
+}
+
+impl ExtractedDir {
+    /// Write the memfs onto the host filesystem, under `dir`.
+    pub async fn write_memfs(memfs: &MemFS, dir: &Path) -> Result<Self> {
+        let unowned = memfs.write_to_host(dir).await?;
+        let paths = nyoom::walk_ordered(memfs.fs(), "/").await?;
+
+        Ok(Self { paths, unowned })
+    }
+}
+
+/// One path in an artifact, see [`Artifact::stream_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The absolute path, ex. `/usr/bin/app`.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// The permission bits, ex. `0o755`. Unused for symlinks.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// The mtime in seconds since the epoch, if the artifact has one.
+    pub mtime: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+    /// A hard link to an earlier entry's absolute path. Only formats with
+    /// hard links, like tarballs, stream these.
+    HardLink(PathBuf),
+}
+
+/// Stream the memfs's entries in walk order, reading each one only when it's
+/// asked for.
+pub fn memfs_entries(memfs: MemFS) -> BoxStream<'static, Result<Entry>> {
+    futures_util::stream::once(async move {
+        let paths = nyoom::walk_ordered(memfs.fs(), "/").await?;
+        let entries =
+            futures_util::stream::iter(paths.into_iter().filter(|path| path != Path::new("/")))
+                .then(move |path| {
+                    let memfs = memfs.clone();
+                    async move { memfs_entry(&memfs, path).await }
+                });
+        Ok::<_, eyre::Report>(entries)
+    })
+    .try_flatten()
+    .boxed()
+}
+
+async fn memfs_entry(memfs: &MemFS, path: PathBuf) -> Result<Entry> {
+    let metadata = memfs.symlink_metadata(&path).await?;
+    let kind = if metadata.is_symlink() {
+        EntryKind::Symlink(memfs.read_link(&path).await?)
+    } else if metadata.is_dir() {
+        EntryKind::Directory
+    } else {
+        EntryKind::File(memfs.read(&path).await?)
+    };
+
+    Ok(Entry {
+        mode: metadata.permissions().mode() & 0o7777,
+        uid: metadata.uid()?,
+        gid: metadata.gid()?,
+        mtime: memfs.mtime(&path),
+        path,
+        kind,
+    })
+}
+
+impl std::fmt::Debug for dyn Artifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Artifact")
+            .field("name", &self.name())
+            .finish_non_exhaustive()
+    }
 }
 
 /// An artifact producer takes in the previous artifact and produces a new one.
@@ -46,6 +288,25 @@ pub trait ArtifactProducer: SelfValidation {
     /// Produce a new artifact, given a previous artifact.
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<Self::Output>;
 
+    /// Check whether the contents this producer is about to package make
+    /// sense for it, ex. a deb with files under `/home`. These don't stop the
+    /// artifact from being produced, so they're returned as warnings, which
+    /// the pipeline only fails on in strict mode. Producers that check
+    /// anything run this on their memfs, after injections, with
+    /// [`check_compatibility`].
+    async fn can_produce_from(&self, _memfs: &MemFS) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Produce a new artifact from a previous artifact that the caller owns
+    /// and doesn't need anymore. Producers that would otherwise copy the
+    /// previous artifact's memfs can override this to take it with
+    /// [`Artifact::into_memfs`] instead. By default, this just borrows the
+    /// artifact.
+    async fn produce_from_owned(&self, previous: Box<dyn Artifact>) -> Result<Self::Output> {
+        self.produce_from(previous.as_ref()).await
+    }
+
     /// Inject this producer's custom changes into the memfs.
     async fn inject<'a>(&self, fs: &'a mut MemFS) -> Result<()> {
         for injection in self.injections() {
@@ -57,6 +318,85 @@ pub trait ArtifactProducer: SelfValidation {
     }
 }
 
+tokio::task_local! {
+    /// Whether compatibility warnings fail production in the current task.
+    /// See [`with_strict_compatibility`].
+    static STRICT_COMPATIBILITY: bool;
+}
+
+/// The producer's input got compatibility warnings in strict mode.
+#[derive(Error, Debug)]
+#[error("input is likely wrong for this producer:\n{}", .0.join("\n"))]
+pub struct IncompatibleInput(pub Vec<String>);
+
+/// Run `f`, typically a producer's `produce_from`, failing it with
+/// [`IncompatibleInput`] if `strict` is set and the producer's input gets any
+/// compatibility warnings.
+pub async fn with_strict_compatibility<F: std::future::Future>(strict: bool, f: F) -> F::Output {
+    STRICT_COMPATIBILITY.scope(strict, f).await
+}
+
+/// Warn about anything in the memfs that doesn't make sense for the producer,
+/// see [`ArtifactProducer::can_produce_from`]. This fails only when run with
+/// [`with_strict_compatibility`].
+pub async fn check_compatibility<P: ArtifactProducer + ?Sized>(
+    producer: &P,
+    memfs: &MemFS,
+) -> Result<()> {
+    let warnings = producer.can_produce_from(memfs).await?;
+    for warning in &warnings {
+        warn!("{}: {warning}", producer.name());
+    }
+
+    let strict = STRICT_COMPATIBILITY
+        .try_with(|strict| *strict)
+        .unwrap_or(false);
+    if strict && !warnings.is_empty() {
+        return Err(IncompatibleInput(warnings).into());
+    }
+
+    Ok(())
+}
+
+/// An object-safe [`ArtifactProducer`]. [`ArtifactProducer`] can't be made
+/// into a trait object because of its `Output` type, so this boxes the output
+/// instead. Every [`ArtifactProducer`] is a [`DynArtifactProducer`], so
+/// custom producers only need to implement the former.
+///
+/// The methods are named differently from [`ArtifactProducer`]'s so that
+/// having both traits in scope isn't ambiguous.
+#[async_trait::async_trait]
+pub trait DynArtifactProducer: Send + Sync + SelfValidation {
+    fn producer_name(&self) -> &str;
+
+    /// Produce a new, boxed artifact, given a previous artifact.
+    async fn produce_boxed(&self, previous: &dyn Artifact) -> Result<Box<dyn Artifact>>;
+}
+
+#[async_trait::async_trait]
+impl<T> DynArtifactProducer for T
+where
+    T: ArtifactProducer + Send + Sync,
+    T::Output: 'static,
+{
+    fn producer_name(&self) -> &str {
+        self.name()
+    }
+
+    async fn produce_boxed(&self, previous: &dyn Artifact) -> Result<Box<dyn Artifact>> {
+        let artifact = self.produce_from(previous).await?;
+        Ok(Box::new(artifact))
+    }
+}
+
+impl std::fmt::Debug for dyn DynArtifactProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArtifactProducer")
+            .field("name", &self.producer_name())
+            .finish_non_exhaustive()
+    }
+}
+
 /// Self-validation for structs! Because all structs should feel good about
 /// themselves :D
 ///
@@ -81,6 +421,97 @@ pub async fn get_artifact_size(artifact: &dyn Artifact) -> Result<u64> {
     memfs.size().await
 }
 
+/// The package file an artifact was read from, for producers that carry its
+/// metadata over into the package they produce.
+pub(crate) fn input_package_path(producer: &str, previous: &dyn Artifact) -> Result<PathBuf> {
+    previous
+        .paths()
+        .and_then(|paths| paths.into_iter().next())
+        .ok_or_else(|| {
+            eyre!(
+                "producer '{producer}' inherits its package metadata from its input, but '{}' is not a package file",
+                previous.name()
+            )
+        })
+}
+
+/// The owners in a producer's memfs that were set on purpose, for formats
+/// whose files would otherwise all be owned by root: those of the input's
+/// own paths, when the input records owners, like another package or an
+/// archive, and those set by chown injections. Files read from the host only
+/// have the building user's owner, and files that injections create only
+/// have the memfs's default one, so they're owned by root instead.
+pub(crate) struct KeptOwners {
+    input_paths: HashSet<PathBuf>,
+    chowned: Vec<(PathBuf, bool)>,
+}
+
+impl KeptOwners {
+    /// Read from the input's memfs before the injections are applied to it.
+    pub(crate) async fn new(
+        previous: &dyn Artifact,
+        memfs: &MemFS,
+        injections: &[Injection],
+    ) -> Result<Self> {
+        let input_paths = if previous.kind() == ArtifactKind::File {
+            HashSet::new()
+        } else {
+            nyoom::walk_ordered(memfs.fs(), "/")
+                .await?
+                .into_iter()
+                .collect()
+        };
+        let chowned = injections
+            .iter()
+            .filter_map(|injection| match injection {
+                Injection::Chown {
+                    path, recursive, ..
+                } => Some((Path::new("/").join(path), *recursive)),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Self {
+            input_paths,
+            chowned,
+        })
+    }
+
+    /// The owner to write for `path`, given its owner in the memfs.
+    pub(crate) fn owner(&self, path: &Path, uid: u32, gid: u32) -> (u32, u32) {
+        let chowned = self.chowned.iter().any(|(chowned, recursive)| {
+            path == chowned || (*recursive && path.starts_with(chowned))
+        });
+        if chowned || self.input_paths.contains(path) {
+            (uid, gid)
+        } else {
+            (0, 0)
+        }
+    }
+
+    /// Give every path in the injected memfs the owner it should be written
+    /// with, for producers that write the memfs's owners as they are.
+    pub(crate) async fn apply(&self, memfs: &MemFS) -> Result<()> {
+        for path in nyoom::walk_ordered(memfs.fs(), "/").await? {
+            let metadata = memfs.fs().symlink_metadata(&path).await?;
+            let (uid, gid) = self.owner(&path, metadata.uid()?, metadata.gid()?);
+            memfs.fs().chown(&path, uid, gid).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the artifact's contents add up to more than `threshold` bytes once
+/// extracted. Artifacts that don't know their [`Artifact::extracted_size`]
+/// never exceed the threshold.
+pub async fn exceeds_extracted_size(artifact: &dyn Artifact, threshold: u64) -> Result<bool> {
+    Ok(artifact
+        .extracted_size()
+        .await?
+        .is_some_and(|size| size > threshold))
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
@@ -99,17 +530,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_basic_transform_works() -> Result<()> {
-        let file_artifact = file::FileArtifact {
-            name: "Cargo.toml".into(),
-            paths: vec![PathBuf::from("Cargo.toml")],
-        };
+        let file_artifact = file::FileArtifactBuilder::new("Cargo.toml")
+            .add_path("Cargo.toml")
+            .build()?;
 
-        let tarball_producer = tarball::TarballProducer {
-            name: "test-tarball-producer".into(),
-            path: "test.tar.gz".into(),
-            compression: CompressionType::Gzip,
-            injections: vec![],
-        };
+        let tarball_producer = tarball::TarballProducerBuilder::new("test-tarball-producer")
+            .path("test.tar.gz")
+            .compression(CompressionType::Gzip)
+            .build()?;
 
         let tarball_artifact = tarball_producer.produce_from(&file_artifact).await?;
 
@@ -117,12 +545,9 @@ mod tests {
         let tarball_path = PathBuf::from(tarball_artifact.name());
         assert!(tarball_path.exists());
 
-        let file_producer = file::FileProducer {
-            name: "test-file-producer".into(),
-            path: "test".into(),
-            injections: vec![],
-            preserve_empty_directories: None,
-        };
+        let file_producer = file::FileProducerBuilder::new("test-file-producer")
+            .path("test")
+            .build()?;
 
         let file_artifact = file_producer.produce_from(&tarball_artifact).await?;
 
@@ -140,4 +565,217 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_packages_report_package_info_uniformly() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let input = memory::EmptyArtifact::new("empty");
+
+        let deb = deb::DebProducerBuilder::new("deb")
+            .path(tmp.path_view().join("info.deb"))
+            .package_name("info")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("package info")
+            .build()?
+            .produce_from(&input)
+            .await?;
+        let arch = arch::ArchProducerBuilder::new("arch")
+            .path(tmp.path_view().join("info.pkg.tar"))
+            .package_name("info")
+            .package_author("peckish <peckish@example.com>")
+            .package_arch("x86_64")
+            .package_ver("0.1.0-1")
+            .package_desc("package info")
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        for (artifact, arch) in [
+            (&deb as &dyn Artifact, "amd64"),
+            (&arch as &dyn Artifact, "x86_64"),
+        ] {
+            assert_eq!(
+                artifact.package_info().await?,
+                Some(PackageInfo {
+                    name: "info".into(),
+                    version: "0.1.0-1".into(),
+                    arch: arch.into(),
+                    description: "package info".into(),
+                })
+            );
+        }
+
+        let tarball = tarball::TarballArtifact {
+            name: "tarball".into(),
+            path: tmp.path_view().join("info.pkg.tar"),
+        };
+        assert_eq!(tarball.package_info().await?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifacts_report_their_kind() -> Result<()> {
+        let path = PathBuf::from("artifact");
+        let artifacts: Vec<(Box<dyn Artifact>, ArtifactKind)> = vec![
+            (
+                Box::new(arch::ArchArtifact {
+                    name: "arch".into(),
+                    path: path.clone(),
+                    pkginfo: None,
+                    signature: None,
+                }),
+                ArtifactKind::Arch,
+            ),
+            (
+                Box::new(cab::CabArtifact {
+                    name: "cab".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::Cab,
+            ),
+            (
+                Box::new(deb::DebArtifact {
+                    name: "deb".into(),
+                    path: path.clone(),
+                    control: None,
+                    postinst: None,
+                    prerm: None,
+                    debug_package: None,
+                }),
+                ArtifactKind::Deb,
+            ),
+            (
+                Box::new(docker::DockerArtifact {
+                    name: "docker".into(),
+                    image: "peckish:latest".into(),
+                    platform: None,
+                    pull_policy: docker::PullPolicy::Never,
+                    include_paths: vec![],
+                    exclude_paths: vec![],
+                }),
+                ArtifactKind::Docker,
+            ),
+            (
+                Box::new(dockerfile::DockerfileArtifact {
+                    name: "dockerfile".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::Dockerfile,
+            ),
+            (
+                Box::new(ext4::Ext4Artifact {
+                    name: "ext4".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::Ext4,
+            ),
+            (
+                Box::new(
+                    file::FileArtifactBuilder::new("file")
+                        .add_path(&path)
+                        .build()?,
+                ),
+                ArtifactKind::File,
+            ),
+            (
+                Box::new(iso::IsoArtifact {
+                    name: "iso".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::Iso,
+            ),
+            (
+                Box::new(macos::MacosPkgArtifact {
+                    name: "macos_pkg".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::MacosPkg,
+            ),
+            (
+                Box::new(memory::EmptyArtifact::new("memory")),
+                ArtifactKind::Memory,
+            ),
+            (
+                Box::new(oci::OciArtifact {
+                    name: "oci".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::Oci,
+            ),
+            (
+                Box::new(rpm::RpmArtifact {
+                    name: "rpm".into(),
+                    path: path.clone(),
+                    spec: None,
+                    debug_package: None,
+                }),
+                ArtifactKind::Rpm,
+            ),
+            (
+                Box::new(script::ScriptInstallerArtifact {
+                    name: "script_installer".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::ScriptInstaller,
+            ),
+            (
+                Box::new(tarball::TarballArtifact {
+                    name: "tarball".into(),
+                    path: path.clone(),
+                }),
+                ArtifactKind::Tarball,
+            ),
+            (
+                Box::new(crate::fs::test_utils::CountingArtifact::default()),
+                ArtifactKind::Custom,
+            ),
+        ];
+
+        for (artifact, kind) in artifacts {
+            assert_eq!(kind, artifact.kind(), "{}", artifact.name());
+            // Caching an artifact doesn't hide what it is.
+            let cached = memory::CachedArtifact::new(artifact);
+            assert_eq!(kind, cached.kind());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_default_stream_entries_walks_the_memfs() -> Result<()> {
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/usr/bin").await?;
+        (*memfs).write("/usr/bin/app", b"app").await?;
+        (*memfs).chown("/usr/bin/app", 1000, 1000).await?;
+        (*memfs).symlink("app", "/usr/bin/alias").await?;
+        memfs.set_mtime("/usr/bin/app", 1_700_000_000);
+        let artifact = memory::MemoryArtifact {
+            name: "memory".into(),
+            fs: memfs,
+        };
+
+        let entries: Vec<Entry> = artifact.stream_entries().await?.try_collect().await?;
+        let entry = |path: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.path == Path::new(path))
+                .unwrap()
+        };
+        assert_eq!(4, entries.len());
+        assert_eq!(EntryKind::Directory, entry("/usr/bin").kind);
+        assert_eq!(
+            EntryKind::Symlink("app".into()),
+            entry("/usr/bin/alias").kind
+        );
+
+        let app = entry("/usr/bin/app");
+        assert_eq!(EntryKind::File(b"app".to_vec()), app.kind);
+        assert_eq!((1000, 1000), (app.uid, app.gid));
+        assert_eq!(Some(1_700_000_000), app.mtime);
+
+        Ok(())
+    }
 }
@@ -1,15 +1,15 @@
 use std::path::PathBuf;
 
-use crate::artifact::tarball::{TarballArtifact, TarballProducerBuilder};
+use crate::artifact::tarball::{open_new_tarball, TarballArtifact, TarballProducerBuilder};
 use crate::artifact::SelfBuilder;
-use crate::fs::{MemFS, TempDir};
+use crate::fs::{MemFS, OwnerRemap, TempDir};
 use crate::util::config::Injection;
 
-use super::{Artifact, ArtifactProducer, SelfValidation};
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfValidation};
 
 use disk_drive::DiskDrive;
 use eyre::Result;
-use flop::tar::{TarFloppyDisk, TarOpenOptions};
+use flop::tar::TarOpenOptions;
 use floppy_disk::mem::MemOpenOptions;
 use floppy_disk::tokio_fs::TokioFloppyDisk;
 use floppy_disk::{FloppyDisk, FloppyOpenOptions};
@@ -17,6 +17,7 @@ use oci_spec::image::{
     Arch, ConfigBuilder, DescriptorBuilder, ImageIndex, ImageIndexBuilder, ImageManifest,
     ImageManifestBuilder, MediaType, Os, PlatformBuilder,
 };
+use serde::Serialize;
 use smoosh::CompressionType;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
@@ -44,6 +45,10 @@ impl Artifact for OciArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Oci
+    }
+
     async fn extract(&self) -> Result<MemFS> {
         // Extract the tarball into memory
         info!("extracting oci image...");
@@ -92,11 +97,14 @@ impl Artifact for OciArtifact {
                         )
                         .await?;
 
-                        let tar_disk =
-                            TarFloppyDisk::open(layer_tmp_dir.path_view().join("layer.tar"))
-                                .await?;
+                        let layer_fs = TarballArtifact {
+                            name: blob_to_path(layer.digest()),
+                            path: layer_tmp_dir.path_view().join("layer.tar"),
+                        }
+                        .extract()
+                        .await?;
 
-                        DiskDrive::copy_between(&tar_disk, &*fs).await?;
+                        fs.apply_layer(layer_fs).await?;
                     }
                     _ => {}
                 }
@@ -139,11 +147,18 @@ impl SelfValidation for OciArtifact {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct OciProducer {
     pub name: String,
     pub path: PathBuf,
     pub architecture: String,
+    /// Give every file in the layer this owner, after the injections.
+    pub remap_owner: Option<OwnerRemap>,
+    /// Give every entry in the layer the same mtime, so that the same files
+    /// always make the same layer, and so the same image digest, no matter
+    /// when they were written. The mtime is `SOURCE_DATE_EPOCH`, or 0 if it
+    /// isn't set.
+    pub reproducible: bool,
     pub injections: Vec<Injection>,
 }
 
@@ -171,14 +186,26 @@ impl ArtifactProducer for OciProducer {
             debug!("applying injection {injection:?}");
             blob = blob.inject(injection.clone());
         }
+        if let Some(remap) = self.remap_owner {
+            blob = blob.remap_owner(remap);
+        }
+        // Entries are always written in path order, and gzip headers with a
+        // zero mtime, so the entry mtimes are all that's left to pin.
+        if self.reproducible {
+            let mtime = crate::util::source_date_epoch()?.unwrap_or(0);
+            blob = blob.file_mtime(mtime).dir_mtime(mtime);
+        }
 
         let blob = blob.build()?.produce_from(previous).await?;
         let blob_sha256 = crate::util::sha256_digest(&blob.path).await?;
         let blob_path = blob_to_path(format!("sha256:{blob_sha256}"));
+        // The descriptor describes the blob as it's stored, so this is the
+        // compressed size, not the size of the layer's contents.
+        let blob_size = tokio::fs::metadata(&blob.path).await?.len();
 
         // Produce layer blob descriptor
         let layer_descriptor = DescriptorBuilder::default()
-            .size(previous.extract().await?.size().await? as i64)
+            .size(blob_size as i64)
             .platform(
                 PlatformBuilder::default()
                     .architecture(match self.architecture.as_str() {
@@ -191,7 +218,7 @@ impl ArtifactProducer for OciProducer {
                     .expect("build amd64 platform"),
             )
             .digest(format!("sha256:{blob_sha256}"))
-            .media_type(MediaType::ImageLayerZstd)
+            .media_type(MediaType::ImageLayerGzip)
             .build()
             .expect("failed building layer descriptor");
 
@@ -237,7 +264,7 @@ impl ArtifactProducer for OciProducer {
 
         // Create tarball
 
-        let oci_tar = TarFloppyDisk::open(self.path.clone()).await?;
+        let oci_tar = open_new_tarball(&self.path).await?;
         oci_tar.create_dir_all("/blobs/sha256").await?;
 
         let mut layer_handle = TarOpenOptions::new()
@@ -321,7 +348,7 @@ mod tests {
     use eyre::Result;
     use floppy_disk::{FloppyFile, FloppyMetadata};
 
-    use crate::fs::test_utils::Fixture;
+    use crate::fs::test_utils::{CountingArtifact, Fixture};
 
     use super::*;
 
@@ -345,6 +372,41 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_flattened_image_tarball_is_reproducible() -> Result<()> {
+        let oci_tarball = Fixture::new("oci.tar").await;
+        let oci_artifact = OciArtifact {
+            name: "test".into(),
+            path: oci_tarball.path_view(),
+        };
+
+        let tmp_dir = TempDir::new().await?;
+        let mut outputs = vec![];
+        for name in ["first.tar.gz", "second.tar.gz"] {
+            let path = tmp_dir.path_view().join(name);
+            TarballProducerBuilder::new(name)
+                .path(&path)
+                .compression(CompressionType::Gzip)
+                .build()?
+                .produce_from(&oci_artifact)
+                .await?;
+            outputs.push(tokio::fs::read(&path).await?);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+
+        let flattened = TarballArtifact {
+            name: "flattened".into(),
+            path: tmp_dir.path_view().join("first.tar.gz"),
+        }
+        .extract()
+        .await?;
+        for path in nyoom::walk_ordered(flattened.fs(), "/").await? {
+            assert!(!path.to_string_lossy().contains(".wh."), "{path:?}");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_oci_producer() -> Result<()> {
         let oci_tarball = Fixture::new("oci.tar").await;
@@ -358,6 +420,7 @@ mod tests {
             name: "test".into(),
             path: tmp_dir.path_view().join("oci.tar"),
             architecture: "amd64".into(),
+            remap_owner: None,
             injections: vec![],
         };
 
@@ -375,4 +438,183 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_layer_descriptor_matches_stored_blob() -> Result<()> {
+        let oci_tarball = Fixture::new("oci.tar").await;
+        let tmp_dir = TempDir::new().await?;
+        let produced = OciProducer {
+            name: "test".into(),
+            path: tmp_dir.path_view().join("oci.tar"),
+            architecture: "amd64".into(),
+            remap_owner: None,
+            injections: vec![],
+        }
+        .produce_from(&OciArtifact {
+            name: "test".into(),
+            path: oci_tarball.path_view(),
+        })
+        .await?;
+
+        let oci_tar = TarballArtifact {
+            name: "oci".into(),
+            path: produced.path,
+        }
+        .extract()
+        .await?;
+        let index = ImageIndex::from_reader(
+            MemOpenOptions::new()
+                .read(true)
+                .open(oci_tar.fs(), "/index.json")
+                .await?,
+        )?;
+        let manifest = ImageManifest::from_reader(
+            MemOpenOptions::new()
+                .read(true)
+                .open(oci_tar.fs(), blob_to_path(index.manifests()[0].digest()))
+                .await?,
+        )?;
+
+        let layer = &manifest.layers()[0];
+        let blob = oci_tar.fs().read(blob_to_path(layer.digest())).await?;
+        assert_eq!(blob.len() as i64, layer.size());
+        assert_eq!(&MediaType::ImageLayerGzip, layer.media_type());
+        // Gzip's magic bytes.
+        assert_eq!(&[0x1f, 0x8b], &blob[..2]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_input_is_extracted_once() -> Result<()> {
+        let counting = CountingArtifact::default();
+        let tmp_dir = TempDir::new().await?;
+        let produced = OciProducer {
+            name: "test".into(),
+            path: tmp_dir.path_view().join("oci.tar"),
+            architecture: "amd64".into(),
+            remap_owner: None,
+            injections: vec![],
+        }
+        .produce_from(&counting)
+        .await?;
+
+        assert_eq!(1, counting.extracts());
+        assert_eq!(
+            b"app".to_vec(),
+            produced.extract().await?.fs().read("/usr/bin/app").await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remapped_owner_is_group_writable() -> Result<()> {
+        use floppy_disk::mem::MemPermissions;
+        use floppy_disk::prelude::*;
+
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/app").await?;
+        memfs.fs().write("/app/run", b"#!/bin/sh\n").await?;
+        memfs
+            .fs()
+            .set_permissions("/app/run", MemPermissions::from_mode(0o100755))
+            .await?;
+
+        let tmp_dir = TempDir::new().await?;
+        let produced = OciProducer {
+            name: "test".into(),
+            path: tmp_dir.path_view().join("oci.tar"),
+            architecture: "amd64".into(),
+            remap_owner: Some(OwnerRemap {
+                uid: 1000,
+                gid: 0,
+                group_writable: true,
+            }),
+            reproducible: false,
+            injections: vec![],
+        }
+        .produce_from(&crate::artifact::memory::MemoryArtifact {
+            name: "memory".into(),
+            fs: memfs,
+        })
+        .await?;
+
+        let extracted = produced.extract().await?;
+        for path in ["/app", "/app/run"] {
+            let metadata = extracted.fs().metadata(path).await?;
+            assert_eq!((1000, 0), (metadata.uid()?, metadata.gid()?), "{path}");
+            let mode = metadata.permissions().mode();
+            assert_eq!(mode & 0o700, (mode & 0o070) << 3, "{path} is {mode:o}");
+        }
+        assert_eq!(
+            0o775,
+            extracted
+                .fs()
+                .metadata("/app/run")
+                .await?
+                .permissions()
+                .mode()
+                & 0o777
+        );
+
+        Ok(())
+    }
+
+    /// The digest of the image manifest in a produced OCI tarball.
+    async fn manifest_digest(path: PathBuf) -> Result<String> {
+        let oci_tar = TarballArtifact {
+            name: "oci".into(),
+            path,
+        }
+        .extract()
+        .await?;
+        let index = ImageIndex::from_reader(
+            MemOpenOptions::new()
+                .read(true)
+                .open(oci_tar.fs(), "/index.json")
+                .await?,
+        )?;
+        Ok(index.manifests()[0].digest().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_reproducible_image_digest_is_stable() -> Result<()> {
+        let tmp_dir = TempDir::new().await?;
+        let produce = |name: &str, mtime: u64, reproducible: bool| {
+            let path = tmp_dir.path_view().join(name);
+            async move {
+                let memfs = MemFS::new();
+                memfs.fs().create_dir_all("/usr/bin").await?;
+                memfs.fs().write("/usr/bin/app", b"app").await?;
+                memfs.set_mtime("/usr/bin/app", mtime);
+                OciProducer {
+                    name: "test".into(),
+                    path: path.clone(),
+                    architecture: "amd64".into(),
+                    remap_owner: None,
+                    reproducible,
+                    injections: vec![],
+                }
+                .produce_from(&crate::artifact::memory::MemoryArtifact {
+                    name: "memory".into(),
+                    fs: memfs,
+                })
+                .await?;
+                manifest_digest(path).await
+            }
+        };
+
+        // The same files, written at different times.
+        assert_eq!(
+            produce("first.tar", 1_600_000_000, true).await?,
+            produce("second.tar", 1_700_000_000, true).await?
+        );
+        assert_ne!(
+            produce("third.tar", 1_600_000_000, false).await?,
+            produce("fourth.tar", 1_700_000_000, false).await?
+        );
+
+        Ok(())
+    }
 }
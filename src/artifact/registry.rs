@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use eyre::{eyre, Result};
+
+use super::{Artifact, DynArtifactProducer};
+
+type ArtifactFactory =
+    Box<dyn Fn(&str, serde_yaml::Value) -> Result<Box<dyn Artifact>> + Send + Sync>;
+type ProducerFactory =
+    Box<dyn Fn(&str, serde_yaml::Value) -> Result<Box<dyn DynArtifactProducer>> + Send + Sync>;
+
+/// A registry of custom artifact and producer types, for formats that peckish
+/// doesn't know about. Custom types are referenced from the config with
+/// `type: "plugin"` and `plugin: "<name>"`:
+///
+/// ```yaml
+/// output:
+///   - name: "my custom output"
+///     type: "plugin"
+///     plugin: "my-format"
+///     # any other keys are passed to the factory
+///     path: "./out.custom"
+/// ```
+///
+/// Each factory is called with the artifact or producer's name, and the rest
+/// of its config as a YAML mapping.
+#[derive(Default)]
+pub struct Registry {
+    artifacts: HashMap<String, ArtifactFactory>,
+    producers: HashMap<String, ProducerFactory>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a factory for a custom input artifact type.
+    pub fn register_artifact<S, F>(mut self, plugin: S, factory: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&str, serde_yaml::Value) -> Result<Box<dyn Artifact>> + Send + Sync + 'static,
+    {
+        self.artifacts.insert(plugin.into(), Box::new(factory));
+        self
+    }
+
+    /// Register a factory for a custom producer type.
+    pub fn register_producer<S, F>(mut self, plugin: S, factory: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&str, serde_yaml::Value) -> Result<Box<dyn DynArtifactProducer>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.producers.insert(plugin.into(), Box::new(factory));
+        self
+    }
+
+    pub fn artifact(
+        &self,
+        plugin: &str,
+        name: &str,
+        options: serde_yaml::Value,
+    ) -> Result<Arc<dyn Artifact>> {
+        let factory = self
+            .artifacts
+            .get(plugin)
+            .ok_or_else(|| eyre!("no artifact plugin registered with name '{plugin}'"))?;
+
+        factory(name, options).map(Arc::from)
+    }
+
+    pub fn producer(
+        &self,
+        plugin: &str,
+        name: &str,
+        options: serde_yaml::Value,
+    ) -> Result<Arc<dyn DynArtifactProducer>> {
+        let factory = self
+            .producers
+            .get(plugin)
+            .ok_or_else(|| eyre!("no producer plugin registered with name '{plugin}'"))?;
+
+        factory(name, options).map(Arc::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use eyre::Result;
+    use smoosh::CompressionType;
+
+    use crate::artifact::tarball::{EntryNames, TarballProducer};
+    use crate::fs::TempDir;
+    use crate::pipeline::Pipeline;
+    use crate::util::config::{ConfiguredProducer, PeckishConfig};
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    fn config_with_plugin(plugin: &str, path: &std::path::Path) -> String {
+        format!(
+            r#"
+metadata:
+  name: "test"
+  version: "0.0.1-1"
+  description: "test"
+  author: "me"
+  arch: "amd64"
+  license: "Apache-2.0"
+
+input:
+  name: "cargo dot toml"
+  type: "file"
+  paths:
+    - "./Cargo.toml"
+
+output:
+  - name: "custom"
+    type: "plugin"
+    plugin: "{plugin}"
+    path: "{}"
+"#,
+            path.display()
+        )
+    }
+
+    fn registry() -> Registry {
+        Registry::new().register_producer("plain-tar", |name, options| {
+            let path: PathBuf = serde_yaml::from_value(options["path"].clone())?;
+            Ok(Box::new(TarballProducer {
+                name: name.into(),
+                path,
+                compression: CompressionType::None,
+                sparse: false,
+                entry_names: EntryNames::Relative,
+                file_mtime: None,
+                dir_mtime: None,
+                rsyncable: false,
+                remap_owner: None,
+                split_size: None,
+                injections: vec![],
+            }))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_plugin_producer_works() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("plugin.tar");
+
+        let config = PeckishConfig::parse_with_registry(
+            &config_with_plugin("plain-tar", &tar),
+            &registry(),
+        )?;
+        assert!(matches!(config.output[0], ConfiguredProducer::Custom(_)));
+        assert_eq!(config.output[0].name(), "custom");
+
+        let artifacts = Pipeline::new(None).run(config).await?;
+        assert_eq!(artifacts.len(), 1);
+        assert!(tar.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_plugin_fails() {
+        let config = config_with_plugin("nope", std::path::Path::new("out.tar"));
+        assert!(PeckishConfig::parse_with_registry(&config, &registry()).is_err());
+        assert!(PeckishConfig::parse(&config).is_err());
+    }
+}
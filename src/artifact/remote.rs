@@ -0,0 +1,203 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use eyre::{eyre, Result};
+use tokio::sync::OnceCell;
+use tracing::*;
+
+use crate::fs::{MemFS, TempDir};
+
+use super::arch::ArchArtifact;
+use super::deb::DebArtifact;
+use super::ext4::Ext4Artifact;
+use super::oci::OciArtifact;
+use super::rpm::RpmArtifact;
+use super::tarball::TarballArtifact;
+use super::{Artifact, ArtifactKind, SelfValidation};
+
+/// An artifact that's downloaded over HTTP(S) before it's read, ex. a release
+/// tarball. It's only downloaded once, the first time it's extracted, into a
+/// temp dir that lives as long as the artifact and its clones do.
+#[derive(Clone)]
+pub struct RemoteArtifact {
+    pub name: String,
+    pub url: String,
+    pub sha256: Option<String>,
+    /// What the downloaded file is read as. Only formats that are a single
+    /// file on disk are supported.
+    pub format: ArtifactKind,
+    download: Arc<OnceCell<Download>>,
+}
+
+struct Download {
+    artifact: Box<dyn Artifact>,
+    _dir: TempDir,
+}
+
+impl RemoteArtifact {
+    pub fn new<S: Into<String>>(
+        name: S,
+        url: S,
+        sha256: Option<String>,
+        format: ArtifactKind,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            url: url.into(),
+            sha256,
+            format,
+            download: Arc::new(OnceCell::new()),
+        }
+    }
+
+    fn open(&self, path: PathBuf) -> Result<Box<dyn Artifact>> {
+        let name = self.name.clone();
+        Ok(match self.format {
+            ArtifactKind::Tarball => Box::new(TarballArtifact { name, path }),
+            ArtifactKind::Arch => Box::new(ArchArtifact {
+                name,
+                path,
+                pkginfo: None,
+                signature: None,
+            }),
+            ArtifactKind::Deb => Box::new(DebArtifact {
+                name,
+                path,
+                control: None,
+                postinst: None,
+                prerm: None,
+                debug_package: None,
+            }),
+            ArtifactKind::Rpm => Box::new(RpmArtifact {
+                name,
+                path,
+                spec: None,
+                debug_package: None,
+            }),
+            ArtifactKind::Ext4 => Box::new(Ext4Artifact { name, path }),
+            ArtifactKind::Oci => Box::new(OciArtifact { name, path }),
+            format => return Err(eyre!("{format} artifacts can't be downloaded")),
+        })
+    }
+
+    async fn downloaded(&self) -> Result<&dyn Artifact> {
+        let download = self
+            .download
+            .get_or_try_init(|| async {
+                info!("downloading {}", self.url);
+                // Keep the url's file name, since some formats care about
+                // the extension.
+                let file_name = self
+                    .url
+                    .rsplit('/')
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("download");
+                let dir = TempDir::new().await?;
+                let path = dir.path_view().join(file_name);
+                crate::util::download_to_file(&self.url, self.sha256.as_deref(), &path).await?;
+
+                let artifact = self.open(path)?;
+                artifact.validate().await?;
+                Ok::<_, eyre::Report>(Download {
+                    artifact,
+                    _dir: dir,
+                })
+            })
+            .await?;
+
+        Ok(download.artifact.as_ref())
+    }
+}
+
+impl std::fmt::Debug for RemoteArtifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteArtifact")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("sha256", &self.sha256)
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl Artifact for RemoteArtifact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        self.format
+    }
+
+    async fn extract(&self) -> Result<MemFS> {
+        self.downloaded().await?.extract().await
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    /// The downloaded file, once it's been downloaded.
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        self.download.get().and_then(|d| d.artifact.paths())
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for RemoteArtifact {
+    async fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            errors.push(format!("{} is not an http:// or https:// url", self.url));
+        }
+
+        if let Err(e) = self.open(PathBuf::new()) {
+            errors.push(e.to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!("Remote artifact is invalid:\n{}", errors.join("\n")));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_http_and_https_urls_are_valid() -> Result<()> {
+        for url in [
+            "http://example.com/release.tar",
+            "https://example.com/release.tar",
+        ] {
+            RemoteArtifact::new("release", url, None, ArtifactKind::Tarball)
+                .validate()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_other_urls_are_invalid() {
+        for url in ["ftp://example.com/release.tar", "example.com/release.tar"] {
+            assert!(
+                RemoteArtifact::new("release", url, None, ArtifactKind::Tarball)
+                    .validate()
+                    .await
+                    .is_err()
+            );
+        }
+    }
+}
@@ -1,27 +1,37 @@
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use disk_drive::DiskDrive;
 use eyre::{eyre, Result};
 use flop::cpio::CpioFloppyDisk;
 
+use floppy_disk::prelude::*;
 use floppy_disk::tokio_fs::{TokioFloppyDisk, TokioOpenOptions};
-use floppy_disk::{FloppyDisk, FloppyOpenOptions};
 use regex::Regex;
-use smoosh::CompressionType;
+use serde::Serialize;
 use tracing::*;
 
 use crate::artifact::Artifact;
 use crate::fs::{MemFS, TempDir};
+use crate::util::changelog::{rpm_entry, ChangelogEntry};
 use crate::util::config::Injection;
+use crate::util::debuginfo::split_debug_info;
+use crate::util::glob::{any_match, Glob};
+use crate::util::{decompress, get_current_time};
 
-use super::file::FileProducer;
-use super::{ArtifactProducer, SelfBuilder, SelfValidation};
+use super::memory::MemoryArtifact;
+use super::{
+    input_package_path, ArtifactKind, ArtifactProducer, KeptOwners, PackageInfo, SelfBuilder,
+    SelfValidation,
+};
 
 #[derive(Debug, Clone)]
 pub struct RpmArtifact {
     pub name: String,
     pub path: PathBuf,
     pub spec: Option<String>,
+    /// The companion `-debuginfo` package, if debug symbols were split out.
+    pub debug_package: Option<PathBuf>,
 }
 
 #[async_trait::async_trait]
@@ -30,6 +40,10 @@ impl Artifact for RpmArtifact {
         &self.name
     }
 
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Rpm
+    }
+
     async fn extract(&self) -> Result<MemFS> {
         use tokio::io::AsyncReadExt;
 
@@ -57,12 +71,7 @@ impl Artifact for RpmArtifact {
             .open(&host, "/rpm.cpio")
             .await?;
 
-        smoosh::recompress(
-            &mut pkg.content.as_slice(),
-            &mut host_cpio,
-            CompressionType::None,
-        )
-        .await?;
+        decompress(&mut pkg.content.as_slice(), &mut host_cpio).await?;
 
         let cpio = CpioFloppyDisk::open(tmp.path_view().join("rpm.cpio")).await?;
         DiskDrive::copy_between(&cpio, &*fs).await?;
@@ -77,11 +86,28 @@ impl Artifact for RpmArtifact {
             name: self.name.clone(),
             path: self.path.clone(),
             spec: None,
+            debug_package: self.debug_package.clone(),
         }))
     }
 
     fn paths(&self) -> Option<Vec<PathBuf>> {
-        Some(vec![self.path.clone()])
+        let mut paths = vec![self.path.clone()];
+        if let Some(debug_package) = &self.debug_package {
+            paths.push(debug_package.clone());
+        }
+        Some(paths)
+    }
+
+    /// Read from the rpm's header, which is small, so this doesn't read the
+    /// whole package.
+    async fn package_info(&self) -> Result<Option<PackageInfo>> {
+        let metadata = rpm::PackageMetadata::open(&self.path)?;
+        Ok(Some(PackageInfo {
+            name: metadata.get_name()?.to_string(),
+            version: format!("{}-{}", metadata.get_version()?, metadata.get_release()?),
+            arch: metadata.get_arch()?.to_string(),
+            description: metadata.get_description()?.to_string(),
+        }))
     }
 }
 
@@ -134,21 +160,121 @@ impl SelfBuilder for RpmArtifactBuilder {
             name: self.name.clone(),
             path: self.path.clone(),
             spec: None,
+            debug_package: None,
         })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RpmProducer {
     pub name: String,
     pub path: PathBuf,
     pub package_name: String,
     pub package_version: String,
+    /// The RPM `Release`, ex. `1.el9`. This is separate from the version,
+    /// unlike the `-N` suffix used by other formats.
+    pub package_release: String,
     pub package_license: String,
     pub package_arch: String,
     pub package_description: String,
+    /// Package names, optionally with a version constraint, ex.
+    /// `glibc >= 2.34`.
     pub dependencies: Vec<String>,
     pub injections: Vec<Injection>,
+    /// Split debug info out of ELF binaries into a companion `-debuginfo`
+    /// package.
+    pub split_debug: bool,
+    /// Written to the package's `%changelog`.
+    pub changelog: Option<Vec<ChangelogEntry>>,
+    /// Take the name, version, release, license, arch, description, and
+    /// dependencies from the input rpm's header instead of the `package_*`
+    /// fields.
+    pub inherit_metadata: bool,
+    /// Paths marked `%config`, as globs, ex. `/etc/**`. If the input is an
+    /// rpm, its file flags are kept as well.
+    pub config_files: Vec<String>,
+    /// Paths marked `%doc`, as globs.
+    pub doc_files: Vec<String>,
+    /// Paths marked `%ghost`, as globs. Ghost files are owned by the package
+    /// but not installed from it.
+    pub ghost_files: Vec<String>,
+}
+
+impl RpmProducer {
+    /// Split a `version-release` string, like the ones the config uses, into
+    /// its version and release. Versions without a release get release `1`.
+    pub(crate) fn split_version(version: &str) -> (String, String) {
+        match version.rsplit_once('-') {
+            Some((version, release)) => (version.into(), release.into()),
+            None => (version.into(), "1".into()),
+        }
+    }
+
+    /// `foo.rpm` -> `foo-debuginfo.rpm`
+    pub(crate) fn debug_package_path(&self) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.package_name.clone());
+        self.path.with_file_name(format!("{stem}-debuginfo.rpm"))
+    }
+
+    /// A copy of this producer with the input rpm's metadata.
+    fn inherited(&self, previous: &dyn Artifact) -> Result<RpmProducer> {
+        let path = input_package_path(&self.name, previous)?;
+        let metadata = rpm::PackageMetadata::open(&path)
+            .map_err(|e| eyre!("cannot inherit metadata from {}: {e}", path.display()))?;
+        let field = |value: Result<&str, rpm::Error>| {
+            value
+                .map(|v| v.to_string())
+                .map_err(|e| eyre!("cannot inherit metadata from {}: {e}", path.display()))
+        };
+
+        Ok(RpmProducer {
+            package_name: field(metadata.get_name())?,
+            package_version: field(metadata.get_version())?,
+            package_release: field(metadata.get_release())?,
+            package_license: field(metadata.get_license())?,
+            package_arch: field(metadata.get_arch())?,
+            package_description: field(metadata.get_description())?,
+            // rpmlib() requirements are added by the builder itself.
+            dependencies: metadata
+                .get_requires()
+                .unwrap_or_default()
+                .iter()
+                .filter(|dep| !dep.name.starts_with("rpmlib("))
+                .map(dependency_to_string)
+                .collect(),
+            inherit_metadata: false,
+            ..self.clone()
+        })
+    }
+
+    async fn produce_debug_package(&self, debug_fs: MemFS) -> Result<RpmArtifact> {
+        info!("packaging debug symbols...");
+        let producer = RpmProducer {
+            name: format!("{} (debug symbols)", self.name),
+            path: self.debug_package_path(),
+            package_name: format!("{}-debuginfo", self.package_name),
+            package_description: format!("debug symbols for {}", self.package_name),
+            dependencies: vec![],
+            injections: vec![],
+            split_debug: false,
+            inherit_metadata: false,
+            config_files: vec![],
+            doc_files: vec![],
+            ghost_files: vec![],
+            ..self.clone()
+        };
+
+        producer
+            .produce_from(&MemoryArtifact {
+                name: producer.name.clone(),
+                fs: debug_fs,
+            })
+            .await
+    }
 }
 
 #[async_trait::async_trait]
@@ -164,20 +290,37 @@ impl ArtifactProducer for RpmProducer {
     }
 
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<Self::Output> {
+        if self.inherit_metadata {
+            return self.inherited(previous)?.produce_from(previous).await;
+        }
+
         info!("producing {}", self.path.display());
         debug!("extracting previous artifact to tmpdir");
         let tmp = TempDir::new().await?;
-        FileProducer {
-            name: self.name.clone(),
-            path: tmp.path_view(),
-            preserve_empty_directories: None,
-            injections: self.injections.clone(),
-        }
-        .produce_from(previous)
-        .await?;
+        let mut memfs = previous.extract().await?;
+        let owners = KeptOwners::new(previous, &memfs, &self.injections).await?;
+        self.inject(&mut memfs).await?;
+
+        let debug_fs = if self.split_debug {
+            info!("splitting debug symbols...");
+            split_debug_info(&memfs).await?
+        } else {
+            None
+        };
+
+        // Owners are read back from the memfs rather than the host, so that
+        // packages with root-owned files can be built without root.
+        memfs.write_contents_to_host(&tmp.path_view()).await?;
+        let users = memfs.fs().read_to_string("/etc/passwd").await.ok();
+        let groups = memfs.fs().read_to_string("/etc/group").await.ok();
         debug!("reading host files...");
         let host_dir = TokioFloppyDisk::new(Some(tmp.path_view()));
         let file_paths = nyoom::walk_ordered(&host_dir, "/").await?;
+        let input_flags = input_file_flags(previous)?;
+        // A symlink's content in the payload is its target, like in any
+        // cpio archive, so the builder reads it from a file of its own.
+        let link_dir = TempDir::new().await?;
+        let link_target = link_dir.path_view().join("target");
 
         debug!("building rpm from tmpdir {}", tmp.display());
         let mut pkg = rpm::PackageBuilder::new(
@@ -187,28 +330,69 @@ impl ArtifactProducer for RpmProducer {
             &self.package_arch,
             &self.package_description,
         )
-        .compression(rpm::CompressionType::None);
+        .release(&self.package_release)
+        .compression(rpm::CompressionType::None)
+        // Clamps the build time and file mtimes, so that rpms built with
+        // `SOURCE_DATE_EPOCH` are reproducible. The builder already sorts
+        // files by path.
+        .source_date(get_current_time()? as u32);
 
         for path in &file_paths {
             let rpm_path = Path::join(Path::new("/"), path.strip_prefix(tmp.path_view())?);
-            if path.is_dir() {
+            let metadata = memfs.fs().symlink_metadata(&rpm_path).await?;
+            if metadata.is_dir() {
                 debug!("skipping directory {}", path.display());
                 continue;
             }
             debug!("writing path to rpm: {}", rpm_path.display());
 
+            let (uid, gid) = owners.owner(&rpm_path, metadata.uid()?, metadata.gid()?);
+            let mut options = rpm::FileOptions::new(rpm_path.to_string_lossy().to_string())
+                .user(id_name(users.as_deref(), uid))
+                .group(id_name(groups.as_deref(), gid));
+            let mode = (metadata.permissions().mode() & 0o7777) as u16;
+            let source = if metadata.is_symlink() {
+                let target = memfs.fs().read_link(&rpm_path).await?;
+                tokio::fs::write(&link_target, target.as_os_str().as_bytes()).await?;
+                options = options
+                    .symlink(target.to_string_lossy())
+                    .mode(rpm::FileMode::symbolic_link(mode));
+                &link_target
+            } else {
+                options = options.mode(rpm::FileMode::regular(mode));
+                path
+            };
+
+            let inherited = input_flags
+                .iter()
+                .find(|(path, _)| path == &rpm_path)
+                .map(|(_, flags)| *flags)
+                .unwrap_or_else(rpm::FileFlags::empty);
+            if inherited.contains(rpm::FileFlags::CONFIG)
+                || any_match(&self.config_files, &rpm_path)
+            {
+                options = options.is_config();
+            }
+            if inherited.contains(rpm::FileFlags::DOC) || any_match(&self.doc_files, &rpm_path) {
+                options = options.is_doc();
+            }
+            if inherited.contains(rpm::FileFlags::GHOST) || any_match(&self.ghost_files, &rpm_path)
+            {
+                options = options.is_ghost();
+            }
+
             // TODO: This should be async... right?
-            pkg = pkg
-                .with_file(
-                    path,
-                    rpm::FileOptions::new(rpm_path.to_string_lossy().to_string()),
-                )
-                .unwrap();
+            pkg = pkg.with_file(source, options).unwrap();
+        }
+
+        for entry in self.changelog.iter().flatten() {
+            let (name, text) = rpm_entry(entry);
+            pkg = pkg.add_changelog_entry(name, text, entry.timestamp()?.timestamp() as u32);
         }
 
         debug!("adding metadata dependencies to rpm...");
         for dep in &self.dependencies {
-            pkg = pkg.requires(rpm::Dependency::any(dep));
+            pkg = pkg.requires(parse_dependency(dep));
         }
 
         info!("building final rpm...");
@@ -222,14 +406,60 @@ impl ArtifactProducer for RpmProducer {
         });
         join_handle.await?;
 
+        let debug_package = if let Some(debug_fs) = debug_fs {
+            Some(self.produce_debug_package(debug_fs).await?.path)
+        } else {
+            None
+        };
+
         Ok(RpmArtifact {
             name: self.name.clone(),
             path: self.path.clone(),
             spec: None,
+            debug_package,
         })
     }
 }
 
+/// The file flags of the rpm an artifact was read from, so that repackaging
+/// it keeps its `%config`, `%doc`, and `%ghost` files. Other artifacts have
+/// none.
+fn input_file_flags(previous: &dyn Artifact) -> Result<Vec<(PathBuf, rpm::FileFlags)>> {
+    if previous.kind() != ArtifactKind::Rpm {
+        return Ok(vec![]);
+    }
+    let Some(path) = previous.paths().and_then(|paths| paths.into_iter().next()) else {
+        return Ok(vec![]);
+    };
+
+    let metadata = rpm::PackageMetadata::open(&path)
+        .map_err(|e| eyre!("cannot read file flags from {}: {e}", path.display()))?;
+    Ok(metadata
+        .get_file_entries()
+        .map_err(|e| eyre!("cannot read file flags from {}: {e}", path.display()))?
+        .into_iter()
+        .map(|entry| (entry.path, entry.flags))
+        .collect())
+}
+
+/// The name rpm should record for a uid or gid. rpms store owners by name,
+/// so ids are looked up in the package's own `/etc/passwd` or `/etc/group`,
+/// if it has one. Unknown ids are written as numbers.
+fn id_name(db: Option<&str>, id: u32) -> String {
+    db.into_iter()
+        .flat_map(|db| db.lines())
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let line_id = fields.nth(1)?;
+            (line_id.parse() == Ok(id)).then(|| name.to_string())
+        })
+        .unwrap_or_else(|| match id {
+            0 => "root".to_string(),
+            id => id.to_string(),
+        })
+}
+
 #[async_trait::async_trait]
 impl SelfValidation for RpmProducer {
     async fn validate(&self) -> Result<()> {
@@ -249,16 +479,27 @@ impl SelfValidation for RpmProducer {
             ));
         }
 
-        let mut errors = vec![];
+        if self.split_debug
+            && TokioFloppyDisk::new(None)
+                .metadata(self.debug_package_path())
+                .await
+                .is_ok()
+        {
+            return Err(eyre::eyre!(
+                "cannot produce artifact '{}': debug package path already exists: {}",
+                self.name,
+                self.debug_package_path().display()
+            ));
+        }
 
-        // Validate any package starting with a letter, followed by any letter,
-        // number, hyphen, or underscore, ending with a letter or number.
-        let package_name_regex = Regex::new(r"^[a-z]([a-z0-9_-]*[a-z0-9])?$")?;
+        let mut errors = vec![];
 
-        // Validate more/less every version number people are likely to use,
-        // and ensure it ends with the Arch-specific versioning number at the
-        // end.
-        let package_version_regex = Regex::new(r"^[a-z0-9][a-z0-9+._-]*(-\d+)$")?;
+        // RPM is a lot more permissive than other formats: names can have
+        // uppercase letters, and versions and releases can have `~` and `^`
+        // for pre- and post-release sorting. Neither versions nor releases can
+        // contain `-`, since that's what separates them in the full NEVRA.
+        let package_name_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9._+-]*$")?;
+        let package_version_regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9._+~^]*$")?;
 
         if !package_name_regex.is_match(&self.package_name) {
             errors.push(format!(
@@ -274,10 +515,36 @@ impl SelfValidation for RpmProducer {
             ));
         }
 
+        if !package_version_regex.is_match(&self.package_release) {
+            errors.push(format!(
+                "package release `{}` is invalid, must match {package_version_regex}",
+                self.package_release
+            ));
+        }
+
         if self.package_description.is_empty() {
             errors.push("package description is empty".to_string());
         }
 
+        for entry in self.changelog.iter().flatten() {
+            if let Err(e) = entry.timestamp() {
+                errors.push(e.to_string());
+            }
+        }
+
+        for pattern in self
+            .config_files
+            .iter()
+            .chain(&self.doc_files)
+            .chain(&self.ghost_files)
+        {
+            if !pattern.starts_with('/') {
+                errors.push(format!("file flag pattern `{pattern}` must be absolute"));
+            } else if let Err(e) = Glob::new(pattern) {
+                errors.push(format!("file flag pattern `{pattern}` is invalid: {e}"));
+            }
+        }
+
         if !errors.is_empty() {
             Err(eyre::eyre!(
                 "RPM producer is invalid:\n{}",
@@ -294,11 +561,18 @@ pub struct RpmProducerBuilder {
     path: PathBuf,
     package_name: String,
     package_version: String,
+    package_release: String,
     package_license: String,
     package_arch: String,
     package_description: String,
     dependencies: Vec<String>,
     injections: Vec<Injection>,
+    split_debug: bool,
+    changelog: Option<Vec<ChangelogEntry>>,
+    inherit_metadata: bool,
+    config_files: Vec<String>,
+    doc_files: Vec<String>,
+    ghost_files: Vec<String>,
 }
 
 #[allow(unused)]
@@ -318,6 +592,11 @@ impl RpmProducerBuilder {
         self
     }
 
+    pub fn package_release<S: Into<String>>(mut self, package_release: S) -> Self {
+        self.package_release = package_release.into();
+        self
+    }
+
     pub fn package_license<S: Into<String>>(mut self, package_license: S) -> Self {
         self.package_license = package_license.into();
         self
@@ -352,6 +631,35 @@ impl RpmProducerBuilder {
         self.injections.push(injection);
         self
     }
+
+    pub fn split_debug(mut self, split_debug: bool) -> Self {
+        self.split_debug = split_debug;
+        self
+    }
+
+    pub fn changelog_entry(mut self, entry: ChangelogEntry) -> Self {
+        self.changelog.get_or_insert_with(Vec::new).push(entry);
+        self
+    }
+    pub fn inherit_metadata(mut self, inherit_metadata: bool) -> Self {
+        self.inherit_metadata = inherit_metadata;
+        self
+    }
+
+    pub fn config_file<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.config_files.push(pattern.into());
+        self
+    }
+
+    pub fn doc_file<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.doc_files.push(pattern.into());
+        self
+    }
+
+    pub fn ghost_file<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.ghost_files.push(pattern.into());
+        self
+    }
 }
 
 impl SelfBuilder for RpmProducerBuilder {
@@ -363,11 +671,18 @@ impl SelfBuilder for RpmProducerBuilder {
             path: PathBuf::new(),
             package_name: String::new(),
             package_version: String::new(),
+            package_release: "1".into(),
             package_license: String::new(),
             package_arch: String::new(),
             package_description: String::new(),
             dependencies: vec![],
             injections: vec![],
+            split_debug: false,
+            changelog: None,
+            inherit_metadata: false,
+            config_files: vec![],
+            doc_files: vec![],
+            ghost_files: vec![],
         }
     }
 
@@ -377,11 +692,442 @@ impl SelfBuilder for RpmProducerBuilder {
             path: self.path.clone(),
             package_name: self.package_name.clone(),
             package_version: self.package_version.clone(),
+            package_release: self.package_release.clone(),
             package_license: self.package_license.clone(),
             package_arch: self.package_arch.clone(),
             package_description: self.package_description.clone(),
             dependencies: self.dependencies.clone(),
             injections: self.injections.clone(),
+            split_debug: self.split_debug,
+            changelog: self.changelog.clone(),
+            inherit_metadata: self.inherit_metadata,
+            config_files: self.config_files.clone(),
+            doc_files: self.doc_files.clone(),
+            ghost_files: self.ghost_files.clone(),
         })
     }
 }
+
+/// Parse a dependency like `glibc >= 2.34` into its name, operator, and
+/// version. Anything without an operator and version is just a name.
+fn parse_dependency(dep: &str) -> rpm::Dependency {
+    match dep.split_whitespace().collect::<Vec<_>>()[..] {
+        [name, "<", version] => rpm::Dependency::less(name, version),
+        [name, "<=", version] => rpm::Dependency::less_eq(name, version),
+        [name, "=", version] => rpm::Dependency::eq(name, version),
+        [name, ">=", version] => rpm::Dependency::greater_eq(name, version),
+        [name, ">", version] => rpm::Dependency::greater(name, version),
+        _ => rpm::Dependency::any(dep.trim()),
+    }
+}
+
+/// The inverse of [`parse_dependency`], so inherited dependencies keep their
+/// version constraints.
+fn dependency_to_string(dep: &rpm::Dependency) -> String {
+    use rpm::DependencyFlags;
+
+    let flags =
+        dep.flags & (DependencyFlags::LESS | DependencyFlags::GREATER | DependencyFlags::EQUAL);
+    let operator = if flags == DependencyFlags::LE {
+        "<="
+    } else if flags == DependencyFlags::GE {
+        ">="
+    } else if flags == DependencyFlags::LESS {
+        "<"
+    } else if flags == DependencyFlags::GREATER {
+        ">"
+    } else if flags == DependencyFlags::EQUAL {
+        "="
+    } else {
+        return dep.name.clone();
+    };
+    if dep.version.is_empty() {
+        return dep.name.clone();
+    }
+
+    format!("{} {operator} {}", dep.name, dep.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::EmptyArtifact;
+    use crate::util::with_source_date_epoch;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    fn producer(tmp: &TempDir, name: &str, version: &str, release: &str) -> Result<RpmProducer> {
+        RpmProducerBuilder::new("rpm")
+            .path(tmp.path_view().join("out.rpm"))
+            .package_name(name)
+            .package_version(version)
+            .package_release(release)
+            .package_license("MIT")
+            .package_arch("x86_64")
+            .package_description("an rpm")
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_valid_rpm_metadata_is_accepted() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        producer(&tmp, "MyApp", "1.2.3", "1.el9")?
+            .validate()
+            .await?;
+        producer(&tmp, "my_app+extras", "1.0~rc1", "2")?
+            .validate()
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_rpm_metadata_is_rejected() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        for (name, version, release) in [
+            ("my app", "1.2.3", "1"),
+            ("-myapp", "1.2.3", "1"),
+            ("myapp", "1.2-3", "1"),
+            ("myapp", "", "1"),
+            ("myapp", "1.2.3", "1-2"),
+            ("myapp", "1.2.3", ""),
+        ] {
+            assert!(
+                producer(&tmp, name, version, release)?
+                    .validate()
+                    .await
+                    .is_err(),
+                "{name} {version} {release} should be invalid"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_time_follows_source_date_epoch() -> Result<()> {
+        with_source_date_epoch(1_600_000_000, async {
+            let tmp = TempDir::new().await?;
+            let path = tmp.path_view().join("build-time.rpm");
+            RpmProducerBuilder::new("build time")
+                .path(&path)
+                .package_name("build-time")
+                .package_version("0.1.0")
+                .package_release("1")
+                .package_license("MIT")
+                .package_arch("x86_64")
+                .package_description("build time")
+                .build()?
+                .produce_from(&EmptyArtifact::new("empty"))
+                .await?;
+
+            let package = rpm::Package::open(&path)?;
+            assert_eq!(1_600_000_000, package.metadata.get_build_time()?);
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_rpm_payload_is_reproducible() -> Result<()> {
+        with_source_date_epoch(1_700_000_000, async {
+            let tmp = TempDir::new().await?;
+
+            let mut packages = vec![];
+            for name in ["first.rpm", "second.rpm"] {
+                let path = tmp.path_view().join(name);
+                RpmProducerBuilder::new("reproducible")
+                    .path(&path)
+                    .package_name("reproducible")
+                    .package_version("0.1.0")
+                    .package_release("1")
+                    .package_license("MIT")
+                    .package_arch("x86_64")
+                    .package_description("reproducible peckish!")
+                    .inject(Injection::Create {
+                        path: "/usr/bin/b".into(),
+                        content: b"b".to_vec(),
+                    })
+                    .inject(Injection::Create {
+                        path: "/etc/a".into(),
+                        content: b"a".to_vec(),
+                    })
+                    .build()?
+                    .produce_from(&EmptyArtifact::new("empty"))
+                    .await?;
+                packages.push(rpm::Package::open(&path)?);
+            }
+
+            let [first, second] = &packages[..] else {
+                unreachable!();
+            };
+            assert_eq!(first.content, second.content);
+            assert_eq!(1_700_000_000, first.metadata.get_build_time()?);
+            for entry in first.metadata.get_file_entries()? {
+                assert_eq!(1_700_000_000, u32::from(entry.modified_at));
+            }
+            assert_eq!(
+                first.metadata.get_file_paths()?,
+                vec![PathBuf::from("/etc/a"), PathBuf::from("/usr/bin/b")]
+            );
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_rpm_owners_come_from_the_memfs() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("owners.rpm");
+        RpmProducerBuilder::new("owners")
+            .path(&path)
+            .package_name("owners")
+            .package_version("0.1.0")
+            .package_release("1")
+            .package_license("MIT")
+            .package_arch("x86_64")
+            .package_description("owners")
+            .inject(Injection::Create {
+                path: "/etc/group".into(),
+                content: b"root:x:0:\nhttp:x:33:\n".to_vec(),
+            })
+            .inject(Injection::Create {
+                path: "/srv/index.html".into(),
+                content: b"hi".to_vec(),
+            })
+            .inject(Injection::Chown {
+                path: "/srv/index.html".into(),
+                uid: 33,
+                gid: 33,
+                recursive: false,
+            })
+            .inject(Injection::Chmod {
+                path: "/srv/index.html".into(),
+                mode: 0o640,
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let package = rpm::Package::open(&path)?;
+        let entry = package
+            .metadata
+            .get_file_entries()?
+            .into_iter()
+            .find(|entry| entry.path == Path::new("/srv/index.html"))
+            .expect("index.html is in the rpm");
+        // There's no /etc/passwd, so the uid is written as a number.
+        assert_eq!("33", entry.ownership.user);
+        assert_eq!("http", entry.ownership.group);
+        assert_eq!(0o640, entry.mode.permissions());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_host_files_are_owned_by_root() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let host_file = tmp.path_view().join("app");
+        tokio::fs::write(&host_file, b"#!/bin/sh\n").await?;
+        let path = tmp.path_view().join("host.rpm");
+        RpmProducerBuilder::new("host")
+            .path(&path)
+            .package_name("host")
+            .package_version("0.1.0")
+            .package_release("1")
+            .package_license("MIT")
+            .package_arch("x86_64")
+            .package_description("host files")
+            .inject(Injection::HostFile {
+                src: host_file.clone(),
+                dest: "/usr/bin/app".into(),
+            })
+            .inject(Injection::HostFile {
+                src: host_file.clone(),
+                dest: "/usr/bin/chowned".into(),
+            })
+            .inject(Injection::Chown {
+                path: "/usr/bin/chowned".into(),
+                uid: 33,
+                gid: 33,
+                recursive: false,
+            })
+            .inject(Injection::Symlink {
+                src: "app".into(),
+                dest: "/usr/bin/link".into(),
+                relative: None,
+            })
+            .build()?
+            .produce_from(&crate::artifact::file::FileArtifactBuilder::new("nothing").build()?)
+            .await?;
+
+        let entries = rpm::Package::open(&path)?.metadata.get_file_entries()?;
+        let entry = |path: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.path == Path::new(path))
+                .unwrap_or_else(|| panic!("{path} is in the rpm"))
+        };
+        assert_eq!("root", entry("/usr/bin/app").ownership.user);
+        assert_eq!("root", entry("/usr/bin/app").ownership.group);
+        assert_eq!("33", entry("/usr/bin/chowned").ownership.user);
+
+        let link = entry("/usr/bin/link");
+        assert!(matches!(link.mode, rpm::FileMode::SymbolicLink { .. }));
+        assert_eq!("app", link.linkto);
+        assert_eq!("root", link.ownership.user);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rpm_file_flags_are_written() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("flags.rpm");
+        RpmProducerBuilder::new("flags")
+            .path(&path)
+            .package_name("flags")
+            .package_version("0.1.0")
+            .package_release("1")
+            .package_license("MIT")
+            .package_arch("x86_64")
+            .package_description("flags")
+            .config_file("/etc/**")
+            .inject(Injection::Create {
+                path: "/etc/flags/flags.conf".into(),
+                content: b"setting = true".to_vec(),
+            })
+            .inject(Injection::Create {
+                path: "/usr/bin/flags".into(),
+                content: b"flags".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let flags = |package: &Path, file: &str| -> Result<rpm::FileFlags> {
+            Ok(rpm::Package::open(package)?
+                .metadata
+                .get_file_entries()?
+                .into_iter()
+                .find(|entry| entry.path == Path::new(file))
+                .map(|entry| entry.flags)
+                .expect("the file is in the rpm"))
+        };
+        assert!(flags(&path, "/etc/flags/flags.conf")?.contains(rpm::FileFlags::CONFIG));
+        assert!(!flags(&path, "/usr/bin/flags")?.contains(rpm::FileFlags::CONFIG));
+
+        // Repackaging the rpm keeps the flag, without configuring it again.
+        let repackaged = tmp.path_view().join("repackaged.rpm");
+        RpmProducerBuilder::new("repackaged")
+            .path(&repackaged)
+            .package_name("flags")
+            .package_version("0.1.0")
+            .package_release("2")
+            .package_license("MIT")
+            .package_arch("x86_64")
+            .package_description("flags")
+            .build()?
+            .produce_from(&RpmArtifactBuilder::new("flags").path(&path).build()?)
+            .await?;
+        assert!(flags(&repackaged, "/etc/flags/flags.conf")?.contains(rpm::FileFlags::CONFIG));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_version() {
+        assert_eq!(
+            RpmProducer::split_version("1.2.3-1.el9"),
+            ("1.2.3".into(), "1.el9".into())
+        );
+        assert_eq!(
+            RpmProducer::split_version("1.2.3"),
+            ("1.2.3".into(), "1".into())
+        );
+    }
+
+    #[test]
+    fn test_dependencies_round_trip_through_the_header_format() {
+        for dep in [
+            "glibc >= 2.34",
+            "openssl < 3",
+            "libfoo = 1.2-1",
+            "bash",
+            "/bin/sh",
+        ] {
+            assert_eq!(dep, dependency_to_string(&parse_dependency(dep)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inherited_metadata_survives_a_round_trip() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let original = RpmProducerBuilder::new("original")
+            .path(tmp.path_view().join("original.rpm"))
+            .package_name("original")
+            .package_version("1.2.3")
+            .package_release("4.el9")
+            .package_license("MIT")
+            .package_arch("x86_64")
+            .package_description("the original package")
+            .dependency("glibc >= 2.34")
+            .dependency("bash")
+            .inject(Injection::Create {
+                path: "/usr/bin/tool".into(),
+                content: b"tool".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        // Inputs from the config only have a path.
+        let input = RpmArtifact {
+            name: "input".into(),
+            path: original.path.clone(),
+            spec: None,
+            debug_package: None,
+        };
+        let patched = RpmProducerBuilder::new("patched")
+            .path(tmp.path_view().join("patched.rpm"))
+            .package_name("from-config")
+            .package_version("9.9.9")
+            .package_release("1")
+            .inherit_metadata(true)
+            .inject(Injection::Create {
+                path: "/etc/patched".into(),
+                content: b"patched".to_vec(),
+            })
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        let metadata = rpm::PackageMetadata::open(&patched.path)?;
+        assert_eq!("original", metadata.get_name()?);
+        assert_eq!("1.2.3", metadata.get_version()?);
+        assert_eq!("4.el9", metadata.get_release()?);
+        assert_eq!("MIT", metadata.get_license()?);
+        assert_eq!("the original package", metadata.get_description()?);
+        let requires = metadata
+            .get_requires()?
+            .iter()
+            .filter(|dep| !dep.name.starts_with("rpmlib("))
+            .map(dependency_to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["glibc >= 2.34", "bash"], requires);
+
+        let memfs = patched.extract().await?;
+        assert_eq!(b"tool".to_vec(), memfs.fs().read("/usr/bin/tool").await?);
+        assert_eq!(b"patched".to_vec(), memfs.fs().read("/etc/patched").await?);
+
+        Ok(())
+    }
+}
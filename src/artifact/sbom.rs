@@ -0,0 +1,576 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use floppy_disk::prelude::*;
+use floppy_disk::tokio_fs::TokioFloppyDisk;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::*;
+
+use crate::artifact::file::{FileArtifact, FilePlacement};
+use crate::artifact::manifest::{ManifestEntryType, ManifestProducer};
+use crate::util::config::Injection;
+use crate::util::{get_current_time, sha256_digest_string};
+
+use super::deb::{ControlFile, DebArtifact};
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation};
+
+/// The SBOM document format to write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 JSON.
+    #[default]
+    #[serde(rename = "cyclonedx")]
+    CycloneDx,
+    /// SPDX 2.3 JSON.
+    Spdx,
+}
+
+/// A third-party package found in the artifact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SbomPackage {
+    pub name: String,
+    pub version: Option<String>,
+    /// Where the package was found, ex. `/var/lib/dpkg/status`.
+    pub source: PathBuf,
+}
+
+/// Produces a minimal software bill of materials for the previous artifact.
+/// The package itself is described by `package_name` and `package_version`,
+/// or by the control file if the previous artifact is a deb; every file in it
+/// is listed with its sha256, along with any packages found in recognizable
+/// metadata:
+///
+/// - a deb's `Depends` and `Pre-Depends`
+/// - `/var/lib/dpkg/status`, as found in Debian-based images
+/// - `/.PKGINFO`, as found in Arch packages
+/// - `requirements.txt` files, anywhere
+#[derive(Debug, Clone, Serialize)]
+pub struct SbomProducer {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: SbomFormat,
+    pub package_name: String,
+    pub package_version: String,
+    pub injections: Vec<Injection>,
+}
+
+impl SbomProducer {
+    /// The control file of the previous artifact, if it's a deb package.
+    async fn input_control(previous: &dyn Artifact) -> Result<Option<ControlFile>> {
+        if previous.kind() != ArtifactKind::Deb {
+            return Ok(None);
+        }
+        let Some(path) = previous.paths().and_then(|paths| paths.into_iter().next()) else {
+            return Ok(None);
+        };
+
+        let deb = DebArtifact {
+            name: previous.name().to_string(),
+            path,
+            control: None,
+            postinst: None,
+            prerm: None,
+            debug_package: None,
+        };
+        Ok(Some(deb.read_control().await?))
+    }
+
+    /// Every package listed in the memfs's known metadata files.
+    pub async fn packages(fs: &MemFloppyDisk) -> Result<Vec<SbomPackage>> {
+        let mut packages = vec![];
+
+        for path in nyoom::walk_ordered(fs, "/").await? {
+            if !fs.symlink_metadata(&path).await?.is_file() {
+                continue;
+            }
+
+            let found = if path == Path::new("/var/lib/dpkg/status") {
+                parse_dpkg_status(&String::from_utf8_lossy(&fs.read(&path).await?))
+            } else if path == Path::new("/.PKGINFO") {
+                parse_pkginfo(&String::from_utf8_lossy(&fs.read(&path).await?))
+            } else if path.file_name() == Some("requirements.txt".as_ref()) {
+                parse_requirements(&String::from_utf8_lossy(&fs.read(&path).await?))
+            } else {
+                continue;
+            };
+
+            debug!("found {} packages in {}", found.len(), path.display());
+            packages.extend(found.into_iter().map(|(name, version)| SbomPackage {
+                name,
+                version,
+                source: path.clone(),
+            }));
+        }
+
+        Ok(packages)
+    }
+
+    fn cyclonedx(&self, packages: &[SbomPackage], files: &[(PathBuf, String)]) -> Value {
+        let libraries = packages.iter().map(|package| {
+            let mut component = json!({
+                "type": "library",
+                "name": package.name,
+                "properties": [{
+                    "name": "peckish:source",
+                    "value": package.source.to_string_lossy(),
+                }],
+            });
+            if let Some(version) = &package.version {
+                component["version"] = json!(version);
+            }
+            component
+        });
+        let files = files.iter().map(|(path, sha256)| {
+            json!({
+                "type": "file",
+                "name": path.to_string_lossy(),
+                "hashes": [{ "alg": "SHA-256", "content": sha256 }],
+            })
+        });
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "version": 1,
+            "metadata": {
+                "tools": [{ "name": "peckish", "version": env!("CARGO_PKG_VERSION") }],
+                "component": {
+                    "type": "application",
+                    "name": self.package_name,
+                    "version": self.package_version,
+                },
+            },
+            "components": libraries.chain(files).collect::<Vec<_>>(),
+        })
+    }
+
+    fn spdx(&self, packages: &[SbomPackage], files: &[(PathBuf, String)]) -> Result<Value> {
+        let package = |id: String, name: &str, version: Option<&str>| {
+            let mut package = json!({
+                "SPDXID": id,
+                "name": name,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+            });
+            if let Some(version) = version {
+                package["versionInfo"] = json!(version);
+            }
+            package
+        };
+
+        let mut spdx_packages = vec![package(
+            "SPDXRef-Package".into(),
+            &self.package_name,
+            Some(&self.package_version),
+        )];
+        let mut relationships = vec![json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": "SPDXRef-Package",
+        })];
+        for (i, dependency) in packages.iter().enumerate() {
+            let id = format!("SPDXRef-Dependency-{i}");
+            spdx_packages.push(package(
+                id.clone(),
+                &dependency.name,
+                dependency.version.as_deref(),
+            ));
+            relationships.push(json!({
+                "spdxElementId": "SPDXRef-Package",
+                "relationshipType": "DEPENDS_ON",
+                "relatedSpdxElement": id,
+            }));
+        }
+
+        let spdx_files = files
+            .iter()
+            .enumerate()
+            .map(|(i, (path, sha256))| {
+                json!({
+                    "SPDXID": format!("SPDXRef-File-{i}"),
+                    "fileName": format!(".{}", path.display()),
+                    "checksums": [{ "algorithm": "SHA256", "checksumValue": sha256 }],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The namespace has to be unique per document, so it's derived from
+        // the contents rather than being random.
+        let contents = files
+            .iter()
+            .map(|(path, sha256)| format!("{sha256}  {}\n", path.display()))
+            .collect::<String>();
+        let namespace = format!(
+            "https://spdx.org/spdxdocs/{}-{}-{}",
+            self.package_name,
+            self.package_version,
+            sha256_digest_string(contents)?
+        );
+        // `SOURCE_DATE_EPOCH`, if it's set, so that the document is
+        // reproducible.
+        let created = chrono::DateTime::from_timestamp(get_current_time()? as i64, 0)
+            .ok_or_else(|| eyre!("creation time is out of range"))?;
+
+        Ok(json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": format!("{}-{}", self.package_name, self.package_version),
+            "documentNamespace": namespace,
+            "creationInfo": {
+                "created": created.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                "creators": [format!("Tool: peckish-{}", env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": spdx_packages,
+            "files": spdx_files,
+            "relationships": relationships,
+        }))
+    }
+}
+
+/// `(name, version)` for every paragraph in a dpkg status database.
+fn parse_dpkg_status(status: &str) -> Vec<(String, Option<String>)> {
+    status
+        .split("\n\n")
+        .filter_map(|paragraph| {
+            let field = |key: &str| {
+                paragraph.lines().find_map(|line| {
+                    line.strip_prefix(key)
+                        .and_then(|rest| rest.strip_prefix(':'))
+                        .map(|value| value.trim().to_string())
+                })
+            };
+            field("Package").map(|name| (name, field("Version")))
+        })
+        .collect()
+}
+
+/// `(name, version)` for every package in a deb relationship field, ex.
+/// `libc6 (>= 2.34), zlib1g | libz`. Only the first of a set of alternatives
+/// is recorded, and versions are only recorded when pinned with `=`.
+fn parse_deb_depends(depends: &str) -> Vec<(String, Option<String>)> {
+    depends
+        .split(',')
+        .filter_map(|relation| relation.split('|').next())
+        .map(str::trim)
+        .filter(|relation| !relation.is_empty())
+        .map(|relation| {
+            let (name, constraint) = match relation.split_once('(') {
+                Some((name, constraint)) => (name, Some(constraint.trim_end_matches(')'))),
+                None => (relation, None),
+            };
+            let version = constraint
+                .and_then(|constraint| constraint.trim().strip_prefix('='))
+                .map(|version| version.trim().to_string());
+            (name.trim().to_string(), version)
+        })
+        .collect()
+}
+
+/// `(name, version)` of the package an Arch `.PKGINFO` describes.
+fn parse_pkginfo(pkginfo: &str) -> Vec<(String, Option<String>)> {
+    let field = |key: &str| {
+        pkginfo.lines().find_map(|line| {
+            line.split_once('=')
+                .filter(|(k, _)| k.trim() == key)
+                .map(|(_, value)| value.trim().to_string())
+        })
+    };
+
+    field("pkgname")
+        .map(|name| vec![(name, field("pkgver"))])
+        .unwrap_or_default()
+}
+
+/// `(name, version)` for every requirement in a pip `requirements.txt`.
+/// Versions are only recorded when pinned with `==`.
+fn parse_requirements(requirements: &str) -> Vec<(String, Option<String>)> {
+    requirements
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('-'))
+        .map(|line| match line.split_once("==") {
+            Some((name, version)) => (name.trim().to_string(), Some(version.trim().to_string())),
+            None => {
+                let name = line
+                    .split(|c: char| !(c.is_alphanumeric() || "-_.".contains(c)))
+                    .next()
+                    .unwrap_or_default();
+                (name.to_string(), None)
+            }
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for SbomProducer {
+    type Output = FileArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<FileArtifact> {
+        info!("producing sbom {}", self.path.display());
+        let control = Self::input_control(previous).await?;
+        let mut memfs = previous.extract().await?;
+        self.inject(&mut memfs).await?;
+
+        let mut packages = vec![];
+        let described = match control {
+            Some(control) => {
+                debug!("describing {} from its control file", control.package);
+                for depends in [&control.pre_depends, &control.depends] {
+                    packages.extend(parse_deb_depends(depends).into_iter().map(
+                        |(name, version)| SbomPackage {
+                            name,
+                            version,
+                            source: PathBuf::from("/DEBIAN/control"),
+                        },
+                    ));
+                }
+                SbomProducer {
+                    package_name: control.package,
+                    package_version: control.version,
+                    ..self.clone()
+                }
+            }
+            None => self.clone(),
+        };
+        packages.extend(Self::packages(&memfs).await?);
+        let files = ManifestProducer::entries(&memfs)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.entry_type == ManifestEntryType::File)
+            .filter_map(|entry| entry.sha256.map(|sha256| (entry.path, sha256)))
+            .collect::<Vec<_>>();
+        debug!(
+            "sbom has {} packages and {} files",
+            packages.len(),
+            files.len()
+        );
+
+        let document = match self.format {
+            SbomFormat::CycloneDx => described.cyclonedx(&packages, &files),
+            SbomFormat::Spdx => described.spdx(&packages, &files)?,
+        };
+        tokio::fs::write(&self.path, serde_json::to_string_pretty(&document)?).await?;
+
+        Ok(FileArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            paths: vec![self.path.clone()],
+            git_mtimes: false,
+            default_excludes: true,
+            extra_excludes: vec![],
+            placement: FilePlacement::AsGiven,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for SbomProducer {
+    async fn validate(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if TokioFloppyDisk::new(None)
+            .metadata(&self.path)
+            .await
+            .is_ok()
+        {
+            return Err(eyre!(
+                "cannot produce artifact '{}': path already exists: {}",
+                self.name,
+                self.path.display()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct SbomProducerBuilder {
+    name: String,
+    path: PathBuf,
+    format: SbomFormat,
+    package_name: String,
+    package_version: String,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl SbomProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn format(mut self, format: SbomFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn package_name<S: Into<String>>(mut self, package_name: S) -> Self {
+        self.package_name = package_name.into();
+        self
+    }
+
+    pub fn package_version<S: Into<String>>(mut self, package_version: S) -> Self {
+        self.package_version = package_version.into();
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for SbomProducerBuilder {
+    type Output = SbomProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from("sbom.json"),
+            format: SbomFormat::default(),
+            package_name: String::new(),
+            package_version: String::new(),
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(SbomProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            format: self.format,
+            package_name: self.package_name.clone(),
+            package_version: self.package_version.clone(),
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::deb::DebProducerBuilder;
+    use crate::artifact::memory::EmptyArtifact;
+    use crate::fs::TempDir;
+    use crate::util::with_source_date_epoch;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_cyclonedx_sbom_from_deb() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let deb = DebProducerBuilder::new("deb")
+            .path(tmp.path_view().join("app.deb"))
+            .package_name("app")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("1.2.3-1")
+            .package_description("an app")
+            .package_depends("libc6 (>= 2.34), libssl3 (= 3.0.11-1) | libssl1.1")
+            .inject(Injection::Create {
+                path: "/usr/share/app/requirements.txt".into(),
+                content: b"# pinned\nrequests==2.31.0\nflask>=3\n".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let path = tmp.path_view().join("sbom.json");
+        // The deb's control file wins over the producer's own metadata.
+        SbomProducerBuilder::new("sbom")
+            .path(&path)
+            .package_name("not-app")
+            .package_version("0.0.0")
+            .build()?
+            .produce_from(&deb)
+            .await?;
+
+        let sbom: Value = serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?;
+        assert_eq!(sbom["bomFormat"], "CycloneDX");
+        assert_eq!(sbom["specVersion"], "1.5");
+        assert_eq!(sbom["version"], 1);
+        assert_eq!(sbom["metadata"]["component"]["name"], "app");
+        assert_eq!(sbom["metadata"]["component"]["version"], "1.2.3-1");
+
+        let components = sbom["components"].as_array().unwrap();
+        assert!(components
+            .iter()
+            .any(|c| c["name"] == "libc6" && c.get("version").is_none()));
+        assert!(components
+            .iter()
+            .any(|c| c["name"] == "libssl3" && c["version"] == "3.0.11-1"));
+        assert!(!components.iter().any(|c| c["name"] == "libssl1.1"));
+        assert!(components
+            .iter()
+            .any(|c| c["name"] == "requests" && c["version"] == "2.31.0"));
+        assert!(components
+            .iter()
+            .any(|c| c["name"] == "flask" && c.get("version").is_none()));
+        assert!(components
+            .iter()
+            .any(|c| c["type"] == "file" && c["name"] == "/usr/share/app/requirements.txt"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spdx_creation_time_follows_source_date_epoch() -> Result<()> {
+        with_source_date_epoch(1_700_000_000, async {
+            let tmp = TempDir::new().await?;
+            let path = tmp.path_view().join("sbom.spdx.json");
+            SbomProducerBuilder::new("sbom")
+                .path(&path)
+                .format(SbomFormat::Spdx)
+                .package_name("app")
+                .package_version("1.2.3-1")
+                .build()?
+                .produce_from(&EmptyArtifact::new("empty"))
+                .await?;
+
+            let sbom: Value = serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?;
+            assert_eq!(sbom["creationInfo"]["created"], "2023-11-14T22:13:20Z");
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[test]
+    fn test_dpkg_status_is_parsed() {
+        let status = indoc::indoc! {"
+            Package: libc6
+            Status: install ok installed
+            Version: 2.36-9
+
+            Package: zlib1g
+            Version: 1:1.2.13
+        "};
+
+        assert_eq!(
+            parse_dpkg_status(status),
+            vec![
+                ("libc6".to_string(), Some("2.36-9".to_string())),
+                ("zlib1g".to_string(), Some("1:1.2.13".to_string())),
+            ]
+        );
+    }
+}
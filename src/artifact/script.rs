@@ -0,0 +1,400 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use eyre::{eyre, Result};
+use floppy_disk::tokio_fs::TokioFloppyDisk;
+use floppy_disk::FloppyDisk;
+use serde::Serialize;
+use smoosh::CompressionType;
+use tracing::*;
+
+use crate::artifact::tarball::{EntryNames, TarballArtifact, TarballProducer};
+use crate::fs::{MemFS, TempDir};
+use crate::util::config::Injection;
+
+use super::memory::MemoryArtifact;
+use super::{Artifact, ArtifactKind, ArtifactProducer, SelfBuilder, SelfValidation};
+
+/// The line separating the extraction stub from the payload.
+const PAYLOAD_MARKER: &[u8] = b"__PECKISH_PAYLOAD__\n";
+
+/// A self-extracting installer: a shell script with a gzipped tarball
+/// appended to it.
+#[derive(Debug, Clone)]
+pub struct ScriptInstallerArtifact {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl Artifact for ScriptInstallerArtifact {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::ScriptInstaller
+    }
+
+    async fn extract(&self) -> Result<MemFS> {
+        let script = tokio::fs::read(&self.path).await?;
+        let payload = script
+            .windows(PAYLOAD_MARKER.len())
+            .position(|window| window == PAYLOAD_MARKER)
+            .map(|start| &script[start + PAYLOAD_MARKER.len()..])
+            .ok_or_else(|| {
+                eyre!(
+                    "{} is not a peckish installer: no payload found",
+                    self.path.display()
+                )
+            })?;
+
+        let tmp = TempDir::new().await?;
+        let payload_path = tmp.path_view().join("payload.tar.gz");
+        tokio::fs::write(&payload_path, payload).await?;
+
+        TarballArtifact {
+            name: self.name.clone(),
+            path: payload_path,
+        }
+        .extract()
+        .await
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn paths(&self) -> Option<Vec<PathBuf>> {
+        Some(vec![self.path.clone()])
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for ScriptInstallerArtifact {
+    async fn validate(&self) -> Result<()> {
+        if !self.path.is_file() {
+            return Err(eyre!("path is not a file: {:?}", self.path));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ScriptInstallerArtifactBuilder {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[allow(unused)]
+impl ScriptInstallerArtifactBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl SelfBuilder for ScriptInstallerArtifactBuilder {
+    type Output = ScriptInstallerArtifact;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from(""),
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(ScriptInstallerArtifact {
+            name: self.name.clone(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// Produces a single `install.sh`-style script that extracts its payload into
+/// the directory given as its first argument, or `/` if none is given.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptInstallerProducer {
+    pub name: String,
+    pub path: PathBuf,
+    /// A shell command to run after extracting, from inside the install
+    /// directory. The directory is also available as `$PECKISH_INSTALL_DIR`.
+    pub post_extract: Option<String>,
+    pub injections: Vec<Injection>,
+}
+
+/// Quote a value for a POSIX shell, so that it's always a single word.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+impl ScriptInstallerProducer {
+    fn stub(&self) -> String {
+        // The command is passed to `sh -c` as one quoted word, so it can't
+        // break out of the subshell, even if it has quotes or parens.
+        let post_extract = self
+            .post_extract
+            .as_ref()
+            .map(|command| {
+                format!(
+                    "(cd \"$PECKISH_INSTALL_DIR\" && sh -c {})\n",
+                    shell_quote(command)
+                )
+            })
+            .unwrap_or_default();
+
+        indoc::formatdoc! {r#"
+            #!/bin/sh
+            # Self-extracting installer for {name}, generated by peckish.
+            set -e
+            PECKISH_INSTALL_DIR="${{1:-/}}"
+            export PECKISH_INSTALL_DIR
+            mkdir -p "$PECKISH_INSTALL_DIR"
+            payload=$(awk '/^__PECKISH_PAYLOAD__$/ {{ print NR + 1; exit 0 }}' "$0")
+            tail -n +"$payload" "$0" | gzip -dc | tar -xf - -C "$PECKISH_INSTALL_DIR"
+            {post_extract}exit 0
+        "#,
+            name = shell_quote(&self.name),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ArtifactProducer for ScriptInstallerProducer {
+    type Output = ScriptInstallerArtifact;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    async fn produce_from(&self, previous: &dyn Artifact) -> Result<ScriptInstallerArtifact> {
+        info!("producing {}", self.path.display());
+        let mut memfs = previous.extract().await?;
+        self.inject(&mut memfs).await?;
+
+        debug!("packing payload...");
+        let tmp = TempDir::new().await?;
+        let payload = tmp.path_view().join("payload.tar.gz");
+        TarballProducer {
+            name: format!("{}-payload", self.name),
+            path: payload.clone(),
+            compression: CompressionType::Gzip,
+            sparse: false,
+            entry_names: EntryNames::Relative,
+            file_mtime: None,
+            dir_mtime: None,
+            rsyncable: false,
+            remap_owner: None,
+            split_size: None,
+            injections: vec![],
+        }
+        .produce_from(&MemoryArtifact {
+            name: self.name.clone(),
+            fs: memfs,
+        })
+        .await?;
+
+        let mut script = self.stub().into_bytes();
+        script.extend_from_slice(PAYLOAD_MARKER);
+        script.extend(tokio::fs::read(&payload).await?);
+        tokio::fs::write(&self.path, script).await?;
+        tokio::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o755)).await?;
+
+        Ok(ScriptInstallerArtifact {
+            name: self.path.to_string_lossy().to_string(),
+            path: self.path.clone(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SelfValidation for ScriptInstallerProducer {
+    async fn validate(&self) -> Result<()> {
+        // The name goes in a comment, where a newline would start a command.
+        if self.name.chars().any(char::is_control) {
+            return Err(eyre!(
+                "cannot produce artifact '{}': name contains control characters",
+                self.name.escape_debug()
+            ));
+        }
+
+        // The stub finds the payload by the first line that's just the
+        // marker, so the command can't have one.
+        let marker = String::from_utf8_lossy(PAYLOAD_MARKER);
+        if let Some(command) = &self.post_extract {
+            if command.lines().any(|line| line == marker.trim_end()) {
+                return Err(eyre!(
+                    "cannot produce artifact '{}': post_extract contains the payload marker",
+                    self.name
+                ));
+            }
+        }
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        if TokioFloppyDisk::new(None)
+            .metadata(&self.path)
+            .await
+            .is_ok()
+        {
+            return Err(eyre!(
+                "cannot produce artifact '{}': path already exists: {}",
+                self.name,
+                self.path.display()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ScriptInstallerProducerBuilder {
+    name: String,
+    path: PathBuf,
+    post_extract: Option<String>,
+    injections: Vec<Injection>,
+}
+
+#[allow(unused)]
+impl ScriptInstallerProducerBuilder {
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn post_extract<S: Into<String>>(mut self, post_extract: S) -> Self {
+        self.post_extract = Some(post_extract.into());
+        self
+    }
+
+    pub fn inject(mut self, injection: Injection) -> Self {
+        self.injections.push(injection);
+        self
+    }
+}
+
+impl SelfBuilder for ScriptInstallerProducerBuilder {
+    type Output = ScriptInstallerProducer;
+
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            path: PathBuf::from("install.sh"),
+            post_extract: None,
+            injections: vec![],
+        }
+    }
+
+    fn build(&self) -> Result<Self::Output> {
+        Ok(ScriptInstallerProducer {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            post_extract: self.post_extract.clone(),
+            injections: self.injections.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::memory::EmptyArtifact;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_installer_extracts_payload() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let installer = tmp.path_view().join("install.sh");
+        let install_dir = tmp.path_view().join("installed");
+
+        let artifact = ScriptInstallerProducerBuilder::new("installer")
+            .path(&installer)
+            .post_extract("touch post-extract-ran")
+            .inject(Injection::Create {
+                path: "/usr/bin/hello".into(),
+                content: b"#!/bin/sh\necho hello\n".to_vec(),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let status = tokio::process::Command::new("sh")
+            .arg(&installer)
+            .arg(&install_dir)
+            .status()
+            .await?;
+        assert!(status.success());
+        assert_eq!(
+            tokio::fs::read(install_dir.join("usr/bin/hello")).await?,
+            b"#!/bin/sh\necho hello\n"
+        );
+        assert!(install_dir.join("post-extract-ran").exists());
+
+        let memfs = artifact.extract().await?;
+        assert_eq!(
+            (*memfs).read("/usr/bin/hello").await?,
+            b"#!/bin/sh\necho hello\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_post_extract_is_quoted() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let installer = tmp.path_view().join("install.sh");
+        let install_dir = tmp.path_view().join("installed");
+
+        ScriptInstallerProducerBuilder::new("it's an installer")
+            .path(&installer)
+            .post_extract("echo 'it''s quoted' > \"post extract\"")
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let status = tokio::process::Command::new("sh")
+            .arg(&installer)
+            .arg(&install_dir)
+            .status()
+            .await?;
+        assert!(status.success());
+        assert_eq!(
+            tokio::fs::read(install_dir.join("post extract")).await?,
+            b"its quoted\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsafe_names_and_commands_are_rejected() -> Result<()> {
+        let tmp = TempDir::new().await?;
+
+        let producer = ScriptInstallerProducerBuilder::new("installer\nrm -rf /")
+            .path(tmp.path_view().join("install.sh"))
+            .build()?;
+        assert!(producer.validate().await.is_err());
+
+        let producer = ScriptInstallerProducerBuilder::new("installer")
+            .path(tmp.path_view().join("install.sh"))
+            .post_extract("true\n__PECKISH_PAYLOAD__\ntrue")
+            .build()?;
+        assert!(producer.validate().await.is_err());
+
+        Ok(())
+    }
+}
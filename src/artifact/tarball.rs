@@ -1,18 +1,32 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
-use disk_drive::DiskDrive;
 use eyre::eyre;
 use eyre::Result;
 use flop::tar::TarFloppyDisk;
+use floppy_disk::mem::MemPermissions;
+use floppy_disk::prelude::*;
 use floppy_disk::tokio_fs::TokioFloppyDisk;
-use floppy_disk::FloppyDisk;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use smoosh::CompressionType;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio_tar_up2date::{EntryType, GnuExtSparseHeader, GnuSparseHeader, Header};
 use tracing::*;
 
-use crate::fs::MemFS;
+use crate::fs::{try_chown, MemFS, OwnerRemap, PathFilter, TempDir};
 use crate::util::config::Injection;
+use crate::util::sparse::{self, DataRegion};
+use crate::util::split;
+use crate::util::{compressor, decompress};
 
-use super::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+use super::{
+    Artifact, ArtifactKind, ArtifactProducer, Entry, EntryKind, ExtractedDir, SelfBuilder,
+    SelfValidation,
+};
 
 /// A tarball on the filesystem at the given path.
 #[derive(Debug, Clone)]
@@ -21,28 +35,347 @@ pub struct TarballArtifact {
     pub path: PathBuf,
 }
 
+impl TarballArtifact {
+    /// Where the whole tarball can be read from. Split tarballs are put back
+    /// together in a temporary directory first, which has to be kept around
+    /// for as long as the path is used.
+    async fn reassembled(&self) -> Result<(PathBuf, Option<TempDir>)> {
+        if !split::is_split(&self.path) {
+            return Ok((self.path.clone(), None));
+        }
+
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("reassembled");
+        split::reassemble(&self.path, &path).await?;
+        Ok((path, Some(tmp)))
+    }
+
+    /// Write the tarball, uncompressed, to `dest`.
+    async fn decompress_to(&self, dest: &Path) -> Result<()> {
+        let (path, _tmp) = self.reassembled().await?;
+        let mut input = tokio::fs::File::open(&path).await?;
+        let mut output = tokio::fs::File::create(dest).await?;
+        decompress(&mut input, &mut output).await?;
+        Ok(())
+    }
+
+    /// Read the tarball's entries into a memfs, skipping the ones the filter
+    /// doesn't keep. Entry names come from the tar reader rather than the
+    /// raw header, so that GNU long names and PAX paths aren't truncated.
+    pub(crate) async fn extract_filtered(&self, filter: &PathFilter) -> Result<MemFS> {
+        let memfs = MemFS::new();
+        let fs = memfs.fs();
+
+        let (path, _tmp) = self.reassembled().await?;
+        let mut file = tokio::fs::File::open(&path).await?;
+        let mut buffer = vec![];
+        decompress(&mut file, &mut buffer).await?;
+
+        let mut archive = tokio_tar_up2date::Archive::new(buffer.as_slice());
+        let mut entries = archive.entries()?;
+        while let Some(mut entry) = entries.try_next().await? {
+            let path = Path::new("/").join(
+                entry
+                    .path()?
+                    .components()
+                    .filter(|c| *c != std::path::Component::CurDir)
+                    .collect::<PathBuf>(),
+            );
+            if path == Path::new("/") {
+                continue;
+            }
+            if !filter.keeps(&path) {
+                continue;
+            }
+
+            debug!("extracting {}", path.display());
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            if let Some(parent) = path.parent() {
+                fs.create_dir_all(parent).await?;
+            }
+
+            if entry_type.is_dir() {
+                fs.create_dir_all(&path).await?;
+            } else if entry_type.is_symlink() {
+                let link = link_name(&mut entry).await?;
+                fs.symlink(link, path).await?;
+                continue;
+            } else if entry_type.is_hard_link() {
+                let link = Path::new("/").join(link_name(&mut entry).await?);
+                match fs.copy(&link, &path).await {
+                    Ok(_) => {}
+                    // The link target may just not have been selected.
+                    Err(_) if !filter.is_empty() => warn!(
+                        "skipping hard link to unselected path: {} -> {}",
+                        path.display(),
+                        link.display()
+                    ),
+                    Err(e) => return Err(e.into()),
+                }
+                continue;
+            } else if entry_type.is_file() || entry_type.is_gnu_sparse() {
+                let mut data = vec![];
+                entry.read_to_end(&mut data).await?;
+                fs.write(&path, data).await?;
+            } else {
+                continue;
+            }
+
+            let (uid, gid) = owner(&mut entry).await?;
+            fs.chown(&path, uid, gid).await?;
+            fs.set_permissions(&path, MemPermissions::from_mode(entry.header().mode()?))
+                .await?;
+        }
+
+        Ok(memfs)
+    }
+}
+
+/// The target of a link entry. The tar reader resolves GNU long link names
+/// itself, but not PAX `linkpath` records, so those are checked first.
+async fn link_name<R: tokio::io::AsyncRead + Unpin + Send>(
+    entry: &mut tokio_tar_up2date::Entry<R>,
+) -> Result<PathBuf> {
+    if let Some(extensions) = entry.pax_extensions().await? {
+        for extension in extensions {
+            let extension = extension?;
+            if extension.key() == Ok("linkpath") {
+                return Ok(PathBuf::from(OsStr::from_bytes(extension.value_bytes())));
+            }
+        }
+    }
+
+    Ok(entry.link_name()?.unwrap_or_default().to_path_buf())
+}
+
+/// The uid and gid of an entry. Ids too large for the header are stored in
+/// PAX `uid`/`gid` records, which take precedence over the header.
+async fn owner<R: tokio::io::AsyncRead + Unpin + Send>(
+    entry: &mut tokio_tar_up2date::Entry<R>,
+) -> Result<(u32, u32)> {
+    let header = entry.header();
+    let (mut uid, mut gid) = (header.uid()? as u32, header.gid()? as u32);
+    if let Some(extensions) = entry.pax_extensions().await? {
+        for extension in extensions {
+            let extension = extension?;
+            let id = || -> Result<u32> {
+                Ok(extension
+                    .value()
+                    .map_err(|e| eyre!("invalid PAX record: {e}"))?
+                    .parse()?)
+            };
+            match extension.key() {
+                Ok("uid") => uid = id()?,
+                Ok("gid") => gid = id()?,
+                _ => {}
+            }
+        }
+    }
+
+    Ok((uid, gid))
+}
+
+/// A tar entry as an [`Entry`], or `None` for the root and for entry types
+/// that aren't streamed.
+async fn stream_entry<R: tokio::io::AsyncRead + Unpin + Send>(
+    mut entry: tokio_tar_up2date::Entry<R>,
+) -> Result<Option<Entry>> {
+    let path = Path::new("/").join(
+        entry
+            .path()?
+            .components()
+            .filter(|c| *c != std::path::Component::CurDir)
+            .collect::<PathBuf>(),
+    );
+    if path == Path::new("/") {
+        return Ok(None);
+    }
+
+    let entry_type = entry.header().entry_type();
+    let kind = if entry_type.is_dir() {
+        EntryKind::Directory
+    } else if entry_type.is_symlink() {
+        EntryKind::Symlink(link_name(&mut entry).await?)
+    } else if entry_type.is_hard_link() {
+        EntryKind::HardLink(Path::new("/").join(link_name(&mut entry).await?))
+    } else if entry_type.is_file() || entry_type.is_gnu_sparse() {
+        let mut data = vec![];
+        entry.read_to_end(&mut data).await?;
+        EntryKind::File(data)
+    } else {
+        return Ok(None);
+    };
+
+    let (uid, gid) = owner(&mut entry).await?;
+    let header = entry.header();
+    Ok(Some(Entry {
+        path,
+        kind,
+        mode: header.mode()? & 0o7777,
+        uid,
+        gid,
+        mtime: Some(header.mtime()?),
+    }))
+}
+
 #[async_trait::async_trait]
 impl Artifact for TarballArtifact {
     fn name(&self) -> &str {
         &self.name
     }
 
-    async fn extract(&self) -> Result<MemFS> {
-        let fs = MemFS::new();
+    fn kind(&self) -> ArtifactKind {
+        ArtifactKind::Tarball
+    }
 
+    async fn extract(&self) -> Result<MemFS> {
         info!("unpacking {}", self.path.display());
-        let tarball = TarFloppyDisk::open(&self.path).await.unwrap();
-        DiskDrive::copy_between(&tarball, &*fs).await?;
+        self.extract_filtered(&PathFilter::default())
+            .await
+            .map_err(|e| {
+                eyre!(
+                    "failed to read tarball {}: {e} (is it actually a tar archive?)",
+                    self.path.display()
+                )
+            })
+    }
 
-        Ok(fs)
+    /// Entries are streamed straight to disk. Only the decompressed tarball
+    /// is written to a temporary file first, so that it never has to be held
+    /// in memory. Each entry is chowned to its owner in the tarball, and the
+    /// paths it couldn't be chowned for, ex. without root, are returned with
+    /// the owners they should have had.
+    async fn extract_to_dir(&self, dir: &Path) -> Result<ExtractedDir> {
+        info!("unpacking {} to {}", self.path.display(), dir.display());
+        let tmp = TempDir::new().await?;
+        let decompressed = tmp.path_view().join("decompressed.tar");
+        self.decompress_to(&decompressed).await?;
+
+        tokio::fs::create_dir_all(dir).await?;
+        let mut archive =
+            tokio_tar_up2date::Archive::new(tokio::fs::File::open(&decompressed).await?);
+        let unpacking = async {
+            // Like `Archive::unpack`, but the owners are kept too, or handed
+            // back like `MemFS::write_to_host` does when they can't be.
+            let mut unowned = vec![];
+            // Parents that the archive doesn't list are created too, and
+            // archives don't have to list parents first, so the paths are
+            // sorted.
+            let mut paths = BTreeSet::from([PathBuf::from("/")]);
+            let mut entries = archive.entries()?;
+            while let Some(entry) = entries.next().await {
+                let mut entry = entry?;
+                let path = Path::new("/").join(
+                    entry
+                        .path()?
+                        .components()
+                        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                        .collect::<PathBuf>(),
+                );
+                let (uid, gid) = owner(&mut entry).await?;
+                // Entries that would land outside of `dir` are skipped.
+                if !entry.unpack_in(dir).await? || path == Path::new("/") {
+                    continue;
+                }
+
+                paths.extend(path.ancestors().map(Path::to_path_buf));
+                let target = dir.join(path.strip_prefix("/")?);
+                let lchown =
+                    |path: &Path, uid, gid| std::os::unix::fs::lchown(path, Some(uid), Some(gid));
+                if let Some(owner) = try_chown(lchown, &path, &target, uid, gid)? {
+                    unowned.push(owner);
+                }
+            }
+
+            Ok::<_, eyre::Report>(ExtractedDir {
+                paths: paths.into_iter().collect(),
+                unowned,
+            })
+        };
+
+        unpacking.await.map_err(|e| {
+            eyre!(
+                "failed to unpack tarball {}: {e} (is it actually a tar archive?)",
+                self.path.display()
+            )
+        })
+    }
+
+    /// The size of the decompressed tarball, which is a close upper bound on
+    /// its contents, since tar only adds headers and padding. Uncompressed
+    /// tarballs are already that size on disk; others are decompressed to
+    /// count them, but none of it is kept.
+    async fn extracted_size(&self) -> Result<Option<u64>> {
+        if !split::is_split(&self.path) && is_uncompressed_tar(&self.path).await? {
+            return self.stored_size().await;
+        }
+
+        let (path, _tmp) = self.reassembled().await?;
+        let mut input = tokio::fs::File::open(&path).await?;
+        let (mut writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let decompressing = async move {
+            decompress(&mut input, &mut writer).await?;
+            writer.shutdown().await?;
+            Ok::<_, eyre::Report>(())
+        };
+        let counting = async {
+            Ok::<_, eyre::Report>(tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?)
+        };
+        let ((), size) = tokio::try_join!(decompressing, counting)?;
+
+        Ok(Some(size))
+    }
+
+    /// Like [`TarballArtifact::extract_to_dir`], only the decompressed
+    /// tarball is written to a temporary file, and entries are read from it
+    /// in archive order as they're asked for. Entry types that a memfs can't
+    /// hold, like devices, are skipped.
+    async fn stream_entries(&self) -> Result<BoxStream<'_, Result<Entry>>> {
+        let tmp = TempDir::new().await?;
+        let decompressed = tmp.path_view().join("decompressed.tar");
+        self.decompress_to(&decompressed).await?;
+
+        let mut archive =
+            tokio_tar_up2date::Archive::new(tokio::fs::File::open(&decompressed).await?);
+        let entries = archive.entries()?;
+        // The temporary directory goes along with the stream, so that the
+        // decompressed tarball is around until the last entry is read.
+        Ok(
+            futures_util::stream::try_unfold((entries, tmp), |(mut entries, tmp)| async move {
+                while let Some(entry) = entries.try_next().await? {
+                    if let Some(entry) = stream_entry(entry).await? {
+                        return Ok(Some((entry, (entries, tmp))));
+                    }
+                }
+                Ok(None)
+            })
+            .boxed(),
+        )
+    }
+
+    /// Only the selected entries are read into memory. smoosh can't seek
+    /// within compressed streams, so the tarball is still decompressed in
+    /// full.
+    async fn extract_paths(&self, paths: &[PathBuf]) -> Result<MemFS> {
+        self.extract_filtered(&PathFilter {
+            include: paths.to_vec(),
+            exclude: vec![],
+        })
+        .await
     }
 
     fn try_clone(&self) -> Result<Box<dyn Artifact>> {
         Ok(Box::new(self.clone()))
     }
 
+    /// Split tarballs are their manifest and parts.
     fn paths(&self) -> Option<Vec<PathBuf>> {
-        Some(vec![self.path.clone()])
+        if split::is_split(&self.path) {
+            Some(split::split_paths(&self.path))
+        } else {
+            Some(vec![self.path.clone()])
+        }
     }
 }
 
@@ -51,11 +384,15 @@ impl SelfValidation for TarballArtifact {
     async fn validate(&self) -> Result<()> {
         let mut errors = vec![];
 
-        if !self.path.exists() {
+        if split::is_split(&self.path) {
+            for part in split::split_paths(&self.path) {
+                if !part.is_file() {
+                    errors.push(format!("part is missing: {:?}", part));
+                }
+            }
+        } else if !self.path.exists() {
             errors.push(format!("path does not exist: {:?}", self.path));
-        }
-
-        if !self.path.is_file() {
+        } else if !self.path.is_file() {
             errors.push(format!("path is not a file: {:?}", self.path));
         }
 
@@ -98,12 +435,55 @@ impl SelfBuilder for TarballArtifactBuilder {
     }
 }
 
+/// Open a tarball for writing. If the path doesn't exist yet, flop creates
+/// the empty tarball itself, but finishes it from a background task that can
+/// race with -- and zero out the start of -- the archive written on close. To
+/// avoid that, we write the empty tarball ourselves first.
+pub(crate) async fn open_new_tarball(path: &Path) -> Result<TarFloppyDisk> {
+    if !path.exists() {
+        // An empty tarball is just two zeroed blocks.
+        tokio::fs::write(path, [0u8; 1024]).await?;
+    }
+
+    Ok(TarFloppyDisk::open(path).await?)
+}
+
+/// How entry names are written in a tarball.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryNames {
+    /// `usr/bin/x`. This is what most tarballs, and Arch packages, use.
+    #[default]
+    Relative,
+    /// `./usr/bin/x`, with a `./` entry for the root. This is what Debian
+    /// expects in a .deb's data.tar.
+    DotRelative,
+}
+
 /// Produces a tarball at the given path on the filesystem.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TarballProducer {
     pub name: String,
     pub path: PathBuf,
+    #[serde(serialize_with = "crate::util::config::serialize_compression")]
     pub compression: CompressionType,
+    /// Store files with large zero-filled regions as GNU sparse entries.
+    pub sparse: bool,
+    pub entry_names: EntryNames,
+    /// The mtime of every file and symlink entry, instead of the memfs's.
+    pub file_mtime: Option<u64>,
+    /// The mtime of every directory entry, instead of the memfs's. Handy for
+    /// zeroing out directory times, which change whenever their contents do.
+    pub dir_mtime: Option<u64>,
+    /// Compress like `gzip --rsyncable`, so that small changes to the input
+    /// only change a small part of the output. Only works with gzip.
+    pub rsyncable: bool,
+    /// Give every entry this owner, after the injections are applied.
+    pub remap_owner: Option<OwnerRemap>,
+    /// Split the tarball into `<path>.part0`, `<path>.part1`, ... of at most
+    /// this many bytes, listed in order in `<path>.parts`, ex. for upload
+    /// limits. The tarball itself isn't kept.
+    pub split_size: Option<u64>,
     pub injections: Vec<Injection>,
 }
 
@@ -120,17 +500,70 @@ impl ArtifactProducer for TarballProducer {
     }
 
     async fn produce_from(&self, previous: &dyn Artifact) -> Result<TarballArtifact> {
+        self.produce_from_memfs(previous.extract().await?).await
+    }
+
+    async fn produce_from_owned(&self, previous: Box<dyn Artifact>) -> Result<TarballArtifact> {
+        self.produce_from_memfs(previous.into_memfs().await?).await
+    }
+}
+
+impl TarballProducer {
+    async fn produce_from_memfs(&self, mut memfs: MemFS) -> Result<TarballArtifact> {
         info!("producing {}", self.path.display());
-        let mut memfs = previous.extract().await?;
         self.inject(&mut memfs).await?;
+        if let Some(remap) = &self.remap_owner {
+            memfs.remap_owners(remap).await?;
+        }
 
         if let Some(parent) = self.path.parent() {
             tokio::fs::create_dir_all(parent).await?;
         }
 
-        let tarball = TarFloppyDisk::open(&self.path).await?;
-        DiskDrive::copy_between(&*memfs, &tarball).await?;
-        tarball.close().await?;
+        // Split tarballs are written straight to their parts, so the whole
+        // tarball is never on disk.
+        let mut output: Box<dyn AsyncWrite + Unpin + Send> = match self.split_size {
+            Some(size) => Box::new(split::SplitWriter::new(&self.path, size)?),
+            None => Box::new(BufWriter::new(tokio::fs::File::create(&self.path).await?)),
+        };
+
+        // flop writes plain ustar headers, which silently truncate names that
+        // don't fit, so tarballs are always written by hand.
+        let mut output = if self.rsyncable {
+            // Rsyncable chunks are cut by looking back over the uncompressed
+            // tarball, so it's built in memory first.
+            let tarball = build_tarball(
+                &memfs,
+                vec![],
+                self.sparse,
+                self.entry_names,
+                self.file_mtime,
+                self.dir_mtime,
+            )
+            .await?;
+            output
+                .write_all(&crate::util::gzip_rsyncable(&tarball)?)
+                .await?;
+            output
+        } else {
+            build_tarball(
+                &memfs,
+                compressor(output, self.compression),
+                self.sparse,
+                self.entry_names,
+                self.file_mtime,
+                self.dir_mtime,
+            )
+            .await?
+        };
+        output.shutdown().await?;
+        if self.split_size.is_some() {
+            info!(
+                "split {} as listed in {}",
+                self.path.display(),
+                split::manifest_path(&self.path).display()
+            );
+        }
 
         Ok(TarballArtifact {
             name: self.path.to_string_lossy().to_string(),
@@ -142,10 +575,33 @@ impl ArtifactProducer for TarballProducer {
 #[async_trait::async_trait]
 impl SelfValidation for TarballProducer {
     async fn validate(&self) -> Result<()> {
+        if self.rsyncable && self.compression != CompressionType::Gzip {
+            return Err(eyre!(
+                "cannot produce artifact '{}': rsyncable only works with gzip compression",
+                self.name
+            ));
+        }
+
+        if self.split_size == Some(0) {
+            return Err(eyre!(
+                "cannot produce artifact '{}': split_size must be greater than 0",
+                self.name
+            ));
+        }
+
         if let Some(parent) = self.path.parent() {
             tokio::fs::create_dir_all(parent).await?;
         }
 
+        let manifest = split::manifest_path(&self.path);
+        if self.split_size.is_some() && manifest.exists() {
+            return Err(eyre!(
+                "cannot produce artifact '{}': path already exists: {}",
+                self.name,
+                manifest.display()
+            ));
+        }
+
         if TokioFloppyDisk::new(None)
             .metadata(&self.path)
             .await
@@ -162,10 +618,280 @@ impl SelfValidation for TarballProducer {
     }
 }
 
+/// Build an uncompressed tarball of the memfs, optionally storing files with
+/// large holes as GNU sparse entries. flop can only write dense entries with
+/// relative names, so this writes the entries itself, the same way flop does.
+async fn build_tarball<W: AsyncWrite + Unpin + Send + 'static>(
+    memfs: &MemFS,
+    writer: W,
+    sparse: bool,
+    entry_names: EntryNames,
+    file_mtime: Option<u64>,
+    dir_mtime: Option<u64>,
+) -> Result<W> {
+    let fs = memfs.fs();
+    let mut archive = tokio_tar_up2date::Builder::new(writer);
+
+    // Only `./`-relative tarballs have an entry for the root.
+    let root = (entry_names == EntryNames::DotRelative).then(|| PathBuf::from("/"));
+    let entry_paths = nyoom::walk_ordered(fs, "/")
+        .await?
+        .into_iter()
+        .filter(|entry_path| entry_path != Path::new("/"));
+
+    for entry_path in root.into_iter().chain(entry_paths) {
+        let name = entry_path.strip_prefix("/").unwrap_or(&entry_path);
+        let mut header = Header::new_gnu();
+        let mtime = if fs.symlink_metadata(&entry_path).await?.is_dir() {
+            dir_mtime
+        } else {
+            file_mtime
+        };
+        header.set_mtime(mtime.or_else(|| memfs.mtime(&entry_path)).unwrap_or(0));
+        if let (Some(atime), Some(gnu)) = (memfs.atime(&entry_path), header.as_gnu_mut()) {
+            gnu.set_atime(atime);
+        }
+
+        if let Ok(link) = fs.read_link(&entry_path).await {
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            let link = link.as_os_str().as_encoded_bytes().to_vec();
+            let link_field = &mut header.as_old_mut().linkname;
+            let pax = (!write_name_field(link_field, &link)).then_some(("linkpath", link));
+            append_entry(
+                &mut archive,
+                &mut header,
+                name,
+                entry_names,
+                pax.into_iter().collect(),
+                &[][..],
+            )
+            .await?;
+            continue;
+        }
+
+        // Installing a package shouldn't change the root directory, so its
+        // entry is always root-owned 0755, regardless of the memfs.
+        if entry_path == Path::new("/") {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_size(0);
+            append_entry(
+                &mut archive,
+                &mut header,
+                name,
+                entry_names,
+                vec![],
+                &[][..],
+            )
+            .await?;
+            continue;
+        }
+
+        let metadata = fs.metadata(&entry_path).await?;
+        header.set_mode(metadata.permissions().mode());
+        let owner = owner_records(&mut header, metadata.uid()?, metadata.gid()?);
+
+        if metadata.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            header.set_size(0);
+            append_entry(&mut archive, &mut header, name, entry_names, owner, &[][..]).await?;
+            continue;
+        }
+
+        let data = fs.read(&entry_path).await?;
+        let regions = if sparse {
+            sparse::data_regions(&data)
+        } else {
+            None
+        };
+        match regions {
+            Some(regions) => {
+                debug!(
+                    "writing sparse file: {} ({} regions)",
+                    entry_path.display(),
+                    regions.len()
+                );
+                let payload = sparse_entry(&mut header, &data, &regions);
+                append_entry(
+                    &mut archive,
+                    &mut header,
+                    name,
+                    entry_names,
+                    owner,
+                    payload.as_slice(),
+                )
+                .await?;
+            }
+            None => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(data.len() as u64);
+                append_entry(
+                    &mut archive,
+                    &mut header,
+                    name,
+                    entry_names,
+                    owner,
+                    data.as_slice(),
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(archive.into_inner().await?)
+}
+
+/// Append an entry with its name in the given style. Names are written into
+/// the header directly, since the tar builder normalizes `./` out of them.
+/// Names that don't fit in the header, and any `extra` records, are written in
+/// a PAX extended header before the entry.
+async fn append_entry<W: AsyncWrite + Unpin + Send + 'static>(
+    archive: &mut tokio_tar_up2date::Builder<W>,
+    header: &mut Header,
+    name: &Path,
+    entry_names: EntryNames,
+    extra: Vec<(&str, Vec<u8>)>,
+    data: &[u8],
+) -> Result<()> {
+    let mut name_bytes = match entry_names {
+        EntryNames::Relative => vec![],
+        EntryNames::DotRelative => b"./".to_vec(),
+    };
+    name_bytes.extend_from_slice(name.as_os_str().as_encoded_bytes());
+
+    let mut records = vec![];
+    if !write_name_field(&mut header.as_old_mut().name, &name_bytes) {
+        records.push(("path", name_bytes));
+    }
+    records.extend(extra);
+
+    if !records.is_empty() {
+        let mut pax_data = vec![];
+        for (key, value) in &records {
+            pax_data.extend(pax_record(key, value));
+        }
+
+        let mut pax = Header::new_ustar();
+        pax.as_old_mut().name[..14].copy_from_slice(b"././@PaxHeader");
+        pax.set_entry_type(EntryType::XHeader);
+        pax.set_mode(0o644);
+        pax.set_size(pax_data.len() as u64);
+        pax.set_cksum();
+        archive.append(&pax, pax_data.as_slice()).await?;
+    }
+
+    header.set_cksum();
+    archive.append(header, data).await?;
+
+    Ok(())
+}
+
+/// The largest id that fits in a header's 7 octal digits.
+const MAX_OCTAL_ID: u32 = 0o7777777;
+
+/// Set the entry's owner. Ids too large for octal are also written as PAX
+/// `uid`/`gid` records, since not every tar reader understands the GNU
+/// base-256 encoding the header falls back to.
+fn owner_records(header: &mut Header, uid: u32, gid: u32) -> Vec<(&'static str, Vec<u8>)> {
+    header.set_uid(uid.into());
+    header.set_gid(gid.into());
+
+    [("uid", uid), ("gid", gid)]
+        .into_iter()
+        .filter(|(_, id)| *id > MAX_OCTAL_ID)
+        .map(|(key, id)| (key, id.to_string().into_bytes()))
+        .collect()
+}
+
+/// Write as much of `value` as fits into a NUL-padded header field. Returns
+/// whether all of it fit.
+fn write_name_field(field: &mut [u8], value: &[u8]) -> bool {
+    let len = value.len().min(field.len());
+    field.fill(0);
+    field[..len].copy_from_slice(&value[..len]);
+    value.len() <= field.len()
+}
+
+/// A PAX extended header record, `<length> <key>=<value>\n`, where the length
+/// counts the whole record, including its own digits.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+
+    let mut record = format!("{len} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Fill in a GNU sparse header for the given regions, and return the entry's
+/// payload: any extended sparse headers, followed by the data regions.
+fn sparse_entry(header: &mut Header, data: &[u8], regions: &[DataRegion]) -> Vec<u8> {
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(regions.iter().map(|r| r.length).sum());
+
+    let gnu = header.as_gnu_mut().unwrap();
+    set_numeric_field(&mut gnu.realsize, data.len() as u64);
+
+    let (inline, extended) = regions.split_at(regions.len().min(gnu.sparse.len()));
+    for (slot, region) in gnu.sparse.iter_mut().zip(inline) {
+        set_sparse_header(slot, region);
+    }
+
+    let mut payload = vec![];
+    let chunks: Vec<_> = extended.chunks(21).collect();
+    gnu.isextended[0] = !chunks.is_empty() as u8;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut ext = GnuExtSparseHeader::new();
+        for (slot, region) in ext.sparse.iter_mut().zip(*chunk) {
+            set_sparse_header(slot, region);
+        }
+        ext.isextended[0] = (i + 1 < chunks.len()) as u8;
+        payload.extend_from_slice(ext.as_bytes());
+    }
+
+    for region in regions {
+        let start = region.offset as usize;
+        payload.extend_from_slice(&data[start..start + region.length as usize]);
+    }
+
+    payload
+}
+
+fn set_sparse_header(header: &mut GnuSparseHeader, region: &DataRegion) {
+    set_numeric_field(&mut header.offset, region.offset);
+    set_numeric_field(&mut header.numbytes, region.length);
+}
+
+/// Tar numeric fields are NUL-terminated octal, or big-endian binary with the
+/// high bit set for values that don't fit.
+fn set_numeric_field(field: &mut [u8; 12], value: u64) {
+    if value < 8u64.pow(11) {
+        field.copy_from_slice(format!("{value:011o}\0").as_bytes());
+    } else {
+        field.fill(0);
+        field[0] = 0x80;
+        field[4..].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
 pub struct TarballProducerBuilder {
     name: String,
     path: PathBuf,
     compression: CompressionType,
+    sparse: bool,
+    entry_names: EntryNames,
+    file_mtime: Option<u64>,
+    dir_mtime: Option<u64>,
+    rsyncable: bool,
+    remap_owner: Option<OwnerRemap>,
+    split_size: Option<u64>,
     injections: Vec<Injection>,
 }
 
@@ -181,6 +907,41 @@ impl TarballProducerBuilder {
         self
     }
 
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn entry_names(mut self, entry_names: EntryNames) -> Self {
+        self.entry_names = entry_names;
+        self
+    }
+
+    pub fn file_mtime(mut self, file_mtime: u64) -> Self {
+        self.file_mtime = Some(file_mtime);
+        self
+    }
+
+    pub fn dir_mtime(mut self, dir_mtime: u64) -> Self {
+        self.dir_mtime = Some(dir_mtime);
+        self
+    }
+
+    pub fn rsyncable(mut self, rsyncable: bool) -> Self {
+        self.rsyncable = rsyncable;
+        self
+    }
+
+    pub fn remap_owner(mut self, remap_owner: OwnerRemap) -> Self {
+        self.remap_owner = Some(remap_owner);
+        self
+    }
+
+    pub fn split_size(mut self, split_size: u64) -> Self {
+        self.split_size = Some(split_size);
+        self
+    }
+
     pub fn inject(mut self, injection: Injection) -> Self {
         self.injections.push(injection);
         self
@@ -195,6 +956,13 @@ impl SelfBuilder for TarballProducerBuilder {
             name: name.into(),
             path: PathBuf::from(""),
             compression: CompressionType::None,
+            sparse: false,
+            entry_names: EntryNames::Relative,
+            file_mtime: None,
+            dir_mtime: None,
+            rsyncable: false,
+            remap_owner: None,
+            split_size: None,
             injections: vec![],
         }
     }
@@ -204,7 +972,648 @@ impl SelfBuilder for TarballProducerBuilder {
             name: self.name.clone(),
             path: self.path.clone(),
             compression: self.compression,
+            sparse: self.sparse,
+            entry_names: self.entry_names,
+            file_mtime: self.file_mtime,
+            dir_mtime: self.dir_mtime,
+            rsyncable: self.rsyncable,
+            remap_owner: self.remap_owner,
+            split_size: self.split_size,
             injections: self.injections.clone(),
         })
     }
 }
+
+/// Whether the file starts with a ustar or GNU tar header, rather than
+/// compressed data.
+async fn is_uncompressed_tar(path: &Path) -> Result<bool> {
+    let mut header = [0u8; 262];
+    let mut file = tokio::fs::File::open(path).await?;
+    match file.read_exact(&mut header).await {
+        Ok(_) => Ok(&header[257..262] == b"ustar"),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::arch::ArchProducerBuilder;
+    use crate::artifact::deb::DebProducerBuilder;
+    use crate::artifact::memory::{EmptyArtifact, MemoryArtifact};
+    use crate::fs::TempDir;
+    use crate::util::compress;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_stored_size_does_not_extract() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        // Not a valid tarball, so this would fail if it were extracted.
+        let tar = tmp.path_view().join("garbage.tar.gz");
+        tokio::fs::write(&tar, vec![0xff; 1234]).await?;
+
+        let artifact = TarballArtifact {
+            name: "garbage".into(),
+            path: tar,
+        };
+        assert_eq!(Some(1234), artifact.stored_size().await?);
+        assert_eq!(None, EmptyArtifact::new("empty").stored_size().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extracted_size_of_an_uncompressed_tarball_is_its_stored_size() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        memfs.fs().write("/file", vec![b'a'; 64 * 1024]).await?;
+
+        let mut sizes = vec![];
+        for (name, compression) in [
+            ("plain.tar", CompressionType::None),
+            ("compressed.tar.gz", CompressionType::Gzip),
+        ] {
+            let artifact = TarballProducerBuilder::new(name)
+                .path(tmp.path_view().join(name))
+                .compression(compression)
+                .build()?
+                .produce_from(&MemoryArtifact {
+                    name: "memory".into(),
+                    fs: memfs.clone(),
+                })
+                .await?;
+            sizes.push((
+                artifact.stored_size().await?,
+                artifact.extracted_size().await?,
+            ));
+        }
+
+        assert_eq!(sizes[0].0, sizes[0].1);
+        assert!(sizes[1].0 < sizes[1].1);
+        assert_eq!(sizes[0].1, sizes[1].1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sparse_tarball_is_smaller() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("sparse.tar");
+
+        // 8MB disk image with a little bit of data at the start and the end.
+        let mut image = vec![0u8; 8 * 1024 * 1024];
+        image[..4].copy_from_slice(b"boot");
+        let len = image.len();
+        image[len - 4..].copy_from_slice(b"tail");
+
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/images").await?;
+        (*memfs).write("/images/disk.img", &image).await?;
+
+        let artifact = TarballProducerBuilder::new("sparse tarball")
+            .path(&tar)
+            .sparse(true)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let tar_size = tokio::fs::metadata(&tar).await?.len();
+        assert!(tar_size < image.len() as u64 / 100);
+
+        let extracted = artifact.extract().await?;
+        assert_eq!((*extracted).read("/images/disk.img").await?, image);
+
+        Ok(())
+    }
+
+    async fn tar_entry_names(tar: &[u8]) -> Result<Vec<String>> {
+        let mut buffer = vec![];
+        smoosh::recompress(&mut &*tar, &mut buffer, CompressionType::None).await?;
+
+        let mut archive = tokio_tar_up2date::Archive::new(buffer.as_slice());
+        let mut entries = archive.entries()?;
+        let mut names = vec![];
+        while let Some(entry) = entries.try_next().await? {
+            names.push(String::from_utf8(entry.path_bytes().to_vec())?);
+        }
+
+        Ok(names)
+    }
+
+    #[tokio::test]
+    async fn test_entry_names_follow_format_convention() -> Result<()> {
+        let tmp = TempDir::new().await?;
+
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/usr/bin").await?;
+        (*memfs).write("/usr/bin/x", b"x").await?;
+        let input = MemoryArtifact {
+            name: "memory".into(),
+            fs: memfs,
+        };
+
+        let deb = DebProducerBuilder::new("names")
+            .path(tmp.path_view().join("names.deb"))
+            .package_name("names")
+            .package_maintainer("peckish <peckish@example.com>")
+            .package_architecture("amd64")
+            .package_version("0.1.0-1")
+            .package_description("names")
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        let deb_bytes = tokio::fs::read(&deb.path).await?;
+        let mut ar = ar::Archive::new(deb_bytes.as_slice());
+        let mut data_tar = vec![];
+        while let Some(entry) = ar.next_entry() {
+            let mut entry = entry?;
+            if entry.header().identifier().starts_with(b"data.tar") {
+                std::io::Read::read_to_end(&mut entry, &mut data_tar)?;
+            }
+        }
+
+        assert_eq!(
+            tar_entry_names(&data_tar).await?,
+            vec!["./", "./usr", "./usr/bin", "./usr/bin/x"]
+        );
+
+        let extracted = deb.extract().await?;
+        assert_eq!((*extracted).read("/usr/bin/x").await?, b"x");
+
+        let arch = ArchProducerBuilder::new("names")
+            .path(tmp.path_view().join("names.pkg.tar"))
+            .package_name("names")
+            .package_ver("0.1.0-1")
+            .package_desc("names")
+            .package_author("peckish <peckish@example.com>")
+            .package_arch("x86_64")
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        let names = tar_entry_names(&tokio::fs::read(&arch.path).await?).await?;
+        assert!(names.contains(&"usr/bin/x".to_string()));
+        assert!(names
+            .iter()
+            .all(|name| !name.starts_with("./") && !name.starts_with('/')));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_long_dot_relative_entry_names() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("long.tar");
+        let long_path = format!("/{}/file", "a".repeat(120));
+
+        let memfs = MemFS::new();
+        (*memfs)
+            .create_dir_all(format!("/{}", "a".repeat(120)))
+            .await?;
+        (*memfs).write(&long_path, b"long").await?;
+
+        TarballProducerBuilder::new("long names")
+            .path(&tar)
+            .entry_names(EntryNames::DotRelative)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let names = tar_entry_names(&tokio::fs::read(&tar).await?).await?;
+        assert_eq!(names.last().unwrap(), &format!(".{long_path}"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_long_names_survive_roundtrip() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("long-roundtrip.tar.gz");
+        let dir = format!("/{}/{}", "b".repeat(90), "c".repeat(90));
+        let long_path = format!("{dir}/{}", "d".repeat(17));
+        let long_link = format!("{dir}/link");
+        assert_eq!(long_path.len(), 200);
+
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all(&dir).await?;
+        (*memfs).write(&long_path, b"long").await?;
+        (*memfs).symlink(&long_path, &long_link).await?;
+
+        TarballProducerBuilder::new("long names")
+            .path(&tar)
+            .compression(CompressionType::Gzip)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let mut raw = vec![];
+        smoosh::recompress(
+            &mut tokio::fs::File::open(&tar).await?,
+            &mut raw,
+            CompressionType::None,
+        )
+        .await?;
+        let pax_path = format!("path={}", &long_path[1..]);
+        assert!(raw
+            .windows(pax_path.len())
+            .any(|window| window == pax_path.as_bytes()));
+
+        let extracted = TarballArtifact {
+            name: "long names".into(),
+            path: tar,
+        }
+        .extract()
+        .await?;
+        assert_eq!((*extracted).read(&long_path).await?, b"long");
+        assert_eq!(
+            (*extracted).read_link(&long_link).await?,
+            PathBuf::from(&long_path)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_paths_only_extracts_selected_paths() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("partial.tar");
+
+        let memfs = MemFS::new();
+        for dir in ["/usr/bin", "/usr/share/doc/x", "/etc"] {
+            (*memfs).create_dir_all(dir).await?;
+        }
+        for file in [
+            "/usr/bin/a",
+            "/usr/bin/b",
+            "/usr/share/doc/x/README",
+            "/usr/share/doc/x/LICENSE",
+            "/etc/conf",
+        ] {
+            (*memfs).write(file, file.as_bytes()).await?;
+        }
+        let input = MemoryArtifact {
+            name: "memory".into(),
+            fs: memfs,
+        };
+
+        let artifact = TarballProducerBuilder::new("partial")
+            .path(&tar)
+            .build()?
+            .produce_from(&input)
+            .await?;
+
+        let selected = vec![PathBuf::from("/usr/bin/a"), PathBuf::from("usr/share/doc")];
+        let expected: Vec<PathBuf> = [
+            "/usr",
+            "/usr/bin",
+            "/usr/bin/a",
+            "/usr/share",
+            "/usr/share/doc",
+            "/usr/share/doc/x",
+            "/usr/share/doc/x/LICENSE",
+            "/usr/share/doc/x/README",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        // Both the tarball override and the default implementation.
+        for extracted in [
+            artifact.extract_paths(&selected).await?,
+            input.extract_paths(&selected).await?,
+        ] {
+            let mut paths = nyoom::walk_ordered(&*extracted, "/")
+                .await?
+                .into_iter()
+                .collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(paths, expected);
+            assert_eq!((*extracted).read("/usr/bin/a").await?, b"/usr/bin/a");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extracting_a_non_tarball_is_an_error() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("not-a-tarball.tar");
+        tokio::fs::write(&path, "this is just some text, not a tar archive\n").await?;
+
+        let artifact = TarballArtifact {
+            name: "not a tarball".into(),
+            path: path.clone(),
+        };
+
+        let error = artifact.extract().await.unwrap_err().to_string();
+        assert!(error.contains(&path.display().to_string()));
+        assert!(error.contains("is it actually a tar archive?"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_touch_sets_the_given_timestamps() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tarball = TarballProducerBuilder::new("touched")
+            .path(tmp.path_view().join("touched.tar"))
+            .inject(Injection::Touch {
+                path: "/var/lib/marker".into(),
+                mtime: Some(1_600_000_000),
+                atime: Some(1_600_000_100),
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let data = tokio::fs::read(&tarball.path).await?;
+        let mut archive = tokio_tar_up2date::Archive::new(data.as_slice());
+        let mut entries = archive.entries()?;
+        while let Some(entry) = futures_util::TryStreamExt::try_next(&mut entries).await? {
+            if entry.path()?.as_ref() == Path::new("var/lib/marker") {
+                let header = entry.header();
+                assert_eq!(1_600_000_000, header.mtime()?);
+                assert_eq!(1_600_000_100, header.as_gnu().unwrap().atime()?);
+                return Ok(());
+            }
+        }
+
+        panic!("touched file is missing from the tarball");
+    }
+
+    #[tokio::test]
+    async fn test_large_ids_survive_a_roundtrip() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/srv").await?;
+        memfs.fs().write("/srv/mapped", b"mapped").await?;
+        memfs.fs().chown("/srv", 5_000_000, 5_000_000).await?;
+        memfs.fs().chown("/srv/mapped", 5_000_000, 1000).await?;
+
+        let artifact = TarballProducerBuilder::new("tarball")
+            .path(tmp.path_view().join("mapped.tar"))
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let raw = tokio::fs::read(&artifact.path).await?;
+        assert!(raw.windows(12).any(|window| window == b"uid=5000000\n"));
+        // Only the id that's too large for the header gets a record.
+        assert!(!raw.windows(8).any(|window| window == b"gid=1000"));
+
+        let extracted = artifact.extract().await?;
+        let metadata = extracted.fs().metadata("/srv/mapped").await?;
+        assert_eq!(5_000_000, metadata.uid()?);
+        assert_eq!(1000, metadata.gid()?);
+        assert_eq!(5_000_000, extracted.fs().metadata("/srv").await?.gid()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_large_ids_survive_extract_to_dir() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/srv").await?;
+        memfs.fs().write("/srv/mapped", b"mapped").await?;
+        memfs
+            .fs()
+            .chown("/srv/mapped", 5_000_000, 6_000_000)
+            .await?;
+
+        let artifact = TarballProducerBuilder::new("tarball")
+            .path(tmp.path_view().join("mapped.tar"))
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        // Without root, the owner is handed back instead of set.
+        let dir = tmp.path_view().join("out");
+        let extracted = artifact.extract_to_dir(&dir).await?;
+        let owner = match extracted
+            .unowned
+            .iter()
+            .find(|owner| owner.path == Path::new("/srv/mapped"))
+        {
+            Some(owner) => (owner.uid, owner.gid),
+            None => {
+                let metadata = tokio::fs::symlink_metadata(dir.join("srv/mapped")).await?;
+                (metadata.uid(), metadata.gid())
+            }
+        };
+        assert_eq!((5_000_000, 6_000_000), owner);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dir_mtime_is_separate_from_file_mtime() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tarball = TarballProducerBuilder::new("mtimes")
+            .path(tmp.path_view().join("mtimes.tar"))
+            .dir_mtime(0)
+            .file_mtime(1_700_000_000)
+            .inject(Injection::Touch {
+                path: "/usr/bin/app".into(),
+                mtime: Some(1_600_000_000),
+                atime: None,
+            })
+            .build()?
+            .produce_from(&EmptyArtifact::new("empty"))
+            .await?;
+
+        let data = tokio::fs::read(&tarball.path).await?;
+        let mut archive = tokio_tar_up2date::Archive::new(data.as_slice());
+        let mut entries = archive.entries()?;
+        let mut mtimes = vec![];
+        while let Some(entry) = futures_util::TryStreamExt::try_next(&mut entries).await? {
+            mtimes.push((
+                entry.path()?.to_string_lossy().to_string(),
+                entry.header().mtime()?,
+            ));
+        }
+        assert_eq!(
+            vec![
+                ("usr".to_string(), 0),
+                ("usr/bin".to_string(), 0),
+                ("usr/bin/app".to_string(), 1_700_000_000),
+            ],
+            mtimes
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_tarball_reassembles() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/data").await?;
+        let data = (0..6_000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        (*memfs).write("/data/file.bin", &data).await?;
+        let input = MemoryArtifact {
+            name: "memory".into(),
+            fs: memfs,
+        };
+
+        let whole = TarballProducerBuilder::new("whole")
+            .path(tmp.path_view().join("whole.tar"))
+            .build()?
+            .produce_from(&input)
+            .await?;
+        let whole = tokio::fs::read(&whole.path).await?;
+
+        let path = tmp.path_view().join("split.tar");
+        let producer = TarballProducerBuilder::new("split")
+            .path(&path)
+            .split_size((whole.len() as u64).div_ceil(3))
+            .build()?;
+        producer.validate().await?;
+        let split = producer.produce_from(&input).await?;
+
+        assert!(!path.exists());
+        let paths = split.paths().unwrap();
+        assert_eq!(
+            vec![
+                split::manifest_path(&path),
+                split::part_path(&path, 0),
+                split::part_path(&path, 1),
+                split::part_path(&path, 2),
+            ],
+            paths
+        );
+        split.validate().await?;
+        assert_eq!(
+            Some(whole.len() as u64 + tokio::fs::metadata(&paths[0]).await?.len()),
+            split.stored_size().await?
+        );
+
+        let reassembled = tmp.path_view().join("reassembled.tar");
+        split::reassemble(&path, &reassembled).await?;
+        assert_eq!(whole, tokio::fs::read(&reassembled).await?);
+
+        let memfs = split.extract().await?;
+        assert_eq!(data, (*memfs).read("/data/file.bin").await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_entries_reads_every_entry() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        for dir in 0..10 {
+            (*memfs).create_dir_all(format!("/data/{dir}")).await?;
+            for file in 0..20 {
+                (*memfs)
+                    .write(
+                        format!("/data/{dir}/{file}.bin"),
+                        vec![dir as u8; 64 * 1024],
+                    )
+                    .await?;
+            }
+        }
+        (*memfs).symlink("/data/0/0.bin", "/data/link").await?;
+
+        let tarball = TarballProducerBuilder::new("large")
+            .path(tmp.path_view().join("large.tar.gz"))
+            .compression(CompressionType::Gzip)
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+
+        let (mut dirs, mut files, mut links, mut bytes) = (0, 0, 0, 0);
+        let mut entries = tarball.stream_entries().await?;
+        while let Some(entry) = entries.try_next().await? {
+            match entry.kind {
+                EntryKind::Directory => dirs += 1,
+                EntryKind::File(data) => {
+                    files += 1;
+                    bytes += data.len();
+                }
+                EntryKind::Symlink(target) => {
+                    assert_eq!(Path::new("/data/0/0.bin"), target);
+                    links += 1;
+                }
+                EntryKind::HardLink(_) => unreachable!(),
+            }
+        }
+        assert_eq!((11, 200, 1), (dirs, files, links));
+        assert_eq!(200 * 64 * 1024, bytes);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tarball_split_across_gzip_members_is_fully_read() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/data").await?;
+        for i in 0..8 {
+            (*memfs)
+                .write(format!("/data/{i}"), vec![i as u8; 16 * 1024])
+                .await?;
+        }
+        let tar = TarballProducerBuilder::new("plain")
+            .path(tmp.path_view().join("plain.tar"))
+            .build()?
+            .produce_from(&MemoryArtifact {
+                name: "memory".into(),
+                fs: memfs,
+            })
+            .await?;
+        let tar = tokio::fs::read(&tar.path).await?;
+
+        // Compressed in two independent members, the way pigz and `cat`ing
+        // gzip files do. Stopping at the first member loses later entries.
+        let (first, second) = tar.split_at(tar.len() / 2);
+        let mut gzipped = compress(first, CompressionType::Gzip).await?;
+        gzipped.extend(compress(second, CompressionType::Gzip).await?);
+        let path = tmp.path_view().join("members.tar.gz");
+        tokio::fs::write(&path, gzipped).await?;
+
+        let memfs = TarballArtifact {
+            name: "members".into(),
+            path,
+        }
+        .extract()
+        .await?;
+        for i in 0..8 {
+            assert_eq!(
+                vec![i as u8; 16 * 1024],
+                (*memfs).read(format!("/data/{i}")).await?
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use eyre::Result;
+use eyre::{eyre, Result};
+use floppy_disk::mem::MemPermissions;
 use floppy_disk::prelude::*;
+use serde::{Deserialize, Serialize};
 use tracing::*;
 
-use crate::util::Fix;
+use crate::util::{cleanup, Fix};
 
 pub struct TempDir {
     path: PathBuf,
@@ -16,6 +19,7 @@ impl TempDir {
         let mut path = std::env::temp_dir();
         path.push(format!("peckish-workdir-{}", rand::random::<u64>()));
         tokio::fs::create_dir_all(&path).await.map_err(Fix::Io)?;
+        cleanup::register_temp_dir(&path);
 
         Ok(TempDir { path })
     }
@@ -31,6 +35,7 @@ impl Drop for TempDir {
         if self.path.exists() {
             std::fs::remove_dir_all(&self.path).unwrap();
         }
+        cleanup::unregister_temp_dir(&self.path);
     }
 }
 
@@ -54,10 +59,64 @@ impl std::ops::Deref for TempDir {
     }
 }
 
-#[repr(transparent)]
+/// What to do when a path being merged into a memfs already exists there.
+/// Directories are always merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    /// The path being merged in wins.
+    #[default]
+    Overwrite,
+    /// Fail the merge.
+    Error,
+    /// The path that's already there wins.
+    Skip,
+}
+
+/// An owner to give every path in a memfs, ex. for images that run as an
+/// arbitrary non-root user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnerRemap {
+    pub uid: u32,
+    pub gid: u32,
+    /// Give the group the owner's permissions, like `chmod g=u`. With gid 0
+    /// this is what OpenShift-style arbitrary uids need.
+    #[serde(default)]
+    pub group_writable: bool,
+}
+
+impl OwnerRemap {
+    /// `nobody:nogroup`.
+    pub const NOBODY: Self = Self {
+        uid: 65534,
+        gid: 65534,
+        group_writable: false,
+    };
+}
+
+/// A path that was written to the host without its owner, since changing
+/// owners needs root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntendedOwner {
+    /// The path in the memfs, ex. `/usr/bin/app`.
+    pub path: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct MemFS {
     fs: Arc<MemFloppyDisk>,
+    /// Modification times, in seconds since the epoch, for paths whose mtime
+    /// was set explicitly. The in-memory disk can't store arbitrary times
+    /// itself.
+    mtimes: Arc<RwLock<HashMap<PathBuf, u64>>>,
+    /// Access times, like `mtimes`. Only formats with a place for them, like
+    /// GNU tar, write them out.
+    atimes: Arc<RwLock<HashMap<PathBuf, u64>>>,
+    /// The umask applied to files and directories that injections create, if
+    /// one was set. Without one, they get the in-memory disk's defaults.
+    umask: Arc<RwLock<Option<u32>>>,
 }
 
 impl MemFS {
@@ -65,7 +124,345 @@ impl MemFS {
     pub fn new() -> Self {
         MemFS {
             fs: Arc::new(MemFloppyDisk::new()),
+            mtimes: Default::default(),
+            atimes: Default::default(),
+            umask: Default::default(),
+        }
+    }
+
+    /// The in-memory disk backing this memfs. Equivalent to dereferencing
+    /// it, but reads better when passing the disk to `DiskDrive` or `nyoom`.
+    pub fn fs(&self) -> &MemFloppyDisk {
+        &self.fs
+    }
+
+    /// Set the mtime that producers should use for the given path.
+    pub fn set_mtime<P: AsRef<Path>>(&self, path: P, mtime: u64) {
+        self.mtimes
+            .write()
+            .unwrap()
+            .insert(Path::new("/").join(path), mtime);
+    }
+
+    /// The mtime set for the given path with [`MemFS::set_mtime`], if any.
+    pub fn mtime<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        self.mtimes
+            .read()
+            .unwrap()
+            .get(&Path::new("/").join(path))
+            .copied()
+    }
+
+    /// Set the access time for the given path, in seconds since the epoch.
+    pub fn set_atime<P: AsRef<Path>>(&self, path: P, atime: u64) {
+        self.atimes
+            .write()
+            .unwrap()
+            .insert(Path::new("/").join(path), atime);
+    }
+
+    /// The atime set for the given path with [`MemFS::set_atime`], if any.
+    pub fn atime<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        self.atimes
+            .read()
+            .unwrap()
+            .get(&Path::new("/").join(path))
+            .copied()
+    }
+
+    /// Set the umask for files and directories that injections create.
+    pub fn set_umask(&self, umask: u32) {
+        *self.umask.write().unwrap() = Some(umask & 0o777);
+    }
+
+    /// The umask set with [`MemFS::set_umask`], if any.
+    pub fn umask(&self) -> Option<u32> {
+        *self.umask.read().unwrap()
+    }
+
+    /// Create a directory and its parents, giving any that didn't exist yet
+    /// the umasked default mode.
+    pub async fn create_dir_all_masked(&self, path: &Path) -> Result<()> {
+        let path = Path::new("/").join(path);
+        let mut created = vec![];
+        for dir in path.ancestors() {
+            if self.fs.metadata(dir).await.is_ok() {
+                break;
+            }
+            created.push(dir.to_path_buf());
+        }
+
+        self.fs.create_dir_all(&path).await?;
+        for dir in created {
+            self.apply_umask(&dir, 0o777).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Give a newly-created path the default mode `base`, minus the umask.
+    /// Does nothing if no umask is set.
+    pub async fn apply_umask(&self, path: &Path, base: u32) -> Result<()> {
+        if let Some(umask) = self.umask() {
+            let file_type = self.fs.metadata(path).await?.permissions().mode() & !0o7777;
+            self.fs
+                .set_permissions(path, MemPermissions::from_mode(file_type | (base & !umask)))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy everything in `other` into this memfs, handling paths that exist
+    /// in both according to `policy`.
+    pub async fn merge(&self, other: &MemFS, policy: MergePolicy) -> Result<()> {
+        let (src, dest) = (other.fs(), self.fs());
+        for path in nyoom::walk_ordered(src, "/").await? {
+            if path == Path::new("/") {
+                continue;
+            }
+
+            let metadata = src.symlink_metadata(&path).await?;
+            if let Ok(existing) = dest.symlink_metadata(&path).await {
+                if existing.is_dir() && metadata.is_dir() {
+                    continue;
+                }
+
+                match policy {
+                    MergePolicy::Overwrite => {
+                        debug!("merge: overwriting {}", path.display());
+                        if existing.is_dir() {
+                            dest.remove_dir_all(&path).await?;
+                        } else {
+                            dest.remove_file(&path).await?;
+                        }
+                    }
+                    MergePolicy::Error => {
+                        return Err(eyre!(
+                            "cannot merge: {} exists in both filesystems, and merge_policy is error",
+                            path.display()
+                        ));
+                    }
+                    MergePolicy::Skip => {
+                        debug!("merge: skipping {}", path.display());
+                        continue;
+                    }
+                }
+            }
+
+            if metadata.is_symlink() {
+                dest.symlink(src.read_link(&path).await?, path.clone())
+                    .await?;
+            } else {
+                if metadata.is_dir() {
+                    dest.create_dir_all(&path).await?;
+                } else {
+                    dest.write(&path, src.read(&path).await?).await?;
+                }
+                dest.chown(&path, metadata.uid()?, metadata.gid()?).await?;
+                dest.set_permissions(
+                    &path,
+                    MemPermissions::from_mode(metadata.permissions().mode()),
+                )
+                .await?;
+            }
+
+            if let Some(mtime) = other.mtime(&path) {
+                self.set_mtime(&path, mtime);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy `src`, and everything under it, from `other` to `dest` in this
+    /// memfs. Paths that already exist are overwritten, except directories,
+    /// which are merged.
+    pub async fn copy_tree_from(&self, other: &MemFS, src: &Path, dest: &Path) -> Result<()> {
+        let (src_fs, dest_fs) = (other.fs(), self.fs());
+        let paths = if src_fs.symlink_metadata(src).await?.is_dir() {
+            nyoom::walk_ordered(src_fs, src).await?
+        } else {
+            [src.to_path_buf()].into()
+        };
+
+        for path in paths {
+            let target = dest.join(path.strip_prefix(src)?);
+            let metadata = src_fs.symlink_metadata(&path).await?;
+            if let Ok(existing) = dest_fs.symlink_metadata(&target).await {
+                if existing.is_dir() && metadata.is_dir() {
+                    continue;
+                }
+                if existing.is_dir() {
+                    dest_fs.remove_dir_all(&target).await?;
+                } else {
+                    dest_fs.remove_file(&target).await?;
+                }
+            }
+            if let Some(parent) = target.parent() {
+                dest_fs.create_dir_all(parent).await?;
+            }
+
+            if metadata.is_symlink() {
+                dest_fs
+                    .symlink(src_fs.read_link(&path).await?, target.clone())
+                    .await?;
+            } else {
+                if metadata.is_dir() {
+                    dest_fs.create_dir_all(&target).await?;
+                } else {
+                    dest_fs.write(&target, src_fs.read(&path).await?).await?;
+                }
+                dest_fs
+                    .chown(&target, metadata.uid()?, metadata.gid()?)
+                    .await?;
+                dest_fs
+                    .set_permissions(
+                        &target,
+                        MemPermissions::from_mode(metadata.permissions().mode()),
+                    )
+                    .await?;
+            }
+
+            if let Some(mtime) = other.mtime(&path) {
+                self.set_mtime(&target, mtime);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply a container image layer on top of this memfs. A whiteout file,
+    /// `.wh.<name>`, in the layer removes `<name>`, and an opaque marker,
+    /// `.wh..wh..opq`, removes everything that was already in its directory.
+    /// The markers themselves are dropped, and the rest of the layer is
+    /// merged in, overwriting what's there.
+    pub async fn apply_layer(&self, layer: MemFS) -> Result<()> {
+        let dest = self.fs();
+        for path in nyoom::walk_ordered(layer.fs(), "/").await? {
+            let Some(whiteout) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(".wh."))
+            else {
+                continue;
+            };
+            let dir = path.parent().unwrap_or(Path::new("/"));
+
+            if whiteout == ".wh..opq" {
+                debug!("layer: clearing opaque directory {}", dir.display());
+                if let Ok(mut entries) = dest.read_dir(dir).await {
+                    let mut children = vec![];
+                    while let Some(entry) = entries.next_entry().await? {
+                        children.push(entry.path());
+                    }
+                    for child in children {
+                        remove_path(dest, &child).await?;
+                    }
+                }
+            } else {
+                let removed = dir.join(whiteout);
+                debug!("layer: whiting out {}", removed.display());
+                if dest.symlink_metadata(&removed).await.is_ok() {
+                    remove_path(dest, &removed).await?;
+                }
+            }
+
+            layer.fs().remove_file(&path).await?;
         }
+
+        self.merge(&layer, MergePolicy::Overwrite).await
+    }
+
+    /// Write everything in this memfs into `dir` on the host. Unprivileged
+    /// users can't give files away, so owners that can't be set are returned
+    /// instead of failing the write.
+    pub async fn write_to_host(&self, dir: &Path) -> Result<Vec<IntendedOwner>> {
+        self.write_to_host_with(dir, |path, uid, gid| {
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+        })
+        .await
+    }
+
+    /// Like [`MemFS::write_to_host`], but leaves every path owned by the
+    /// current user. For producers that read owners from the memfs instead.
+    pub async fn write_contents_to_host(&self, dir: &Path) -> Result<()> {
+        self.write_to_host_with(dir, |_, _, _| Ok(())).await?;
+        Ok(())
+    }
+
+    async fn write_to_host_with<F>(&self, dir: &Path, chown: F) -> Result<Vec<IntendedOwner>>
+    where
+        F: Fn(&Path, u32, u32) -> std::io::Result<()> + Copy + Send + 'static,
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        tokio::fs::create_dir_all(dir).await?;
+        let mut unowned = vec![];
+        let mut dirs = vec![];
+        let mut files = vec![];
+
+        // Directories and symlinks are made in walk order, so that every
+        // parent exists before anything is written into it. Files are
+        // written after, several at a time.
+        for path in nyoom::walk_ordered(self.fs.as_ref(), "/").await? {
+            if path == Path::new("/") {
+                continue;
+            }
+            let target = dir.join(path.strip_prefix("/")?);
+            let metadata = self.fs.symlink_metadata(&path).await?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            let (uid, gid) = (metadata.uid()?, metadata.gid()?);
+
+            if metadata.is_symlink() {
+                if tokio::fs::symlink_metadata(&target).await.is_ok() {
+                    tokio::fs::remove_file(&target).await?;
+                }
+                tokio::fs::symlink(self.fs.read_link(&path).await?, &target).await?;
+            } else if metadata.is_dir() {
+                tokio::fs::create_dir_all(&target).await?;
+                dirs.push((target.clone(), mode));
+            } else {
+                files.push((path, target, mode, uid, gid));
+                continue;
+            }
+
+            if let Some(owner) = try_chown(chown, &path, &target, uid, gid)? {
+                unowned.push(owner);
+            }
+        }
+
+        let mut writes = tokio::task::JoinSet::new();
+        for (path, target, mode, uid, gid) in files {
+            if writes.len() >= HOST_WRITE_CONCURRENCY {
+                if let Some(owner) = writes.join_next().await {
+                    unowned.extend(owner??);
+                }
+            }
+
+            let fs = self.fs.clone();
+            writes.spawn(async move {
+                tokio::fs::write(&target, fs.read(&path).await?).await?;
+                let owner = try_chown(chown, &path, &target, uid, gid)?;
+                // Changing the owner clears setuid bits, so the mode comes
+                // after.
+                tokio::fs::set_permissions(&target, std::fs::Permissions::from_mode(mode)).await?;
+                Ok::<_, eyre::Report>(owner)
+            });
+        }
+        while let Some(owner) = writes.join_next().await {
+            unowned.extend(owner??);
+        }
+
+        // Directories get their modes last, so that read-only ones can still
+        // be written into.
+        for (dir, mode) in dirs.into_iter().rev() {
+            tokio::fs::set_permissions(&dir, std::fs::Permissions::from_mode(mode)).await?;
+        }
+
+        // Files finish in any order, so put them back in walk order.
+        unowned.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(unowned)
     }
 
     pub async fn size(&self) -> Result<u64> {
@@ -80,6 +477,132 @@ impl MemFS {
         Ok(size)
     }
 
+    /// A sha256 over every path's name, type, mode, and contents, in sorted
+    /// order, so that two memfses built the same way have the same digest.
+    /// Owners and mtimes aren't included. Symlink modes are unused, so only
+    /// their targets are.
+    pub async fn content_digest(&self) -> Result<String> {
+        use sha2::{Digest, Sha256};
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut hasher = Sha256::new();
+        for path in nyoom::walk_ordered(self.fs.as_ref(), "/").await? {
+            let metadata = self.fs.symlink_metadata(&path).await?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            // Every field is length-prefixed, so that one entry can't run
+            // into the next.
+            let (kind, mode, contents) = if metadata.is_symlink() {
+                let target = self.fs.read_link(&path).await?;
+                (b'l', 0, target.as_os_str().as_bytes().to_vec())
+            } else if metadata.is_dir() {
+                (b'd', mode, vec![])
+            } else {
+                (b'f', mode, self.fs.read(&path).await?)
+            };
+
+            let name = path.as_os_str().as_bytes();
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name);
+            hasher.update([kind]);
+            hasher.update(mode.to_le_bytes());
+            hasher.update((contents.len() as u64).to_le_bytes());
+            hasher.update(&contents);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Give every path the remap's owner, and its owner's permissions to its
+    /// group if asked to. Symlink modes are left alone, since they're unused.
+    pub async fn remap_owners(&self, remap: &OwnerRemap) -> Result<()> {
+        for path in nyoom::walk_ordered(self.fs.as_ref(), "/").await? {
+            self.fs.chown(&path, remap.uid, remap.gid).await?;
+
+            let metadata = self.fs.symlink_metadata(&path).await?;
+            if remap.group_writable && !metadata.is_symlink() {
+                let mode = metadata.permissions().mode();
+                let mode = mode | ((mode & 0o700) >> 3);
+                self.fs
+                    .set_permissions(&path, MemPermissions::from_mode(mode))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give directories and executables 0o755, and every other file 0o644,
+    /// ex. to undo a builder's umask of 0o002. Files are executable if they
+    /// have any execute bit, or start with ELF magic. Setuid, setgid, and
+    /// sticky bits are cleared with a warning, and symlinks are left alone.
+    pub async fn normalize_permissions(&self) -> Result<()> {
+        for path in nyoom::walk_ordered(self.fs.as_ref(), "/").await? {
+            let metadata = self.fs.symlink_metadata(&path).await?;
+            let mode = metadata.permissions().mode();
+            let normalized = if metadata.is_dir() {
+                0o755
+            } else if metadata.is_file() {
+                let executable = mode & 0o111 != 0 || {
+                    let data = self.fs.read(&path).await?;
+                    data.starts_with(b"\x7fELF")
+                };
+                if executable {
+                    0o755
+                } else {
+                    0o644
+                }
+            } else {
+                continue;
+            };
+
+            let special = [(0o4000, "setuid"), (0o2000, "setgid"), (0o1000, "sticky")]
+                .into_iter()
+                .filter(|(bit, _)| mode & bit != 0)
+                .map(|(_, name)| name)
+                .collect::<Vec<_>>();
+            if !special.is_empty() {
+                warn!(
+                    "clearing the {} bits of {}, mode {:o}",
+                    special.join(" and "),
+                    path.display(),
+                    mode & 0o7777
+                );
+            }
+
+            self.fs
+                .set_permissions(
+                    &path,
+                    MemPermissions::from_mode((mode & !0o7777) | normalized),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove everything that isn't one of the given paths, under one of
+    /// them, or one of their parent directories.
+    pub async fn retain_paths(&self, paths: &[PathBuf]) -> Result<()> {
+        for path in nyoom::walk_ordered(self.fs.as_ref(), "/")
+            .await?
+            .iter()
+            .rev()
+        {
+            if path_is_selected(path, paths) {
+                continue;
+            }
+
+            let metadata = self.fs.symlink_metadata(path).await?;
+            if metadata.is_dir() {
+                self.fs.remove_dir_all(path).await?;
+            } else {
+                self.fs.remove_file(path).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn resolve_symlink(&self, path: &Path) -> Result<PathBuf> {
         self.do_resolve_symlink(path, 0).await
     }
@@ -101,6 +624,88 @@ impl MemFS {
     }
 }
 
+/// How many files [`MemFS::write_to_host`] writes at once.
+const HOST_WRITE_CONCURRENCY: usize = 32;
+
+/// Give `target` its owner from the memfs, returning the owner if the
+/// current user isn't allowed to set it.
+pub(crate) fn try_chown<F>(
+    chown: F,
+    path: &Path,
+    target: &Path,
+    uid: u32,
+    gid: u32,
+) -> Result<Option<IntendedOwner>>
+where
+    F: Fn(&Path, u32, u32) -> std::io::Result<()>,
+{
+    match chown(target, uid, gid) {
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Ok(Some(IntendedOwner {
+            path: path.to_path_buf(),
+            uid,
+            gid,
+        })),
+        Err(e) => Err(eyre!("could not chown {}: {e}", target.display())),
+    }
+}
+
+async fn remove_path(fs: &MemFloppyDisk, path: &Path) -> Result<()> {
+    if fs.symlink_metadata(path).await?.is_dir() {
+        fs.remove_dir_all(path).await?;
+    } else {
+        fs.remove_file(path).await?;
+    }
+
+    Ok(())
+}
+
+/// Whether `path` is one of `selected`, under one of them, or one of their
+/// parent directories. Relative paths are treated as relative to `/`.
+pub fn path_is_selected(path: &Path, selected: &[PathBuf]) -> bool {
+    let path = Path::new("/").join(path);
+    selected.iter().any(|selected| {
+        let selected = Path::new("/").join(selected);
+        path.starts_with(&selected) || selected.starts_with(&path)
+    })
+}
+
+/// Which paths to keep when reading an artifact. Included paths keep their
+/// parent directories as well, and exclusions win over inclusions. An empty
+/// `include` keeps everything that isn't excluded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathFilter {
+    pub include: Vec<PathBuf>,
+    pub exclude: Vec<PathBuf>,
+}
+
+impl PathFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether `path` is kept. Layer whiteouts are kept if the path they
+    /// remove is, so that filtering a layer doesn't bring removed files back.
+    pub fn keeps(&self, path: &Path) -> bool {
+        let path = Path::new("/").join(path);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        let target = match path.file_name().and_then(|name| name.to_str()) {
+            Some(".wh..wh..opq") => dir.to_path_buf(),
+            Some(name) => match name.strip_prefix(".wh.") {
+                Some(removed) => dir.join(removed),
+                None => path.clone(),
+            },
+            None => path.clone(),
+        };
+
+        (self.include.is_empty() || path_is_selected(&target, &self.include))
+            && !self
+                .exclude
+                .iter()
+                .any(|excluded| target.starts_with(Path::new("/").join(excluded)))
+    }
+}
+
 impl std::ops::Deref for MemFS {
     type Target = MemFloppyDisk;
 
@@ -118,10 +723,15 @@ impl AsRef<MemFloppyDisk> for MemFS {
 #[cfg(test)]
 pub(crate) mod test_utils {
     use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
+    use eyre::Result;
     use tracing::debug;
 
-    use super::TempDir;
+    use crate::artifact::{Artifact, SelfValidation};
+
+    use super::{MemFS, TempDir};
 
     pub struct Fixture {
         pub which: String,
@@ -164,4 +774,328 @@ pub(crate) mod test_utils {
             debug!("!!! DROPPING FIXTURE {:?}", self.which);
         }
     }
+
+    /// An artifact with `/file` and `/usr/bin/app` in it, which counts how
+    /// many times it's been extracted, and how many times it's been handed
+    /// over with [`Artifact::into_memfs`]. Clones share their counts.
+    #[derive(Clone, Default)]
+    pub struct CountingArtifact {
+        extracts: Arc<AtomicUsize>,
+        handed_over: Arc<AtomicUsize>,
+    }
+
+    impl CountingArtifact {
+        pub fn extracts(&self) -> usize {
+            self.extracts.load(Ordering::SeqCst)
+        }
+
+        pub fn handed_over(&self) -> usize {
+            self.handed_over.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Artifact for CountingArtifact {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        async fn extract(&self) -> Result<MemFS> {
+            self.extracts.fetch_add(1, Ordering::SeqCst);
+            let memfs = MemFS::new();
+            memfs.fs().write("/file", b"hi").await?;
+            memfs.fs().create_dir_all("/usr/bin").await?;
+            memfs.fs().write("/usr/bin/app", b"app").await?;
+            Ok(memfs)
+        }
+
+        async fn into_memfs(self: Box<Self>) -> Result<MemFS> {
+            self.handed_over.fetch_add(1, Ordering::SeqCst);
+            self.extract().await
+        }
+
+        fn try_clone(&self) -> Result<Box<dyn Artifact>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn paths(&self) -> Option<Vec<PathBuf>> {
+            None
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SelfValidation for CountingArtifact {
+        async fn validate(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_write_to_host_without_root_keeps_going() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().write("/usr/bin/app", b"app").await?;
+        memfs
+            .fs()
+            .set_permissions("/usr/bin/app", MemPermissions::from_mode(0o4755))
+            .await?;
+        memfs.fs().chown("/usr/bin/app", 0, 0).await?;
+        memfs.fs().chown("/usr", 0, 0).await?;
+        memfs.fs().chown("/usr/bin", 1000, 1000).await?;
+
+        // Behave like an unprivileged user, who can only give paths to uid
+        // 1000.
+        let tmp = TempDir::new().await?;
+        let unowned = memfs
+            .write_to_host_with(&tmp.path_view(), |_, uid, _| {
+                if uid == 1000 {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::from_raw_os_error(1))
+                }
+            })
+            .await?;
+
+        assert_eq!(
+            vec![
+                IntendedOwner {
+                    path: "/usr".into(),
+                    uid: 0,
+                    gid: 0,
+                },
+                IntendedOwner {
+                    path: "/usr/bin/app".into(),
+                    uid: 0,
+                    gid: 0,
+                },
+            ],
+            unowned
+        );
+        let app = tmp.path_view().join("usr/bin/app");
+        assert_eq!(b"app", tokio::fs::read(&app).await?.as_slice());
+        assert_eq!(
+            0o4755,
+            std::os::unix::fs::PermissionsExt::mode(
+                &tokio::fs::metadata(&app).await?.permissions()
+            ) & 0o7777
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_to_host_writes_large_trees() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let memfs = MemFS::new();
+        for dir in 0..20 {
+            let dir = format!("/data/{dir:02}/nested");
+            memfs.fs().create_dir_all(&dir).await?;
+            for file in 0..100 {
+                let path = format!("{dir}/{file:03}");
+                memfs.fs().write(&path, path.as_bytes()).await?;
+                let mode = if file % 2 == 0 { 0o755 } else { 0o600 };
+                memfs
+                    .fs()
+                    .set_permissions(&path, MemPermissions::from_mode(mode))
+                    .await?;
+            }
+        }
+        // A read-only directory still has to be written into.
+        memfs
+            .fs()
+            .set_permissions("/data/00/nested", MemPermissions::from_mode(0o555))
+            .await?;
+
+        let tmp = TempDir::new().await?;
+        memfs.write_contents_to_host(&tmp.path_view()).await?;
+
+        for dir in 0..20 {
+            for file in 0..100 {
+                let path = format!("data/{dir:02}/nested/{file:03}");
+                let target = tmp.path_view().join(&path);
+                assert_eq!(
+                    format!("/{path}").into_bytes(),
+                    tokio::fs::read(&target).await?
+                );
+                let mode = tokio::fs::metadata(&target).await?.permissions().mode() & 0o777;
+                assert_eq!(if file % 2 == 0 { 0o755 } else { 0o600 }, mode, "{path}");
+            }
+        }
+        let nested = tmp.path_view().join("data/00/nested");
+        assert_eq!(
+            0o555,
+            tokio::fs::metadata(&nested).await?.permissions().mode() & 0o777
+        );
+        // So that the temp dir can be cleaned up.
+        tokio::fs::set_permissions(&nested, std::fs::Permissions::from_mode(0o755)).await?;
+
+        Ok(())
+    }
+
+    async fn digest_tree() -> Result<MemFS> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().write("/usr/bin/app", b"app").await?;
+        memfs
+            .fs()
+            .set_permissions("/usr/bin/app", MemPermissions::from_mode(0o755))
+            .await?;
+        memfs.fs().write("/README", b"readme").await?;
+        memfs.fs().symlink("/usr/bin/app", "/app").await?;
+        Ok(memfs)
+    }
+
+    #[tokio::test]
+    async fn test_content_digest_matches_identical_trees() -> Result<()> {
+        let digest = digest_tree().await?.content_digest().await?;
+        assert_eq!(digest, digest_tree().await?.content_digest().await?);
+
+        let changed = digest_tree().await?;
+        changed.fs().write("/README", b"readme!").await?;
+        assert_ne!(digest, changed.content_digest().await?);
+
+        let changed = digest_tree().await?;
+        changed
+            .fs()
+            .set_permissions("/usr/bin/app", MemPermissions::from_mode(0o644))
+            .await?;
+        assert_ne!(digest, changed.content_digest().await?);
+
+        let changed = digest_tree().await?;
+        changed.fs().remove_file("/app").await?;
+        changed.fs().symlink("/README", "/app").await?;
+        assert_ne!(digest, changed.content_digest().await?);
+
+        Ok(())
+    }
+
+    async fn colliding_filesystems() -> Result<(MemFS, MemFS)> {
+        let first = MemFS::new();
+        first.fs().create_dir_all("/etc").await?;
+        first.fs().write("/etc/config", b"first").await?;
+        first.fs().write("/etc/only-first", b"first").await?;
+
+        let second = MemFS::new();
+        second.fs().create_dir_all("/etc").await?;
+        second.fs().write("/etc/config", b"second").await?;
+        second.fs().write("/etc/only-second", b"second").await?;
+
+        Ok((first, second))
+    }
+
+    #[tokio::test]
+    async fn test_merge_policies_handle_colliding_paths() -> Result<()> {
+        let (first, second) = colliding_filesystems().await?;
+        first.merge(&second, MergePolicy::Overwrite).await?;
+        assert_eq!(first.fs().read("/etc/config").await?, b"second");
+        assert_eq!(first.fs().read("/etc/only-first").await?, b"first");
+        assert_eq!(first.fs().read("/etc/only-second").await?, b"second");
+
+        let (first, second) = colliding_filesystems().await?;
+        first.merge(&second, MergePolicy::Skip).await?;
+        assert_eq!(first.fs().read("/etc/config").await?, b"first");
+        assert_eq!(first.fs().read("/etc/only-second").await?, b"second");
+
+        let (first, second) = colliding_filesystems().await?;
+        let err = first
+            .merge(&second, MergePolicy::Error)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/etc/config exists in both filesystems"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_layer_resolves_whiteouts() -> Result<()> {
+        let base = MemFS::new();
+        base.fs().create_dir_all("/etc/opaque").await?;
+        base.fs().write("/etc/removed", b"base").await?;
+        base.fs().write("/etc/kept", b"base").await?;
+        base.fs().write("/etc/opaque/old", b"base").await?;
+
+        let layer = MemFS::new();
+        layer.fs().create_dir_all("/etc/opaque").await?;
+        layer.fs().write("/etc/.wh.removed", b"").await?;
+        layer.fs().write("/etc/opaque/.wh..wh..opq", b"").await?;
+        layer.fs().write("/etc/opaque/new", b"layer").await?;
+
+        base.apply_layer(layer).await?;
+        let paths = nyoom::walk_ordered(base.fs(), "/").await?;
+        assert_eq!(
+            paths.into_iter().collect::<Vec<_>>(),
+            ["/etc", "/etc/kept", "/etc/opaque", "/etc/opaque/new"]
+                .map(PathBuf::from)
+                .to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_normalize_permissions() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/usr/bin").await?;
+        memfs.fs().create_dir_all("/usr/share/app").await?;
+        memfs
+            .fs()
+            .write("/usr/bin/script", b"#!/bin/sh\necho hi\n")
+            .await?;
+        memfs
+            .fs()
+            .write("/usr/share/app/example.sh", b"#!/bin/sh\necho hi\n")
+            .await?;
+        memfs.fs().write("/usr/bin/app", b"\x7fELF...").await?;
+        memfs.fs().write("/usr/share/app/data", b"data").await?;
+        memfs.fs().write("/usr/share/app/run", b"run").await?;
+        for (path, mode) in [
+            ("/usr/share/app", 0o40775),
+            ("/usr/bin/script", 0o100775),
+            ("/usr/share/app/example.sh", 0o100664),
+            ("/usr/bin/app", 0o100664),
+            ("/usr/share/app/data", 0o100664),
+            ("/usr/share/app/run", 0o104770),
+        ] {
+            memfs
+                .fs()
+                .set_permissions(path, MemPermissions::from_mode(mode))
+                .await?;
+        }
+
+        memfs.normalize_permissions().await?;
+
+        for (path, mode) in [
+            ("/usr/share/app", 0o755),
+            ("/usr/bin/script", 0o755),
+            // Only the execute bits and ELF magic count, not a `#!`.
+            ("/usr/share/app/example.sh", 0o644),
+            ("/usr/bin/app", 0o755),
+            ("/usr/share/app/data", 0o644),
+            ("/usr/share/app/run", 0o755),
+        ] {
+            let metadata = memfs.fs().symlink_metadata(path).await?;
+            assert_eq!(mode, metadata.permissions().mode() & 0o7777, "{path}");
+        }
+        assert!(memfs
+            .fs()
+            .symlink_metadata("/usr/bin/script")
+            .await?
+            .is_file());
+
+        Ok(())
+    }
 }
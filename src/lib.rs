@@ -12,9 +12,16 @@
 //! ## supported formats
 //!
 //! - Arch packages
+//! - Windows cabinets (output only)
 //! - Debian packages
 //! - Docker images
+//! - Dockerfiles with a build context (output only)
+//! - ISO 9660 images
 //! - Normal files
+//! - macOS flat packages (output only)
+//! - JSON/YAML manifests of an artifact's contents (output only)
+//! - SPDX and CycloneDX SBOMs (output only)
+//! - Self-extracting shell script installers (output only)
 //! - Tarballs
 
 pub mod artifact;
@@ -23,13 +30,19 @@ pub mod pipeline;
 pub mod util;
 
 pub mod prelude {
-    pub use crate::artifact::{Artifact, ArtifactProducer, SelfBuilder, SelfValidation};
+    pub use crate::artifact::{
+        Artifact, ArtifactKind, ArtifactProducer, PackageInfo, SelfBuilder, SelfValidation,
+    };
     pub use crate::util::config::{Injection, PeckishConfig};
 
     pub mod arch {
         pub use crate::artifact::arch::*;
     }
 
+    pub mod cab {
+        pub use crate::artifact::cab::*;
+    }
+
     pub mod deb {
         pub use crate::artifact::deb::*;
     }
@@ -38,45 +51,97 @@ pub mod prelude {
         pub use crate::artifact::docker::*;
     }
 
+    pub mod dockerfile {
+        pub use crate::artifact::dockerfile::*;
+    }
+
     pub mod file {
         pub use crate::artifact::file::*;
     }
 
+    pub mod iso {
+        pub use crate::artifact::iso::*;
+    }
+
+    pub mod macos {
+        pub use crate::artifact::macos::*;
+    }
+
+    pub mod manifest {
+        pub use crate::artifact::manifest::*;
+    }
+
+    pub mod sbom {
+        pub use crate::artifact::sbom::*;
+    }
+
+    pub mod script {
+        pub use crate::artifact::script::*;
+    }
+
     pub mod tarball {
         pub use crate::artifact::tarball::*;
     }
 
     pub mod artifact {
         pub use crate::artifact::arch::ArchArtifact;
+        pub use crate::artifact::cab::CabArtifact;
         pub use crate::artifact::deb::DebArtifact;
         pub use crate::artifact::docker::DockerArtifact;
+        pub use crate::artifact::dockerfile::DockerfileArtifact;
         pub use crate::artifact::file::FileArtifact;
         pub use crate::artifact::get_artifact_size;
+        pub use crate::artifact::iso::IsoArtifact;
+        pub use crate::artifact::macos::MacosPkgArtifact;
         pub use crate::artifact::rpm::RpmArtifact;
         pub use crate::artifact::tarball::TarballArtifact;
     }
 
     pub mod producer {
         pub use crate::artifact::arch::ArchProducer;
+        pub use crate::artifact::cab::CabProducer;
         pub use crate::artifact::deb::DebProducer;
         pub use crate::artifact::docker::DockerProducer;
+        pub use crate::artifact::dockerfile::DockerfileProducer;
         pub use crate::artifact::file::FileProducer;
+        pub use crate::artifact::iso::IsoProducer;
+        pub use crate::artifact::macos::MacosPkgProducer;
+        pub use crate::artifact::manifest::ManifestProducer;
         pub use crate::artifact::rpm::RpmProducer;
+        pub use crate::artifact::sbom::SbomProducer;
+        pub use crate::artifact::script::ScriptInstallerProducer;
         pub use crate::artifact::tarball::TarballProducer;
     }
 
     pub mod builder {
         pub use crate::artifact::arch::{ArchArtifactBuilder, ArchProducerBuilder};
+        pub use crate::artifact::cab::{CabArtifactBuilder, CabProducerBuilder};
         pub use crate::artifact::deb::{DebArtifactBuilder, DebProducerBuilder};
         pub use crate::artifact::docker::{DockerArtifactBuilder, DockerProducerBuilder};
+        pub use crate::artifact::dockerfile::{
+            DockerfileArtifactBuilder, DockerfileProducerBuilder,
+        };
         pub use crate::artifact::file::{FileArtifactBuilder, FileProducerBuilder};
+        pub use crate::artifact::iso::{IsoArtifactBuilder, IsoProducerBuilder};
+        pub use crate::artifact::macos::{MacosPkgArtifactBuilder, MacosPkgProducerBuilder};
+        pub use crate::artifact::manifest::ManifestProducerBuilder;
         pub use crate::artifact::rpm::{RpmArtifactBuilder, RpmProducerBuilder};
+        pub use crate::artifact::sbom::SbomProducerBuilder;
+        pub use crate::artifact::script::ScriptInstallerProducerBuilder;
         pub use crate::artifact::tarball::{TarballArtifactBuilder, TarballProducerBuilder};
         pub use crate::artifact::SelfBuilder;
     }
 
+    pub mod registry {
+        pub use crate::artifact::registry::Registry;
+        pub use crate::artifact::DynArtifactProducer;
+    }
+
     pub mod pipeline {
         pub use crate::pipeline::Pipeline;
-        pub use crate::util::config::{ConfiguredArtifact, ConfiguredProducer, PeckishConfig};
+        pub use crate::util::config::{
+            ConfiguredArtifact, ConfiguredProducer, PeckishConfig, PeckishConfigBuilder,
+        };
+        pub use crate::util::lint::LintConfig;
     }
 }
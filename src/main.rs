@@ -1,11 +1,15 @@
+use std::io::IsTerminal;
 use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 use color_eyre::Result;
 use tracing::*;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
-use crate::pipeline::Pipeline;
-use crate::util::config::PeckishConfig;
+use crate::pipeline::cache::DEFAULT_CACHE_FILE;
+use crate::pipeline::{Pipeline, ReportFormat};
+use crate::util::config::{ConfigFormat, PeckishConfig};
+use crate::util::{cleanup, confirm};
 
 mod artifact;
 mod fs;
@@ -33,6 +37,67 @@ struct Input {
     )]
     report_file: Option<PathBuf>,
 
+    #[arg(
+        long = "report-format",
+        value_enum,
+        default_value_t = ReportFormat::Text,
+        help = "Format of the report file. `json` includes each producer's labels."
+    )]
+    report_format: ReportFormat,
+
+    #[arg(
+        long = "allow-hooks",
+        help = "Allow running the before/after hook commands in the config."
+    )]
+    allow_hooks: bool,
+
+    #[arg(
+        long = "allow-network",
+        help = "Allow inputs and injections that download files from the network."
+    )]
+    allow_network: bool,
+
+    #[arg(
+        short = 'f',
+        long = "force",
+        help = "Remove existing output files instead of failing."
+    )]
+    force: bool,
+
+    #[arg(
+        short = 'y',
+        long = "yes",
+        help = "Don't ask before overwriting outputs or replacing Docker images."
+    )]
+    yes: bool,
+
+    #[arg(
+        long = "strict",
+        help = "Fail on likely config mistakes, like a config with no outputs or a deb with files in /home, instead of warning."
+    )]
+    strict: bool,
+
+    #[arg(
+        long = "lint",
+        help = "Check each produced artifact for packaging problems like world-writable files."
+    )]
+    lint: bool,
+
+    #[arg(
+        long = "no-cache",
+        help = "Always run every producer, even if its input is unchanged since the last run."
+    )]
+    no_cache: bool,
+
+    #[arg(
+        long = "print-config",
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "yaml",
+        help = "Print the config once it's resolved, ex. with path tokens expanded, and exit."
+    )]
+    print_config: Option<ConfigFormat>,
+
     #[command(subcommand)]
     pub command: Option<PeckishSubcommand>,
 }
@@ -44,7 +109,21 @@ pub enum PeckishSubcommand {
         about = "Test all packages and ensure they install.",
         subcommand_negates_reqs = true
     )]
-    Test,
+    Test {
+        #[arg(
+            short = 'j',
+            long = "jobs",
+            default_value_t = 1,
+            help = "How many packages to install in parallel containers at once."
+        )]
+        jobs: usize,
+    },
+    #[command(
+        name = "schema",
+        about = "Print a JSON Schema for peckish.yaml, for editors and CI to validate against.",
+        subcommand_negates_reqs = true
+    )]
+    Schema,
 }
 
 #[tokio::main]
@@ -57,24 +136,84 @@ async fn main() -> Result<()> {
         .issue_url(concat!(env!("CARGO_PKG_REPOSITORY"), "/issues/new"))
         .add_issue_metadata("version", env!("CARGO_PKG_VERSION"))
         .install()?;
+    let args = Input::parse();
+
+    // Keep stdout to just the config or schema when printing them, so they
+    // can be piped.
+    let printing_schema = matches!(args.command, Some(PeckishSubcommand::Schema));
+    let writer = if args.print_config.is_some() || printing_schema {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
     let subscriber = tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(writer)
         .compact()
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
     tracing_log::LogTracer::init()?;
 
-    let args = Input::parse();
-
     debug!("starting peckish");
+    if let Some(format) = args.print_config {
+        print!(
+            "{}",
+            PeckishConfig::load_resolved(args.config_file, format).await?
+        );
+        return Ok(());
+    }
+
+    if printing_schema {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&util::schema::config_schema())?
+        );
+        return Ok(());
+    }
+
     let config = PeckishConfig::load(args.config_file).await?;
 
-    match args.command {
-        Some(PeckishSubcommand::Test) => {
-            tester::test_packages(config).await?;
+    let run = async {
+        match args.command {
+            Some(PeckishSubcommand::Test { jobs }) => {
+                tester::test_packages(config, jobs).await?;
+            }
+            _ => {
+                // Only ask when someone is there to answer, so that CI runs
+                // don't hang.
+                if !args.yes && std::io::stdin().is_terminal() {
+                    let operations = confirm::destructive_operations(&config, args.force).await;
+                    // Reading stdin blocks, so keep it off of the runtime.
+                    tokio::task::spawn_blocking(move || {
+                        confirm::confirm(&operations, std::io::stdin().lock(), std::io::stderr())
+                    })
+                    .await??;
+                }
+
+                Pipeline::new(args.report_file)
+                    .report_format(args.report_format)
+                    .allow_hooks(args.allow_hooks)
+                    .allow_network(args.allow_network)
+                    .force(args.force)
+                    .strict(args.strict)
+                    .lint(args.lint)
+                    .cache((!args.no_cache).then(|| DEFAULT_CACHE_FILE.into()))
+                    .run(config)
+                    .await?;
+            }
         }
-        _ => {
-            Pipeline::new(args.report_file).run(config).await?;
+
+        Ok::<_, eyre::Report>(())
+    };
+
+    // Dropping the run cancels it, but destructors don't get a chance to
+    // remove temp dirs that are still in use, or containers at all.
+    tokio::select! {
+        result = run => result?,
+        signal = cleanup::interrupted() => {
+            warn!("received {}, cleaning up...", signal?);
+            cleanup::run().await;
+            std::process::exit(130);
         }
     }
 
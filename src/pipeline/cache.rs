@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use eyre::Result;
+use tracing::*;
+
+use crate::artifact::Artifact;
+use crate::util::config::{ConfiguredProducer, Injection};
+use crate::util::{sha256_digest, sha256_digest_string, source_date_epoch};
+
+/// The default location of the build cache, relative to the working directory.
+pub const DEFAULT_CACHE_FILE: &str = ".peckish-cache.json";
+
+/// A record of the inputs each producer was last run with, so that producers
+/// whose input hasn't changed since the last run can be skipped.
+///
+/// Entries are keyed by producer name, and store a hash of the input
+/// artifact's files along with the producer's config.
+#[derive(Debug, Default)]
+pub struct BuildCache {
+    path: PathBuf,
+    entries: HashMap<String, String>,
+}
+
+impl BuildCache {
+    /// Load the cache from the given file. A missing or unreadable cache file
+    /// is treated as an empty cache.
+    pub async fn load<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let entries = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("ignoring invalid build cache {}: {e}", path.display());
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { path, entries })
+    }
+
+    /// Whether the producer was last run with the given input hash.
+    pub fn is_fresh(&self, producer: &str, input_hash: &str) -> bool {
+        self.entries.get(producer).map(String::as_str) == Some(input_hash)
+    }
+
+    /// Record a successful run of the producer, and write the cache to disk.
+    pub async fn record(&mut self, producer: &str, input_hash: String) -> Result<()> {
+        self.entries.insert(producer.into(), input_hash);
+        tokio::fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?).await?;
+        Ok(())
+    }
+}
+
+/// Hash the files of an artifact along with the config of the producer that
+/// consumes it, and every host file the producer reads on top of that, ex.
+/// `host_file` injections. The config includes a step's `umask` and
+/// `normalize_permissions`, since they're applied as injections, and
+/// `SOURCE_DATE_EPOCH` is hashed too, since it sets output mtimes. Returns
+/// `None` if the artifact has no files on disk to hash, ex. Docker images, or
+/// the producer reads something that can't be checked for changes, ex. a
+/// custom producer's config, in which case the producer can't be cached.
+pub async fn hash_input(
+    artifact: &dyn Artifact,
+    producer: &ConfiguredProducer,
+) -> Result<Option<String>> {
+    if let ConfiguredProducer::Custom(_) = producer {
+        return Ok(None);
+    }
+    let (Some(mut paths), Some(sources)) = (artifact.paths(), producer_sources(producer)) else {
+        return Ok(None);
+    };
+    // A missing source fails the producer, which is better at explaining
+    // why than the cache is.
+    if sources.iter().any(|source| !source.exists()) {
+        return Ok(None);
+    }
+    paths.extend(sources);
+
+    // Remote files and from_artifact downloads are pinned by the URL and
+    // sha256 in the producer's config.
+    let mut hashes = vec![
+        serde_json::to_string(producer)?,
+        format!("SOURCE_DATE_EPOCH={:?}", source_date_epoch()?),
+    ];
+    for path in paths {
+        for file in walk_files(&path).await? {
+            hashes.push(format!(
+                "{} {}",
+                file.display(),
+                sha256_digest(&file).await?
+            ));
+        }
+    }
+
+    Ok(Some(sha256_digest_string(hashes.join("\n"))?))
+}
+
+/// The host files and directories the producer reads besides its input, or
+/// `None` if it reads something that can't be hashed.
+fn producer_sources(producer: &ConfiguredProducer) -> Option<Vec<PathBuf>> {
+    let mut sources: Vec<PathBuf> = producer
+        .host_inputs()
+        .into_iter()
+        .map(Path::to_path_buf)
+        .collect();
+
+    for injection in producer.injections() {
+        match injection {
+            Injection::HostFile { src, .. } | Injection::HostDir { src, .. } => {
+                sources.push(src.clone())
+            }
+            Injection::RemoteFile { sha256: None, .. } => return None,
+            Injection::FromArtifact { artifact, .. } => sources.extend(
+                artifact
+                    .cacheable_host_paths()?
+                    .into_iter()
+                    .map(Path::to_path_buf),
+            ),
+            _ => {}
+        }
+    }
+
+    Some(sources)
+}
+
+/// Every file under the path, in a stable order. A file path is returned as-is.
+pub(crate) async fn walk_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![path.to_path_buf()];
+
+    while let Some(path) = pending.pop() {
+        if tokio::fs::metadata(&path).await?.is_dir() {
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending.push(entry.path());
+            }
+        } else {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
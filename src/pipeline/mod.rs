@@ -1,19 +1,30 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
-use eyre::Result;
+use eyre::{eyre, Result};
 use itertools::Itertools;
+use serde::Serialize;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tracing::*;
 
-use crate::artifact::Artifact;
-use crate::util::config::{ConfiguredArtifact, PeckishConfig};
+use crate::artifact::memory::{CachedArtifact, EmptyArtifact};
+use crate::artifact::{with_strict_compatibility, Artifact, IncompatibleInput};
+use crate::util::config::{ConfiguredProducer, PeckishConfig};
+use crate::util::lint::{self, LintConfig, Severity};
+
+use self::cache::BuildCache;
+
+pub mod cache;
 
 #[macro_export]
 macro_rules! validate {
-    ($config:expr, $validator:expr) => {
+    ($config:expr, $step:expr, $phase:expr, $validator:expr) => {
         if let Err(e) = $validator.validate().await {
-            error!("{}: validation failed: {}", $validator.name(), e);
+            let e = $crate::pipeline::step_failed($step, $validator.name(), $phase, e);
+            error!("{e}");
             if $config.chain {
                 return Err(e);
             } else {
@@ -23,94 +34,574 @@ macro_rules! validate {
     };
 }
 
+/// Where in a pipeline step something failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Removing the producer's existing outputs, for `--force`.
+    Overwrite,
+    /// Validating the producer's config.
+    Validation,
+    /// Checking that the input's contents make sense for the producer.
+    Compatibility,
+    /// Running the producer's `before` hook.
+    BeforeHook,
+    /// Producing the artifact.
+    Production,
+    /// Syncing the produced artifact's files to disk.
+    Finalize,
+    /// Validating the produced artifact.
+    OutputValidation,
+    /// Linting the produced artifact, for `--lint`.
+    Lint,
+    /// Running the producer's `after` hook.
+    AfterHook,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Overwrite => "removing existing outputs",
+            Phase::Validation => "producer validation",
+            Phase::Compatibility => "compatibility checks",
+            Phase::BeforeHook => "before hook",
+            Phase::Production => "production",
+            Phase::Finalize => "finalizing outputs",
+            Phase::OutputValidation => "output validation",
+            Phase::Lint => "lint",
+            Phase::AfterHook => "after hook",
+        })
+    }
+}
+
+/// Add the step number, producer name, and phase to an error from a pipeline
+/// step, so that it's clear where a multi-step config broke. Steps start at 1.
+pub fn step_failed(step: usize, name: &str, phase: Phase, e: eyre::Report) -> eyre::Report {
+    eyre!("step {step} ({name}) failed during {phase}: {e}")
+}
+
+/// How the report of produced artifacts is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// Every artifact path, one per line.
+    #[default]
+    Text,
+    /// An entry per artifact, with its producer, kind, paths, and labels.
+    Json,
+}
+
 /// A pipeline that can run a given config. This is the main entrypoint for
 /// running a peckish config.
 #[derive(Default)]
 pub struct Pipeline {
     report_file: Option<PathBuf>,
+    report_format: ReportFormat,
+    allow_hooks: bool,
+    allow_network: bool,
+    force: bool,
+    strict: bool,
+    lint: bool,
+    cache_file: Option<PathBuf>,
 }
 
 impl Pipeline {
     #[allow(clippy::new_without_default)]
     pub fn new(report_file: Option<PathBuf>) -> Self {
-        Self { report_file }
+        Self {
+            report_file,
+            report_format: ReportFormat::Text,
+            allow_hooks: false,
+            allow_network: false,
+            force: false,
+            strict: false,
+            lint: false,
+            cache_file: None,
+        }
+    }
+
+    /// How to write the report file, if there is one.
+    pub fn report_format(mut self, report_format: ReportFormat) -> Self {
+        self.report_format = report_format;
+        self
+    }
+
+    /// Allow running the shell hooks in the config. Hooks run arbitrary
+    /// commands on the host, so they're opt-in.
+    pub fn allow_hooks(mut self, allow_hooks: bool) -> Self {
+        self.allow_hooks = allow_hooks;
+        self
+    }
+
+    /// Allow injections that download files, like `remote_file`. These reach
+    /// out to the network, so they're opt-in.
+    pub fn allow_network(mut self, allow_network: bool) -> Self {
+        self.allow_network = allow_network;
+        self
+    }
+
+    /// Remove existing outputs before producing them, instead of failing.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Turn warnings about likely config mistakes into errors.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Extract every produced artifact and check it for common packaging
+    /// problems. Error-level findings fail the step.
+    pub fn lint(mut self, lint: bool) -> Self {
+        self.lint = lint;
+        self
+    }
+
+    /// Skip producers whose output already exists and whose input hasn't
+    /// changed since the last run, as recorded in the given cache file.
+    pub fn cache(mut self, cache_file: Option<PathBuf>) -> Self {
+        self.cache_file = cache_file;
+        self
     }
 
     pub async fn run(&self, config: PeckishConfig) -> Result<Vec<Box<dyn Artifact>>> {
+        if config.output.is_empty() {
+            if self.strict {
+                return Err(eyre!(
+                    "config has no outputs, so there is nothing to produce"
+                ));
+            }
+            warn!("config has no outputs, so there is nothing to produce! only the input will be validated");
+        }
+
         info!("running pipeline with {} steps!", config.output.len());
-        let mut input_artifact: Box<dyn Artifact> = match config.input {
-            ConfiguredArtifact::File(file) => Box::new(file),
-            ConfiguredArtifact::Tarball(tarball) => Box::new(tarball),
-            ConfiguredArtifact::Docker(docker) => Box::new(docker),
-            ConfiguredArtifact::Arch(arch) => Box::new(arch),
-            ConfiguredArtifact::Deb(deb) => Box::new(deb),
-            ConfiguredArtifact::Rpm(rpm) => Box::new(rpm),
-            ConfiguredArtifact::Ext4(ext4) => Box::new(ext4),
-            ConfiguredArtifact::Oci(oci) => Box::new(oci),
-        };
+        if !self.allow_hooks && config.hooks.values().any(|hooks| !hooks.is_empty()) {
+            return Err(eyre!(
+                "config contains hooks, but hooks are not allowed! rerun with --allow-hooks to run them"
+            ));
+        }
+
+        if !self.allow_network
+            && config
+                .output
+                .iter()
+                .flat_map(|producer| producer.injections())
+                .any(|injection| injection.needs_network())
+        {
+            return Err(eyre!(
+                "config contains injections that need network access, but network access is not allowed! rerun with --allow-network to run them"
+            ));
+        }
+
+        if !self.allow_network && config.input.needs_network() {
+            return Err(eyre!(
+                "input is downloaded from a url, but network access is not allowed! rerun with --allow-network to download it"
+            ));
+        }
+
+        let mut input_artifact = config.input.into_artifact()?;
         info!("input: {}", input_artifact.name());
 
-        input_artifact.validate().await?;
+        input_artifact.validate().await.map_err(|e| {
+            eyre!(
+                "input ({}) failed during input validation: {e}",
+                input_artifact.name()
+            )
+        })?;
 
-        let mut output_artifacts: Vec<Box<dyn Artifact>> = vec![];
+        if config.output.is_empty() {
+            info!("input {} is valid", input_artifact.name());
+            return Ok(vec![]);
+        }
+
+        // Without chaining, every producer reads the same input, so only
+        // extract it once.
+        if !config.chain && config.output.len() > 1 {
+            input_artifact = Box::new(CachedArtifact::new(input_artifact));
+        }
+
+        // Artifacts are kept with the producer that made them, since a failed
+        // step without chaining is skipped.
+        let mut output_artifacts: Vec<(&ConfiguredProducer, Box<dyn Artifact>)> = vec![];
+        let mut cache = match &self.cache_file {
+            Some(cache_file) => Some(BuildCache::load(cache_file).await?),
+            None => None,
+        };
 
         for (i, producer) in config.output.iter().enumerate() {
-            info!("* step {}: {}", i + 1, producer.name());
-            validate!(config, producer);
+            let step = i + 1;
+            let failed = |phase, e| step_failed(step, producer.name(), phase, e);
+            info!("* step {step}: {}", producer.name());
+            if self.force || config.overwrite {
+                remove_existing_outputs(producer)
+                    .await
+                    .map_err(|e| failed(Phase::Overwrite, e))?;
+            }
 
-            let next_artifact = producer.produce_from(input_artifact.as_ref()).await?;
+            let input_hash = match &cache {
+                Some(_) => cache::hash_input(input_artifact.as_ref(), producer).await?,
+                None => None,
+            };
+            let cached = match (&cache, &input_hash) {
+                (Some(cache), Some(input_hash)) if cache.is_fresh(producer.name(), input_hash) => {
+                    producer.existing_output()
+                }
+                _ => None,
+            };
+            if let Some(existing) = cached {
+                info!("* input unchanged, skipping: {}", producer.name());
+                if config.chain {
+                    input_artifact = existing.try_clone()?;
+                }
+                output_artifacts.push((producer, existing));
+                continue;
+            }
+
+            validate!(config, step, Phase::Validation, producer);
+
+            let hooks = config.hooks.get(producer.name());
+            if let Some(before) = hooks.and_then(|hooks| hooks.before.as_ref()) {
+                run_hook("before", before, input_artifact.as_ref())
+                    .await
+                    .map_err(|e| failed(Phase::BeforeHook, e))?;
+            }
+
+            // Without chaining, the last step is the last to read the input, so
+            // it can have the input instead of a copy of it.
+            let produced = if !config.chain && step == config.output.len() {
+                let input = std::mem::replace(
+                    &mut input_artifact,
+                    Box::new(EmptyArtifact::new("consumed input")),
+                );
+                with_strict_compatibility(self.strict, producer.produce_from_owned(input)).await
+            } else {
+                with_strict_compatibility(
+                    self.strict,
+                    producer.produce_from(input_artifact.as_ref()),
+                )
+                .await
+            };
+            // Producers check their input while producing, once it's injected.
+            let next_artifact =
+                produced.map_err(|e| match e.downcast_ref::<IncompatibleInput>() {
+                    Some(_) => failed(Phase::Compatibility, e),
+                    None => failed(Phase::Production, e),
+                })?;
+            finalize_outputs(next_artifact.as_ref())
+                .await
+                .map_err(|e| failed(Phase::Finalize, e))?;
+
+            validate!(config, step, Phase::OutputValidation, next_artifact);
+
+            if self.lint {
+                lint_artifact(next_artifact.as_ref(), &config.lint)
+                    .await
+                    .map_err(|e| failed(Phase::Lint, e))?;
+            }
+
+            if let Some(after) = hooks.and_then(|hooks| hooks.after.as_ref()) {
+                run_hook("after", after, next_artifact.as_ref())
+                    .await
+                    .map_err(|e| failed(Phase::AfterHook, e))?;
+            }
 
-            validate!(config, next_artifact);
+            if let (Some(cache), Some(input_hash)) = (&mut cache, input_hash) {
+                cache.record(producer.name(), input_hash).await?;
+            }
 
             if config.chain {
                 input_artifact = next_artifact.try_clone()?;
             }
 
-            info!("* created artifact: {}", next_artifact.name());
-            output_artifacts.push(next_artifact);
+            match next_artifact.stored_size().await {
+                Ok(Some(size)) => {
+                    info!(
+                        "* created artifact: {} ({size} bytes)",
+                        next_artifact.name()
+                    )
+                }
+                _ => info!("* created artifact: {}", next_artifact.name()),
+            }
+            output_artifacts.push((producer, next_artifact));
         }
 
         if let Some(report_file) = &self.report_file {
-            let mut output_buffer = String::new();
-
-            for artifact in &output_artifacts {
-                if let Some(paths) = artifact.paths() {
-                    output_buffer.push_str(
-                        paths
-                            .iter()
-                            .map(|p| p.canonicalize().unwrap())
-                            .map(|d| format!("{}", d.display()))
-                            .join("\n")
-                            .as_str(),
-                    );
-
-                    if paths.len() == 1 {
-                        output_buffer.push('\n');
-                    }
+            let output_buffer = match self.report_format {
+                ReportFormat::Text => {
+                    build_report(output_artifacts.iter().map(|(_, a)| a.as_ref()))
                 }
-            }
+                ReportFormat::Json => build_json_report(&output_artifacts, &config.labels)?,
+            };
 
+            // The report is only written once everything it lists is synced,
+            // so it has to be synced too to be worth anything.
             let mut file = File::create(report_file).await?;
             file.write_all(output_buffer.as_bytes()).await?;
+            file.flush().await?;
+            file.sync_all().await?;
+            sync_dir(&parent_dir(report_file)).await?;
 
             info!("wrote report to {}", report_file.display());
         }
 
-        Ok(output_artifacts)
+        Ok(output_artifacts.into_iter().map(|(_, a)| a).collect())
     }
 }
 
+/// List the paths of every artifact, one per line. Paths are canonicalized
+/// where possible; paths that can't be, ex. because they were removed after
+/// being produced, are reported as-is.
+fn build_report<'a>(artifacts: impl Iterator<Item = &'a dyn Artifact>) -> String {
+    let mut output_buffer = String::new();
+
+    for artifact in artifacts {
+        if let Some(paths) = artifact.paths() {
+            for path in report_paths(&paths) {
+                output_buffer.push_str(&format!("{}\n", path.display()));
+            }
+        }
+    }
+
+    output_buffer
+}
+
+fn report_paths(paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .map(|p| {
+            p.canonicalize().unwrap_or_else(|e| {
+                warn!("could not canonicalize {}: {e}", p.display());
+                p.clone()
+            })
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct ReportEntry<'a> {
+    producer: &'a str,
+    name: &'a str,
+    kind: String,
+    paths: Vec<PathBuf>,
+    labels: &'a BTreeMap<String, String>,
+}
+
+/// List every artifact as a JSON object, along with the labels set on its
+/// producer in the config.
+fn build_json_report(
+    artifacts: &[(&ConfiguredProducer, Box<dyn Artifact>)],
+    labels: &HashMap<String, BTreeMap<String, String>>,
+) -> Result<String> {
+    let no_labels = BTreeMap::new();
+    let entries = artifacts
+        .iter()
+        .map(|(producer, artifact)| ReportEntry {
+            producer: producer.name(),
+            name: artifact.name(),
+            kind: artifact.kind().to_string(),
+            paths: artifact
+                .paths()
+                .map(|paths| report_paths(&paths))
+                .unwrap_or_default(),
+            labels: labels.get(producer.name()).unwrap_or(&no_labels),
+        })
+        .collect::<Vec<_>>();
+
+    let mut output_buffer = serde_json::to_string_pretty(&entries)?;
+    output_buffer.push('\n');
+    Ok(output_buffer)
+}
+
+/// Log every lint finding for the artifact, failing if any are errors.
+async fn lint_artifact(artifact: &dyn Artifact, config: &LintConfig) -> Result<()> {
+    let memfs = artifact.extract().await?;
+    let findings = lint::lint(&memfs, config).await?;
+    for finding in &findings {
+        match finding.severity {
+            Severity::Info => info!("lint: {finding}"),
+            Severity::Warning => warn!("lint: {finding}"),
+            Severity::Error => error!("lint: {finding}"),
+        }
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(eyre!(
+            "{} has {errors} lint error(s), suppress them in `lint.suppress` if they're intended",
+            artifact.name()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Artifacts with more paths than this, ex. big [`FileProducer`] trees, have
+/// the filesystems they're on synced as a whole, since syncing every file on
+/// its own is slow.
+///
+/// [`FileProducer`]: crate::artifact::file::FileProducer
+const SYNCFS_THRESHOLD: usize = 64;
+
+/// Flush the artifact's files, and the directories they're listed in, to
+/// disk, so that everything a run reports is complete even if peckish, or the
+/// machine, goes down right after. Producers don't all sync what they write,
+/// so it's done once here instead. Symlinks can't be synced themselves, and
+/// artifacts that aren't stored as files, like Docker images, have nothing to
+/// sync.
+async fn finalize_outputs(artifact: &dyn Artifact) -> Result<()> {
+    let paths = artifact.paths().unwrap_or_default();
+    let dirs = paths
+        .iter()
+        .map(PathBuf::as_path)
+        .map(parent_dir)
+        .collect::<BTreeSet<_>>();
+
+    if paths.len() > SYNCFS_THRESHOLD {
+        // Any directory on a filesystem is enough to sync all of it.
+        let mut filesystems = BTreeMap::new();
+        for dir in dirs {
+            let device = tokio::fs::metadata(&dir).await?.dev();
+            filesystems.entry(device).or_insert(dir);
+        }
+        for dir in filesystems.into_values() {
+            debug!("syncing the filesystem {} is on", dir.display());
+            syncfs(&dir).await?;
+        }
+
+        return Ok(());
+    }
+
+    for path in &paths {
+        if tokio::fs::symlink_metadata(path).await?.is_symlink() {
+            continue;
+        }
+
+        debug!("syncing {}", path.display());
+        File::open(path).await?.sync_all().await?;
+    }
+
+    for dir in dirs {
+        sync_dir(&dir).await?;
+    }
+
+    Ok(())
+}
+
+/// The directory a path is listed in, ex. `.` for a bare file name.
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Sync a directory, so that the entries in it, and not just their contents,
+/// are on disk.
+async fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir).await?.sync_all().await?;
+
+    Ok(())
+}
+
+/// Sync everything on the filesystem that `dir` is on, see syncfs(2).
+async fn syncfs(dir: &Path) -> Result<()> {
+    let dir = File::open(dir).await?.into_std().await;
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: the fd stays open until `dir` is dropped, after the call.
+        match unsafe { libc::syncfs(dir.as_raw_fd()) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Remove the files that a producer would write to. Directories are never
+/// removed, so that a misconfigured path can't take a whole tree with it.
+async fn remove_existing_outputs(producer: &ConfiguredProducer) -> Result<()> {
+    // A file producer's output is a whole directory tree, so it can't be
+    // overwritten. Fail here rather than later with "path already exists".
+    if let ConfiguredProducer::File(file) = producer {
+        if tokio::fs::symlink_metadata(&file.path).await.is_ok() {
+            return Err(eyre!(
+                "{}: cannot overwrite file output {}, since it's a directory tree; remove it first",
+                producer.name(),
+                file.path.display()
+            ));
+        }
+    }
+
+    for path in producer.output_paths() {
+        let metadata = match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        if metadata.is_dir() {
+            return Err(eyre!(
+                "{}: refusing to overwrite directory: {}",
+                producer.name(),
+                path.display()
+            ));
+        }
+
+        info!("removing existing output: {}", path.display());
+        tokio::fs::remove_file(&path).await?;
+    }
+
+    Ok(())
+}
+
+/// Run a hook command on the host. The artifact's name and paths are passed in
+/// `PECKISH_ARTIFACT_NAME` and `PECKISH_ARTIFACT_PATH`; multiple paths are
+/// separated by `:`.
+async fn run_hook(kind: &str, command: &str, artifact: &dyn Artifact) -> Result<()> {
+    info!("running {kind} hook: {command}");
+    let paths = artifact
+        .paths()
+        .unwrap_or_default()
+        .iter()
+        .map(|p| p.display().to_string())
+        .join(":");
+
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PECKISH_ARTIFACT_NAME", artifact.name())
+        .env("PECKISH_ARTIFACT_PATH", paths)
+        .status()
+        .await?;
+
+    if !status.success() {
+        return Err(eyre!("{kind} hook `{command}` failed: {status}"));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use eyre::Result;
-    use smoosh::CompressionType;
+    use floppy_disk::FloppyDisk;
     use tokio::fs::File;
-    use tokio::io::AsyncReadExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
-    use crate::artifact::file::{FileArtifact, FileProducer};
-    use crate::artifact::tarball::TarballProducer;
-    use crate::fs::TempDir;
-    use crate::util::config::{ConfiguredProducer, Injection};
+    use crate::artifact::file::{FileArtifactBuilder, FileProducerBuilder};
+    use crate::artifact::tarball::TarballProducerBuilder;
+    use crate::artifact::SelfBuilder;
+    use crate::fs::test_utils::{CountingArtifact, Fixture};
+    use crate::fs::{MemFS, TempDir};
+    use crate::util::config::{ConfiguredArtifact, Injection, PeckishConfigBuilder, ProducerHooks};
+    use crate::util::sha256_digest_bytes;
 
     use super::*;
 
@@ -124,19 +615,19 @@ mod tests {
         let tmp = TempDir::new().await?;
         let tar = tmp.path_view().join("cargo.toml.tar");
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![ConfiguredProducer::Tarball(TarballProducer {
-                name: "cargo dot toml output".into(),
-                path: tar.clone(),
-                compression: CompressionType::None,
-                injections: vec![],
-            })],
-        };
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         assert!(pipeline.run(config).await.is_ok());
@@ -144,36 +635,130 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_outputs_are_complete_when_run_returns() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let out = tmp.path_view().join("out");
+        let tar = tmp.path_view().join("out.tar");
+        let content = b"small, but it all has to be there\n".repeat(100);
+        let create = Injection::Create {
+            path: "/usr/share/app/data".into(),
+            content: content.clone(),
+        };
+
+        let config = PeckishConfigBuilder::new()
+            .input(FileArtifactBuilder::new("nothing").build()?)
+            .add_output(
+                FileProducerBuilder::new("files")
+                    .path(&out)
+                    .inject(create.clone())
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("tarball")
+                    .path(&tar)
+                    .inject(create)
+                    .build()?,
+            )
+            .build()?;
+
+        let artifacts = Pipeline::new(None).run(config).await?;
+        assert_eq!(2, artifacts.len());
+
+        let data = out.join("usr/share/app/data");
+        assert_eq!(
+            content.len() as u64,
+            tokio::fs::metadata(&data).await?.len()
+        );
+        assert_eq!(content, tokio::fs::read(&data).await?);
+
+        // A header for each of /usr, /usr/share, /usr/share/app, and the
+        // file, the data padded to a whole block, and the two end-of-archive
+        // blocks.
+        let blocks = 4 + content.len().div_ceil(512) + 2;
+        let size = tokio::fs::metadata(&tar).await?.len();
+        assert_eq!((blocks * 512) as u64, size);
+        assert_eq!(Some(size), artifacts[1].stored_size().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spilled_outputs_keep_intended_owners() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("owned.tar");
+        let out = tmp.path_view().join("out");
+        let files = FileProducerBuilder::new("spilled")
+            .path(&out)
+            .spill_threshold(0)
+            .build()?;
+
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(FileArtifactBuilder::new("nothing").build()?)
+            .add_output(
+                TarballProducerBuilder::new("owned tarball")
+                    .path(&tar)
+                    .inject(Injection::Create {
+                        path: "/srv/data".into(),
+                        content: b"owned".to_vec(),
+                    })
+                    .inject(Injection::Chown {
+                        path: "/srv/data".into(),
+                        uid: 4242,
+                        gid: 4343,
+                        recursive: false,
+                    })
+                    .build()?,
+            )
+            .add_output(files.clone())
+            .build()?;
+        Pipeline::new(None).run(config).await?;
+
+        // Only root can hand the file to another user; everyone else gets
+        // the owners written down.
+        let data = out.join("srv/data");
+        assert_eq!(b"owned".to_vec(), tokio::fs::read(&data).await?);
+        let metadata = tokio::fs::symlink_metadata(&data).await?;
+        if (metadata.uid(), metadata.gid()) != (4242, 4343) {
+            let manifest = tokio::fs::read_to_string(files.owners_manifest_path()).await?;
+            assert!(
+                manifest.contains("/srv/data uid=4242 gid=4343\n"),
+                "{manifest}"
+            );
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_move_injection_works() -> Result<()> {
         let tar_dir = TempDir::new().await?;
         let tar = tar_dir.path_view().join("cargo.toml.moveinject.tar");
         let tmp = TempDir::new().await?;
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![
-                ConfiguredProducer::Tarball(TarballProducer {
-                    name: "cargo dot toml output".into(),
-                    path: tar.clone(),
-                    compression: CompressionType::None,
-                    injections: vec![Injection::Move {
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .inject(Injection::Move {
                         src: "Cargo.toml".into(),
                         dest: "Cargo-2.toml".into(),
-                    }],
-                }),
-                ConfiguredProducer::File(FileProducer {
-                    name: "unwrapper".into(),
-                    path: tmp.path_view(),
-                    preserve_empty_directories: None,
-                    injections: vec![],
-                }),
-            ],
-        };
+                    })
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("unwrapper")
+                    .path(tmp.path_view())
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         pipeline.run(config).await?;
@@ -189,30 +774,28 @@ mod tests {
         let tar = tar_dir.path_view().join("cargo.toml.copyinject.tar");
         let tmp = TempDir::new().await?;
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![
-                ConfiguredProducer::Tarball(TarballProducer {
-                    name: "cargo dot toml output".into(),
-                    path: tar.clone(),
-                    compression: CompressionType::None,
-                    injections: vec![Injection::Copy {
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .inject(Injection::Copy {
                         src: "Cargo.toml".into(),
                         dest: "Cargo-2.toml".into(),
-                    }],
-                }),
-                ConfiguredProducer::File(FileProducer {
-                    name: "unwrapper".into(),
-                    path: tmp.path_view(),
-                    preserve_empty_directories: None,
-                    injections: vec![],
-                }),
-            ],
-        };
+                    })
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("unwrapper")
+                    .path(tmp.path_view())
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         pipeline.run(config).await?;
@@ -228,30 +811,29 @@ mod tests {
         let tar = tar_dir.path_view().join("cargo.toml.symlinkinject.tar");
         let tmp = TempDir::new().await?;
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![
-                ConfiguredProducer::Tarball(TarballProducer {
-                    name: "cargo dot toml output".into(),
-                    path: tar.clone(),
-                    compression: CompressionType::None,
-                    injections: vec![Injection::Symlink {
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .inject(Injection::Symlink {
                         src: "Cargo.toml".into(),
                         dest: "Cargo-2.toml".into(),
-                    }],
-                }),
-                ConfiguredProducer::File(FileProducer {
-                    name: "unwrapper".into(),
-                    path: tmp.path_view(),
-                    preserve_empty_directories: None,
-                    injections: vec![],
-                }),
-            ],
-        };
+                        relative: None,
+                    })
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("unwrapper")
+                    .path(tmp.path_view())
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         pipeline.run(config).await?;
@@ -267,29 +849,29 @@ mod tests {
         let tar = tar_dir.path_view().join("cargo.toml.touchinject.tar");
         let tmp = TempDir::new().await?;
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![
-                ConfiguredProducer::Tarball(TarballProducer {
-                    name: "cargo dot toml output".into(),
-                    path: tar.clone(),
-                    compression: CompressionType::None,
-                    injections: vec![Injection::Touch {
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .inject(Injection::Touch {
                         path: "Cargo-2.toml".into(),
-                    }],
-                }),
-                ConfiguredProducer::File(FileProducer {
-                    name: "unwrapper".into(),
-                    path: tmp.path_view(),
-                    preserve_empty_directories: None,
-                    injections: vec![],
-                }),
-            ],
-        };
+                        mtime: None,
+                        atime: None,
+                    })
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("unwrapper")
+                    .path(tmp.path_view())
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         pipeline.run(config).await?;
@@ -305,29 +887,27 @@ mod tests {
         let tar = tar_dir.path_view().join("cargo.toml.deleteinject.tar");
         let tmp = TempDir::new().await?;
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![
-                ConfiguredProducer::Tarball(TarballProducer {
-                    name: "cargo dot toml output".into(),
-                    path: tar.clone(),
-                    compression: CompressionType::None,
-                    injections: vec![Injection::Delete {
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .inject(Injection::Delete {
                         path: "Cargo.toml".into(),
-                    }],
-                }),
-                ConfiguredProducer::File(FileProducer {
-                    name: "unwrapper".into(),
-                    path: tmp.path_view(),
-                    preserve_empty_directories: None,
-                    injections: vec![],
-                }),
-            ],
-        };
+                    })
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("unwrapper")
+                    .path(tmp.path_view())
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         pipeline.run(config).await?;
@@ -342,30 +922,28 @@ mod tests {
         let tar = tar_dir.path_view().join("cargo.toml.createinject.tar");
         let tmp = TempDir::new().await?;
 
-        let config = PeckishConfig {
-            chain: true,
-            input: ConfiguredArtifact::File(FileArtifact {
-                name: "cargo dot toml".into(),
-                paths: vec!["Cargo.toml".into()],
-            }),
-            output: vec![
-                ConfiguredProducer::Tarball(TarballProducer {
-                    name: "cargo dot toml output".into(),
-                    path: tar.clone(),
-                    compression: CompressionType::None,
-                    injections: vec![Injection::Create {
+        let config = PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("cargo dot toml output")
+                    .path(&tar)
+                    .inject(Injection::Create {
                         path: "Cargo-2.toml".into(),
                         content: "test".into(),
-                    }],
-                }),
-                ConfiguredProducer::File(FileProducer {
-                    name: "unwrapper".into(),
-                    path: tmp.path_view(),
-                    preserve_empty_directories: None,
-                    injections: vec![],
-                }),
-            ],
-        };
+                    })
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("unwrapper")
+                    .path(tmp.path_view())
+                    .build()?,
+            )
+            .build()?;
 
         let pipeline = Pipeline::new(None);
         pipeline.run(config).await?;
@@ -378,4 +956,687 @@ mod tests {
 
         Ok(())
     }
+
+    fn hooked_config(tar: PathBuf, hooks: ProducerHooks) -> Result<PeckishConfigBuilder> {
+        Ok(PeckishConfigBuilder::new()
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("hooked tarball")
+                    .path(tar)
+                    .build()?,
+            )
+            .hooks("hooked tarball", hooks))
+    }
+
+    #[tokio::test]
+    async fn test_after_hook_works() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("cargo.toml.hooked.tar");
+        let sentinel = tmp.path_view().join("sentinel");
+
+        let config = hooked_config(
+            tar.clone(),
+            ProducerHooks {
+                before: None,
+                after: Some(format!(
+                    "test -f \"$PECKISH_ARTIFACT_PATH\" && touch {}",
+                    sentinel.display()
+                )),
+            },
+        )?
+        .build()?;
+
+        Pipeline::new(None).allow_hooks(true).run(config).await?;
+        assert!(sentinel.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failing_hook_aborts_pipeline() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("cargo.toml.failhook.tar");
+
+        let config = hooked_config(
+            tar.clone(),
+            ProducerHooks {
+                before: Some("exit 1".into()),
+                after: None,
+            },
+        )?
+        .build()?;
+
+        assert!(Pipeline::new(None)
+            .allow_hooks(true)
+            .run(config)
+            .await
+            .is_err());
+        assert!(!tar.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hooks_require_allow_hooks() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("cargo.toml.disallowedhook.tar");
+        let sentinel = tmp.path_view().join("sentinel");
+
+        let config = hooked_config(
+            tar.clone(),
+            ProducerHooks {
+                before: Some(format!("touch {}", sentinel.display())),
+                after: None,
+            },
+        )?
+        .build()?;
+
+        assert!(Pipeline::new(None).run(config).await.is_err());
+        assert!(!sentinel.exists());
+        assert!(!tar.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_force_overwrites_existing_output() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("cargo.toml.force.tar");
+        let config = || {
+            hooked_config(tar.clone(), ProducerHooks::default())?
+                .chain(true)
+                .build()
+        };
+
+        Pipeline::new(None).run(config()?).await?;
+        assert!(Pipeline::new(None).run(config()?).await.is_err());
+        Pipeline::new(None).force(true).run(config()?).await?;
+        assert!(tar.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_errors_name_the_failing_step_and_phase() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("cargo.toml.exists.tar");
+        tokio::fs::write(&tar, b"already here").await?;
+
+        let config = hooked_config(tar.clone(), ProducerHooks::default())?
+            .chain(true)
+            .build()?;
+
+        let err = Pipeline::new(None).run(config).await.unwrap_err();
+        let err = err.to_string();
+        assert!(err.starts_with("step 1 (hooked tarball) failed during producer validation:"));
+        assert!(err.contains("path already exists"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_output_is_warned_about() -> Result<()> {
+        let config = || {
+            PeckishConfigBuilder::new()
+                .input(
+                    FileArtifactBuilder::new("cargo dot toml")
+                        .add_path("Cargo.toml")
+                        .build()?,
+                )
+                .build()
+        };
+
+        let (guard, logs) = crate::util::capture_logs();
+        assert!(Pipeline::new(None).run(config()?).await?.is_empty());
+        drop(guard);
+        assert!(logs.contains("config has no outputs"));
+
+        assert!(Pipeline::new(None)
+            .strict(true)
+            .run(config()?)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_force_never_removes_directories() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let tar = tmp.path_view().join("actually-a-directory");
+        tokio::fs::create_dir_all(tar.join("keep")).await?;
+
+        let config = hooked_config(tar.clone(), ProducerHooks::default())?
+            .overwrite(true)
+            .build()?;
+
+        assert!(Pipeline::new(None).run(config).await.is_err());
+        assert!(tar.join("keep").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_force_refuses_existing_file_outputs() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let out = tmp.path_view().join("out");
+        tokio::fs::create_dir_all(out.join("keep")).await?;
+
+        let config = PeckishConfigBuilder::new()
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("file output")
+                    .path(out.clone())
+                    .build()?,
+            )
+            .build()?;
+
+        let err = Pipeline::new(None)
+            .force(true)
+            .run(config)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("cannot overwrite file output"));
+        assert!(out.join("keep").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_skips_unchanged_input() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let input = tmp.path_view().join("input.txt");
+        let tar = tmp.path_view().join("cached.tar");
+        let cache_file = tmp.path_view().join("cache.json");
+        tokio::fs::write(&input, "hello").await?;
+
+        let config = || {
+            hooked_config(tar.clone(), ProducerHooks::default())?
+                .chain(true)
+                .input(FileArtifactBuilder::new("input").add_path(&input).build()?)
+                .build()
+        };
+        let pipeline = Pipeline::new(None).cache(Some(cache_file.clone()));
+
+        pipeline.run(config()?).await?;
+        let modified = tokio::fs::metadata(&tar).await?.modified()?;
+
+        // The output already exists, so this would fail validation if the
+        // producer actually ran.
+        let artifacts = pipeline.run(config()?).await?;
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].paths(), Some(vec![tar.clone()]));
+        assert_eq!(tokio::fs::metadata(&tar).await?.modified()?, modified);
+
+        tokio::fs::write(&input, "goodbye").await?;
+        assert!(pipeline.run(config()?).await.is_err());
+        Pipeline::new(None)
+            .cache(Some(cache_file.clone()))
+            .force(true)
+            .run(config()?)
+            .await?;
+        pipeline.run(config()?).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_sees_host_file_changes() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let license = tmp.path_view().join("LICENSE");
+        let tar = tmp.path_view().join("cached.tar");
+        let cache_file = tmp.path_view().join("cache.json");
+        tokio::fs::write(&license, "MIT").await?;
+
+        let config = || {
+            PeckishConfigBuilder::new()
+                .input(
+                    FileArtifactBuilder::new("cargo dot toml")
+                        .add_path("Cargo.toml")
+                        .build()?,
+                )
+                .add_output(
+                    TarballProducerBuilder::new("licensed")
+                        .path(&tar)
+                        .inject(Injection::HostFile {
+                            src: license.clone(),
+                            dest: "/usr/share/licenses/app/LICENSE".into(),
+                        })
+                        .build()?,
+                )
+                .build()
+        };
+        let pipeline = Pipeline::new(None).cache(Some(cache_file.clone()));
+
+        pipeline.run(config()?).await?;
+        pipeline.run(config()?).await?;
+
+        // The input is the same, but the injected file isn't, so the
+        // producer runs again and finds its old output in the way.
+        tokio::fs::write(&license, "Apache-2.0").await?;
+        assert!(pipeline.run(config()?).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_covers_source_date_epoch_and_umask() -> Result<()> {
+        let input = FileArtifactBuilder::new("cargo dot toml")
+            .add_path("Cargo.toml")
+            .build()?;
+        let producer = |injections: Vec<Injection>| -> Result<ConfiguredProducer> {
+            Ok(injections
+                .into_iter()
+                .fold(
+                    TarballProducerBuilder::new("keyed"),
+                    |builder, injection| builder.inject(injection),
+                )
+                .path("keyed.tar")
+                .build()?
+                .into())
+        };
+        let hash = |epoch, injections| {
+            let producer = producer(injections);
+            let input = &input;
+            crate::util::with_source_date_epoch(epoch, async move {
+                cache::hash_input(input, &producer?).await
+            })
+        };
+
+        let plain = hash(1, vec![]).await?;
+        assert!(plain.is_some());
+        assert_eq!(hash(1, vec![]).await?, plain);
+        assert_ne!(hash(2, vec![]).await?, plain);
+        assert_ne!(
+            hash(1, vec![Injection::Umask { umask: 0o022 }]).await?,
+            plain
+        );
+        assert_ne!(hash(1, vec![Injection::NormalizePermissions]).await?, plain);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_labels_are_in_the_json_report() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let report_file = tmp.path_view().join("report.json");
+        let config = PeckishConfig::parse(&indoc::formatdoc! {r#"
+            metadata:
+              name: test
+              version: 0.0.1-1
+              description: test
+              author: me
+              arch: amd64
+              license: Apache-2.0
+            input:
+              name: cargo dot toml
+              type: file
+              paths:
+                - Cargo.toml
+            output:
+              - name: labeled
+                type: tarball
+                path: {labeled}
+                labels:
+                  build: "1234"
+                  channel: nightly
+              - name: unlabeled
+                type: tarball
+                path: {unlabeled}
+        "#,
+            labeled = tmp.path_view().join("labeled.tar").display(),
+            unlabeled = tmp.path_view().join("unlabeled.tar").display(),
+        })?;
+
+        Pipeline::new(Some(report_file.clone()))
+            .report_format(ReportFormat::Json)
+            .run(config)
+            .await?;
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&tokio::fs::read(&report_file).await?)?;
+        assert_eq!("labeled", report[0]["producer"]);
+        assert_eq!("tarball", report[0]["kind"]);
+        assert_eq!("1234", report[0]["labels"]["build"]);
+        assert_eq!("nightly", report[0]["labels"]["channel"]);
+        assert_eq!("unlabeled", report[1]["producer"]);
+        assert_eq!(serde_json::json!({}), report[1]["labels"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outputs_are_synced_one_by_one_or_by_filesystem() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let mut small = FileArtifactBuilder::new("small");
+        let mut large = FileArtifactBuilder::new("large");
+        for i in 0..=SYNCFS_THRESHOLD {
+            let path = tmp.path_view().join(format!("{i}.txt"));
+            tokio::fs::write(&path, b"hi").await?;
+            if i < 2 {
+                small = small.add_path(&path);
+            }
+            large = large.add_path(path);
+        }
+
+        finalize_outputs(&small.build()?).await?;
+        finalize_outputs(&large.build()?).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_falls_back_to_non_canonical_paths() -> Result<()> {
+        let artifacts: Vec<Box<dyn Artifact>> = vec![
+            Box::new(
+                FileArtifactBuilder::new("gone")
+                    .add_path("./does/not/exist")
+                    .build()?,
+            ),
+            Box::new(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            ),
+        ];
+
+        let report = build_report(artifacts.iter().map(|a| a.as_ref()));
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "./does/not/exist");
+        assert_eq!(
+            lines[1],
+            PathBuf::from("Cargo.toml")
+                .canonicalize()?
+                .display()
+                .to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_has_a_line_per_path() -> Result<()> {
+        let artifacts: Vec<Box<dyn Artifact>> = vec![
+            Box::new(
+                FileArtifactBuilder::new("two paths")
+                    .add_path("./does/not/exist")
+                    .add_path("./does/not/exist/either")
+                    .build()?,
+            ),
+            Box::new(
+                FileArtifactBuilder::new("one path")
+                    .add_path("./also/missing")
+                    .build()?,
+            ),
+        ];
+
+        let report = build_report(artifacts.iter().map(|a| a.as_ref()));
+        assert_eq!(
+            report,
+            "./does/not/exist\n./does/not/exist/either\n./also/missing\n"
+        );
+
+        Ok(())
+    }
+
+    /// Serve a single HTTP response on a random local port, returning the URL
+    /// to request.
+    async fn serve_once(
+        status: &'static str,
+        body: impl AsRef<[u8]> + Send + 'static,
+    ) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/file", listener.local_addr()?);
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            let headers = format!(
+                "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                body.as_ref().len()
+            );
+            stream.write_all(headers.as_bytes()).await?;
+            stream.write_all(body.as_ref()).await?;
+            stream.shutdown().await
+        });
+
+        Ok(url)
+    }
+
+    fn remote_file_config(tmp: &TempDir, injection: Injection) -> Result<PeckishConfig> {
+        PeckishConfigBuilder::new()
+            .chain(true)
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                FileProducerBuilder::new("downloader")
+                    .path(tmp.path_view())
+                    .inject(injection)
+                    .build()?,
+            )
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_remote_file_injection_works() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let url = serve_once("200 OK", b"remote content").await?;
+
+        let config = remote_file_config(
+            &tmp,
+            Injection::RemoteFile {
+                url,
+                dest: "/usr/share/licenses/remote/LICENSE".into(),
+                sha256: Some(sha256_digest_bytes(b"remote content")),
+            },
+        )?;
+
+        Pipeline::new(None).allow_network(true).run(config).await?;
+        let downloaded =
+            tokio::fs::read(tmp.path_view().join("usr/share/licenses/remote/LICENSE")).await?;
+        assert_eq!(downloaded, b"remote content");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_file_injection_requires_allow_network() -> Result<()> {
+        let tmp = TempDir::new().await?;
+
+        let config = remote_file_config(
+            &tmp,
+            Injection::RemoteFile {
+                url: "http://127.0.0.1:1/file".into(),
+                dest: "/file".into(),
+                sha256: None,
+            },
+        )?;
+
+        assert!(Pipeline::new(None).run(config).await.is_err());
+        assert!(!tmp.path_view().join("Cargo.toml").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_file_injection_rejects_bad_downloads() -> Result<()> {
+        let mut memfs = MemFS::new();
+
+        let url = serve_once("404 Not Found", b"nope").await?;
+        let error = Injection::RemoteFile {
+            url,
+            dest: "/file".into(),
+            sha256: None,
+        }
+        .inject(&mut memfs)
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("404"));
+
+        let url = serve_once("200 OK", b"tampered").await?;
+        let error = Injection::RemoteFile {
+            url,
+            dest: "/file".into(),
+            sha256: Some(sha256_digest_bytes(b"original")),
+        }
+        .inject(&mut memfs)
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("checksum mismatch"));
+
+        assert!((*memfs).metadata("/file").await.is_err());
+
+        Ok(())
+    }
+
+    fn remote_input_config(tmp: &TempDir, url: &str, sha256: &str) -> Result<PeckishConfig> {
+        PeckishConfig::parse(&format!(
+            r#"
+metadata:
+  name: "test"
+  version: "0.0.1-1"
+  description: "test"
+  author: "me"
+  arch: "amd64"
+  license: "Apache-2.0"
+
+input:
+  name: "release"
+  type: "tarball"
+  url: "{url}"
+  sha256: "{sha256}"
+
+output:
+  - name: "unpacked"
+    type: "file"
+    path: "{}"
+"#,
+            tmp.path_view().display()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_input_is_downloaded_from_url() -> Result<()> {
+        let fixture = Fixture::new("oci.tar").await;
+        let tarball = tokio::fs::read(fixture.path_view()).await?;
+        let sha256 = sha256_digest_bytes(&tarball);
+        let url = serve_once("200 OK", tarball).await?;
+
+        let tmp = TempDir::new().await?;
+        let config = remote_input_config(&tmp, &url, &sha256)?;
+        Pipeline::new(None).allow_network(true).run(config).await?;
+
+        assert!(tmp.path_view().join("oci-layout").exists());
+        assert!(tmp.path_view().join("index.json").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_url_input_requires_allow_network() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let config = remote_input_config(&tmp, "http://127.0.0.1:1/release.tar", "abc")?;
+
+        let error = Pipeline::new(None).run(config).await.unwrap_err();
+        assert!(error.to_string().contains("--allow-network"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unchained_input_is_only_extracted_once() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let counting = CountingArtifact::default();
+        let tarball = |name: &str| {
+            TarballProducerBuilder::new(name).path(tmp.path_view().join(format!("{name}.tar")))
+        };
+
+        let config = PeckishConfigBuilder::new()
+            .input(ConfiguredArtifact::Custom(Arc::new(counting.clone())))
+            .add_output(
+                tarball("first")
+                    .inject(Injection::Delete {
+                        path: "/file".into(),
+                    })
+                    .build()?,
+            )
+            .add_output(tarball("second").build()?)
+            .add_output(tarball("third").build()?)
+            .build()?;
+
+        let artifacts = Pipeline::new(None).run(config).await?;
+        assert_eq!(1, counting.extracts());
+
+        // The first producer's injection mustn't leak into the others.
+        assert!(artifacts[0]
+            .extract()
+            .await?
+            .fs()
+            .metadata("/file")
+            .await
+            .is_err());
+        assert_eq!(
+            b"hi",
+            artifacts[2]
+                .extract()
+                .await?
+                .fs()
+                .read("/file")
+                .await?
+                .as_slice()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_last_unchained_step_is_handed_the_input() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let counting = CountingArtifact::default();
+        let config = PeckishConfigBuilder::new()
+            .input(ConfiguredArtifact::Custom(Arc::new(counting.clone())))
+            .add_output(
+                TarballProducerBuilder::new("only")
+                    .path(tmp.path_view().join("only.tar"))
+                    .build()?,
+            )
+            .build()?;
+
+        let artifacts = Pipeline::new(None).run(config).await?;
+        assert_eq!(1, counting.handed_over());
+        assert_eq!(1, counting.extracts());
+        assert_eq!(
+            b"hi",
+            artifacts[0]
+                .extract()
+                .await?
+                .fs()
+                .read("/file")
+                .await?
+                .as_slice()
+        );
+
+        Ok(())
+    }
 }
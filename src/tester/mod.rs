@@ -1,81 +1,159 @@
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use bollard::container::{CreateContainerOptions, WaitContainerOptions};
 use bollard::service::Mount;
-use eyre::Result;
+use eyre::{eyre, Result};
 use itertools::Itertools;
+use tokio::sync::Semaphore;
 use tracing::{error, info, warn};
 
-use crate::util::config::PeckishConfig;
+use crate::util::cleanup;
+use crate::util::config::{ConfiguredProducer, PeckishConfig};
 
-pub async fn test_packages(config: PeckishConfig) -> Result<()> {
-    for output in config.output.into_iter() {
+/// Installing a single package in a container.
+#[derive(Debug, Clone)]
+struct TestJob {
+    producer: String,
+    kind: &'static str,
+    package: PathBuf,
+    command: Vec<String>,
+    image: &'static str,
+}
+
+impl TestJob {
+    /// Run `command` in `image` with the package's path appended to it.
+    fn new(
+        producer: &str,
+        kind: &'static str,
+        package: &Path,
+        image: &'static str,
+        command: &[&str],
+    ) -> Self {
+        let mut command = command.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        command.push(app_path(package));
+
+        Self {
+            producer: producer.to_string(),
+            kind,
+            package: package.to_path_buf(),
+            command,
+            image,
+        }
+    }
+
+    /// Run the install, returning whether it exited successfully.
+    async fn run(&self) -> Result<bool> {
+        info!("testing producer: {} on {}", self.producer, self.image);
+        run_command_in_docker_with_mount(
+            self.kind,
+            self.package.clone(),
+            file_name(&self.package),
+            self.command.clone(),
+            self.image.into(),
+        )
+        .await
+    }
+}
+
+fn jobs_for(output: Vec<ConfiguredProducer>) -> Vec<TestJob> {
+    let mut jobs = vec![];
+    for output in output {
         match output {
-            crate::util::config::ConfiguredProducer::Tarball(producer) => {
-                info!("testing producer: {}", producer.name);
-                run_command_in_docker_with_mount(
-                    "tar".into(),
-                    producer.path.clone(),
-                    file_name(&producer.path),
-                    vec!["tar".into(), "fv".into(), app_path(&producer.path)],
-                    "alpine:latest".into(),
-                )
-                .await?;
+            ConfiguredProducer::Tarball(producer) => {
+                jobs.push(TestJob::new(
+                    &producer.name,
+                    "tar",
+                    &producer.path,
+                    "alpine:latest",
+                    &["tar", "tvf"],
+                ));
             }
-            crate::util::config::ConfiguredProducer::Arch(producer) => {
-                info!("testing producer: {}", producer.name);
-                run_command_in_docker_with_mount(
-                    "arch".into(),
-                    producer.path.clone(),
-                    file_name(&producer.path),
-                    vec![
-                        "pacman".into(),
-                        "--noconfirm".into(),
-                        "-U".into(),
-                        app_path(&producer.path),
-                    ],
-                    "archlinux:latest".into(),
-                )
-                .await?;
+            ConfiguredProducer::Arch(producer) => {
+                jobs.push(TestJob::new(
+                    &producer.name,
+                    "arch",
+                    &producer.path,
+                    "archlinux:latest",
+                    &["pacman", "--noconfirm", "-U"],
+                ));
             }
-            crate::util::config::ConfiguredProducer::Deb(producer) => {
-                info!("testing producer: {}", producer.name);
-                run_command_in_docker_with_mount(
-                    "deb".into(),
-                    producer.path.clone(),
-                    file_name(&producer.path),
-                    vec!["dpkg".into(), "-i".into(), app_path(&producer.path)],
-                    "debian:latest".into(),
-                )
-                .await?;
-
-                run_command_in_docker_with_mount(
-                    "deb".into(),
-                    producer.path.clone(),
-                    file_name(&producer.path),
-                    vec!["dpkg".into(), "-i".into(), app_path(&producer.path)],
-                    "ubuntu:latest".into(),
-                )
-                .await?;
+            ConfiguredProducer::Deb(producer) => {
+                for image in ["debian:latest", "ubuntu:latest"] {
+                    jobs.push(TestJob::new(
+                        &producer.name,
+                        "deb",
+                        &producer.path,
+                        image,
+                        &["dpkg", "-i"],
+                    ));
+                }
             }
-            crate::util::config::ConfiguredProducer::Rpm(producer) => {
-                info!("testing producer: {}", producer.name);
-                run_command_in_docker_with_mount(
-                    "rpm".into(),
-                    producer.path.clone(),
-                    file_name(&producer.path),
-                    vec!["rpm".into(), "-i".into(), app_path(&producer.path)],
-                    "fedora:latest".into(),
-                )
-                .await?;
+            ConfiguredProducer::Rpm(producer) => {
+                jobs.push(TestJob::new(
+                    &producer.name,
+                    "rpm",
+                    &producer.path,
+                    "fedora:latest",
+                    &["rpm", "-i"],
+                ));
             }
             _ => warn!("not handling producer: {}", output.name()),
         }
     }
 
+    jobs
+}
+
+/// Install every package that can be tested in a container, running up to
+/// `jobs` containers at once. Every install is run, even if some fail.
+pub async fn test_packages(config: PeckishConfig, jobs: usize) -> Result<()> {
+    let results = run_jobs(jobs_for(config.output), jobs).await;
+
+    let mut failures = vec![];
+    for (job, result) in results {
+        match result {
+            Ok(true) => info!("{} on {}: passed", job.producer, job.image),
+            Ok(false) => {
+                failures.push(format!("{} on {}: install failed", job.producer, job.image))
+            }
+            Err(e) => failures.push(format!("{} on {}: {e}", job.producer, job.image)),
+        }
+    }
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            error!("{failure}");
+        }
+
+        return Err(eyre!(
+            "{} package test(s) failed:\n{}",
+            failures.len(),
+            failures.join("\n")
+        ));
+    }
+
     Ok(())
 }
 
+/// Run the jobs with at most `concurrency` at once, returning every job's
+/// result in the order the jobs were given.
+async fn run_jobs(jobs: Vec<TestJob>, concurrency: usize) -> Vec<(TestJob, Result<bool>)> {
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let runs = jobs.into_iter().map(|job| {
+        let semaphore = semaphore.clone();
+        async move {
+            let result = match semaphore.acquire().await {
+                Ok(_permit) => job.run().await,
+                Err(e) => Err(e.into()),
+            };
+            (job, result)
+        }
+    });
+
+    futures_util::future::join_all(runs).await
+}
+
 fn file_name(src: &Path) -> String {
     src.file_name().unwrap().to_string_lossy().to_string()
 }
@@ -86,13 +164,14 @@ fn app_path(src: &Path) -> String {
 }
 
 async fn run_command_in_docker_with_mount(
-    kind: String,
+    kind: &str,
     mount_src: PathBuf,
     mount_dest: String,
     command: Vec<String>,
     image: String,
-) -> Result<()> {
-    let name = format!("peckish-tester-{kind}");
+) -> Result<bool> {
+    // Jobs run at the same time, so names can't just be the package kind.
+    let name = format!("peckish-tester-{kind}-{}", rand::random::<u64>());
     let docker = bollard::Docker::connect_with_local_defaults()?;
 
     // create the container from $image
@@ -118,6 +197,7 @@ async fn run_command_in_docker_with_mount(
             },
         )
         .await?;
+    cleanup::register_container(&name);
 
     // start the container
     docker
@@ -168,6 +248,70 @@ async fn run_command_in_docker_with_mount(
     }
 
     docker.remove_container(&name, None).await?;
+    cleanup::unregister_container(&name);
 
-    Ok(())
+    Ok(!needs_logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::artifact::memory::EmptyArtifact;
+    use crate::artifact::tarball::TarballProducerBuilder;
+    use crate::artifact::{ArtifactProducer, SelfBuilder};
+    use crate::fs::TempDir;
+    use crate::util::config::Injection;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    async fn docker_available() -> bool {
+        match bollard::Docker::connect_with_local_defaults() {
+            Ok(docker) => docker.ping().await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_jobs_all_report_results() -> Result<()> {
+        if !docker_available().await {
+            warn!("docker is not available, skipping");
+            return Ok(());
+        }
+
+        let tmp = TempDir::new().await?;
+        let mut jobs = vec![];
+        for name in ["one", "two"] {
+            let path = tmp.path_view().join(format!("{name}.tar"));
+            TarballProducerBuilder::new(name)
+                .path(&path)
+                .inject(Injection::Create {
+                    path: format!("/{name}").into(),
+                    content: name.as_bytes().to_vec(),
+                })
+                .build()?
+                .produce_from(&EmptyArtifact::new("empty"))
+                .await?;
+            jobs.push(TestJob::new(
+                name,
+                "tar",
+                &path,
+                "alpine:latest",
+                &["tar", "tvf"],
+            ));
+        }
+
+        let results = run_jobs(jobs, 2).await;
+        assert_eq!(2, results.len());
+        assert_eq!("one", results[0].0.producer);
+        assert_eq!("two", results[1].0.producer);
+        for (job, result) in results {
+            assert!(result?, "{} failed", job.producer);
+        }
+
+        Ok(())
+    }
 }
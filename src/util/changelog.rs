@@ -0,0 +1,65 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// A single release in a package's changelog. Changelogs are listed newest
+/// first, as both deb and rpm expect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangelogEntry {
+    pub version: String,
+    /// The release date, as `YYYY-MM-DD`.
+    pub date: String,
+    /// Who made the release, ex. `Jane Doe <jane@example.com>`.
+    pub author: String,
+    pub notes: Vec<String>,
+}
+
+impl ChangelogEntry {
+    /// The release date, at midnight UTC.
+    pub fn timestamp(&self) -> Result<DateTime<Utc>> {
+        let date = NaiveDate::parse_from_str(&self.date, "%Y-%m-%d").map_err(|e| {
+            eyre!(
+                "changelog date `{}` for version {} is invalid, must be YYYY-MM-DD: {e}",
+                self.date,
+                self.version
+            )
+        })?;
+
+        Ok(date.and_time(Default::default()).and_utc())
+    }
+}
+
+/// Render a `debian/changelog`, as installed to
+/// `/usr/share/doc/<package>/changelog.Debian.gz`.
+pub fn render_debian(package: &str, entries: &[ChangelogEntry]) -> Result<String> {
+    let mut out = String::new();
+    for entry in entries {
+        out.push_str(&format!(
+            "{package} ({}) unstable; urgency=medium\n\n",
+            entry.version
+        ));
+        for note in &entry.notes {
+            out.push_str(&format!("  * {note}\n"));
+        }
+        out.push_str(&format!(
+            "\n -- {}  {}\n\n",
+            entry.author,
+            entry.timestamp()?.to_rfc2822()
+        ));
+    }
+
+    Ok(out)
+}
+
+/// The `(name, text)` pair RPM stores for a `%changelog` entry, ex.
+/// `Jane Doe <jane@example.com> - 1.0.0` and `- fixed a bug`.
+pub fn rpm_entry(entry: &ChangelogEntry) -> (String, String) {
+    let text = entry
+        .notes
+        .iter()
+        .map(|note| format!("- {note}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    (format!("{} - {}", entry.author, entry.version), text)
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use bollard::container::RemoveContainerOptions;
+use tracing::*;
+
+/// Everything that's currently left on the host, and would be leaked if
+/// peckish exited without running destructors, ex. on Ctrl-C.
+static REGISTRY: Mutex<Cleanup> = Mutex::new(Cleanup::new());
+
+/// Temp dirs and containers waiting to be cleaned up.
+#[derive(Debug, Default)]
+pub struct Cleanup {
+    temp_dirs: BTreeSet<PathBuf>,
+    containers: BTreeSet<String>,
+}
+
+impl Cleanup {
+    pub const fn new() -> Self {
+        Self {
+            temp_dirs: BTreeSet::new(),
+            containers: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_temp_dir(&mut self, path: &Path) {
+        self.temp_dirs.insert(path.to_path_buf());
+    }
+
+    pub fn add_container(&mut self, name: &str) {
+        self.containers.insert(name.to_string());
+    }
+
+    /// Remove every temp dir. Failures are only logged, since this runs
+    /// while peckish is already on its way out.
+    pub fn remove_temp_dirs(&mut self) {
+        for path in std::mem::take(&mut self.temp_dirs) {
+            if !path.exists() {
+                continue;
+            }
+
+            info!("removing temp dir {}", path.display());
+            if let Err(e) = std::fs::remove_dir_all(&path) {
+                warn!("could not remove temp dir {}: {e}", path.display());
+            }
+        }
+    }
+
+    /// Force-remove every container, even if it's still running.
+    pub async fn remove_containers(&mut self) {
+        let containers = std::mem::take(&mut self.containers);
+        if containers.is_empty() {
+            return;
+        }
+
+        let docker = match bollard::Docker::connect_with_local_defaults() {
+            Ok(docker) => docker,
+            Err(e) => {
+                warn!("could not connect to docker to remove containers: {e}");
+                return;
+            }
+        };
+        for name in containers {
+            info!("removing container {name}");
+            let options = RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            };
+            if let Err(e) = docker.remove_container(&name, Some(options)).await {
+                warn!("could not remove container {name}: {e}");
+            }
+        }
+    }
+}
+
+/// Remember a temp dir, so that it's removed if peckish is interrupted.
+pub fn register_temp_dir(path: &Path) {
+    REGISTRY.lock().unwrap().add_temp_dir(path);
+}
+
+/// Forget a temp dir that's been removed.
+pub fn unregister_temp_dir(path: &Path) {
+    REGISTRY.lock().unwrap().temp_dirs.remove(path);
+}
+
+/// Remember a container, so that it's removed if peckish is interrupted.
+pub fn register_container(name: &str) {
+    REGISTRY.lock().unwrap().add_container(name);
+}
+
+/// Forget a container that's been removed.
+pub fn unregister_container(name: &str) {
+    REGISTRY.lock().unwrap().containers.remove(name);
+}
+
+/// Remove every registered temp dir and container.
+pub async fn run() {
+    let mut cleanup = std::mem::take(&mut *REGISTRY.lock().unwrap());
+    cleanup.remove_temp_dirs();
+    cleanup.remove_containers().await;
+}
+
+/// Wait for SIGINT or SIGTERM, returning the name of the signal.
+pub async fn interrupted() -> std::io::Result<&'static str> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[test]
+    fn test_registered_temp_dirs_are_removed() {
+        let mut cleanup = Cleanup::new();
+        let dirs = (0..2)
+            .map(|_| {
+                let path =
+                    std::env::temp_dir().join(format!("peckish-cleanup-{}", rand::random::<u64>()));
+                std::fs::create_dir_all(path.join("nested")).unwrap();
+                std::fs::write(path.join("nested/file"), b"hi").unwrap();
+                cleanup.add_temp_dir(&path);
+                path
+            })
+            .collect::<Vec<_>>();
+        // Dirs that are already gone are skipped.
+        cleanup.add_temp_dir(&std::env::temp_dir().join("peckish-cleanup-missing"));
+
+        cleanup.remove_temp_dirs();
+
+        for dir in dirs {
+            assert!(!dir.exists(), "{} was not removed", dir.display());
+        }
+        assert!(cleanup.temp_dirs.is_empty());
+    }
+}
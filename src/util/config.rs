@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use disk_drive::DiskDrive;
 use eyre::{eyre, Result};
-use floppy_disk::mem::MemOpenOptions;
+use floppy_disk::mem::{MemOpenOptions, MemPermissions};
 use floppy_disk::tokio_fs::TokioFloppyDisk;
-use floppy_disk::{FloppyDisk, FloppyMetadata, FloppyOpenOptions};
+use floppy_disk::{
+    FloppyDisk, FloppyDiskUnixExt, FloppyMetadata, FloppyOpenOptions, FloppyUnixPermissions,
+};
 use serde::{Deserialize, Serialize};
 use smoosh::CompressionType;
 use tokio::fs::File;
@@ -13,127 +16,932 @@ use tokio::io::AsyncReadExt;
 use tracing::*;
 
 use crate::artifact::arch::{ArchArtifact, ArchProducer};
-use crate::artifact::deb::{DebArtifact, DebProducer};
-use crate::artifact::docker::{DockerArtifact, DockerProducer};
-use crate::artifact::ext4::{Ext4Artifact, Ext4Producer};
-use crate::artifact::file::{FileArtifact, FileProducer};
+use crate::artifact::cab::{CabArtifact, CabCompression, CabProducer};
+use crate::artifact::deb::{DebArtifact, DebProducer, DEB_FORMAT_VERSION};
+use crate::artifact::docker::{DockerArtifact, DockerProducer, PullPolicy};
+use crate::artifact::dockerfile::DockerfileProducer;
+use crate::artifact::ext4::{Ext4Artifact, Ext4Producer, DEFAULT_SIZE_SLACK};
+use crate::artifact::file::{
+    copy_host_dir, excluded_names, FileArtifact, FilePlacement, FileProducer,
+};
+use crate::artifact::iso::{IsoArtifact, IsoProducer};
+use crate::artifact::macos::{MacosPkgArtifact, MacosPkgProducer};
+use crate::artifact::manifest::{ManifestFormat, ManifestProducer};
 use crate::artifact::oci::{OciArtifact, OciProducer};
+use crate::artifact::registry::Registry;
+use crate::artifact::remote::RemoteArtifact;
 use crate::artifact::rpm::{RpmArtifact, RpmProducer};
-use crate::artifact::tarball::{TarballArtifact, TarballProducer};
-use crate::artifact::{Artifact, ArtifactProducer, SelfValidation};
-use crate::fs::MemFS;
+use crate::artifact::sbom::{SbomFormat, SbomProducer};
+use crate::artifact::script::{ScriptInstallerArtifact, ScriptInstallerProducer};
+use crate::artifact::tarball::{EntryNames, TarballArtifact, TarballProducer};
+use crate::artifact::{
+    Artifact, ArtifactKind, ArtifactProducer, DynArtifactProducer, SelfValidation,
+};
+use crate::fs::{MemFS, MergePolicy, OwnerRemap};
+use crate::util::changelog::ChangelogEntry;
+use crate::util::elf::{self, ElfPatch};
+use crate::util::lint::LintConfig;
+use crate::util::split;
+use crate::util::{download, get_current_time};
+
+/// The newest config version that this release of peckish understands.
+pub const CONFIG_VERSION: u32 = 1;
 
 #[derive(Debug)]
 pub struct PeckishConfig {
     pub input: ConfiguredArtifact,
     pub output: Vec<ConfiguredProducer>,
     pub chain: bool,
+    /// Remove existing outputs before producing them, instead of failing.
+    pub overwrite: bool,
+    /// Shell hooks to run around producers, keyed by producer name.
+    pub hooks: HashMap<String, ProducerHooks>,
+    /// Settings for `--lint`.
+    pub lint: LintConfig,
+    /// Labels to put in the JSON report, keyed by producer name.
+    pub labels: HashMap<String, BTreeMap<String, String>>,
 }
 
 impl PeckishConfig {
+    /// A config that runs each output on the input, with everything else
+    /// left at its default.
+    pub fn new(input: ConfiguredArtifact, output: Vec<ConfiguredProducer>) -> Self {
+        Self {
+            input,
+            output,
+            chain: false,
+            overwrite: false,
+            hooks: HashMap::new(),
+            lint: LintConfig::default(),
+            labels: HashMap::new(),
+        }
+    }
+
     pub async fn load(config: Option<String>) -> Result<Self> {
-        let config_file: PathBuf = config.unwrap_or_else(|| "./peckish.yaml".into()).into();
-        info!("loading config from {}", config_file.display());
-        let mut config_file = File::open(config_file).await?;
-        let mut config_str = String::new();
-        config_file.read_to_string(&mut config_str).await?;
+        Self::load_with_registry(config, &Registry::default()).await
+    }
+
+    /// Load a config, resolving `plugin` inputs and outputs from the given
+    /// registry.
+    pub async fn load_with_registry(config: Option<String>, registry: &Registry) -> Result<Self> {
+        Self::parse_with_registry(&read_config_file(config).await?, registry)
+    }
+
+    /// Load a config and render it the way peckish sees it once it's
+    /// resolved, ex. with path tokens expanded, for `--print-config`.
+    pub async fn load_resolved(config: Option<String>, format: ConfigFormat) -> Result<String> {
+        Self::resolve(&read_config_file(config).await?, format)
+    }
+
+    /// Resolve a config from a YAML string and render it, see
+    /// [`PeckishConfig::load_resolved`]. The config is checked the same way
+    /// as when it's run, so a config that prints is one that loads.
+    pub fn resolve(config_str: &str, format: ConfigFormat) -> Result<String> {
+        Self::parse(config_str)?;
+        let config = resolve_internal(config_str)?;
+
+        Ok(match format {
+            ConfigFormat::Yaml => serde_yaml::to_string(&config)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(&config)? + "\n",
+        })
+    }
+
+    /// Parse a config from a YAML string.
+    pub fn parse(config_str: &str) -> Result<Self> {
+        Self::parse_with_registry(config_str, &Registry::default())
+    }
 
-        let config: InternalConfig = serde_yaml::from_str(&config_str)?;
+    /// Parse a config from a YAML string, resolving `plugin` inputs and
+    /// outputs from the given registry.
+    pub fn parse_with_registry(config_str: &str, registry: &Registry) -> Result<Self> {
+        let config = resolve_internal(config_str)?;
+
+        let mut output: Vec<ConfiguredProducer> = config
+            .output
+            .iter()
+            .map(|o| o.producer.convert(&config, registry))
+            .collect::<Result<_>>()?;
+
+        for (producer, step) in output.iter_mut().zip(&config.output) {
+            if let Some(umask) = step.umask {
+                let name = producer.name().to_string();
+                producer
+                    .injections_mut()
+                    .ok_or_else(|| {
+                        eyre!("producer '{name}': umask is not supported for plugin producers")
+                    })?
+                    .insert(0, Injection::Umask { umask });
+            }
+
+            if step.normalize_permissions {
+                let name = producer.name().to_string();
+                producer
+                    .injections_mut()
+                    .ok_or_else(|| {
+                        eyre!("producer '{name}': normalize_permissions is not supported for plugin producers")
+                    })?
+                    .push(Injection::NormalizePermissions);
+            }
+        }
+
+        let input = config.input.clone().convert(registry)?;
+        check_outputs(&input, &output, config.chain)?;
+
+        let mut hooks = HashMap::new();
+        for (producer, step) in output.iter().zip(&config.output) {
+            if step.hooks.is_empty() {
+                continue;
+            }
+
+            hooks.insert(producer.name().to_string(), step.hooks.clone());
+        }
+
+        let labels = output
+            .iter()
+            .zip(&config.output)
+            .filter(|(_, step)| !step.labels.is_empty())
+            .map(|(producer, step)| (producer.name().to_string(), step.labels.clone()))
+            .collect();
 
         Ok(Self {
-            input: config.input.clone().into(),
-            output: config
-                .output
-                .iter()
-                .map(|o| o.clone().convert(&config))
-                .collect(),
+            input,
+            output,
             chain: config.chain,
+            overwrite: config.overwrite,
+            hooks,
+            lint: config.lint,
+            labels,
         })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How `--print-config` renders the resolved config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+async fn read_config_file(config: Option<String>) -> Result<String> {
+    let config_file: PathBuf = config.unwrap_or_else(|| "./peckish.yaml".into()).into();
+    info!("loading config from {}", config_file.display());
+    let mut config_file = File::open(config_file).await?;
+    let mut config_str = String::new();
+    config_file.read_to_string(&mut config_str).await?;
+
+    Ok(config_str)
+}
+
+/// Parse a config's YAML and resolve everything that doesn't depend on the
+/// registry, like path tokens.
+fn resolve_internal(config_str: &str) -> Result<InternalConfig> {
+    // Check the version before anything else, so that a config from a
+    // newer peckish fails with a useful error instead of a parse error.
+    let probe: ConfigVersionProbe = serde_yaml::from_str(config_str)?;
+    check_config_version(probe.version)?;
+
+    let mut config: InternalConfig = serde_yaml::from_str(config_str)?;
+    config.check_injection_names()?;
+    for step in &mut config.output {
+        step.producer
+            .expand_path_tokens(&config.metadata)
+            .map_err(|e| eyre!("producer '{}': {e}", step.producer.name()))?;
+    }
+
+    Ok(config)
+}
+
+/// Check the producers of a config against each other and their injections.
+fn check_outputs(
+    input: &ConfiguredArtifact,
+    output: &[ConfiguredProducer],
+    chain: bool,
+) -> Result<()> {
+    // Hooks, logs, and reports all refer to producers by name, so names
+    // have to be unique.
+    let mut names = HashSet::new();
+    for producer in output {
+        if !names.insert(producer.name()) {
+            return Err(eyre!(
+                "producer name '{}' is used more than once, producer names must be unique",
+                producer.name()
+            ));
+        }
+    }
+
+    for (i, producer) in output.iter().enumerate() {
+        Injection::check_order(producer.injections())
+            .and_then(|_| Injection::check_artifact_sources(&output[i..]))
+            .map_err(|e| eyre!("producer '{}': {e}", producer.name()))?;
+    }
+
+    check_self_references(input, output, chain)
+}
+
+/// Producers can't write to the artifact they read from, since it'd be
+/// removed or written over while it's still being read. Every producer reads
+/// the input, unless the pipeline is chained, in which case each one reads
+/// the previous producer's output. The error names the chain of steps that
+/// loops back on itself.
+fn check_self_references(
+    input: &ConfiguredArtifact,
+    output: &[ConfiguredProducer],
+    chain: bool,
+) -> Result<()> {
+    let mut steps = vec!["input".to_string()];
+    let mut reads = input.clone().into_artifact()?.paths().unwrap_or_default();
+
+    for producer in output {
+        let writes = producer.output_paths();
+        steps.push(format!("'{}'", producer.name()));
+        if let Some(path) = writes
+            .iter()
+            .find(|path| reads.iter().any(|read| same_path(read, path)))
+        {
+            return Err(eyre!(
+                "producer '{}' writes to {}, which it also reads from: {}",
+                producer.name(),
+                path.display(),
+                steps.join(" -> ")
+            ));
+        }
+
+        if chain {
+            reads = writes;
+        } else {
+            steps.pop();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether two paths are the same, without touching the disk, since outputs
+/// usually don't exist yet.
+fn same_path(a: &Path, b: &Path) -> bool {
+    let normalize = |path: &Path| -> PathBuf {
+        let path = if path.is_relative() {
+            std::env::current_dir()
+                .map(|cwd| cwd.join(path))
+                .unwrap_or_else(|_| path.to_path_buf())
+        } else {
+            path.to_path_buf()
+        };
+        path.components()
+            .filter(|c| !matches!(c, std::path::Component::CurDir))
+            .collect()
+    };
+
+    normalize(a) == normalize(b)
+}
+
+/// Builds a [`PeckishConfig`] in code, without going through YAML. The
+/// config is checked the same way a parsed one is when it's built.
+#[derive(Debug, Default)]
+pub struct PeckishConfigBuilder {
+    input: Option<ConfiguredArtifact>,
+    output: Vec<ConfiguredProducer>,
+    chain: bool,
+    overwrite: bool,
+    hooks: HashMap<String, ProducerHooks>,
+    lint: LintConfig,
+    labels: HashMap<String, BTreeMap<String, String>>,
+}
+
+#[allow(unused)]
+impl PeckishConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn input<A: Into<ConfiguredArtifact>>(mut self, input: A) -> Self {
+        self.input = Some(input.into());
+        self
+    }
+
+    pub fn add_output<P: Into<ConfiguredProducer>>(mut self, output: P) -> Self {
+        self.output.push(output.into());
+        self
+    }
+
+    pub fn chain(mut self, chain: bool) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    pub fn hooks<S: Into<String>>(mut self, producer: S, hooks: ProducerHooks) -> Self {
+        self.hooks.insert(producer.into(), hooks);
+        self
+    }
+
+    pub fn lint(mut self, lint: LintConfig) -> Self {
+        self.lint = lint;
+        self
+    }
+
+    pub fn labels<S: Into<String>>(
+        mut self,
+        producer: S,
+        labels: BTreeMap<String, String>,
+    ) -> Self {
+        self.labels.insert(producer.into(), labels);
+        self
+    }
+
+    pub fn build(&self) -> Result<PeckishConfig> {
+        let input = self
+            .input
+            .clone()
+            .ok_or_else(|| eyre!("config has no input"))?;
+        check_outputs(&input, &self.output, self.chain)?;
+        for producer in self.hooks.keys() {
+            if !self.output.iter().any(|p| p.name() == producer) {
+                return Err(eyre!("hooks are set for unknown producer '{producer}'"));
+            }
+        }
+        for producer in self.labels.keys() {
+            if !self.output.iter().any(|p| p.name() == producer) {
+                return Err(eyre!("labels are set for unknown producer '{producer}'"));
+            }
+        }
+
+        Ok(PeckishConfig {
+            input,
+            output: self.output.clone(),
+            chain: self.chain,
+            overwrite: self.overwrite,
+            hooks: self.hooks.clone(),
+            lint: self.lint.clone(),
+            labels: self.labels.clone(),
+        })
+    }
+}
+
+/// Shell commands to run on the host before and after a producer runs. The
+/// commands are run with `sh -c`, and a nonzero exit aborts the pipeline.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProducerHooks {
+    /// Run before the producer, with the input artifact in the environment.
+    #[serde(default)]
+    pub before: Option<String>,
+    /// Run after the producer, with the produced artifact in the environment.
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+impl ProducerHooks {
+    pub fn is_empty(&self) -> bool {
+        self.before.is_none() && self.after.is_none()
+    }
+}
+
+fn check_config_version(version: u32) -> Result<()> {
+    if version > CONFIG_VERSION {
+        return Err(eyre!(
+            "config version {version} is newer than the newest supported version ({CONFIG_VERSION}), please upgrade peckish"
+        ));
+    }
+
+    if version < CONFIG_VERSION {
+        warn!(
+            "config version {version} is older than the current version ({CONFIG_VERSION}), some fields may be interpreted differently"
+        );
+    }
+
+    Ok(())
+}
+
+/// Replace `{name}`, `{version}`, and `{arch}` with the package's metadata,
+/// ex. `./{name}_{version}_{arch}.deb` becomes `./peckish_0.0.8_amd64.deb`.
+fn expand_tokens(template: &str, metadata: &PackageMetadata) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| eyre!("unclosed token in path `{template}`"))?;
+        let token = &rest[start + 1..start + end];
+        expanded.push_str(match token {
+            "name" | "version" | "arch" => metadata.get(token).ok_or_else(|| {
+                eyre!("path `{template}` uses `{{{token}}}`, but `metadata.{token}` isn't set")
+            })?,
+            _ => {
+                return Err(eyre!(
+                    "unknown token `{{{token}}}` in path `{template}`, expected one of {{name}}, {{version}}, or {{arch}}"
+                ))
+            }
+        });
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+fn default_config_version() -> u32 {
+    1
+}
+
+fn default_deb_format_version() -> String {
+    DEB_FORMAT_VERSION.into()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigVersionProbe {
+    #[serde(default = "default_config_version")]
+    version: u32,
+}
+
+/// Only package formats need every field, so a config that only produces
+/// ex. tarballs can leave out some or all of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct PackageMetadata {
-    name: String,
-    version: String,
-    description: String,
-    author: String,
-    arch: String,
-    license: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    license: Option<String>,
+}
+
+impl PackageMetadata {
+    fn get(&self, field: &str) -> Option<&str> {
+        match field {
+            "name" => self.name.as_deref(),
+            "version" => self.version.as_deref(),
+            "description" => self.description.as_deref(),
+            "author" => self.author.as_deref(),
+            "arch" => self.arch.as_deref(),
+            "license" => self.license.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct InternalConfig {
+    #[serde(default = "default_config_version")]
+    version: u32,
     #[serde(default)]
     chain: bool,
+    #[serde(default)]
+    overwrite: bool,
+    #[serde(default)]
+    defaults: ProducerDefaults,
+    #[serde(default)]
     metadata: PackageMetadata,
     input: InputArtifact,
-    output: Vec<OutputProducer>,
+    output: Vec<OutputStep>,
     #[serde(default)]
     injections: HashMap<String, Injection>,
+    #[serde(default)]
+    lint: LintConfig,
+}
+
+/// Defaults for any producer that doesn't set its own.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ProducerDefaults {
+    #[serde(default)]
+    compression: Option<ConfigCompression>,
+}
+
+/// The keys a struct reads when it's deserialized, straight from its
+/// `Deserialize` impl, so lists of them can't drift from the struct. Only
+/// works for plain structs, since flattened ones are read as maps.
+pub(crate) fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(
+            self,
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(serde::de::Error::custom("only reading the field names"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
 }
 
+impl InternalConfig {
+    /// Reject injections with reserved names and references to injections
+    /// that don't exist, and warn about injections no producer uses, since
+    /// they're usually typos or dead config.
+    fn check_injection_names(&self) -> Result<()> {
+        // Injections can't be named after top-level config keys, so that a
+        // misplaced key can't be mistaken for an injection or vice versa.
+        let top_level_keys = struct_fields::<InternalConfig>();
+        let mut reserved = self
+            .injections
+            .keys()
+            .filter(|name| top_level_keys.contains(&name.as_str()))
+            .collect::<Vec<_>>();
+        reserved.sort();
+        if let Some(name) = reserved.first() {
+            return Err(eyre!(
+                "injection name '{name}' is reserved, since it's also a top-level config key"
+            ));
+        }
+
+        let mut used = HashSet::new();
+        for step in &self.output {
+            for injection in step.producer.injection_refs() {
+                let InjectionRef::Named(injection) = injection else {
+                    continue;
+                };
+                if !self.injections.contains_key(injection) {
+                    return Err(eyre!(
+                        "producer '{}' uses injection '{injection}', which isn't defined in `injections`",
+                        step.producer.name()
+                    ));
+                }
+                used.insert(injection.as_str());
+            }
+        }
+
+        let mut unused = self
+            .injections
+            .keys()
+            .filter(|name| !used.contains(name.as_str()))
+            .collect::<Vec<_>>();
+        unused.sort();
+        for name in unused {
+            warn!("injection '{name}' is defined, but no producer uses it");
+        }
+
+        Ok(())
+    }
+
+    /// A producer's injections, with named ones looked up in `injections`.
+    /// Names are checked up front, so every one of them exists by now.
+    fn resolve_injections(&self, injections: &[InjectionRef]) -> Vec<Injection> {
+        injections
+            .iter()
+            .map(|injection| match injection {
+                InjectionRef::Named(name) => self.injections[name].clone(),
+                InjectionRef::Inline(injection) => injection.clone(),
+            })
+            .collect()
+    }
+
+    /// A producer's compression, falling back to `defaults.compression`, and
+    /// then to the format's own default.
+    fn compression_or(
+        &self,
+        compression: &Option<ConfigCompression>,
+        format_default: CompressionType,
+    ) -> CompressionType {
+        compression
+            .clone()
+            .or_else(|| self.defaults.compression.clone())
+            .map(|c| c.into())
+            .unwrap_or(format_default)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OutputStep {
+    #[serde(flatten)]
+    producer: OutputProducer,
+    #[serde(flatten)]
+    hooks: ProducerHooks,
+    /// Applied before the step's own injections, see [`Injection::Umask`].
+    #[serde(default)]
+    umask: Option<u32>,
+    /// Applied after the step's own injections, see
+    /// [`Injection::NormalizePermissions`].
+    #[serde(default)]
+    normalize_permissions: bool,
+    /// Echoed into the JSON report for the step's artifact.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+/// An artifact as it's written in a config, ex. the pipeline's `input`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
-enum InputArtifact {
-    File { name: String, paths: Vec<PathBuf> },
-    Tarball { name: String, path: PathBuf },
-    Docker { name: String, image: String },
-    Arch { name: String, path: PathBuf },
-    Deb { name: String, path: PathBuf },
-    Rpm { name: String, path: PathBuf },
-    Ext4 { name: String, path: PathBuf },
-    Oci { name: String, path: PathBuf },
-}
-
-// Safety: This is intended to be a one-way conversion
-#[allow(clippy::from_over_into)]
-impl Into<ConfiguredArtifact> for InputArtifact {
-    fn into(self) -> ConfiguredArtifact {
+pub enum InputArtifact {
+    File {
+        name: String,
+        paths: Vec<PathBuf>,
+        #[serde(default)]
+        git_mtimes: bool,
+        #[serde(default = "default_true")]
+        default_excludes: bool,
+        #[serde(default)]
+        extra_excludes: Vec<String>,
+        #[serde(default)]
+        placement: ConfigFilePlacement,
+        /// Only used with `placement: "prefix"`.
+        #[serde(default)]
+        prefix: Option<PathBuf>,
+    },
+    Tarball {
+        name: String,
+        #[serde(flatten)]
+        source: InputSource,
+    },
+    Docker {
+        name: String,
+        image: String,
+        #[serde(default)]
+        platform: Option<String>,
+        #[serde(default)]
+        pull_policy: PullPolicy,
+        #[serde(default)]
+        include_paths: Vec<PathBuf>,
+        #[serde(default)]
+        exclude_paths: Vec<PathBuf>,
+    },
+    Arch {
+        name: String,
+        #[serde(flatten)]
+        source: InputSource,
+    },
+    Deb {
+        name: String,
+        #[serde(flatten)]
+        source: InputSource,
+    },
+    Rpm {
+        name: String,
+        #[serde(flatten)]
+        source: InputSource,
+    },
+    Ext4 {
+        name: String,
+        #[serde(flatten)]
+        source: InputSource,
+    },
+    Oci {
+        name: String,
+        #[serde(flatten)]
+        source: InputSource,
+    },
+    Plugin {
+        name: String,
+        plugin: String,
+        #[serde(flatten)]
+        options: serde_yaml::Mapping,
+    },
+}
+
+/// Where an input that's a single file is read from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InputSource {
+    Path {
+        path: PathBuf,
+    },
+    /// Downloaded before it's read. Needs `--allow-network`.
+    Url {
+        url: String,
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+}
+
+impl From<PathBuf> for InputSource {
+    fn from(path: PathBuf) -> Self {
+        InputSource::Path { path }
+    }
+}
+
+// This is intended to be a one-way conversion
+impl InputArtifact {
+    fn source(&self) -> Option<&InputSource> {
         match self {
-            InputArtifact::File { name, paths } => {
-                ConfiguredArtifact::File(FileArtifact { name, paths })
-            }
+            InputArtifact::Tarball { source, .. }
+            | InputArtifact::Arch { source, .. }
+            | InputArtifact::Deb { source, .. }
+            | InputArtifact::Rpm { source, .. }
+            | InputArtifact::Ext4 { source, .. }
+            | InputArtifact::Oci { source, .. } => Some(source),
+            InputArtifact::File { .. }
+            | InputArtifact::Docker { .. }
+            | InputArtifact::Plugin { .. } => None,
+        }
+    }
 
-            InputArtifact::Tarball { name, path } => {
-                ConfiguredArtifact::Tarball(TarballArtifact { name, path })
-            }
+    /// The files on the host this artifact is read from.
+    fn host_paths(&self) -> Vec<&Path> {
+        match (self, self.source()) {
+            (InputArtifact::File { paths, .. }, _) => paths.iter().map(PathBuf::as_path).collect(),
+            (_, Some(InputSource::Path { path })) => vec![path],
+            _ => vec![],
+        }
+    }
 
-            InputArtifact::Docker { name, image } => {
-                ConfiguredArtifact::Docker(DockerArtifact { name, image })
-            }
+    /// The files on the host this artifact is read from, or `None` if it's
+    /// read from somewhere that can't be checked for changes, ex. a Docker
+    /// daemon or a download without a `sha256`.
+    pub(crate) fn cacheable_host_paths(&self) -> Option<Vec<&Path>> {
+        match (self, self.source()) {
+            (_, Some(InputSource::Url { sha256: None, .. }))
+            | (InputArtifact::Docker { .. } | InputArtifact::Plugin { .. }, _) => None,
+            _ => Some(self.host_paths()),
+        }
+    }
+
+    /// The kind of artifact a single file input is read as.
+    fn kind(&self) -> Option<ArtifactKind> {
+        match self {
+            InputArtifact::Tarball { .. } => Some(ArtifactKind::Tarball),
+            InputArtifact::Arch { .. } => Some(ArtifactKind::Arch),
+            InputArtifact::Deb { .. } => Some(ArtifactKind::Deb),
+            InputArtifact::Rpm { .. } => Some(ArtifactKind::Rpm),
+            InputArtifact::Ext4 { .. } => Some(ArtifactKind::Ext4),
+            InputArtifact::Oci { .. } => Some(ArtifactKind::Oci),
+            _ => None,
+        }
+    }
+
+    /// Whether reading this artifact needs network access.
+    pub fn needs_network(&self) -> bool {
+        matches!(self.source(), Some(InputSource::Url { .. }))
+    }
 
-            InputArtifact::Arch { name, path } => ConfiguredArtifact::Arch(ArchArtifact {
+    fn convert(self, registry: &Registry) -> Result<ConfiguredArtifact> {
+        let kind = self.kind();
+        Ok(match self {
+            InputArtifact::File {
+                name,
+                paths,
+                git_mtimes,
+                default_excludes,
+                extra_excludes,
+                placement,
+                prefix,
+            } => ConfiguredArtifact::File(FileArtifact {
+                placement: match (placement, prefix) {
+                    (ConfigFilePlacement::AsGiven, None) => FilePlacement::AsGiven,
+                    (ConfigFilePlacement::Root, None) => FilePlacement::Root,
+                    (ConfigFilePlacement::Prefix, Some(prefix)) => FilePlacement::Prefix(prefix),
+                    (ConfigFilePlacement::Prefix, None) => {
+                        return Err(eyre!(
+                            "file input '{name}' has placement prefix, but no prefix"
+                        ))
+                    }
+                    (_, Some(_)) => {
+                        return Err(eyre!(
+                            "file input '{name}' has a prefix, but its placement isn't prefix"
+                        ))
+                    }
+                },
+                name,
+                paths,
+                git_mtimes,
+                default_excludes,
+                extra_excludes,
+            }),
+
+            InputArtifact::Tarball {
+                name,
+                source: InputSource::Path { path },
+            } => ConfiguredArtifact::Tarball(TarballArtifact { name, path }),
+
+            InputArtifact::Docker {
+                name,
+                image,
+                platform,
+                pull_policy,
+                include_paths,
+                exclude_paths,
+            } => ConfiguredArtifact::Docker(DockerArtifact {
+                name,
+                image,
+                platform,
+                pull_policy,
+                include_paths,
+                exclude_paths,
+            }),
+
+            InputArtifact::Arch {
+                name,
+                source: InputSource::Path { path },
+            } => ConfiguredArtifact::Arch(ArchArtifact {
                 name,
                 path,
                 pkginfo: None,
+                signature: None,
             }),
 
-            InputArtifact::Deb { name, path } => ConfiguredArtifact::Deb(DebArtifact {
+            InputArtifact::Deb {
+                name,
+                source: InputSource::Path { path },
+            } => ConfiguredArtifact::Deb(DebArtifact {
                 name,
                 path,
                 control: None,
                 postinst: None,
                 prerm: None,
+                debug_package: None,
             }),
 
-            InputArtifact::Rpm { name, path } => ConfiguredArtifact::Rpm(RpmArtifact {
+            InputArtifact::Rpm {
+                name,
+                source: InputSource::Path { path },
+            } => ConfiguredArtifact::Rpm(RpmArtifact {
                 name,
                 path,
                 spec: None,
+                debug_package: None,
             }),
 
-            InputArtifact::Ext4 { name, path } => {
-                ConfiguredArtifact::Ext4(Ext4Artifact { name, path })
-            }
+            InputArtifact::Ext4 {
+                name,
+                source: InputSource::Path { path },
+            } => ConfiguredArtifact::Ext4(Ext4Artifact { name, path }),
 
-            InputArtifact::Oci { name, path } => {
-                ConfiguredArtifact::Oci(OciArtifact { name, path })
+            InputArtifact::Oci {
+                name,
+                source: InputSource::Path { path },
+            } => ConfiguredArtifact::Oci(OciArtifact { name, path }),
+
+            InputArtifact::Tarball {
+                name,
+                source: InputSource::Url { url, sha256 },
             }
-        }
+            | InputArtifact::Arch {
+                name,
+                source: InputSource::Url { url, sha256 },
+            }
+            | InputArtifact::Deb {
+                name,
+                source: InputSource::Url { url, sha256 },
+            }
+            | InputArtifact::Rpm {
+                name,
+                source: InputSource::Url { url, sha256 },
+            }
+            | InputArtifact::Ext4 {
+                name,
+                source: InputSource::Url { url, sha256 },
+            }
+            | InputArtifact::Oci {
+                name,
+                source: InputSource::Url { url, sha256 },
+            } => ConfiguredArtifact::Remote(RemoteArtifact::new(name, url, sha256, kind.unwrap())),
+
+            InputArtifact::Plugin {
+                name,
+                plugin,
+                options,
+            } => ConfiguredArtifact::Custom(registry.artifact(
+                &plugin,
+                &name,
+                serde_yaml::Value::Mapping(options),
+            )?),
+        })
     }
 }
 
+/// An injection on a producer, either the name of one in `injections` or a
+/// one-off written out inline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum InjectionRef {
+    Named(String),
+    Inline(Injection),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum OutputProducer {
@@ -143,7 +951,11 @@ enum OutputProducer {
         #[serde(default)]
         preserve_empty_directories: Option<bool>,
         #[serde(default)]
-        injections: Vec<String>,
+        spill_threshold: Option<u64>,
+        #[serde(default)]
+        checksums: bool,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
     },
 
     Tarball {
@@ -151,7 +963,20 @@ enum OutputProducer {
         path: PathBuf,
         compression: Option<ConfigCompression>,
         #[serde(default)]
-        injections: Vec<String>,
+        sparse: bool,
+        entry_names: Option<EntryNames>,
+        #[serde(default)]
+        file_mtime: Option<u64>,
+        #[serde(default)]
+        dir_mtime: Option<u64>,
+        #[serde(default)]
+        rsyncable: bool,
+        #[serde(default)]
+        remap_owner: Option<ConfigOwnerRemap>,
+        #[serde(default)]
+        split_size: Option<u64>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
     },
 
     Docker {
@@ -159,16 +984,44 @@ enum OutputProducer {
         image: String,
         base_image: Option<String>,
         #[serde(default)]
+        merge_policy: MergePolicy,
+        #[serde(default)]
         entrypoint: Option<Vec<String>>,
         #[serde(default)]
-        injections: Vec<String>,
+        remap_owner: Option<ConfigOwnerRemap>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    Dockerfile {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        base_image: Option<String>,
+        #[serde(default)]
+        cmd: Option<Vec<String>>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
     },
 
     Arch {
         name: String,
         path: PathBuf,
+        compression: Option<ConfigCompression>,
+        #[serde(default)]
+        provides: Vec<String>,
+        #[serde(default)]
+        replaces: Vec<String>,
+        #[serde(default)]
+        conflicts: Vec<String>,
+        #[serde(default)]
+        backup: Vec<PathBuf>,
         #[serde(default)]
-        injections: Vec<String>,
+        signing_key: Option<String>,
+        #[serde(default)]
+        gpg_home: Option<PathBuf>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
     },
 
     Deb {
@@ -176,14 +1029,32 @@ enum OutputProducer {
         path: PathBuf,
         compression: Option<ConfigCompression>,
         #[serde(default)]
+        control_compression: Option<ConfigCompression>,
+        #[serde(default)]
+        data_compression: Option<ConfigCompression>,
+        #[serde(default)]
+        rsyncable: bool,
+        #[serde(default)]
         prerm: Option<PathBuf>,
         #[serde(default)]
         postinst: Option<PathBuf>,
         #[serde(default)]
-        depends: String,
+        depends: StringOrList,
+        #[serde(default)]
+        pre_depends: StringOrList,
+        #[serde(default)]
+        breaks: StringOrList,
+        #[serde(default)]
+        split_debug: bool,
+        #[serde(default = "default_deb_format_version")]
+        format_version: String,
+        #[serde(default)]
+        changelog: Option<Vec<ChangelogEntry>>,
+        #[serde(default)]
+        conffiles: Vec<PathBuf>,
 
         #[serde(default)]
-        injections: Vec<String>,
+        injections: Vec<InjectionRef>,
     },
 
     Rpm {
@@ -192,14 +1063,30 @@ enum OutputProducer {
         #[serde(default)]
         spec: Option<String>,
         #[serde(default)]
-        injections: Vec<String>,
+        release: Option<String>,
+        #[serde(default)]
+        split_debug: bool,
+        #[serde(default)]
+        changelog: Option<Vec<ChangelogEntry>>,
+        #[serde(default)]
+        config_files: Vec<String>,
+        #[serde(default)]
+        doc_files: Vec<String>,
+        #[serde(default)]
+        ghost_files: Vec<String>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
     },
 
     Ext4 {
         name: String,
         path: PathBuf,
         #[serde(default)]
-        injections: Vec<String>,
+        size_slack: Option<u64>,
+        #[serde(default)]
+        template: Option<PathBuf>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
     },
 
     Oci {
@@ -207,190 +1094,642 @@ enum OutputProducer {
         path: String,
         architecture: String,
         #[serde(default)]
-        injections: Vec<String>,
+        remap_owner: Option<ConfigOwnerRemap>,
+        #[serde(default)]
+        reproducible: bool,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    ScriptInstaller {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        post_extract: Option<String>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    Manifest {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        format: ManifestFormat,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    Sbom {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        format: SbomFormat,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    MacosPkg {
+        name: String,
+        path: PathBuf,
+        identifier: String,
+        #[serde(default)]
+        install_location: Option<PathBuf>,
+        #[serde(default)]
+        preinstall: Option<PathBuf>,
+        #[serde(default)]
+        postinstall: Option<PathBuf>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    Iso {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        volume_label: Option<String>,
+        #[serde(default = "default_true")]
+        rock_ridge: bool,
+        #[serde(default = "default_true")]
+        joliet: bool,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    Cab {
+        name: String,
+        path: PathBuf,
+        #[serde(default)]
+        compression: CabCompression,
+        #[serde(default)]
+        uncompressed: Vec<PathBuf>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    /// The same package format as the input, keeping the input's metadata.
+    Passthrough {
+        name: String,
+        path: PathBuf,
+        compression: Option<ConfigCompression>,
+        #[serde(default)]
+        injections: Vec<InjectionRef>,
+    },
+
+    Plugin {
+        name: String,
+        plugin: String,
+        #[serde(flatten)]
+        options: serde_yaml::Mapping,
     },
 }
 
 // This is intended to be a one-way conversion
 #[allow(clippy::from_over_into)]
 impl OutputProducer {
-    fn convert(&self, config: &InternalConfig) -> ConfiguredProducer {
+    fn name(&self) -> &str {
+        match self {
+            OutputProducer::File { name, .. }
+            | OutputProducer::Tarball { name, .. }
+            | OutputProducer::Docker { name, .. }
+            | OutputProducer::Dockerfile { name, .. }
+            | OutputProducer::Arch { name, .. }
+            | OutputProducer::Deb { name, .. }
+            | OutputProducer::Rpm { name, .. }
+            | OutputProducer::Ext4 { name, .. }
+            | OutputProducer::Oci { name, .. }
+            | OutputProducer::ScriptInstaller { name, .. }
+            | OutputProducer::Manifest { name, .. }
+            | OutputProducer::Sbom { name, .. }
+            | OutputProducer::MacosPkg { name, .. }
+            | OutputProducer::Iso { name, .. }
+            | OutputProducer::Cab { name, .. }
+            | OutputProducer::Passthrough { name, .. }
+            | OutputProducer::Plugin { name, .. } => name,
+        }
+    }
+
+    /// The injections this producer uses. Plugins configure their own
+    /// injections, if they have any.
+    fn injection_refs(&self) -> &[InjectionRef] {
         match self {
+            OutputProducer::File { injections, .. }
+            | OutputProducer::Tarball { injections, .. }
+            | OutputProducer::Docker { injections, .. }
+            | OutputProducer::Dockerfile { injections, .. }
+            | OutputProducer::Arch { injections, .. }
+            | OutputProducer::Deb { injections, .. }
+            | OutputProducer::Rpm { injections, .. }
+            | OutputProducer::Ext4 { injections, .. }
+            | OutputProducer::Oci { injections, .. }
+            | OutputProducer::ScriptInstaller { injections, .. }
+            | OutputProducer::Manifest { injections, .. }
+            | OutputProducer::Sbom { injections, .. }
+            | OutputProducer::MacosPkg { injections, .. }
+            | OutputProducer::Iso { injections, .. }
+            | OutputProducer::Cab { injections, .. }
+            | OutputProducer::Passthrough { injections, .. } => injections,
+            OutputProducer::Plugin { .. } => &[],
+        }
+    }
+
+    /// Expand metadata tokens, like `{version}`, in the output path.
+    fn expand_path_tokens(&mut self, metadata: &PackageMetadata) -> Result<()> {
+        match self {
+            OutputProducer::File { path, .. }
+            | OutputProducer::Tarball { path, .. }
+            | OutputProducer::Dockerfile { path, .. }
+            | OutputProducer::Arch { path, .. }
+            | OutputProducer::Deb { path, .. }
+            | OutputProducer::Rpm { path, .. }
+            | OutputProducer::Ext4 { path, .. }
+            | OutputProducer::ScriptInstaller { path, .. }
+            | OutputProducer::Manifest { path, .. }
+            | OutputProducer::Sbom { path, .. }
+            | OutputProducer::MacosPkg { path, .. }
+            | OutputProducer::Iso { path, .. }
+            | OutputProducer::Cab { path, .. }
+            | OutputProducer::Passthrough { path, .. } => {
+                *path = expand_tokens(&path.to_string_lossy(), metadata)?.into();
+            }
+            OutputProducer::Oci { path, .. } => *path = expand_tokens(path, metadata)?,
+            OutputProducer::Docker { .. } | OutputProducer::Plugin { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    fn convert(&self, config: &InternalConfig, registry: &Registry) -> Result<ConfiguredProducer> {
+        self.convert_with(config, registry, false)
+    }
+
+    /// Passthrough outputs take their metadata from the input package, so
+    /// when `inherited` is set, missing metadata is left empty instead of
+    /// being an error.
+    fn convert_with(
+        &self,
+        config: &InternalConfig,
+        registry: &Registry,
+        inherited: bool,
+    ) -> Result<ConfiguredProducer> {
+        let metadata = |field: &str| match config.metadata.get(field) {
+            Some(value) => Ok(value.to_string()),
+            None if inherited => Ok(String::new()),
+            None => Err(eyre!(
+                "producer '{}' needs `metadata.{field}`, but it isn't set",
+                self.name()
+            )),
+        };
+
+        Ok(match self {
             OutputProducer::File {
                 name,
                 path,
                 preserve_empty_directories,
+                spill_threshold,
+                checksums,
                 injections,
             } => ConfiguredProducer::File(FileProducer {
                 name: name.clone(),
                 path: path.clone(),
                 preserve_empty_directories: *preserve_empty_directories,
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                spill_threshold: *spill_threshold,
+                checksums: *checksums,
+                injections: config.resolve_injections(injections),
             }),
 
             OutputProducer::Tarball {
                 name,
                 path,
                 compression,
+                sparse,
+                entry_names,
+                file_mtime,
+                dir_mtime,
+                rsyncable,
+                remap_owner,
+                split_size,
                 injections,
             } => ConfiguredProducer::Tarball(TarballProducer {
                 name: name.clone(),
                 path: path.clone(),
-                compression: compression
-                    .clone()
-                    .unwrap_or(ConfigCompression::None)
-                    .into(),
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                compression: config.compression_or(compression, CompressionType::None),
+                sparse: *sparse,
+                entry_names: entry_names.unwrap_or_default(),
+                file_mtime: *file_mtime,
+                dir_mtime: *dir_mtime,
+                rsyncable: *rsyncable,
+                remap_owner: remap_owner.map(Into::into),
+                split_size: *split_size,
+                injections: config.resolve_injections(injections),
             }),
 
             OutputProducer::Docker {
                 name,
                 image,
                 base_image,
+                merge_policy,
                 entrypoint,
+                remap_owner,
                 injections,
             } => ConfiguredProducer::Docker(DockerProducer {
                 name: name.clone(),
                 image: image.clone(),
                 base_image: base_image.clone(),
+                merge_policy: *merge_policy,
                 cmd: entrypoint.clone(),
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                remap_owner: remap_owner.map(Into::into),
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::Dockerfile {
+                name,
+                path,
+                base_image,
+                cmd,
+                injections,
+            } => ConfiguredProducer::Dockerfile(DockerfileProducer {
+                name: name.clone(),
+                path: path.clone(),
+                base_image: base_image.clone(),
+                cmd: cmd.clone(),
+                injections: config.resolve_injections(injections),
             }),
 
             OutputProducer::Arch {
                 name,
                 path,
+                compression,
+                provides,
+                replaces,
+                conflicts,
+                backup,
+                signing_key,
+                gpg_home,
                 injections,
             } => ConfiguredProducer::Arch(ArchProducer {
                 name: name.clone(),
-                package_name: config.metadata.name.clone(),
-                package_desc: config.metadata.description.clone(),
-                package_ver: config.metadata.version.clone(),
-                package_author: config.metadata.author.clone(),
-                package_arch: self.convert_architecture(&config.metadata),
+                package_name: metadata("name")?,
+                package_desc: metadata("description")?,
+                package_ver: metadata("version")?,
+                package_author: metadata("author")?,
+                package_arch: self.convert_architecture(&metadata("arch")?)?,
                 path: path.clone(),
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                compression: config.compression_or(compression, CompressionType::Zstd),
+                injections: config.resolve_injections(injections),
+                inherit_metadata: false,
+                package_provides: provides.clone(),
+                package_replaces: replaces.clone(),
+                package_conflicts: conflicts.clone(),
+                package_backup: backup.clone(),
+                signing_key: signing_key.clone(),
+                gpg_home: gpg_home.clone(),
             }),
 
             OutputProducer::Deb {
                 name,
                 path,
                 compression,
+                control_compression,
+                data_compression,
+                rsyncable,
                 prerm,
                 postinst,
                 depends,
+                pre_depends,
+                breaks,
+                split_debug,
+                format_version,
+                changelog,
+                conffiles,
                 injections,
             } => ConfiguredProducer::Deb(DebProducer {
                 name: name.clone(),
                 path: path.clone(),
-                compression: compression
-                    .clone()
-                    .map(|c| c.into())
-                    .unwrap_or(CompressionType::None),
+                compression: config.compression_or(compression, CompressionType::None),
+                control_compression: control_compression.clone().map(|c| c.into()),
+                data_compression: data_compression.clone().map(|c| c.into()),
+                rsyncable: *rsyncable,
                 prerm: prerm.clone(),
                 postinst: postinst.clone(),
-                package_name: config.metadata.name.clone(),
-                package_maintainer: config.metadata.author.clone(),
-                package_architecture: self.convert_architecture(&config.metadata),
-                package_version: config.metadata.version.clone(),
-                package_depends: depends.clone(),
-                package_description: config.metadata.description.clone(),
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                package_name: metadata("name")?,
+                package_maintainer: metadata("author")?,
+                package_architecture: self.convert_architecture(&metadata("arch")?)?,
+                package_version: metadata("version")?,
+                package_depends: depends.joined(),
+                package_pre_depends: pre_depends.joined(),
+                package_breaks: breaks.joined(),
+                package_description: metadata("description")?,
+                split_debug: *split_debug,
+                format_version: format_version.clone(),
+                changelog: changelog.clone(),
+                injections: config.resolve_injections(injections),
+                inherit_metadata: false,
+                package_conffiles: conffiles.clone(),
             }),
 
             OutputProducer::Rpm {
                 name,
                 path,
                 spec: _spec,
+                release,
+                split_debug,
+                changelog,
+                config_files,
+                doc_files,
+                ghost_files,
+                injections,
+            } => {
+                let version = metadata("version")?;
+                // RPM versions can't contain `-`, so with an explicit release
+                // the whole metadata version is upstream's, and its `-`s
+                // become `_`, ex. "1.0-rc1" is version "1.0_rc1".
+                let (package_version, package_release) = match release {
+                    Some(release) => (version.replace('-', "_"), release.clone()),
+                    None => RpmProducer::split_version(&version),
+                };
+
+                ConfiguredProducer::Rpm(RpmProducer {
+                    name: name.clone(),
+                    path: path.clone(),
+                    package_name: metadata("name")?,
+                    package_version,
+                    package_release,
+                    package_license: metadata("license")?,
+                    package_arch: self.convert_architecture(&metadata("arch")?)?,
+                    package_description: metadata("description")?,
+                    dependencies: vec![],
+                    split_debug: *split_debug,
+                    changelog: changelog.clone(),
+                    injections: config.resolve_injections(injections),
+                    inherit_metadata: false,
+                    config_files: config_files.clone(),
+                    doc_files: doc_files.clone(),
+                    ghost_files: ghost_files.clone(),
+                })
+            }
+
+            OutputProducer::Ext4 {
+                name,
+                path,
+                size_slack,
+                template,
                 injections,
-            } => ConfiguredProducer::Rpm(RpmProducer {
+            } => ConfiguredProducer::Ext4(Ext4Producer {
                 name: name.clone(),
                 path: path.clone(),
-                package_name: config.metadata.name.clone(),
-                package_version: config.metadata.version.clone(),
-                package_license: config.metadata.license.clone(),
-                package_arch: self.convert_architecture(&config.metadata),
-                package_description: config.metadata.description.clone(),
-                dependencies: vec![],
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                size_slack: size_slack.unwrap_or(DEFAULT_SIZE_SLACK),
+                template: template.clone(),
+                injections: config.resolve_injections(injections),
             }),
 
-            OutputProducer::Ext4 {
+            OutputProducer::Oci {
                 name,
                 path,
+                architecture,
+                remap_owner,
+                reproducible,
                 injections,
-            } => ConfiguredProducer::Ext4(Ext4Producer {
+            } => ConfiguredProducer::Oci(OciProducer {
+                name: name.clone(),
+                path: path.clone().into(),
+                architecture: architecture.clone(),
+                remap_owner: remap_owner.map(Into::into),
+                reproducible: *reproducible,
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::ScriptInstaller {
+                name,
+                path,
+                post_extract,
+                injections,
+            } => ConfiguredProducer::ScriptInstaller(ScriptInstallerProducer {
+                name: name.clone(),
+                path: path.clone(),
+                post_extract: post_extract.clone(),
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::Manifest {
+                name,
+                path,
+                format,
+                injections,
+            } => ConfiguredProducer::Manifest(ManifestProducer {
+                name: name.clone(),
+                path: path.clone(),
+                format: *format,
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::Sbom {
+                name,
+                path,
+                format,
+                injections,
+            } => ConfiguredProducer::Sbom(SbomProducer {
                 name: name.clone(),
                 path: path.clone(),
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
+                format: *format,
+                package_name: metadata("name")?,
+                package_version: metadata("version")?,
+                injections: config.resolve_injections(injections),
             }),
 
-            OutputProducer::Oci {
+            OutputProducer::MacosPkg {
+                name,
+                path,
+                identifier,
+                install_location,
+                preinstall,
+                postinstall,
+                injections,
+            } => ConfiguredProducer::MacosPkg(MacosPkgProducer {
+                name: name.clone(),
+                path: path.clone(),
+                identifier: identifier.clone(),
+                version: metadata("version")?,
+                install_location: install_location.clone().unwrap_or_else(|| "/".into()),
+                preinstall: preinstall.clone(),
+                postinstall: postinstall.clone(),
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::Iso {
+                name,
+                path,
+                volume_label,
+                rock_ridge,
+                joliet,
+                injections,
+            } => ConfiguredProducer::Iso(IsoProducer {
+                name: name.clone(),
+                path: path.clone(),
+                volume_label: match volume_label {
+                    Some(volume_label) => volume_label.clone(),
+                    None => IsoProducer::volume_label_for(&metadata("name")?),
+                },
+                rock_ridge: *rock_ridge,
+                joliet: *joliet,
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::Cab {
+                name,
+                path,
+                compression,
+                uncompressed,
+                injections,
+            } => ConfiguredProducer::Cab(CabProducer {
+                name: name.clone(),
+                path: path.clone(),
+                compression: *compression,
+                uncompressed: uncompressed.clone(),
+                injections: config.resolve_injections(injections),
+            }),
+
+            OutputProducer::Passthrough {
+                name,
+                path,
+                compression,
+                injections,
+            } => {
+                let (name, path, compression, injections) = (
+                    name.clone(),
+                    path.clone(),
+                    compression.clone(),
+                    injections.clone(),
+                );
+                let producer = match &config.input {
+                    InputArtifact::Deb { .. } => OutputProducer::Deb {
+                        name,
+                        path,
+                        compression,
+                        control_compression: None,
+                        data_compression: None,
+                        rsyncable: false,
+                        prerm: None,
+                        postinst: None,
+                        depends: StringOrList::default(),
+                        pre_depends: StringOrList::default(),
+                        breaks: StringOrList::default(),
+                        split_debug: false,
+                        format_version: default_deb_format_version(),
+                        changelog: None,
+                        conffiles: vec![],
+                        injections,
+                    },
+                    InputArtifact::Arch { .. } => OutputProducer::Arch {
+                        name,
+                        path,
+                        compression,
+                        provides: vec![],
+                        replaces: vec![],
+                        conflicts: vec![],
+                        backup: vec![],
+                        signing_key: None,
+                        gpg_home: None,
+                        injections,
+                    },
+                    InputArtifact::Rpm { .. } => OutputProducer::Rpm {
+                        name,
+                        path,
+                        spec: None,
+                        release: None,
+                        split_debug: false,
+                        changelog: None,
+                        config_files: vec![],
+                        doc_files: vec![],
+                        ghost_files: vec![],
+                        injections,
+                    },
+                    _ => {
+                        return Err(eyre!(
+                            "producer '{name}': passthrough outputs need a deb, arch, or rpm input"
+                        ))
+                    }
+                };
+
+                match producer.convert_with(config, registry, true)? {
+                    ConfiguredProducer::Deb(producer) => ConfiguredProducer::Deb(DebProducer {
+                        inherit_metadata: true,
+                        ..producer
+                    }),
+                    ConfiguredProducer::Arch(producer) => ConfiguredProducer::Arch(ArchProducer {
+                        inherit_metadata: true,
+                        ..producer
+                    }),
+                    ConfiguredProducer::Rpm(producer) => ConfiguredProducer::Rpm(RpmProducer {
+                        inherit_metadata: true,
+                        ..producer
+                    }),
+                    producer => producer,
+                }
+            }
+
+            OutputProducer::Plugin {
                 name,
-                path,
-                architecture,
-                injections,
-            } => ConfiguredProducer::Oci(OciProducer {
-                name: name.clone(),
-                path: path.clone().into(),
-                architecture: architecture.clone(),
-                injections: injections
-                    .iter()
-                    .map(|i| config.injections[i].clone())
-                    .collect(),
-            }),
-        }
+                plugin,
+                options,
+            } => ConfiguredProducer::Custom(registry.producer(
+                plugin,
+                name,
+                serde_yaml::Value::Mapping(options.clone()),
+            )?),
+        })
     }
 
-    fn convert_architecture(&self, metadata: &PackageMetadata) -> String {
-        match self {
-            OutputProducer::Arch { .. } => match metadata.arch.as_str() {
+    /// Map `metadata.arch` to the spelling each package format expects.
+    /// Architecture-independent packages are `any` for Arch, `all` for Debian,
+    /// and `noarch` for RPM; any of the three is accepted in the config.
+    fn convert_architecture(&self, arch: &str) -> Result<String> {
+        // Only inherited metadata is missing by now, and it's replaced with
+        // the input's architecture anyway.
+        if arch.is_empty() {
+            return Ok(String::new());
+        }
+        let arch_independent = ARCH_INDEPENDENT.contains(&arch);
+
+        Ok(match self {
+            OutputProducer::Arch { .. } if arch_independent => "any".into(),
+            OutputProducer::Arch { .. } => match arch {
                 "x86_64" => "x86_64".into(),
                 "amd64" => "x86_64".into(),
-                "any" => "any".into(),
-                _ => panic!("unsupported architecture for arch linux: {}", metadata.arch),
+                _ => return Err(eyre!("unsupported architecture for arch linux: {arch}")),
             },
 
-            OutputProducer::Deb { .. } => match metadata.arch.as_str() {
+            OutputProducer::Deb { .. } if arch_independent => "all".into(),
+            OutputProducer::Deb { .. } => match arch {
                 "x86_64" => "amd64".into(),
                 "amd64" => "amd64".into(),
-                "any" => "all".into(),
                 other => other.into(),
             },
 
-            OutputProducer::Rpm { .. } => match metadata.arch.as_str() {
+            OutputProducer::Rpm { .. } if arch_independent => "noarch".into(),
+            OutputProducer::Rpm { .. } => match arch {
                 "x86_64" => "x86_64".into(),
                 "amd64" => "x86_64".into(),
-                "any" => "noarch".into(),
                 other => other.into(),
             },
 
-            _ => metadata.arch.clone(),
-        }
+            _ => arch.into(),
+        })
     }
 }
 
+/// Every format's name for an architecture-independent package.
+const ARCH_INDEPENDENT: &[&str] = &["any", "all", "noarch"];
+
+/// Where a file input's paths end up, see [`FilePlacement`].
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigFilePlacement {
+    #[default]
+    AsGiven,
+    Root,
+    Prefix,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum ConfigCompression {
@@ -403,6 +1742,38 @@ enum ConfigCompression {
     Zstd,
 }
 
+impl From<CompressionType> for ConfigCompression {
+    fn from(compression: CompressionType) -> Self {
+        match compression {
+            CompressionType::None => ConfigCompression::None,
+            CompressionType::Deflate => ConfigCompression::Deflate,
+            CompressionType::Gzip => ConfigCompression::Gzip,
+            CompressionType::Xz => ConfigCompression::Xz,
+            CompressionType::Zlib => ConfigCompression::Zlib,
+            CompressionType::Zstd => ConfigCompression::Zstd,
+            CompressionType::Bzip => ConfigCompression::Bzip,
+        }
+    }
+}
+
+/// Serialize a [`CompressionType`] the way it's written in a config.
+pub(crate) fn serialize_compression<S: serde::Serializer>(
+    compression: &CompressionType,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    ConfigCompression::from(*compression).serialize(serializer)
+}
+
+/// [`serialize_compression`], for compressions that fall back to another.
+pub(crate) fn serialize_optional_compression<S: serde::Serializer>(
+    compression: &Option<CompressionType>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    compression
+        .map(ConfigCompression::from)
+        .serialize(serializer)
+}
+
 #[allow(clippy::from_over_into)]
 impl Into<CompressionType> for ConfigCompression {
     fn into(self) -> CompressionType {
@@ -418,6 +1789,55 @@ impl Into<CompressionType> for ConfigCompression {
     }
 }
 
+/// An owner to remap every path to, see [`OwnerRemap`]. Either `nobody`, or
+/// a `uid` and `gid`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ConfigOwnerRemap {
+    Named(ConfigNamedOwner),
+    Ids(OwnerRemap),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConfigNamedOwner {
+    Nobody,
+}
+
+impl From<ConfigOwnerRemap> for OwnerRemap {
+    fn from(remap: ConfigOwnerRemap) -> Self {
+        match remap {
+            ConfigOwnerRemap::Named(ConfigNamedOwner::Nobody) => OwnerRemap::NOBODY,
+            ConfigOwnerRemap::Ids(remap) => remap,
+        }
+    }
+}
+
+/// A field that can be written as either a single string or a list of
+/// strings, ex. `depends: "libc6, zlib1g"` or `depends: [libc6, zlib1g]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+impl Default for StringOrList {
+    fn default() -> Self {
+        StringOrList::String(String::new())
+    }
+}
+
+impl StringOrList {
+    /// The string as-is, or the list joined with `, `.
+    fn joined(&self) -> String {
+        match self {
+            StringOrList::String(s) => s.clone(),
+            StringOrList::List(list) => list.join(", "),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ConfiguredArtifact {
     File(FileArtifact),
@@ -428,18 +1848,99 @@ pub enum ConfiguredArtifact {
     Rpm(RpmArtifact),
     Ext4(Ext4Artifact),
     Oci(OciArtifact),
+    Remote(RemoteArtifact),
+    /// A custom artifact, usually from a [`Registry`] plugin.
+    Custom(Arc<dyn Artifact>),
 }
 
-#[derive(Debug, Clone)]
+/// Lets builders take artifacts and producers without wrapping them first.
+macro_rules! impl_from_variants {
+    ($configured:ident { $($variant:ident($inner:ty)),* $(,)? }) => {
+        $(
+            impl From<$inner> for $configured {
+                fn from(inner: $inner) -> Self {
+                    $configured::$variant(inner)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_variants!(ConfiguredArtifact {
+    File(FileArtifact),
+    Tarball(TarballArtifact),
+    Docker(DockerArtifact),
+    Arch(ArchArtifact),
+    Deb(DebArtifact),
+    Rpm(RpmArtifact),
+    Ext4(Ext4Artifact),
+    Oci(OciArtifact),
+    Remote(RemoteArtifact),
+});
+
+impl_from_variants!(ConfiguredProducer {
+    File(FileProducer),
+    Tarball(TarballProducer),
+    Docker(DockerProducer),
+    Dockerfile(DockerfileProducer),
+    Arch(ArchProducer),
+    Deb(DebProducer),
+    Rpm(RpmProducer),
+    Ext4(Ext4Producer),
+    Oci(OciProducer),
+    ScriptInstaller(ScriptInstallerProducer),
+    Manifest(ManifestProducer),
+    Sbom(SbomProducer),
+    MacosPkg(MacosPkgProducer),
+    Iso(IsoProducer),
+    Cab(CabProducer),
+});
+
+impl ConfiguredArtifact {
+    pub fn into_artifact(self) -> Result<Box<dyn Artifact>> {
+        Ok(match self {
+            ConfiguredArtifact::File(file) => Box::new(file),
+            ConfiguredArtifact::Tarball(tarball) => Box::new(tarball),
+            ConfiguredArtifact::Docker(docker) => Box::new(docker),
+            ConfiguredArtifact::Arch(arch) => Box::new(arch),
+            ConfiguredArtifact::Deb(deb) => Box::new(deb),
+            ConfiguredArtifact::Rpm(rpm) => Box::new(rpm),
+            ConfiguredArtifact::Ext4(ext4) => Box::new(ext4),
+            ConfiguredArtifact::Oci(oci) => Box::new(oci),
+            ConfiguredArtifact::Remote(remote) => Box::new(remote),
+            ConfiguredArtifact::Custom(artifact) => artifact.try_clone()?,
+        })
+    }
+
+    /// Whether reading this artifact needs network access.
+    pub fn needs_network(&self) -> bool {
+        matches!(self, ConfiguredArtifact::Remote(_))
+    }
+}
+
+/// A producer, as it's configured. Serializes with a `type` tag naming the
+/// kind of producer, except for custom producers, which can't be serialized.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum ConfiguredProducer {
     File(FileProducer),
     Tarball(TarballProducer),
     Docker(DockerProducer),
+    Dockerfile(DockerfileProducer),
     Arch(ArchProducer),
     Deb(DebProducer),
     Rpm(RpmProducer),
     Ext4(Ext4Producer),
     Oci(OciProducer),
+    ScriptInstaller(ScriptInstallerProducer),
+    Manifest(ManifestProducer),
+    Sbom(SbomProducer),
+    MacosPkg(MacosPkgProducer),
+    Iso(IsoProducer),
+    Cab(CabProducer),
+    /// A custom producer, usually from a [`Registry`] plugin.
+    #[serde(skip)]
+    Custom(Arc<dyn DynArtifactProducer>),
 }
 
 // We can't make transparent enum variants or similar easily here, so this
@@ -450,11 +1951,209 @@ impl ConfiguredProducer {
             ConfiguredProducer::File(producer) => &producer.name,
             ConfiguredProducer::Tarball(producer) => &producer.name,
             ConfiguredProducer::Docker(producer) => &producer.name,
+            ConfiguredProducer::Dockerfile(producer) => &producer.name,
             ConfiguredProducer::Arch(producer) => &producer.name,
             ConfiguredProducer::Deb(producer) => &producer.name,
             ConfiguredProducer::Rpm(producer) => &producer.name,
             ConfiguredProducer::Ext4(producer) => &producer.name,
             ConfiguredProducer::Oci(producer) => &producer.name,
+            ConfiguredProducer::ScriptInstaller(producer) => &producer.name,
+            ConfiguredProducer::Manifest(producer) => &producer.name,
+            ConfiguredProducer::Sbom(producer) => &producer.name,
+            ConfiguredProducer::MacosPkg(producer) => &producer.name,
+            ConfiguredProducer::Iso(producer) => &producer.name,
+            ConfiguredProducer::Cab(producer) => &producer.name,
+            ConfiguredProducer::Custom(producer) => producer.producer_name(),
+        }
+    }
+
+    /// The injections applied by this producer. Custom producers manage their
+    /// own injections, so none are returned for them.
+    pub fn injections(&self) -> &[Injection] {
+        match self {
+            ConfiguredProducer::File(producer) => &producer.injections,
+            ConfiguredProducer::Tarball(producer) => &producer.injections,
+            ConfiguredProducer::Docker(producer) => &producer.injections,
+            ConfiguredProducer::Dockerfile(producer) => &producer.injections,
+            ConfiguredProducer::Arch(producer) => &producer.injections,
+            ConfiguredProducer::Deb(producer) => &producer.injections,
+            ConfiguredProducer::Rpm(producer) => &producer.injections,
+            ConfiguredProducer::Ext4(producer) => &producer.injections,
+            ConfiguredProducer::Oci(producer) => &producer.injections,
+            ConfiguredProducer::ScriptInstaller(producer) => &producer.injections,
+            ConfiguredProducer::Manifest(producer) => &producer.injections,
+            ConfiguredProducer::Sbom(producer) => &producer.injections,
+            ConfiguredProducer::MacosPkg(producer) => &producer.injections,
+            ConfiguredProducer::Iso(producer) => &producer.injections,
+            ConfiguredProducer::Cab(producer) => &producer.injections,
+            ConfiguredProducer::Custom(_) => &[],
+        }
+    }
+
+    /// Files on the host that the producer reads besides its input and
+    /// injections, ex. maintainer scripts.
+    pub fn host_inputs(&self) -> Vec<&Path> {
+        let paths = match self {
+            ConfiguredProducer::Deb(producer) => vec![&producer.prerm, &producer.postinst],
+            ConfiguredProducer::MacosPkg(producer) => {
+                vec![&producer.preinstall, &producer.postinstall]
+            }
+            ConfiguredProducer::Ext4(producer) => vec![&producer.template],
+            _ => vec![],
+        };
+
+        paths.into_iter().flatten().map(PathBuf::as_path).collect()
+    }
+
+    fn injections_mut(&mut self) -> Option<&mut Vec<Injection>> {
+        match self {
+            ConfiguredProducer::File(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Tarball(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Docker(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Dockerfile(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Arch(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Deb(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Rpm(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Ext4(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Oci(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::ScriptInstaller(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Manifest(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Sbom(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::MacosPkg(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Iso(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Cab(producer) => Some(&mut producer.injections),
+            ConfiguredProducer::Custom(_) => None,
+        }
+    }
+
+    /// The files this producer writes to. Directory outputs, like the file
+    /// producer's, and non-filesystem outputs are not included.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        match self {
+            ConfiguredProducer::File(_) => vec![],
+            ConfiguredProducer::Tarball(producer) if producer.split_size.is_some() => {
+                let paths = split::split_paths(&producer.path);
+                if paths.is_empty() {
+                    vec![split::manifest_path(&producer.path)]
+                } else {
+                    paths
+                }
+            }
+            ConfiguredProducer::Tarball(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Docker(_) => vec![],
+            ConfiguredProducer::Dockerfile(_) => vec![],
+            ConfiguredProducer::Arch(producer) => {
+                let mut paths = vec![producer.path.clone()];
+                paths.extend(producer.signature_path());
+                paths
+            }
+            ConfiguredProducer::Deb(producer) => {
+                let mut paths = vec![producer.path.clone()];
+                if producer.split_debug {
+                    paths.push(producer.debug_package_path());
+                }
+                paths
+            }
+            ConfiguredProducer::Rpm(producer) => {
+                let mut paths = vec![producer.path.clone()];
+                if producer.split_debug {
+                    paths.push(producer.debug_package_path());
+                }
+                paths
+            }
+            ConfiguredProducer::Ext4(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Oci(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::ScriptInstaller(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Manifest(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Sbom(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::MacosPkg(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Iso(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Cab(producer) => vec![producer.path.clone()],
+            ConfiguredProducer::Custom(_) => vec![],
+        }
+    }
+
+    /// The artifact this producer already wrote on a previous run, if all of
+    /// its outputs still exist. Producers whose output can't be rebuilt from
+    /// the config alone, like file, Docker, and custom producers, return
+    /// `None`.
+    pub fn existing_output(&self) -> Option<Box<dyn Artifact>> {
+        let paths = self.output_paths();
+        if paths.is_empty() || !paths.iter().all(|path| path.is_file()) {
+            return None;
+        }
+
+        match self {
+            ConfiguredProducer::File(_) => None,
+            ConfiguredProducer::Tarball(producer) => Some(Box::new(TarballArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+            })),
+            ConfiguredProducer::Docker(_) => None,
+            ConfiguredProducer::Dockerfile(_) => None,
+            ConfiguredProducer::Arch(producer) => Some(Box::new(ArchArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+                pkginfo: None,
+                signature: producer.signature_path(),
+            })),
+            ConfiguredProducer::Deb(producer) => Some(Box::new(DebArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+                control: None,
+                postinst: None,
+                prerm: None,
+                debug_package: paths.get(1).cloned(),
+            })),
+            ConfiguredProducer::Rpm(producer) => Some(Box::new(RpmArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+                spec: None,
+                debug_package: paths.get(1).cloned(),
+            })),
+            ConfiguredProducer::Ext4(producer) => Some(Box::new(Ext4Artifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+            })),
+            ConfiguredProducer::Oci(producer) => Some(Box::new(OciArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+            })),
+            ConfiguredProducer::ScriptInstaller(producer) => {
+                Some(Box::new(ScriptInstallerArtifact {
+                    name: producer.name.clone(),
+                    path: producer.path.clone(),
+                }))
+            }
+            ConfiguredProducer::Manifest(producer) => Some(Box::new(FileArtifact {
+                name: producer.name.clone(),
+                paths: vec![producer.path.clone()],
+                git_mtimes: false,
+                default_excludes: true,
+                extra_excludes: vec![],
+                placement: FilePlacement::AsGiven,
+            })),
+            ConfiguredProducer::Sbom(producer) => Some(Box::new(FileArtifact {
+                name: producer.name.clone(),
+                paths: vec![producer.path.clone()],
+                git_mtimes: false,
+                default_excludes: true,
+                extra_excludes: vec![],
+                placement: FilePlacement::AsGiven,
+            })),
+            ConfiguredProducer::MacosPkg(producer) => Some(Box::new(MacosPkgArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+            })),
+            ConfiguredProducer::Iso(producer) => Some(Box::new(IsoArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+            })),
+            ConfiguredProducer::Cab(producer) => Some(Box::new(CabArtifact {
+                name: producer.name.clone(),
+                path: producer.path.clone(),
+            })),
+            ConfiguredProducer::Custom(_) => None,
         }
     }
 
@@ -463,11 +2162,19 @@ impl ConfiguredProducer {
             ConfiguredProducer::File(producer) => producer.validate().await,
             ConfiguredProducer::Tarball(producer) => producer.validate().await,
             ConfiguredProducer::Docker(producer) => producer.validate().await,
+            ConfiguredProducer::Dockerfile(producer) => producer.validate().await,
             ConfiguredProducer::Arch(producer) => producer.validate().await,
             ConfiguredProducer::Deb(producer) => producer.validate().await,
             ConfiguredProducer::Rpm(producer) => producer.validate().await,
             ConfiguredProducer::Ext4(producer) => producer.validate().await,
             ConfiguredProducer::Oci(producer) => producer.validate().await,
+            ConfiguredProducer::ScriptInstaller(producer) => producer.validate().await,
+            ConfiguredProducer::Manifest(producer) => producer.validate().await,
+            ConfiguredProducer::Sbom(producer) => producer.validate().await,
+            ConfiguredProducer::MacosPkg(producer) => producer.validate().await,
+            ConfiguredProducer::Iso(producer) => producer.validate().await,
+            ConfiguredProducer::Cab(producer) => producer.validate().await,
+            ConfiguredProducer::Custom(producer) => producer.validate().await,
         }
     }
 
@@ -482,6 +2189,9 @@ impl ConfiguredProducer {
             ConfiguredProducer::Docker(producer) => {
                 Ok(producer.produce_from(previous).await.map(Box::new)?)
             }
+            ConfiguredProducer::Dockerfile(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
             ConfiguredProducer::Arch(producer) => {
                 Ok(producer.produce_from(previous).await.map(Box::new)?)
             }
@@ -497,6 +2207,81 @@ impl ConfiguredProducer {
             ConfiguredProducer::Oci(producer) => {
                 Ok(producer.produce_from(previous).await.map(Box::new)?)
             }
+            ConfiguredProducer::ScriptInstaller(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Manifest(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Sbom(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::MacosPkg(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Iso(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Cab(producer) => {
+                Ok(producer.produce_from(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Custom(producer) => producer.produce_boxed(previous).await,
+        }
+    }
+
+    /// Like [`ConfiguredProducer::produce_from`], for an input nothing else
+    /// needs afterwards, see [`ArtifactProducer::produce_from_owned`].
+    pub async fn produce_from_owned(
+        &self,
+        previous: Box<dyn Artifact>,
+    ) -> Result<Box<dyn Artifact>> {
+        match self {
+            ConfiguredProducer::File(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Tarball(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Docker(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Dockerfile(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Arch(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Deb(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Rpm(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Ext4(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Oci(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::ScriptInstaller(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Manifest(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Sbom(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::MacosPkg(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Iso(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Cab(producer) => {
+                Ok(producer.produce_from_owned(previous).await.map(Box::new)?)
+            }
+            ConfiguredProducer::Custom(producer) => producer.produce_boxed(previous.as_ref()).await,
         }
     }
 }
@@ -504,15 +2289,102 @@ impl ConfiguredProducer {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Injection {
-    Move { src: PathBuf, dest: PathBuf },
-    Copy { src: PathBuf, dest: PathBuf },
-    Symlink { src: PathBuf, dest: PathBuf },
-    Touch { path: PathBuf },
-    Delete { path: PathBuf },
-    Create { path: PathBuf, content: Vec<u8> },
-    HostFile { src: PathBuf, dest: PathBuf },
-    HostDir { src: PathBuf, dest: PathBuf },
-    // TODO: chown, chmod
+    Move {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    Copy {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    Symlink {
+        src: PathBuf,
+        dest: PathBuf,
+        /// Point the symlink at `src` relative to `dest`'s directory, rather
+        /// than at `src` verbatim.
+        #[serde(default)]
+        relative: Option<bool>,
+    },
+    Touch {
+        path: PathBuf,
+        /// Defaults to the current time, or `SOURCE_DATE_EPOCH`.
+        #[serde(default)]
+        mtime: Option<u64>,
+        /// Defaults to the current time, or `SOURCE_DATE_EPOCH`.
+        #[serde(default)]
+        atime: Option<u64>,
+    },
+    Delete {
+        path: PathBuf,
+    },
+    Create {
+        path: PathBuf,
+        content: Vec<u8>,
+    },
+    HostFile {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    /// Leaves out the same names that file inputs do, see
+    /// [`crate::artifact::file::DEFAULT_EXCLUDES`].
+    HostDir {
+        src: PathBuf,
+        dest: PathBuf,
+        #[serde(default = "default_true")]
+        default_excludes: bool,
+        #[serde(default)]
+        extra_excludes: Vec<String>,
+    },
+    /// Download a file over HTTP or HTTPS. Only allowed when the pipeline is run with
+    /// `--allow-network`.
+    RemoteFile {
+        url: String,
+        dest: PathBuf,
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+    /// Set the permission bits of a path. The file type is left alone.
+    Chmod {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// Copy a path, and everything under it, out of another artifact. The
+    /// artifact is written like a pipeline's `input`, but can't be a plugin.
+    FromArtifact {
+        artifact: Box<InputArtifact>,
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    /// Mask the permissions of files and directories that later injections
+    /// create, like a shell's umask. New files default to 0o666 and new
+    /// directories to 0o777 before masking.
+    Umask {
+        umask: u32,
+    },
+    /// Reset every path's mode to 0o755 for directories and executables, and
+    /// 0o644 for everything else. See [`MemFS::normalize_permissions`].
+    NormalizePermissions,
+    /// Set the owner of a path, and of everything under it if `recursive`.
+    /// Only the memfs is changed, never the host, so this works without root;
+    /// the owners end up in the package's headers.
+    Chown {
+        path: PathBuf,
+        uid: u32,
+        gid: u32,
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// Change the RPATH/RUNPATH or interpreter of an ELF file, ex. to run a
+    /// binary built for another prefix. See [`crate::util::elf::patch`].
+    PatchElf {
+        path: PathBuf,
+        #[serde(default)]
+        set_rpath: Option<String>,
+        #[serde(default)]
+        set_interpreter: Option<String>,
+        #[serde(default)]
+        remove_rpath: bool,
+    },
 }
 
 impl Injection {
@@ -522,7 +2394,7 @@ impl Injection {
             Injection::Move { src, dest } => {
                 debug!("moving {:?} to {:?}", src, dest);
                 if let Some(parent) = dest.parent() {
-                    fs.create_dir_all(parent).await?;
+                    memfs.create_dir_all_masked(parent).await?;
                     debug!("created parent: {parent:?}");
                 }
 
@@ -534,20 +2406,36 @@ impl Injection {
                 fs.copy(src, dest).await?;
             }
 
-            Injection::Symlink { src, dest } => {
+            Injection::Symlink {
+                src,
+                dest,
+                relative,
+            } => {
+                let src = if relative.unwrap_or(false) {
+                    Self::relative_link_target(src, dest)
+                } else {
+                    src.clone()
+                };
                 debug!("symlinking {} to {}", dest.display(), src.display());
-                fs.symlink(src, dest).await?;
+                fs.symlink(&src, dest).await?;
             }
 
-            Injection::Touch { path } => {
+            Injection::Touch { path, mtime, atime } => {
                 debug!("touching {:?}", path);
-                fs.create_dir_all(path.parent().unwrap()).await?;
+                memfs.create_dir_all_masked(path.parent().unwrap()).await?;
+                let is_new = fs.metadata(path).await.is_err();
                 MemOpenOptions::new()
                     .create(true)
                     .read(true)
                     .write(true)
                     .open(fs, path)
                     .await?;
+                if is_new {
+                    memfs.apply_umask(path, 0o666).await?;
+                }
+                let now = get_current_time()?;
+                memfs.set_mtime(path, mtime.unwrap_or(now));
+                memfs.set_atime(path, atime.unwrap_or(now));
             }
 
             Injection::Delete { path } => {
@@ -560,42 +2448,316 @@ impl Injection {
                 }
             }
 
-            Injection::Create { path, content } => {
-                debug!("creating {:?} with content {:?}", path, content);
-                fs.create_dir_all(path.parent().unwrap()).await?;
-                fs.write(path, content).await?;
+            Injection::Create { path, content } => {
+                debug!("creating {:?} with content {:?}", path, content);
+                memfs.create_dir_all_masked(path.parent().unwrap()).await?;
+                let is_new = fs.metadata(path).await.is_err();
+                fs.write(path, content).await?;
+                if is_new {
+                    memfs.apply_umask(path, 0o666).await?;
+                }
+            }
+
+            Injection::HostFile { src, dest } => {
+                debug!("copying host file {:?} to {:?}", src, dest);
+                let src = if src.starts_with("./") {
+                    let mut out = std::env::current_dir()?;
+                    out.push(src.strip_prefix("./")?);
+                    out
+                } else {
+                    src.clone()
+                };
+                let host = TokioFloppyDisk::new(None);
+                DiskDrive::copy_from_src_to_dest(&host, fs, src, dest).await?;
+            }
+
+            Injection::HostDir {
+                src,
+                dest,
+                default_excludes,
+                extra_excludes,
+            } => {
+                debug!("copying host directory {:?} to {:?}", src, dest);
+                let src = if src.starts_with("./") {
+                    let mut out = std::env::current_dir()?;
+                    out.push(src.strip_prefix("./")?);
+                    out
+                } else {
+                    src.clone()
+                };
+                let excludes = excluded_names(*default_excludes, extra_excludes);
+                copy_host_dir(memfs, &src, &Path::new("/").join(dest), &excludes).await?;
+            }
+
+            Injection::FromArtifact {
+                artifact,
+                src,
+                dest,
+            } => {
+                let artifact = artifact
+                    .as_ref()
+                    .clone()
+                    .convert(&Registry::default())?
+                    .into_artifact()?;
+                debug!("copying {:?} from {} to {:?}", src, artifact.name(), dest);
+                let other = artifact.extract_paths(std::slice::from_ref(src)).await?;
+                if other.metadata(src).await.is_err() {
+                    return Err(eyre!(
+                        "path {} does not exist in {}",
+                        src.display(),
+                        artifact.name()
+                    ));
+                }
+                if let Some(parent) = dest.parent() {
+                    memfs.create_dir_all_masked(parent).await?;
+                }
+                memfs.copy_tree_from(&other, src, dest).await?;
+            }
+
+            Injection::Chmod { path, mode } => {
+                debug!("chmodding {:?} to {:o}", path, mode);
+                let file_type = fs.metadata(path).await?.permissions().mode() & !0o7777;
+                fs.set_permissions(path, MemPermissions::from_mode(file_type | (mode & 0o7777)))
+                    .await?;
+            }
+
+            Injection::Chown {
+                path,
+                uid,
+                gid,
+                recursive,
+            } => {
+                debug!("chowning {:?} to {uid}:{gid}", path);
+                let mut paths = vec![path.clone()];
+                if *recursive && fs.symlink_metadata(path).await?.is_dir() {
+                    paths.extend(nyoom::walk_ordered(fs, path).await?);
+                }
+                for path in paths {
+                    fs.chown(&path, *uid, *gid).await?;
+                }
+            }
+
+            Injection::RemoteFile { url, dest, sha256 } => {
+                debug!("downloading {url} to {:?}", dest);
+                let content = download(url, sha256.as_deref()).await?;
+                if let Some(parent) = dest.parent() {
+                    memfs.create_dir_all_masked(parent).await?;
+                }
+                let is_new = fs.metadata(dest).await.is_err();
+                fs.write(dest, content).await?;
+                if is_new {
+                    memfs.apply_umask(dest, 0o666).await?;
+                }
+            }
+
+            Injection::Umask { umask } => {
+                debug!("setting umask to {:o}", umask);
+                memfs.set_umask(*umask);
+            }
+
+            Injection::NormalizePermissions => {
+                debug!("normalizing permissions");
+                memfs.normalize_permissions().await?;
+            }
+
+            Injection::PatchElf {
+                path,
+                set_rpath,
+                set_interpreter,
+                remove_rpath,
+            } => {
+                debug!("patching ELF file {:?}", path);
+                let data = fs.read(path).await?;
+                let patch = ElfPatch {
+                    set_rpath: set_rpath.as_deref(),
+                    set_interpreter: set_interpreter.as_deref(),
+                    remove_rpath: *remove_rpath,
+                };
+                let patched = elf::patch(&data, &patch)
+                    .map_err(|e| eyre!("cannot patch {}: {e}", path.display()))?;
+                fs.write(path, patched).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that no injection uses a path that only a later injection in
+    /// the same list creates. Injections are applied in the order they're
+    /// listed, so e.g. a chmod listed before the create for the same file
+    /// would fail halfway through producing.
+    pub fn check_order(injections: &[Injection]) -> Result<()> {
+        for (i, injection) in injections.iter().enumerate() {
+            let Some(used) = injection.used_path() else {
+                continue;
+            };
+            let created = |other: &Injection| {
+                other
+                    .created_path()
+                    .map(|created| used.starts_with(created))
+                    .unwrap_or(false)
+            };
+
+            if injections[..i].iter().any(created) {
+                continue;
+            }
+
+            if let Some(later) = injections[i + 1..].iter().find(|other| created(other)) {
+                return Err(eyre!(
+                    "{} injection for {} runs before the {} injection that creates it, but injections are applied in the order they're listed",
+                    injection.kind(),
+                    used.display(),
+                    later.kind()
+                ));
             }
+        }
 
-            Injection::HostFile { src, dest } => {
-                debug!("copying host file {:?} to {:?}", src, dest);
-                let src = if src.starts_with("./") {
-                    let mut out = std::env::current_dir()?;
-                    out.push(src.strip_prefix("./")?);
-                    out
-                } else {
-                    src.clone()
-                };
-                let host = TokioFloppyDisk::new(None);
-                DiskDrive::copy_from_src_to_dest(&host, fs, src, dest).await?;
+        Ok(())
+    }
+
+    /// Check that every `from_artifact` injection reads an artifact that
+    /// exists by the time the producer runs. It can't be a plugin, and it
+    /// can't be the output of the producer itself or of a later producer,
+    /// since that'd be read before it's written, or loop back on itself.
+    /// `producers` is the producer and every producer after it.
+    fn check_artifact_sources(producers: &[ConfiguredProducer]) -> Result<()> {
+        let Some(producer) = producers.first() else {
+            return Ok(());
+        };
+
+        for injection in producer.injections() {
+            let Injection::FromArtifact { artifact, .. } = injection else {
+                continue;
+            };
+            if let InputArtifact::Plugin { name, .. } = artifact.as_ref() {
+                return Err(eyre!(
+                    "from_artifact injection reads '{name}', but plugin artifacts can't be injected from"
+                ));
             }
 
-            Injection::HostDir { src, dest } => {
-                debug!("copying host directory {:?} to {:?}", src, dest);
-                let src = if src.starts_with("./") {
-                    let mut out = std::env::current_dir()?;
-                    out.push(src.strip_prefix("./")?);
-                    out
-                } else {
-                    src.clone()
+            for path in artifact.host_paths() {
+                let Some(writer) = producers.iter().find(|later| {
+                    later
+                        .output_paths()
+                        .iter()
+                        .any(|output| same_path(output, path))
+                }) else {
+                    continue;
                 };
-                let host = TokioFloppyDisk::new(None);
-                DiskDrive::copy_from_src_to_dest(&host, fs, src, dest).await?;
+                if writer.name() == producer.name() {
+                    return Err(eyre!(
+                        "from_artifact injection reads {}, which is this producer's own output",
+                        path.display()
+                    ));
+                }
+                return Err(eyre!(
+                    "from_artifact injection reads {}, which producer '{}' only writes later",
+                    path.display(),
+                    writer.name()
+                ));
             }
         }
 
         Ok(())
     }
 
+    /// The path this injection expects to already exist, if any.
+    fn used_path(&self) -> Option<&Path> {
+        match self {
+            Injection::Move { src, .. } | Injection::Copy { src, .. } => Some(src),
+            Injection::Delete { path }
+            | Injection::Chmod { path, .. }
+            | Injection::Chown { path, .. }
+            | Injection::PatchElf { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The path this injection creates, if any.
+    fn created_path(&self) -> Option<&Path> {
+        match self {
+            Injection::Move { dest, .. }
+            | Injection::Copy { dest, .. }
+            | Injection::Symlink { dest, .. }
+            | Injection::HostFile { dest, .. }
+            | Injection::HostDir { dest, .. }
+            | Injection::RemoteFile { dest, .. }
+            | Injection::FromArtifact { dest, .. } => Some(dest),
+            Injection::Touch { path, .. } | Injection::Create { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The injection's `type`, as written in configs.
+    fn kind(&self) -> &'static str {
+        match self {
+            Injection::Move { .. } => "move",
+            Injection::Copy { .. } => "copy",
+            Injection::Symlink { .. } => "symlink",
+            Injection::Touch { .. } => "touch",
+            Injection::Delete { .. } => "delete",
+            Injection::Create { .. } => "create",
+            Injection::HostFile { .. } => "host_file",
+            Injection::HostDir { .. } => "host_dir",
+            Injection::RemoteFile { .. } => "remote_file",
+            Injection::FromArtifact { .. } => "from_artifact",
+            Injection::Chmod { .. } => "chmod",
+            Injection::Umask { .. } => "umask",
+            Injection::NormalizePermissions => "normalize_permissions",
+            Injection::Chown { .. } => "chown",
+            Injection::PatchElf { .. } => "patch_elf",
+        }
+    }
+
+    /// Whether applying this injection needs network access.
+    pub fn needs_network(&self) -> bool {
+        match self {
+            Injection::RemoteFile { .. } => true,
+            Injection::FromArtifact { artifact, .. } => artifact.needs_network(),
+            _ => false,
+        }
+    }
+
+    /// The shortest path from `dest`'s directory to `src`. Both paths are
+    /// treated as rooted at `/`, and are normalized lexically.
+    fn relative_link_target(src: &Path, dest: &Path) -> PathBuf {
+        fn normalize(path: &Path) -> Vec<std::path::Component<'_>> {
+            let mut out = vec![];
+            for component in path.components() {
+                match component {
+                    std::path::Component::Normal(_) => out.push(component),
+                    std::path::Component::ParentDir => {
+                        out.pop();
+                    }
+                    _ => {}
+                }
+            }
+            out
+        }
+
+        let src = normalize(src);
+        let dest_dir = normalize(dest.parent().unwrap_or(Path::new("/")));
+        let common = src
+            .iter()
+            .zip(dest_dir.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let mut target = PathBuf::new();
+        for _ in common..dest_dir.len() {
+            target.push("..");
+        }
+        for component in &src[common..] {
+            target.push(component);
+        }
+
+        if target.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            target
+        }
+    }
+
     #[async_recursion::async_recursion]
     async fn do_move_file(memfs: &MemFS, src: &Path, dest: &Path, depth: u8) -> Result<()> {
         // if src doesn't exist in the memfs, return an error, without an exists() method
@@ -667,3 +2829,954 @@ impl Injection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+
+    use crate::artifact::file::FileArtifactBuilder;
+    use crate::artifact::tarball::TarballProducerBuilder;
+    use crate::artifact::SelfBuilder;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    fn config_with_version(version: Option<u32>) -> String {
+        let version = version
+            .map(|v| format!("version: {v}\n"))
+            .unwrap_or_default();
+        format!(
+            r#"{version}
+metadata:
+  name: "test"
+  version: "0.0.1-1"
+  description: "test"
+  author: "me"
+  arch: "amd64"
+  license: "Apache-2.0"
+
+input:
+  name: "cargo dot toml"
+  type: "file"
+  paths:
+    - "./Cargo.toml"
+
+output:
+  - name: "tarball"
+    type: "tarball"
+    path: "./out/test.tar"
+"#
+        )
+    }
+
+    #[test]
+    fn test_missing_config_version_defaults_to_1() -> Result<()> {
+        let config: InternalConfig = serde_yaml::from_str(&config_with_version(None))?;
+        assert_eq!(config.version, 1);
+        assert!(PeckishConfig::parse(&config_with_version(None)).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_current_config_version_is_accepted() {
+        assert!(PeckishConfig::parse(&config_with_version(Some(CONFIG_VERSION))).is_ok());
+    }
+
+    #[test]
+    fn test_older_config_version_is_accepted() {
+        assert!(PeckishConfig::parse(&config_with_version(Some(CONFIG_VERSION - 1))).is_ok());
+    }
+
+    #[test]
+    fn test_newer_config_version_is_rejected() {
+        let err = PeckishConfig::parse(&config_with_version(Some(CONFIG_VERSION + 1)))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("newer than the newest supported version"));
+    }
+
+    #[test]
+    fn test_newer_config_version_is_rejected_before_parsing() {
+        let err = PeckishConfig::parse("version: 9999\nsome_future_field: true\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("newer than the newest supported version"));
+    }
+
+    #[test]
+    fn test_hooks_are_parsed_per_producer() -> Result<()> {
+        let config = PeckishConfig::parse(&config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            "path: \"./out/test.tar\"\n    after: \"echo done\"",
+        ))?;
+
+        let hooks = &config.hooks["tarball"];
+        assert!(hooks.before.is_none());
+        assert_eq!(hooks.after.as_deref(), Some("echo done"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_printed_config_has_expanded_paths() -> Result<()> {
+        let config = config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            "path: \"./out/{name}_{version}_{arch}.tar\"",
+        );
+
+        let yaml = PeckishConfig::resolve(&config, ConfigFormat::Yaml)?;
+        assert!(yaml.contains("./out/test_0.0.1-1_amd64.tar"), "{yaml}");
+        assert!(!yaml.contains("{version}"), "{yaml}");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&PeckishConfig::resolve(&config, ConfigFormat::Json)?)?;
+        assert_eq!("./out/test_0.0.1-1_amd64.tar", json["output"][0]["path"]);
+        assert_eq!(1, json["version"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_tokens_are_expanded_from_metadata() -> Result<()> {
+        let config = config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            "path: \"./out/{name}_{version}_{arch}.tar\"",
+        );
+        let parsed = PeckishConfig::parse(&config)?;
+        let ConfiguredProducer::Tarball(producer) = &parsed.output[0] else {
+            unreachable!();
+        };
+        assert_eq!(PathBuf::from("./out/test_0.0.1-1_amd64.tar"), producer.path);
+
+        let config = config.replace("{arch}", "{release}");
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains("producer 'tarball': unknown token `{release}`"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_producer_names_are_rejected() {
+        let config = config_with_version(None)
+            + r#"
+  - name: "tarball"
+    type: "tarball"
+    path: "./out/other.tar"
+"#;
+
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains("producer name 'tarball' is used more than once"));
+    }
+
+    #[test]
+    fn test_producer_writing_its_input_is_rejected() {
+        let config = config_with_version(None).replace("./out/test.tar", "./Cargo.toml");
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains(
+            "producer 'tarball' writes to ./Cargo.toml, which it also reads from: input -> 'tarball'"
+        ));
+    }
+
+    #[test]
+    fn test_chained_producer_writing_its_input_is_rejected() -> Result<()> {
+        let second = r#"
+  - name: "repack"
+    type: "tarball"
+    path: "./out/test.tar"
+"#;
+        let config = config_with_version(None) + second;
+        // Without chaining, both read the input, so they only clash on the
+        // output path, which is caught when producing.
+        PeckishConfig::parse(&config)?;
+
+        let config = format!("chain: true\n{config}");
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains(
+            "producer 'repack' writes to ./out/test.tar, which it also reads from: input -> 'tarball' -> 'repack'"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_chmod_is_rejected() {
+        let config = config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            r#"path: "./out/test.tar"
+    injections:
+      - "chmod-script"
+      - "touch-script"
+
+injections:
+  chmod-script:
+    type: "chmod"
+    path: "/usr/bin/script"
+    mode: 0o755
+  touch-script:
+    type: "touch"
+    path: "/usr/bin/script""#,
+        );
+
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains(
+            "producer 'tarball': chmod injection for /usr/bin/script runs before the touch injection"
+        ));
+
+        let config = config.replace(
+            "      - \"chmod-script\"\n      - \"touch-script\"",
+            "      - \"touch-script\"\n      - \"chmod-script\"",
+        );
+        assert!(PeckishConfig::parse(&config).is_ok());
+    }
+
+    #[test]
+    fn test_named_and_inline_injections_are_mixed() -> Result<()> {
+        let config = config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            r#"path: "./out/test.tar"
+    injections:
+      - type: "touch"
+        path: "/first"
+      - "touch-named"
+      - type: "delete"
+        path: "/first"
+
+injections:
+  touch-named:
+    type: "touch"
+    path: "/named""#,
+        );
+
+        let config = PeckishConfig::parse(&config)?;
+        let injections = config.output[0].injections();
+        assert_eq!(3, injections.len());
+        assert!(
+            matches!(&injections[0], Injection::Touch { path, .. } if path == Path::new("/first"))
+        );
+        assert!(
+            matches!(&injections[1], Injection::Touch { path, .. } if path == Path::new("/named"))
+        );
+        assert!(
+            matches!(&injections[2], Injection::Delete { path } if path == Path::new("/first"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_injections_are_warned_about() -> Result<()> {
+        let config = config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            r#"path: "./out/test.tar"
+    injections:
+      - "touch-used"
+
+injections:
+  touch-used:
+    type: "touch"
+    path: "/used"
+  touch-unused:
+    type: "touch"
+    path: "/unused""#,
+        );
+
+        let (guard, logs) = crate::util::capture_logs();
+        PeckishConfig::parse(&config)?;
+        drop(guard);
+        assert!(logs.contains("injection 'touch-unused' is defined, but no producer uses it"));
+        assert!(!logs.contains("injection 'touch-used'"));
+
+        let typo = config.replace("      - \"touch-used\"", "      - \"touch-usde\"");
+        let err = PeckishConfig::parse(&typo).unwrap_err().to_string();
+        assert!(err.contains(
+            "producer 'tarball' uses injection 'touch-usde', which isn't defined in `injections`"
+        ));
+
+        let reserved = config.replace("  touch-unused:", "  metadata:");
+        let err = PeckishConfig::parse(&reserved).unwrap_err().to_string();
+        assert!(err.contains("injection name 'metadata' is reserved"));
+
+        Ok(())
+    }
+
+    /// A value of the type a schema property asks for. Arrays and maps are
+    /// left empty, since only the keys of the object they're in matter.
+    fn schema_example(schema: &serde_json::Value) -> serde_json::Value {
+        if schema.get("$ref").is_some() {
+            return serde_json::json!({ "name": "in", "type": "tarball", "path": "./in.tar" });
+        }
+        if let Some(value) = schema.get("const") {
+            return value.clone();
+        }
+        if let Some(values) = schema.get("enum") {
+            return values[0].clone();
+        }
+        if let Some(schemas) = schema.get("oneOf") {
+            return schema_example(&schemas[0]);
+        }
+        match schema["type"].as_str() {
+            Some("string") => "x".into(),
+            Some("boolean") => false.into(),
+            Some("integer") => 0.into(),
+            Some("array") => serde_json::json!([]),
+            Some("object") => serde_json::json!({}),
+            other => panic!("no example for {other:?} in {schema}"),
+        }
+    }
+
+    /// Check that every `type` of a tagged schema, with every key it lists
+    /// set, reads into `T` and writes back out with exactly those keys.
+    fn assert_schema_keys_match<T>(schema: &serde_json::Value)
+    where
+        T: serde::de::DeserializeOwned + Serialize,
+    {
+        let common = schema["properties"].as_object().unwrap();
+        for condition in schema["allOf"].as_array().unwrap() {
+            let kind = condition["if"]["properties"]["type"]["const"]
+                .as_str()
+                .unwrap();
+            // Plugins take any keys, as options for the plugin.
+            if kind == "plugin" {
+                continue;
+            }
+
+            let mut object = serde_json::Map::new();
+            for (key, property) in common
+                .iter()
+                .chain(condition["then"]["properties"].as_object().unwrap())
+            {
+                object.insert(key.clone(), schema_example(property));
+            }
+            object.insert("type".into(), kind.into());
+            // Single-file inputs take a `path` or a `url`, never both.
+            if object.contains_key("path") && kind != "remote_file" {
+                object.remove("url");
+                object.remove("sha256");
+            }
+
+            let value = serde_json::Value::Object(object.clone());
+            let parsed: T = serde_json::from_value(value)
+                .unwrap_or_else(|e| panic!("`{kind}` wasn't accepted with every key set: {e}"));
+            let mut written = serde_json::to_value(&parsed)
+                .unwrap()
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            written.sort();
+            let mut expected = object.keys().cloned().collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(expected, written, "schema keys for `{kind}` don't match");
+        }
+    }
+
+    #[test]
+    fn test_schema_keys_match_the_config() {
+        let schema = crate::util::schema::config_schema();
+        let keys = |schema: &serde_json::Value| {
+            let mut keys = schema["properties"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        let fields = |fields: &[&str]| {
+            let mut fields = fields.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+            fields.sort();
+            fields
+        };
+
+        assert_eq!(keys(&schema), fields(struct_fields::<InternalConfig>()));
+        let properties = &schema["properties"];
+        assert_eq!(
+            keys(&properties["metadata"]),
+            fields(struct_fields::<PackageMetadata>())
+        );
+        assert_eq!(
+            keys(&properties["defaults"]),
+            fields(struct_fields::<ProducerDefaults>())
+        );
+        assert_eq!(
+            keys(&properties["lint"]),
+            fields(struct_fields::<crate::util::lint::LintConfig>())
+        );
+
+        assert_schema_keys_match::<InputArtifact>(&schema["definitions"]["input"]);
+        assert_schema_keys_match::<Injection>(&schema["definitions"]["injection"]);
+        assert_schema_keys_match::<OutputStep>(&properties["output"]["items"]);
+    }
+
+    #[test]
+    fn test_struct_fields_are_read_from_the_struct() {
+        assert_eq!(
+            &[
+                "version",
+                "chain",
+                "overwrite",
+                "defaults",
+                "metadata",
+                "input",
+                "output",
+                "injections",
+                "lint",
+            ],
+            struct_fields::<InternalConfig>()
+        );
+        assert!(struct_fields::<InputArtifact>().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chmod_injection_keeps_file_type() -> Result<()> {
+        let mut memfs = MemFS::new();
+        (*memfs).write("/script", b"echo hi").await?;
+
+        Injection::Chmod {
+            path: "/script".into(),
+            mode: 0o755,
+        }
+        .inject(&mut memfs)
+        .await?;
+
+        let metadata = (*memfs).metadata("/script").await?;
+        assert!(metadata.is_file());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_host_dir_injection_leaves_out_excludes() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let tree = tmp.path_view().join("tree");
+        tokio::fs::create_dir_all(tree.join(".git/objects")).await?;
+        tokio::fs::create_dir_all(tree.join("src")).await?;
+        tokio::fs::write(tree.join(".git/HEAD"), b"ref: refs/heads/main").await?;
+        tokio::fs::write(tree.join("src/main.rs"), b"fn main() {}").await?;
+        tokio::fs::write(tree.join("notes.txt"), b"notes").await?;
+
+        let mut memfs = MemFS::new();
+        Injection::HostDir {
+            src: tree.clone(),
+            dest: "/opt/tree".into(),
+            default_excludes: true,
+            extra_excludes: vec!["notes.txt".into()],
+        }
+        .inject(&mut memfs)
+        .await?;
+
+        assert_eq!(
+            (*memfs).read("/opt/tree/src/main.rs").await?,
+            b"fn main() {}"
+        );
+        assert!((*memfs).metadata("/opt/tree/.git").await.is_err());
+        assert!((*memfs).metadata("/opt/tree/notes.txt").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_artifact_injection_copies_a_subtree() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let base = tmp.path_view().join("base.tar");
+        let tools = MemFS::new();
+        tools.fs().create_dir_all("/usr/bin").await?;
+        tools.fs().write("/usr/bin/tool", b"#!/bin/sh").await?;
+        tools.fs().create_dir_all("/etc").await?;
+        tools.fs().write("/etc/tool.conf", b"not copied").await?;
+        TarballProducerBuilder::new("base")
+            .path(&base)
+            .build()?
+            .produce_from(&crate::artifact::memory::MemoryArtifact {
+                name: "tools".into(),
+                fs: tools,
+            })
+            .await?;
+
+        let mut memfs = MemFS::new();
+        (*memfs).write("/README", b"hi").await?;
+        Injection::FromArtifact {
+            artifact: Box::new(InputArtifact::Tarball {
+                name: "base".into(),
+                source: base.clone().into(),
+            }),
+            src: "/usr/bin".into(),
+            dest: "/opt/tools/bin".into(),
+        }
+        .inject(&mut memfs)
+        .await?;
+
+        assert_eq!(
+            b"#!/bin/sh".to_vec(),
+            (*memfs).read("/opt/tools/bin/tool").await?
+        );
+        assert!((*memfs).metadata("/etc/tool.conf").await.is_err());
+        assert_eq!(b"hi".to_vec(), (*memfs).read("/README").await?);
+
+        // Reading the producer's own output is a cycle.
+        let config = config_with_version(None).replace(
+            "path: \"./out/test.tar\"",
+            r#"path: "./out/test.tar"
+    injections:
+      - "from-self"
+
+injections:
+  from-self:
+    type: "from_artifact"
+    artifact:
+      name: "self"
+      type: "tarball"
+      path: "out/test.tar"
+    src: "/"
+    dest: "/nested""#,
+        );
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains("reads out/test.tar, which is this producer's own output"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_artifact_sources_are_checked_at_load_time() {
+        let injected = |artifact: &str| {
+            config_with_version(None)
+                .replace(
+                    "path: \"./out/test.tar\"",
+                    r#"path: "./out/test.tar"
+    injections:
+      - "from-other"
+  - name: "other"
+    type: "tarball"
+    path: "./out/other.tar""#,
+                )
+                .replace(
+                    "output:",
+                    &format!(
+                        r#"injections:
+  from-other:
+    type: "from_artifact"
+    artifact:
+{artifact}
+    src: "/"
+    dest: "/nested"
+
+output:"#
+                    ),
+                )
+        };
+
+        // The other tarball isn't written until after the first one reads it.
+        let err = PeckishConfig::parse(&injected(
+            r#"      name: "other"
+      type: "tarball"
+      path: "./out/other.tar""#,
+        ))
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("reads ./out/other.tar, which producer 'other' only writes later"),
+            "{err}"
+        );
+
+        let err = PeckishConfig::parse(&injected(
+            r#"      name: "plugged"
+      type: "plugin"
+      plugin: "whatever""#,
+        ))
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("plugin artifacts can't be injected from"),
+            "{err}"
+        );
+
+        assert!(PeckishConfig::parse(&injected(
+            r#"      name: "base"
+      type: "tarball"
+      path: "./base.tar""#,
+        ))
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_relative_symlink_injection_works() -> Result<()> {
+        let mut memfs = MemFS::new();
+        (*memfs).create_dir_all("/usr/lib/peckish").await?;
+        (*memfs).create_dir_all("/usr/bin").await?;
+        (*memfs).write("/usr/lib/peckish/peckish", b"hi").await?;
+
+        Injection::Symlink {
+            src: "/usr/lib/peckish/peckish".into(),
+            dest: "/usr/bin/peckish".into(),
+            relative: Some(true),
+        }
+        .inject(&mut memfs)
+        .await?;
+
+        let target = (*memfs).read_link("/usr/bin/peckish").await?;
+        assert_eq!(target, PathBuf::from("../lib/peckish/peckish"));
+        assert!(target.is_relative());
+        assert_eq!((*memfs).read("/usr/bin/peckish").await?, b"hi");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_link_target() {
+        let target = |src: &str, dest: &str| {
+            Injection::relative_link_target(Path::new(src), Path::new(dest))
+        };
+
+        assert_eq!(
+            target("/usr/lib/foo", "/usr/bin/foo"),
+            Path::new("../lib/foo")
+        );
+        assert_eq!(target("/usr/bin/foo", "/usr/bin/bar"), Path::new("foo"));
+        assert_eq!(target("/foo", "/a/b/c/foo"), Path::new("../../../foo"));
+        assert_eq!(
+            target("usr/./lib/../bin/foo", "/usr/bin/bar"),
+            Path::new("foo")
+        );
+        assert_eq!(target("/usr/bin", "/usr/bin/here"), Path::new("."));
+    }
+
+    #[tokio::test]
+    async fn test_arch_independent_packages_are_marked_per_format() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let out = tmp.path_view();
+        let config = config_with_version(None).replace("arch: \"amd64\"", "arch: \"any\"")
+            + &format!(
+                r#"
+  - name: "arch"
+    type: "arch"
+    path: "{out}/test.pkg.tar"
+    compression: "none"
+  - name: "deb"
+    type: "deb"
+    path: "{out}/test.deb"
+  - name: "rpm"
+    type: "rpm"
+    path: "{out}/test.rpm"
+"#,
+                out = out.display()
+            );
+
+        let config = PeckishConfig::parse(&config)?;
+        let input = FileArtifactBuilder::new("cargo dot toml")
+            .add_path("Cargo.toml")
+            .build()?;
+        for producer in &config.output[1..] {
+            producer.produce_from(&input).await?;
+        }
+
+        let pkginfo = TarballArtifact {
+            name: "arch".into(),
+            path: out.join("test.pkg.tar"),
+        }
+        .extract()
+        .await?;
+        let pkginfo = String::from_utf8((*pkginfo).read("/.PKGINFO").await?)?;
+        assert!(pkginfo.contains("arch = any\n"));
+
+        // control.tar is uncompressed, so the control file is readable as-is.
+        let deb = tokio::fs::read(out.join("test.deb")).await?;
+        let field = b"Architecture: all\n";
+        assert!(deb.windows(field.len()).any(|window| window == field));
+
+        let rpm = rpm::Package::open(out.join("test.rpm"))?;
+        assert_eq!(rpm.metadata.get_arch()?, "noarch");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_configured_packages_report_package_info() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let out = tmp.path_view();
+        let config = config_with_version(None)
+            + &format!(
+                r#"
+  - name: "arch"
+    type: "arch"
+    path: "{out}/test.pkg.tar"
+    compression: "none"
+  - name: "deb"
+    type: "deb"
+    path: "{out}/test.deb"
+"#,
+                out = out.display()
+            );
+        let config = PeckishConfig::parse(&config)?;
+        let input = FileArtifactBuilder::new("cargo dot toml")
+            .add_path("Cargo.toml")
+            .build()?;
+        for producer in &config.output[1..] {
+            producer.produce_from(&input).await?;
+        }
+
+        for (kind, path, arch) in [
+            ("arch", "test.pkg.tar", "x86_64"),
+            ("deb", "test.deb", "amd64"),
+        ] {
+            let config = config_with_version(None).replace(
+                "  type: \"file\"\n  paths:\n    - \"./Cargo.toml\"",
+                &format!(
+                    "  type: \"{kind}\"\n  path: \"{}\"",
+                    out.join(path).display()
+                ),
+            );
+            let artifact = PeckishConfig::parse(&config)?.input.into_artifact()?;
+            let info = artifact
+                .package_info()
+                .await?
+                .ok_or_else(|| eyre!("{kind} has no package info"))?;
+            assert_eq!(info.name, "test");
+            assert_eq!(info.version, "0.0.1-1");
+            assert_eq!(info.arch, arch);
+            assert_eq!(info.description, "test");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_default_compression_applies_to_producers() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let tar = tmp.path_view().join("default-compression.tar.xz");
+        let config = config_with_version(None)
+            .replace("metadata:", "defaults:\n  compression: \"xz\"\n\nmetadata:")
+            .replace("./out/test.tar", &tar.to_string_lossy())
+            + r#"
+  - name: "explicit tarball"
+    type: "tarball"
+    path: "./out/explicit.tar"
+    compression: "gzip"
+  - name: "arch"
+    type: "arch"
+    path: "./out/test.pkg.tar.xz"
+"#;
+
+        let config = PeckishConfig::parse(&config)?;
+        let compressions = config
+            .output
+            .iter()
+            .map(|producer| match producer {
+                ConfiguredProducer::Tarball(tarball) => tarball.compression,
+                ConfiguredProducer::Arch(arch) => arch.compression,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            compressions,
+            vec![
+                CompressionType::Xz,
+                CompressionType::Gzip,
+                CompressionType::Xz
+            ]
+        );
+
+        let ConfiguredProducer::Tarball(producer) = &config.output[0] else {
+            unreachable!();
+        };
+        producer.produce_from(&input).await?;
+        let tarball = tokio::fs::read(&tar).await?;
+        assert!(tarball.starts_with(b"\xfd7zXZ\0"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deb_depends_can_be_a_string_or_a_list() -> Result<()> {
+        let depends = |depends: &str| -> Result<String> {
+            let config = PeckishConfig::parse(
+                &(config_with_version(None)
+                    + &format!(
+                        r#"
+  - name: "deb"
+    type: "deb"
+    path: "./out/test.deb"
+    depends: {depends}
+"#
+                    )),
+            )?;
+            let ConfiguredProducer::Deb(deb) = &config.output[1] else {
+                unreachable!();
+            };
+            Ok(deb.package_depends.clone())
+        };
+
+        assert_eq!(depends("\"libc6, zlib1g\"")?, "libc6, zlib1g");
+        assert_eq!(depends("[libc6, zlib1g]")?, "libc6, zlib1g");
+        assert_eq!(depends("\n      - libc6\n      - zlib1g")?, "libc6, zlib1g");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rpm_release_allows_dashes_in_the_version() -> Result<()> {
+        let config = PeckishConfig::parse(
+            &(config_with_version(None).replace("0.0.1-1", "1.0-rc1")
+                + r#"
+  - name: "rpm"
+    type: "rpm"
+    path: "./out/test.rpm"
+    release: "2"
+"#),
+        )?;
+        let ConfiguredProducer::Rpm(rpm) = &config.output[1] else {
+            unreachable!();
+        };
+        assert_eq!(rpm.package_version, "1.0_rc1");
+        assert_eq!(rpm.package_release, "2");
+        rpm.validate().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_normalize_permissions_runs_after_injections() -> Result<()> {
+        let config = config_with_version(None)
+            + r#"    normalize_permissions: true
+    injections:
+      - "data"
+
+injections:
+  data:
+    type: "touch"
+    path: "/usr/share/pkg/data"
+"#;
+        let config = PeckishConfig::parse(&config)?;
+        let injections = config.output[0].injections();
+        assert!(matches!(
+            injections.last(),
+            Some(Injection::NormalizePermissions)
+        ));
+
+        let mut memfs = MemFS::new();
+        for injection in injections {
+            injection.inject(&mut memfs).await?;
+        }
+
+        for (path, mode) in [("/usr/share/pkg/data", 0o644), ("/usr/share/pkg", 0o755)] {
+            let metadata = memfs.fs().metadata(path).await?;
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{path}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_umask_applies_to_injected_files() -> Result<()> {
+        let config = config_with_version(None)
+            + r#"    umask: 0o022
+    injections:
+      - "touch-script"
+
+injections:
+  touch-script:
+    type: "touch"
+    path: "/usr/lib/pkg/script"
+"#;
+        let config = PeckishConfig::parse(&config)?;
+        let injections = config.output[0].injections();
+        assert!(matches!(injections[0], Injection::Umask { umask: 0o022 }));
+
+        let mut memfs = MemFS::new();
+        for injection in injections {
+            injection.inject(&mut memfs).await?;
+        }
+
+        for (path, mode) in [("/usr/lib/pkg/script", 0o644), ("/usr/lib/pkg", 0o755)] {
+            let metadata = memfs.fs().metadata(path).await?;
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{path}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_builder_builds_a_runnable_config() -> Result<()> {
+        use crate::fs::TempDir;
+        use crate::pipeline::Pipeline;
+
+        let tmp = TempDir::new().await?;
+        let config = PeckishConfigBuilder::new()
+            .input(
+                FileArtifactBuilder::new("cargo dot toml")
+                    .add_path("./Cargo.toml")
+                    .build()?,
+            )
+            .add_output(
+                TarballProducerBuilder::new("tarball")
+                    .path(tmp.path_view().join("cargo.tar"))
+                    .build()?,
+            )
+            .chain(true)
+            .build()?;
+        assert!(config.chain);
+
+        let artifacts = Pipeline::new(None).run(config).await?;
+        assert_eq!(1, artifacts.len());
+        assert!(tmp.path_view().join("cargo.tar").exists());
+
+        let no_input = PeckishConfigBuilder::new().build();
+        assert!(no_input.unwrap_err().to_string().contains("no input"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tarball_only_config_needs_no_metadata() -> Result<()> {
+        use crate::fs::TempDir;
+        use crate::pipeline::Pipeline;
+
+        let tmp = TempDir::new().await?;
+        let config = PeckishConfig::parse(&format!(
+            r#"
+input:
+  name: "cargo dot toml"
+  type: "file"
+  paths:
+    - "./Cargo.toml"
+
+output:
+  - name: "tarball"
+    type: "tarball"
+    path: "{}"
+"#,
+            tmp.path_view().join("cargo.tar").display()
+        ))?;
+
+        Pipeline::new(None).run(config).await?;
+        assert!(tmp.path_view().join("cargo.tar").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_metadata_names_the_producer_and_field() {
+        let config = config_with_version(None)
+            .replace("  license: \"Apache-2.0\"\n", "")
+            .replace("type: \"tarball\"", "type: \"rpm\"");
+
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(
+            err.contains("producer 'tarball' needs `metadata.license`, but it isn't set"),
+            "{err}"
+        );
+
+        let config = config_with_version(None)
+            .replace("  version: \"0.0.1-1\"\n", "")
+            .replace("./out/test.tar", "./out/{version}.tar");
+        let err = PeckishConfig::parse(&config).unwrap_err().to_string();
+        assert!(err.contains("`metadata.version` isn't set"), "{err}");
+    }
+}
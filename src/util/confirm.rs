@@ -0,0 +1,101 @@
+use std::io::{BufRead, Write};
+
+use eyre::{eyre, Result};
+
+use crate::util::config::{ConfiguredProducer, PeckishConfig};
+
+/// What running the config would clobber: existing outputs that `--force` or
+/// `overwrite` would remove, and Docker image tags that would be replaced.
+pub async fn destructive_operations(config: &PeckishConfig, force: bool) -> Vec<String> {
+    let mut operations = vec![];
+    // Only ask the daemon if there are images to ask about, since it may not
+    // be running at all.
+    let docker = config
+        .output
+        .iter()
+        .any(|producer| matches!(producer, ConfiguredProducer::Docker(_)))
+        .then(bollard::Docker::connect_with_local_defaults)
+        .and_then(|docker| docker.ok());
+
+    for producer in &config.output {
+        if force || config.overwrite {
+            for path in producer.output_paths() {
+                if tokio::fs::symlink_metadata(&path).await.is_ok() {
+                    operations.push(format!("overwrite {}", path.display()));
+                }
+            }
+        }
+
+        if let (ConfiguredProducer::Docker(producer), Some(docker)) = (producer, &docker) {
+            if docker.inspect_image(&producer.image).await.is_ok() {
+                operations.push(format!("replace Docker image {}", producer.image));
+            }
+        }
+    }
+
+    operations
+}
+
+/// Ask before doing the given operations, failing if the answer isn't yes.
+/// Nothing is asked when there's nothing to do.
+pub fn confirm<R: BufRead, W: Write>(
+    operations: &[String],
+    mut input: R,
+    mut output: W,
+) -> Result<()> {
+    if operations.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(output, "this run will:")?;
+    for operation in operations {
+        writeln!(output, "  - {operation}")?;
+    }
+    write!(output, "continue? [y/N] ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(eyre!("aborted, nothing was changed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operations() -> Vec<String> {
+        vec!["overwrite ./out.tar".to_string()]
+    }
+
+    #[test]
+    fn test_nothing_to_do_skips_the_prompt() -> Result<()> {
+        let mut output = vec![];
+        confirm(&[], &b"n\n"[..], &mut output)?;
+        assert!(output.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_declined_prompt_aborts() -> Result<()> {
+        let mut output = vec![];
+        assert!(confirm(&operations(), &b"n\n"[..], &mut output).is_err());
+        assert!(String::from_utf8(output)?.contains("overwrite ./out.tar"));
+
+        // No answer at all, ex. stdin was closed, is a no too.
+        assert!(confirm(&operations(), &b""[..], vec![]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accepted_prompt_continues() -> Result<()> {
+        confirm(&operations(), &b"y\n"[..], vec![])?;
+        confirm(&operations(), &b"Yes\n"[..], vec![])?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use floppy_disk::{FloppyDisk, FloppyMetadata};
+use object::{Object, ObjectSection};
+use tracing::*;
+
+use crate::fs::MemFS;
+
+/// Where debuggers look for split debug info, keyed by build-id.
+const BUILD_ID_DIR: &str = "/usr/lib/debug/.build-id";
+
+/// Split the debug info out of every ELF file in the memfs that has a GNU
+/// build-id. The debug sections are stripped from the files in `memfs`, and
+/// the unstripped files are written to `.build-id` paths in a new memfs. If
+/// there was nothing to split, `None` is returned.
+pub async fn split_debug_info(memfs: &MemFS) -> Result<Option<MemFS>> {
+    let fs = &**memfs;
+    let debug_fs = MemFS::new();
+    let mut split_any = false;
+
+    for path in nyoom::walk_ordered(fs, "/").await? {
+        if !fs.symlink_metadata(&path).await?.is_file() {
+            continue;
+        }
+
+        let data = fs.read(&path).await?;
+        let Some(build_id) = debug_build_id(&data)? else {
+            continue;
+        };
+
+        debug!("splitting debug info from {}", path.display());
+        let stripped = strip_debug_sections(&data)
+            .map_err(|e| eyre!("failed to strip debug info from {path:?}: {e}"))?;
+        fs.write(&path, stripped).await?;
+
+        let debug_path = build_id_path(&build_id);
+        (*debug_fs)
+            .create_dir_all(debug_path.parent().unwrap())
+            .await?;
+        (*debug_fs).write(&debug_path, data).await?;
+        debug!("wrote debug info to {}", debug_path.display());
+
+        split_any = true;
+    }
+
+    Ok(if split_any { Some(debug_fs) } else { None })
+}
+
+/// Returns the build-id of the given file, if it's an ELF file that has both
+/// a build-id and debug info to split out.
+fn debug_build_id(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    if !data.starts_with(b"\x7fELF") {
+        return Ok(None);
+    }
+
+    let elf = object::File::parse(data)?;
+    let has_debug_info = elf
+        .sections()
+        .any(|section| section.name().is_ok_and(is_debug_section));
+
+    match elf.build_id()? {
+        Some(build_id) if has_debug_info && !build_id.is_empty() => Ok(Some(build_id.to_vec())),
+        _ => Ok(None),
+    }
+}
+
+fn strip_debug_sections(data: &[u8]) -> Result<Vec<u8>> {
+    let mut builder = object::build::elf::Builder::read(data)?;
+    for section in &mut builder.sections {
+        if let Ok(name) = std::str::from_utf8(&section.name) {
+            let name = name
+                .strip_prefix(".rela")
+                .or_else(|| name.strip_prefix(".rel"))
+                .unwrap_or(name);
+            section.delete = is_debug_section(name);
+        }
+    }
+    builder.delete_orphans();
+
+    let mut out = vec![];
+    builder.write(&mut out)?;
+    Ok(out)
+}
+
+fn is_debug_section(name: &str) -> bool {
+    name.starts_with(".debug_") || name.starts_with(".zdebug_")
+}
+
+/// `ab/cdef....debug`, as gdb and friends expect.
+fn build_id_path(build_id: &[u8]) -> PathBuf {
+    let hex = build_id
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    Path::new(BUILD_ID_DIR)
+        .join(&hex[..2])
+        .join(format!("{}.debug", &hex[2..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::Result;
+    use floppy_disk::FloppyDisk;
+
+    use crate::fs::test_utils::Fixture;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_split_debug_info_works() -> Result<()> {
+        let fixture = Fixture::new("hello-debug").await;
+        let data = tokio::fs::read(fixture.path_view()).await?;
+        let build_id = debug_build_id(&data)?.unwrap();
+
+        let memfs = MemFS::new();
+        (*memfs).create_dir_all("/usr/bin").await?;
+        (*memfs).write("/usr/bin/hello", &data).await?;
+
+        let debug_fs = split_debug_info(&memfs).await?.unwrap();
+
+        let stripped = (*memfs).read("/usr/bin/hello").await?;
+        assert!(stripped.len() < data.len());
+        assert!(debug_build_id(&stripped)?.is_none());
+
+        let split = (*debug_fs).read(build_id_path(&build_id)).await?;
+        assert_eq!(split, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_debug_info_ignores_non_elf_files() -> Result<()> {
+        let memfs = MemFS::new();
+        (*memfs).write("/not-an-elf", b"hello").await?;
+
+        assert!(split_debug_info(&memfs).await?.is_none());
+        assert_eq!((*memfs).read("/not-an-elf").await?, b"hello");
+
+        Ok(())
+    }
+}
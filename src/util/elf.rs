@@ -0,0 +1,162 @@
+use eyre::{eyre, Result};
+use object::build::elf::{Builder, Dynamic, SectionData};
+use object::elf::{DT_RPATH, DT_RUNPATH};
+
+/// Changes to make to an ELF file's dynamic linking info, like `patchelf`.
+#[derive(Debug, Clone, Default)]
+pub struct ElfPatch<'a> {
+    /// Replace the RPATH/RUNPATH, or add a RUNPATH if there's neither.
+    pub set_rpath: Option<&'a str>,
+    pub set_interpreter: Option<&'a str>,
+    pub remove_rpath: bool,
+}
+
+/// Apply the patch to an ELF file. Sections are never moved, so values can
+/// only be replaced with ones that fit in the file's existing layout.
+pub fn patch(data: &[u8], patch: &ElfPatch) -> Result<Vec<u8>> {
+    if !data.starts_with(b"\x7fELF") {
+        return Err(eyre!("not an ELF file"));
+    }
+    if patch.set_rpath.is_some() && patch.remove_rpath {
+        return Err(eyre!("set_rpath and remove_rpath can't both be set"));
+    }
+
+    let mut builder = Builder::read(data)?;
+
+    if patch.set_rpath.is_some() || patch.remove_rpath {
+        let dynamic = builder
+            .dynamic_data_mut()
+            .ok_or_else(|| eyre!("not dynamically linked, so it has no rpath"))?;
+        let is_rpath = |entry: &Dynamic| matches!(entry.tag(), DT_RPATH | DT_RUNPATH);
+
+        if patch.remove_rpath {
+            dynamic.retain(|entry| !is_rpath(entry));
+        }
+
+        if let Some(rpath) = patch.set_rpath {
+            let mut replaced = false;
+            for entry in dynamic.iter_mut() {
+                if let Dynamic::String { tag, val } = entry {
+                    if matches!(*tag, DT_RPATH | DT_RUNPATH) {
+                        *val = rpath.as_bytes().to_vec().into();
+                        replaced = true;
+                    }
+                }
+            }
+            if !replaced {
+                dynamic.push(Dynamic::String {
+                    tag: DT_RUNPATH,
+                    val: rpath.as_bytes().to_vec().into(),
+                });
+            }
+        }
+    }
+
+    if let Some(interpreter) = patch.set_interpreter {
+        let interp = builder
+            .interp_data_mut()
+            .ok_or_else(|| eyre!("has no interpreter to replace"))?;
+        // The loader reads the whole PT_INTERP segment, so the new path is
+        // padded out to the old size instead of shrinking it.
+        if interpreter.len() >= interp.len() {
+            return Err(eyre!(
+                "interpreter {interpreter} is longer than the existing one, which only has room for {} bytes",
+                interp.len() - 1
+            ));
+        }
+        let mut padded = interpreter.as_bytes().to_vec();
+        padded.resize(interp.len(), 0);
+        *interp = padded.into();
+    }
+
+    // Growing an allocated section would overwrite whatever comes after it.
+    for section in &builder.sections {
+        if !matches!(
+            section.data,
+            SectionData::Dynamic(_) | SectionData::DynamicString
+        ) {
+            continue;
+        }
+        let size = builder.section_size(section) as u64;
+        if size > section.sh_size {
+            return Err(eyre!(
+                "{} would grow from {} to {size} bytes, and peckish can't move sections to make room",
+                String::from_utf8_lossy(&section.name),
+                section.sh_size
+            ));
+        }
+    }
+
+    let mut out = vec![];
+    builder.write(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::test_utils::Fixture;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    fn runpath(data: &[u8]) -> Result<Option<String>> {
+        let builder = Builder::read(data)?;
+        let rpath =
+            builder
+                .dynamic_data()
+                .unwrap_or_default()
+                .iter()
+                .find_map(|entry| match entry {
+                    Dynamic::String { tag, val } if matches!(*tag, DT_RPATH | DT_RUNPATH) => {
+                        Some(String::from_utf8_lossy(val).to_string())
+                    }
+                    _ => None,
+                });
+
+        Ok(rpath)
+    }
+
+    #[tokio::test]
+    async fn test_rpath_is_replaced_and_removed() -> Result<()> {
+        let fixture = Fixture::new("hello-rpath").await;
+        let data = tokio::fs::read(fixture.path_view()).await?;
+        assert_eq!(Some("/opt/vendor/toolchain/lib".into()), runpath(&data)?);
+
+        let patched = patch(
+            &data,
+            &ElfPatch {
+                set_rpath: Some("$ORIGIN/../lib"),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(Some("$ORIGIN/../lib".into()), runpath(&patched)?);
+
+        let removed = patch(
+            &patched,
+            &ElfPatch {
+                remove_rpath: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(None, runpath(&removed)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_elf_files_are_rejected() {
+        let err = patch(
+            b"#!/bin/sh\n",
+            &ElfPatch {
+                remove_rpath: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not an ELF file"));
+    }
+}
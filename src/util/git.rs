@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use eyre::Result;
+use tracing::*;
+
+/// The commit time, in seconds since the epoch, of the last commit that
+/// touched each file under `dir`, keyed by the file's path relative to `dir`.
+/// This is one `git log` for the whole tree, rather than one per file.
+/// Untracked files aren't in the map. Returns `None` if `dir` isn't in a git
+/// repo, or if git isn't installed.
+pub async fn last_commit_times(dir: &Path) -> Result<Option<HashMap<PathBuf, u64>>> {
+    let output = match tokio::process::Command::new("git")
+        .args(["-c", "core.quotePath=false", "log", "--relative"])
+        .arg("--format=%x00%ct")
+        .arg("--name-only")
+        .arg("--")
+        .arg(".")
+        .current_dir(dir)
+        .output()
+        .await
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "git is not installed, so git mtimes can't be used for {}",
+                dir.display()
+            );
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        debug!("not in a git repo: {}", dir.display());
+        return Ok(None);
+    }
+
+    // Commits are listed newest first, so the first time a file shows up is
+    // its last commit.
+    let mut times = HashMap::new();
+    let mut time = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(commit_time) = line.strip_prefix('\0') {
+            time = Some(commit_time.trim().parse()?);
+        } else if let (Some(time), false) = (time, line.is_empty()) {
+            times.entry(PathBuf::from(line)).or_insert(time);
+        }
+    }
+
+    Ok(Some(times))
+}
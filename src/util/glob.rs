@@ -0,0 +1,80 @@
+use std::path::Path;
+
+use eyre::Result;
+use regex::Regex;
+
+/// A shell-style path pattern, ex. `/etc/**/*.conf`. `*` and `?` don't match
+/// `/`, while `**` matches any number of directories, including none.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    regex: Regex,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // `/**/` also matches a single `/`.
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+
+        Ok(Self {
+            regex: Regex::new(&regex)?,
+        })
+    }
+
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.regex.is_match(&path.as_ref().to_string_lossy())
+    }
+}
+
+/// Whether any of the patterns match the path. Invalid patterns never match;
+/// producers are expected to reject them while validating.
+pub fn any_match<P: AsRef<Path>>(patterns: &[String], path: P) -> bool {
+    patterns
+        .iter()
+        .filter_map(|pattern| Glob::new(pattern).ok())
+        .any(|glob| glob.matches(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matching() -> Result<()> {
+        let glob = Glob::new("/etc/*.conf")?;
+        assert!(glob.matches("/etc/app.conf"));
+        assert!(!glob.matches("/etc/app/app.conf"));
+        assert!(!glob.matches("/etc/app.conf.bak"));
+
+        let glob = Glob::new("/usr/share/doc/**")?;
+        assert!(glob.matches("/usr/share/doc/app/README"));
+        assert!(!glob.matches("/usr/share/man/app.1"));
+
+        let glob = Glob::new("/etc/**/*.conf")?;
+        assert!(glob.matches("/etc/app.conf"));
+        assert!(glob.matches("/etc/app/nested/app.conf"));
+
+        let glob = Glob::new("/var/log/app.?")?;
+        assert!(glob.matches("/var/log/app.1"));
+        assert!(!glob.matches("/var/log/app.10"));
+
+        Ok(())
+    }
+}
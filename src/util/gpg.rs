@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use tracing::*;
+
+/// Where the detached signature for a file goes, ex. `foo.pkg.tar.zst` ->
+/// `foo.pkg.tar.zst.sig`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_os_string();
+    signature.push(".sig");
+    signature.into()
+}
+
+/// A gpg command using the keyring in `home`, or gpg's default keyring
+/// (`GNUPGHOME`, then `~/.gnupg`) if there's none.
+fn gpg(home: Option<&Path>) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new("gpg");
+    if let Some(home) = home {
+        command.env("GNUPGHOME", home);
+    }
+    command.arg("--batch");
+    command
+}
+
+/// Check that gpg has a secret key for `key`, which can be anything gpg
+/// accepts as a user id, ex. a fingerprint or an email address.
+pub async fn check_secret_key(home: Option<&Path>, key: &str) -> Result<()> {
+    let output = gpg(home)
+        .args(["--list-secret-keys", "--", key])
+        .output()
+        .await
+        .map_err(|e| eyre!("cannot run gpg to check signing key {key}: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!("gpg has no secret key for signing key {key}"));
+    }
+
+    Ok(())
+}
+
+/// Write a binary detached signature for `path` next to it, the way pacman
+/// and `repo-add` expect. Returns the signature's path.
+pub async fn detach_sign(home: Option<&Path>, key: &str, path: &Path) -> Result<PathBuf> {
+    let signature = signature_path(path);
+    info!("signing {} with {key}...", path.display());
+    // `--local-user=` and `--` keep a key or path starting with `-` from
+    // being read as an option.
+    let output = gpg(home)
+        .arg("--yes")
+        .arg(format!("--local-user={key}"))
+        .arg("--output")
+        .arg(&signature)
+        .args(["--detach-sign", "--"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| eyre!("cannot run gpg to sign {}: {e}", path.display()))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "gpg failed to sign {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(signature)
+}
@@ -0,0 +1,219 @@
+use std::path::{Component, Path, PathBuf};
+
+use eyre::Result;
+use floppy_disk::{FloppyDisk, FloppyMetadata, FloppyUnixPermissions};
+use serde::{Deserialize, Serialize};
+
+use crate::fs::MemFS;
+
+/// How bad a lint finding is. Only [`Severity::Error`] findings fail a
+/// pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A packaging problem found in an artifact's filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The name of the rule that produced this finding, ex. `world-writable`.
+    /// This is what's used to suppress it.
+    pub rule: &'static str,
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} [{}] {}: {}",
+            self.severity,
+            self.rule,
+            self.path.display(),
+            self.message
+        )
+    }
+}
+
+/// Lint settings from the config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LintConfig {
+    /// Findings to ignore. Either a rule name, to ignore it everywhere, or
+    /// `rule:/path`, to ignore it for a single path.
+    #[serde(default)]
+    pub suppress: Vec<String>,
+}
+
+impl LintConfig {
+    fn is_suppressed(&self, rule: &str, path: &Path) -> bool {
+        self.suppress.iter().any(|s| match s.split_once(':') {
+            Some((suppressed_rule, suppressed_path)) => {
+                suppressed_rule == rule && Path::new(suppressed_path) == path
+            }
+            None => s == rule,
+        })
+    }
+}
+
+/// Check every path in the memfs for common packaging problems. Findings are
+/// returned in path order, without any that `config` suppresses.
+pub async fn lint(memfs: &MemFS, config: &LintConfig) -> Result<Vec<Finding>> {
+    let fs = memfs.fs();
+    let mut findings = vec![];
+
+    for path in nyoom::walk_ordered(fs, "/").await? {
+        let metadata = fs.symlink_metadata(&path).await?;
+        let mut found = |severity, rule, message: String| {
+            if !config.is_suppressed(rule, &path) {
+                findings.push(Finding {
+                    severity,
+                    rule,
+                    path: path.clone(),
+                    message,
+                });
+            }
+        };
+
+        let in_tmp = path.starts_with("/tmp") || path.starts_with("/var/tmp");
+        if in_tmp && path != Path::new("/tmp") && path != Path::new("/var/tmp") {
+            found(
+                Severity::Warning,
+                "tmp-files",
+                "files in temporary directories are usually removed on boot".into(),
+            );
+        }
+
+        if metadata.is_symlink() {
+            let target = fs.read_link(&path).await?;
+            if target.is_absolute() {
+                found(
+                    Severity::Info,
+                    "absolute-symlink",
+                    format!(
+                        "symlink to {} depends on where the artifact is installed",
+                        target.display()
+                    ),
+                );
+            } else if escapes_root(&path, &target) {
+                found(
+                    Severity::Error,
+                    "symlink-escapes-root",
+                    format!("symlink to {} points outside of the root", target.display()),
+                );
+            }
+            continue;
+        }
+
+        let mode = metadata.permissions().mode() & 0o7777;
+        let sticky_dir = metadata.is_dir() && mode & 0o1000 != 0;
+        if mode & 0o002 != 0 && !sticky_dir {
+            found(
+                Severity::Warning,
+                "world-writable",
+                format!("mode {mode:o} lets any user modify it"),
+            );
+        }
+
+        if metadata.is_file() {
+            if mode & 0o6000 != 0 {
+                found(
+                    Severity::Warning,
+                    "setuid",
+                    format!("mode {mode:o} runs it as its owner or group"),
+                );
+            }
+
+            if metadata.len() == 0 {
+                found(Severity::Info, "empty-file", "file is empty".into());
+            }
+        }
+    }
+
+    Ok(findings)
+}
+
+/// Whether a relative symlink target climbs above `/` when resolved from the
+/// symlink's directory.
+fn escapes_root(link: &Path, target: &Path) -> bool {
+    let mut depth = link.parent().map_or(0, |parent| {
+        parent
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .count()
+    });
+
+    for component in target.components() {
+        match component {
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use floppy_disk::mem::MemPermissions;
+
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[tokio::test]
+    async fn test_world_writable_file_is_reported() -> Result<()> {
+        let memfs = MemFS::new();
+        memfs.fs().create_dir_all("/etc").await?;
+        memfs.fs().write("/etc/config", b"a=b").await?;
+        memfs
+            .fs()
+            .set_permissions("/etc/config", MemPermissions::from_mode(0o666))
+            .await?;
+        memfs
+            .fs()
+            .symlink("../../../outside", "/etc/escape")
+            .await?;
+
+        let findings = lint(&memfs, &LintConfig::default()).await?;
+        let world_writable = findings
+            .iter()
+            .find(|f| f.rule == "world-writable" && f.path == Path::new("/etc/config"))
+            .expect("world-writable file was not reported");
+        assert_eq!(Severity::Warning, world_writable.severity);
+        assert!(findings
+            .iter()
+            .any(|f| f.rule == "symlink-escapes-root" && f.path == Path::new("/etc/escape")));
+
+        let suppressed = lint(
+            &memfs,
+            &LintConfig {
+                suppress: vec!["world-writable:/etc/config".into()],
+            },
+        )
+        .await?;
+        assert!(!suppressed
+            .iter()
+            .any(|f| f.rule == "world-writable" && f.path == Path::new("/etc/config")));
+
+        Ok(())
+    }
+}
@@ -1,13 +1,26 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use eyre::{eyre, Result};
 use sha2::Sha256;
+use smoosh::CompressionType;
 use thiserror::Error;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::*;
 
+pub mod changelog;
+pub mod cleanup;
 pub mod config;
+pub mod confirm;
+pub mod debuginfo;
+pub mod elf;
+pub mod git;
+pub mod glob;
+pub mod gpg;
+pub mod lint;
+pub mod schema;
+pub mod sparse;
+pub mod split;
 
 #[derive(Error, Debug)]
 pub enum Fix {
@@ -33,15 +46,108 @@ pub fn test_init() {
     });
 }
 
-pub fn get_current_time() -> Result<u64> {
-    if let Ok(source_date_epoch) = std::env::var("SOURCE_DATE_EPOCH") {
-        let source_date_epoch = source_date_epoch.parse::<u64>()?;
-        let current_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
-        if source_date_epoch > current_time {
-            return Err(eyre!("SOURCE_DATE_EPOCH is set to a time in the future"));
+/// Logs captured by [`capture_logs`].
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedLogs {
+    pub fn contains(&self, needle: &str) -> bool {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).contains(needle)
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Capture everything logged on the current thread until the guard is
+/// dropped. `#[tokio::test]`s run on a single thread, so this sees all of
+/// their logs.
+#[cfg(test)]
+pub fn capture_logs() -> (tracing::subscriber::DefaultGuard, CapturedLogs) {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+
+    (tracing::subscriber::set_default(subscriber), logs)
+}
+
+tokio::task_local! {
+    /// Overrides `SOURCE_DATE_EPOCH` for the current task. See
+    /// [`with_source_date_epoch`].
+    static SOURCE_DATE_EPOCH_OVERRIDE: u64;
+}
+
+/// Run `f` as if `SOURCE_DATE_EPOCH` was set to `epoch`, without touching the
+/// process environment, so that tests running in parallel don't see it.
+#[cfg(test)]
+pub async fn with_source_date_epoch<F: std::future::Future>(epoch: u64, f: F) -> F::Output {
+    SOURCE_DATE_EPOCH_OVERRIDE.scope(epoch, f).await
+}
+
+/// Run a tool that tests check peckish's output against, ex. `isoinfo`, and
+/// return its stdout, or `None` if it isn't installed, so that the check can
+/// be skipped.
+#[cfg(test)]
+pub async fn run_if_installed<I, S>(program: &str, args: I) -> Result<Option<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    match tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => {
+            Ok(Some(String::from_utf8_lossy(&output.stdout).to_string()))
         }
+        Ok(output) => Err(eyre!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("{program} is not installed, skipping");
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `SOURCE_DATE_EPOCH`, if it's set.
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    let source_date_epoch = match SOURCE_DATE_EPOCH_OVERRIDE.try_with(|epoch| *epoch) {
+        Ok(epoch) => epoch,
+        Err(_) => match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(source_date_epoch) => source_date_epoch.parse::<u64>()?,
+            Err(_) => return Ok(None),
+        },
+    };
+
+    let current_time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    if source_date_epoch > current_time {
+        return Err(eyre!("SOURCE_DATE_EPOCH is set to a time in the future"));
+    }
+    Ok(Some(source_date_epoch))
+}
+
+pub fn get_current_time() -> Result<u64> {
+    if let Some(source_date_epoch) = source_date_epoch()? {
         Ok(source_date_epoch)
     } else {
         let now = std::time::SystemTime::now()
@@ -51,32 +157,713 @@ pub fn get_current_time() -> Result<u64> {
     }
 }
 
+/// How much of a file digests read at once, unless told otherwise. Big
+/// enough that hashing large packages isn't dominated by read calls.
+pub const DIGEST_BUFFER_SIZE: usize = 64 * 1024;
+
 pub async fn sha256_digest(path: &Path) -> Result<String> {
-    let input = File::open(path).await?;
-    let mut reader = BufReader::new(input);
+    sha256_digest_with_buffer(path, DIGEST_BUFFER_SIZE).await
+}
 
-    let digest = {
-        use sha2::Digest;
-        let mut hasher = Sha256::new();
-        let mut buffer = [0; 1024];
-        loop {
-            let count = reader.read(&mut buffer).await?;
-            if count == 0 {
-                break;
-            }
-            hasher.update(&buffer[..count]);
+/// [`sha256_digest`], reading `buffer_size` bytes at a time.
+pub async fn sha256_digest_with_buffer(path: &Path, buffer_size: usize) -> Result<String> {
+    use sha2::Digest;
+
+    let mut input = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    read_chunks(&mut input, buffer_size, |chunk| hasher.update(chunk)).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The md5 of everything the reader has, read `buffer_size` bytes at a time
+/// instead of all at once.
+pub async fn md5_digest<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer_size: usize,
+) -> Result<String> {
+    let mut context = md5::Context::new();
+    read_chunks(reader, buffer_size, |chunk| context.consume(chunk)).await?;
+    Ok(format!("{:x}", context.compute()))
+}
+
+/// Hand everything the reader has to `consume`, `buffer_size` bytes at a
+/// time.
+async fn read_chunks<R, F>(reader: &mut R, buffer_size: usize, mut consume: F) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(&[u8]),
+{
+    let mut buffer = vec![0; buffer_size.max(1)];
+    loop {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            return Ok(());
         }
-        hasher.finalize()
-    };
-    Ok(format!("{:x}", digest))
+        consume(&buffer[..count]);
+    }
 }
 
 pub fn sha256_digest_string<S: Into<String>>(string: S) -> Result<String> {
+    Ok(sha256_digest_bytes(string.into().as_bytes()))
+}
+
+pub fn sha256_digest_bytes(bytes: &[u8]) -> String {
     let digest = {
         use sha2::Digest;
         let mut hasher = Sha256::new();
-        hasher.update(string.into().as_bytes());
+        hasher.update(bytes);
         hasher.finalize()
     };
-    Ok(format!("{:x}", digest))
+    format!("{:x}", digest)
+}
+
+/// How many redirects [`download`] follows before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+/// Download a file over HTTP or HTTPS, checking it against `sha256` if given.
+pub async fn download(url: &str, sha256: Option<&str>) -> Result<Vec<u8>> {
+    let mut content = vec![];
+    download_into(url, sha256, &mut content).await?;
+    Ok(content)
+}
+
+/// Download a file over HTTP or HTTPS to `path`, checking it against
+/// `sha256` if given. The body is streamed to a `.part` file next to `path`
+/// as it arrives, rather than held in memory, and only moved to `path` once
+/// it's been verified.
+pub async fn download_to_file(url: &str, sha256: Option<&str>, path: &Path) -> Result<()> {
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let downloaded = async {
+        let mut file = File::create(&partial).await?;
+        download_into(url, sha256, &mut file).await?;
+        file.flush().await?;
+        Ok::<_, eyre::Report>(())
+    }
+    .await;
+    if let Err(e) = downloaded {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+/// Stream a download into the writer, following redirects and hashing the
+/// body as it arrives. Downloads without a `sha256` aren't verified at all, so
+/// they're warned about.
+async fn download_into<W: AsyncWrite + Unpin>(
+    url: &str,
+    sha256: Option<&str>,
+    writer: &mut W,
+) -> Result<()> {
+    use hyper::body::HttpBody;
+    use sha2::Digest;
+
+    if sha256.is_none() {
+        warn!("downloading {url} without a sha256, so its contents will not be verified!");
+    }
+
+    let mut hasher = Sha256::new();
+    match fetch(url).await? {
+        DownloadBody::Http(mut body) => {
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|e| eyre!("failed to download {url}: {e}"))?;
+                hasher.update(&chunk);
+                writer.write_all(&chunk).await?;
+            }
+        }
+        DownloadBody::Https(mut curl) => {
+            let mut stdout = curl
+                .stdout
+                .take()
+                .ok_or_else(|| eyre!("failed to download {url}: curl has no stdout"))?;
+            let mut buffer = vec![0; DIGEST_BUFFER_SIZE];
+            loop {
+                let n = stdout.read(&mut buffer).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+                writer.write_all(&buffer[..n]).await?;
+            }
+
+            let output = curl.wait_with_output().await?;
+            if !output.status.success() {
+                return Err(eyre!(
+                    "failed to download {url}: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+        }
+    }
+
+    if let Some(expected) = sha256 {
+        let actual = format!("{:x}", hasher.finalize());
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(eyre!(
+                "checksum mismatch for {url}: expected sha256 {expected}, got {actual}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Where a download's body is read from.
+enum DownloadBody {
+    Http(hyper::Body),
+    /// There's no TLS backend in the dependency tree, so https downloads are
+    /// run through curl, which writes the body to its stdout.
+    Https(tokio::process::Child),
+}
+
+/// GET the url, following up to [`MAX_REDIRECTS`] redirects, and return the
+/// body of the final successful response. Once the url is https, the rest of
+/// the download, redirects included, is handed to curl.
+async fn fetch(url: &str) -> Result<DownloadBody> {
+    let client = hyper::Client::new();
+
+    let mut uri = parse_download_url(url)?;
+    for redirects in 0..=MAX_REDIRECTS {
+        if uri.scheme_str() == Some("https") {
+            return curl(url, &uri, MAX_REDIRECTS - redirects).map(DownloadBody::Https);
+        }
+
+        let response = client
+            .get(uri.clone())
+            .await
+            .map_err(|e| eyre!("failed to download {url}: {e}"))?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(hyper::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| {
+                    eyre!(
+                        "failed to download {url}: server responded with {} but no location",
+                        response.status()
+                    )
+                })?;
+            let next = redirect_target(&uri, location)?;
+            debug!("{uri} redirected to {next}");
+            uri = next;
+            continue;
+        }
+
+        if response.status() != hyper::StatusCode::OK {
+            return Err(eyre!(
+                "failed to download {url}: server responded with {}",
+                response.status()
+            ));
+        }
+
+        return Ok(DownloadBody::Http(response.into_body()));
+    }
+
+    Err(eyre!(
+        "failed to download {url}: more than {MAX_REDIRECTS} redirects"
+    ))
+}
+
+/// Start curl downloading an https uri to its stdout. `--proto-redir` keeps
+/// curl from following redirects off of https, same as [`redirect_target`].
+fn curl(url: &str, uri: &hyper::Uri, max_redirects: usize) -> Result<tokio::process::Child> {
+    tokio::process::Command::new("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--location",
+            "--proto",
+            "=https",
+            "--proto-redir",
+            "=https",
+            "--max-redirs",
+        ])
+        .arg(max_redirects.to_string())
+        .arg("--")
+        .arg(uri.to_string())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| eyre!("cannot run curl to download {url}: {e}"))
+}
+
+fn parse_download_url(url: &str) -> Result<hyper::Uri> {
+    let uri: hyper::Uri = url.parse().map_err(|e| eyre!("invalid url {url}: {e}"))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => Ok(uri),
+        _ => Err(eyre!(
+            "cannot download {url}: only http:// and https:// urls are supported"
+        )),
+    }
+}
+
+/// Resolve a `Location` header against the url that sent it. Locations are
+/// either absolute urls, or absolute paths on the same host. Redirects from
+/// https to plain http are refused, since they'd drop the download's TLS.
+fn redirect_target(from: &hyper::Uri, location: &str) -> Result<hyper::Uri> {
+    let target = if location.starts_with('/') && !location.starts_with("//") {
+        let scheme = from.scheme_str().unwrap_or("http");
+        let authority = from.authority().map(|a| a.as_str()).unwrap_or_default();
+        parse_download_url(&format!("{scheme}://{authority}{location}"))?
+    } else {
+        parse_download_url(location)?
+    };
+
+    if from.scheme_str() == Some("https") && target.scheme_str() != Some("https") {
+        return Err(eyre!(
+            "refusing to follow a redirect from {from} to {target}, since it isn't https"
+        ));
+    }
+
+    Ok(target)
+}
+
+/// Compress the data as the given type. Unlike [`smoosh::recompress`], this
+/// shuts the encoder down once it's done, so that formats with a trailer, like
+/// gzip and xz, are actually complete.
+///
+/// Output is byte-for-byte reproducible: gzip headers are always written with
+/// a zero mtime and the "unknown" OS byte, no matter when or where peckish
+/// runs.
+pub async fn compress(data: &[u8], compression: CompressionType) -> Result<Vec<u8>> {
+    let mut output = vec![];
+    {
+        let mut encoder = compressor(&mut output, compression);
+        encoder.write_all(data).await?;
+        encoder.shutdown().await?;
+    }
+
+    Ok(output)
+}
+
+/// Compress everything written to the writer as the given type, the same way
+/// as [`compress`]. The encoder has to be shut down to finish the output.
+pub fn compressor<'a, W: AsyncWrite + Unpin + Send + 'a>(
+    writer: W,
+    compression: CompressionType,
+) -> Box<dyn AsyncWrite + Unpin + Send + 'a> {
+    use async_compression::tokio::write::{
+        BzEncoder, DeflateEncoder, GzipEncoder, XzEncoder, ZlibEncoder, ZstdEncoder,
+    };
+
+    match compression {
+        CompressionType::Bzip => Box::new(BzEncoder::new(writer)),
+        CompressionType::Deflate => Box::new(DeflateEncoder::new(writer)),
+        CompressionType::Gzip => Box::new(GzipEncoder::new(writer)),
+        CompressionType::Xz => Box::new(XzEncoder::new(writer)),
+        CompressionType::Zlib => Box::new(ZlibEncoder::new(writer)),
+        CompressionType::Zstd => Box::new(ZstdEncoder::new(writer)),
+        CompressionType::None => Box::new(writer),
+    }
+}
+
+/// Decompress everything the reader has into the writer, detecting the
+/// compression the same way [`smoosh::recompress`] does. Unlike smoosh, gzip
+/// and xz data made of several concatenated members, ex. `cat a.gz b.gz`, is
+/// read to the end, rather than silently stopping after the first member.
+pub async fn decompress<R, W>(reader: &mut R, writer: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    use async_compression::tokio::bufread::{GzipDecoder, XzDecoder};
+
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+    // Peek at the magic, then put it back in front of the rest.
+    let mut magic = vec![0; XZ_MAGIC.len()];
+    let mut filled = 0;
+    while filled < magic.len() {
+        let count = reader.read(&mut magic[filled..]).await?;
+        if count == 0 {
+            break;
+        }
+        filled += count;
+    }
+    magic.truncate(filled);
+    let is_gzip = magic.starts_with(GZIP_MAGIC);
+    let is_xz = magic.starts_with(XZ_MAGIC);
+    let mut input = tokio::io::BufReader::new(std::io::Cursor::new(magic).chain(reader));
+
+    if is_gzip {
+        let mut decoder = GzipDecoder::new(input);
+        decoder.multiple_members(true);
+        tokio::io::copy(&mut decoder, writer).await?;
+    } else if is_xz {
+        let mut decoder = XzDecoder::new(input);
+        decoder.multiple_members(true);
+        tokio::io::copy(&mut decoder, writer).await?;
+    } else {
+        smoosh::recompress(&mut input, writer, CompressionType::None).await?;
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// The size of the window `gzip --rsyncable` sums bytes over to find
+/// boundaries. It's also the smallest distance between two boundaries, and
+/// has to be a power of two.
+const RSYNC_WINDOW: usize = 4096;
+
+/// Compress the data as gzip, resetting the compressor wherever the data
+/// hits a content-defined boundary, like `gzip --rsyncable`. A change to the
+/// input then only changes the compressed output up to the next boundary
+/// after it, so rsync and other delta tools can reuse the rest, at the cost
+/// of a slightly larger output. Output is reproducible like [`compress`]'s.
+pub fn gzip_rsyncable(data: &[u8]) -> Result<Vec<u8>> {
+    use flate2::{Compress, Compression, Crc, FlushCompress};
+
+    // mtime 0 and the "unknown" OS byte, the same as `compress`.
+    let mut output = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    // Every chunk gets a fresh compressor, so that nothing after a boundary
+    // depends on the data before it. Chunks end in a full flush, which
+    // byte-aligns the output, so their raw deflate streams can just be
+    // stuck together.
+    let chunk = |input: &[u8], output: &mut Vec<u8>, flush| {
+        deflate(
+            &mut Compress::new(Compression::default(), false),
+            input,
+            output,
+            flush,
+        )
+    };
+
+    let mut start = 0;
+    let mut sum = 0usize;
+    for (i, byte) in data.iter().enumerate() {
+        sum += *byte as usize;
+        if i >= RSYNC_WINDOW {
+            sum -= data[i - RSYNC_WINDOW] as usize;
+        }
+
+        if sum & (RSYNC_WINDOW - 1) == 0 && i + 1 - start >= RSYNC_WINDOW {
+            chunk(&data[start..=i], &mut output, FlushCompress::Full)?;
+            start = i + 1;
+        }
+    }
+    chunk(&data[start..], &mut output, FlushCompress::Finish)?;
+
+    let mut crc = Crc::new();
+    crc.update(data);
+    output.extend_from_slice(&crc.sum().to_le_bytes());
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    Ok(output)
+}
+
+/// Feed all of `input` to the compressor and flush it as asked.
+fn deflate(
+    compress: &mut flate2::Compress,
+    input: &[u8],
+    output: &mut Vec<u8>,
+    flush: flate2::FlushCompress,
+) -> Result<()> {
+    let mut consumed = 0;
+    loop {
+        output.reserve(input.len() - consumed + 1024);
+        let before = compress.total_in();
+        let status = compress.compress_vec(&input[consumed..], output, flush)?;
+        consumed += (compress.total_in() - before) as usize;
+
+        // The flush is done once the compressor stops filling the output.
+        let done = match flush {
+            flate2::FlushCompress::Finish => status == flate2::Status::StreamEnd,
+            _ => consumed == input.len() && output.len() < output.capacity(),
+        };
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        test_init();
+    }
+
+    #[tokio::test]
+    async fn test_digests_dont_depend_on_buffer_size() -> Result<()> {
+        let tmp = crate::fs::TempDir::new().await?;
+        let path = tmp.path_view().join("data");
+        let data = words(50_000, 3);
+        tokio::fs::write(&path, &data).await?;
+
+        let expected_sha256 = sha256_digest_bytes(&data);
+        let expected_md5 = format!("{:x}", md5::compute(&data));
+        for buffer_size in [1, 7, 1024, DIGEST_BUFFER_SIZE, 1024 * 1024] {
+            assert_eq!(
+                expected_sha256,
+                sha256_digest_with_buffer(&path, buffer_size).await?,
+                "sha256 with a {buffer_size} byte buffer"
+            );
+            assert_eq!(
+                expected_md5,
+                md5_digest(&mut data.as_slice(), buffer_size).await?,
+                "md5 with a {buffer_size} byte buffer"
+            );
+        }
+        assert_eq!(expected_sha256, sha256_digest(&path).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_source_date_epoch_override_is_scoped() -> Result<()> {
+        let inside = with_source_date_epoch(1_700_000_000, async { get_current_time() }).await?;
+        assert_eq!(1_700_000_000, inside);
+        // Only the override's own task sees it.
+        assert_ne!(1_700_000_000, get_current_time()?);
+
+        Ok(())
+    }
+
+    /// Counts how many reads it takes to get through the data.
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl AsyncRead for CountingReader<'_> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let count = buf.remaining().min(self.data.len());
+            buf.put_slice(&self.data[..count]);
+            self.data = &self.data[count..];
+            self.reads += 1;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_digest_buffer_reads_in_big_chunks() -> Result<()> {
+        let data = vec![0u8; 16 * 1024 * 1024];
+        let mut reader = CountingReader {
+            data: &data,
+            reads: 0,
+        };
+        md5_digest(&mut reader, DIGEST_BUFFER_SIZE).await?;
+
+        // One read per buffer, plus the one that hits the end. The old 1 KiB
+        // buffer took 16,385.
+        assert_eq!(16 * 1024 * 1024 / DIGEST_BUFFER_SIZE + 1, reader.reads);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gzip_output_is_reproducible() -> Result<()> {
+        let data = b"reproducible ".repeat(64);
+        let first = compress(&data, CompressionType::Gzip).await?;
+        let second = compress(&data, CompressionType::Gzip).await?;
+        assert_eq!(first, second);
+
+        // mtime, then the OS byte
+        assert_eq!(&first[4..8], &[0, 0, 0, 0]);
+        assert_eq!(first[9], 0xff);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concatenated_members_are_all_decompressed() -> Result<()> {
+        for compression in [CompressionType::Gzip, CompressionType::Xz] {
+            // Like `cat first.gz second.gz`, or pigz's and pixz's output.
+            let mut concatenated = compress(b"first member\n", compression).await?;
+            concatenated.extend(compress(b"second member\n", compression).await?);
+            concatenated.extend(compress(b"third member\n", compression).await?);
+
+            let mut decompressed = vec![];
+            decompress(&mut concatenated.as_slice(), &mut decompressed).await?;
+            assert_eq!(
+                b"first member\nsecond member\nthird member\n".as_slice(),
+                decompressed,
+                "{compression:?}"
+            );
+        }
+
+        // Anything else is still left to smoosh.
+        let mut decompressed = vec![];
+        decompress(&mut b"plain".as_slice(), &mut decompressed).await?;
+        assert_eq!(b"plain".as_slice(), decompressed);
+
+        Ok(())
+    }
+
+    /// Compressible, but not so repetitive that boundaries land everywhere.
+    fn words(count: usize, seed: u64) -> Vec<u8> {
+        let words = [
+            "peckish ", "package ", "tarball ", "deb ", "rpm ", "mirror\n",
+        ];
+        let mut state = seed;
+        (0..count)
+            .flat_map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                words[(state >> 33) as usize % words.len()].bytes()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rsyncable_gzip_resyncs_after_a_change() -> Result<()> {
+        let original = words(200_000, 1);
+        let mut changed = original.clone();
+        // A change near the start, so that everything after it has the
+        // chance to resync.
+        changed.splice(1_000..1_010, words(3, 2));
+
+        let original_gz = gzip_rsyncable(&original)?;
+        let changed_gz = gzip_rsyncable(&changed)?;
+
+        let mut decoded = vec![];
+        smoosh::recompress(
+            &mut changed_gz.as_slice(),
+            &mut decoded,
+            CompressionType::None,
+        )
+        .await?;
+        assert_eq!(changed, decoded);
+
+        // Skip the trailer, since the checksum always changes.
+        let common_suffix = |a: &[u8], b: &[u8]| {
+            a[..a.len() - 8]
+                .iter()
+                .rev()
+                .zip(b[..b.len() - 8].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let shared = common_suffix(&original_gz, &changed_gz);
+        assert!(
+            shared > original_gz.len() * 9 / 10,
+            "only {shared} of {} bytes are shared",
+            original_gz.len()
+        );
+
+        // Without resetting the compressor, the change is carried through
+        // the rest of the output.
+        let plain = common_suffix(
+            &compress(&original, CompressionType::Gzip).await?,
+            &compress(&changed, CompressionType::Gzip).await?,
+        );
+        assert!(plain < shared);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_targets_are_resolved() -> Result<()> {
+        let from: hyper::Uri = "https://example.com/releases/latest".parse()?;
+        assert_eq!(
+            redirect_target(&from, "/releases/1.0.tar")?,
+            "https://example.com/releases/1.0.tar"
+        );
+        assert_eq!(
+            redirect_target(&from, "https://cdn.example.com/1.0.tar")?,
+            "https://cdn.example.com/1.0.tar"
+        );
+        assert!(redirect_target(&from, "ftp://example.com/1.0.tar").is_err());
+        assert!(redirect_target(&from, "http://example.com/1.0.tar").is_err());
+
+        let from: hyper::Uri = "http://example.com/releases/latest".parse()?;
+        assert_eq!(
+            redirect_target(&from, "https://example.com/releases/1.0.tar")?,
+            "https://example.com/releases/1.0.tar"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_downloads_follow_redirects_to_a_file() -> Result<()> {
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/latest", listener.local_addr()?);
+        tokio::spawn(async move {
+            let responses = [
+                "HTTP/1.1 302 Found\r\nlocation: /release\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\ncontent-length: 7\r\nconnection: close\r\n\r\nrelease",
+            ];
+            for response in responses {
+                let (mut stream, _) = listener.accept().await?;
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let n = stream.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).await?;
+                stream.shutdown().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        });
+
+        let tmp = crate::fs::TempDir::new().await?;
+        let path = tmp.path_view().join("release");
+        download_to_file(&url, Some(&sha256_digest_bytes(b"release")), &path).await?;
+        assert_eq!(tokio::fs::read(&path).await?, b"release");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_downloads_are_not_written() -> Result<()> {
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/release", listener.local_addr()?);
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 8\r\nconnection: close\r\n\r\ntampered",
+                )
+                .await?;
+            stream.shutdown().await?;
+            Ok::<_, std::io::Error>(())
+        });
+
+        let tmp = crate::fs::TempDir::new().await?;
+        let path = tmp.path_view().join("release");
+        tokio::fs::write(&path, b"old").await?;
+        assert!(
+            download_to_file(&url, Some(&sha256_digest_bytes(b"release")), &path)
+                .await
+                .is_err()
+        );
+        assert_eq!(tokio::fs::read(&path).await?, b"old");
+        assert!(!tmp.path_view().join("release.part").exists());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,549 @@
+//! A JSON Schema for `peckish.yaml`, for editors and CI to validate configs
+//! against. It's written out by hand, since the config structs don't derive
+//! a schema, so the tests check its `type`s and keys against what the config
+//! actually accepts.
+
+use serde_json::{json, Map, Value};
+
+const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+fn strings() -> Value {
+    json!({ "type": "array", "items": { "type": "string" } })
+}
+
+fn one_of(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn compression() -> Value {
+    one_of(&["none", "bzip", "deflate", "gzip", "xz", "zlib", "zstd"])
+}
+
+fn owner_remap() -> Value {
+    json!({
+        "oneOf": [
+            { "const": "nobody" },
+            {
+                "type": "object",
+                "properties": {
+                    "uid": integer(),
+                    "gid": integer(),
+                    "group_writable": boolean(),
+                },
+                "required": ["uid", "gid"],
+            },
+        ],
+    })
+}
+
+fn changelog() -> Value {
+    json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": {
+                "version": string(),
+                "date": string(),
+                "author": string(),
+                "notes": strings(),
+            },
+            "required": ["version", "date", "author", "notes"],
+        },
+    })
+}
+
+/// Single-file inputs are read from either a `path` or a `url`.
+fn source() -> Vec<(&'static str, Value)> {
+    vec![("path", string()), ("url", string()), ("sha256", string())]
+}
+
+/// One `type` of a tagged object, and the keys it takes on top of the
+/// common ones.
+struct Variant {
+    kind: &'static str,
+    required: &'static [&'static str],
+    properties: Vec<(&'static str, Value)>,
+}
+
+impl Variant {
+    fn new(
+        kind: &'static str,
+        required: &'static [&'static str],
+        properties: Vec<(&'static str, Value)>,
+    ) -> Self {
+        Self {
+            kind,
+            required,
+            properties,
+        }
+    }
+}
+
+/// An object tagged by its `type`, with the keys every type takes in
+/// `common`. Each type's own keys only apply when its `type` is set.
+fn tagged(common: Vec<(&str, Value)>, common_required: &[&str], variants: Vec<Variant>) -> Value {
+    let kinds = variants.iter().map(|v| v.kind).collect::<Vec<_>>();
+
+    let mut properties = Map::new();
+    properties.insert("type".into(), one_of(&kinds));
+    for (key, value) in common {
+        properties.insert(key.into(), value);
+    }
+
+    let mut required = vec!["type"];
+    required.extend(common_required);
+
+    let conditions = variants
+        .into_iter()
+        .map(|variant| {
+            let properties = variant
+                .properties
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect::<Map<_, _>>();
+            json!({
+                "if": { "properties": { "type": { "const": variant.kind } } },
+                "then": { "properties": properties, "required": variant.required },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "allOf": conditions,
+    })
+}
+
+fn injection() -> Value {
+    tagged(
+        vec![],
+        &[],
+        vec![
+            Variant::new(
+                "move",
+                &["src", "dest"],
+                vec![("src", string()), ("dest", string())],
+            ),
+            Variant::new(
+                "copy",
+                &["src", "dest"],
+                vec![("src", string()), ("dest", string())],
+            ),
+            Variant::new(
+                "symlink",
+                &["src", "dest"],
+                vec![
+                    ("src", string()),
+                    ("dest", string()),
+                    ("relative", boolean()),
+                ],
+            ),
+            Variant::new(
+                "touch",
+                &["path"],
+                vec![
+                    ("path", string()),
+                    ("mtime", integer()),
+                    ("atime", integer()),
+                ],
+            ),
+            Variant::new("delete", &["path"], vec![("path", string())]),
+            Variant::new(
+                "create",
+                &["path", "content"],
+                vec![
+                    ("path", string()),
+                    (
+                        "content",
+                        json!({ "type": "array", "items": { "type": "integer", "minimum": 0, "maximum": 255 } }),
+                    ),
+                ],
+            ),
+            Variant::new(
+                "host_file",
+                &["src", "dest"],
+                vec![("src", string()), ("dest", string())],
+            ),
+            Variant::new(
+                "host_dir",
+                &["src", "dest"],
+                vec![
+                    ("src", string()),
+                    ("dest", string()),
+                    ("default_excludes", boolean()),
+                    ("extra_excludes", strings()),
+                ],
+            ),
+            Variant::new(
+                "remote_file",
+                &["url", "dest"],
+                vec![("url", string()), ("dest", string()), ("sha256", string())],
+            ),
+            Variant::new(
+                "chmod",
+                &["path", "mode"],
+                vec![("path", string()), ("mode", integer())],
+            ),
+            Variant::new(
+                "from_artifact",
+                &["artifact", "src", "dest"],
+                vec![
+                    ("artifact", json!({ "$ref": "#/definitions/input" })),
+                    ("src", string()),
+                    ("dest", string()),
+                ],
+            ),
+            Variant::new("umask", &["umask"], vec![("umask", integer())]),
+            Variant::new("normalize_permissions", &[], vec![]),
+            Variant::new(
+                "chown",
+                &["path", "uid", "gid"],
+                vec![
+                    ("path", string()),
+                    ("uid", integer()),
+                    ("gid", integer()),
+                    ("recursive", boolean()),
+                ],
+            ),
+            Variant::new(
+                "patch_elf",
+                &["path"],
+                vec![
+                    ("path", string()),
+                    ("set_rpath", string()),
+                    ("set_interpreter", string()),
+                    ("remove_rpath", boolean()),
+                ],
+            ),
+        ],
+    )
+}
+
+fn input() -> Value {
+    let single_file = |kind| Variant::new(kind, &[], source());
+
+    tagged(
+        vec![("name", string())],
+        &["name"],
+        vec![
+            Variant::new(
+                "file",
+                &["paths"],
+                vec![
+                    ("paths", strings()),
+                    ("git_mtimes", boolean()),
+                    ("default_excludes", boolean()),
+                    ("extra_excludes", strings()),
+                    ("placement", one_of(&["as_given", "root", "prefix"])),
+                    ("prefix", string()),
+                ],
+            ),
+            single_file("tarball"),
+            Variant::new(
+                "docker",
+                &["image"],
+                vec![
+                    ("image", string()),
+                    ("platform", string()),
+                    (
+                        "pull_policy",
+                        one_of(&["always", "if_not_present", "never"]),
+                    ),
+                    ("include_paths", strings()),
+                    ("exclude_paths", strings()),
+                ],
+            ),
+            single_file("arch"),
+            single_file("deb"),
+            single_file("rpm"),
+            single_file("ext4"),
+            single_file("oci"),
+            Variant::new("plugin", &["plugin"], vec![("plugin", string())]),
+        ],
+    )
+}
+
+fn output() -> Value {
+    let injections = json!({
+        "type": "array",
+        "items": {
+            "oneOf": [
+                { "type": "string" },
+                { "$ref": "#/definitions/injection" },
+            ],
+        },
+    });
+    let with_path = |kind, mut properties: Vec<(&'static str, Value)>| {
+        properties.insert(0, ("path", string()));
+        Variant::new(kind, &["path"], properties)
+    };
+
+    tagged(
+        vec![
+            ("name", string()),
+            ("injections", injections),
+            ("before", string()),
+            ("after", string()),
+            ("umask", integer()),
+            ("normalize_permissions", boolean()),
+            (
+                "labels",
+                json!({ "type": "object", "additionalProperties": { "type": "string" } }),
+            ),
+        ],
+        &["name"],
+        vec![
+            with_path(
+                "file",
+                vec![
+                    ("preserve_empty_directories", boolean()),
+                    ("spill_threshold", integer()),
+                    ("checksums", boolean()),
+                ],
+            ),
+            with_path(
+                "tarball",
+                vec![
+                    ("compression", compression()),
+                    ("sparse", boolean()),
+                    ("entry_names", one_of(&["relative", "dot_relative"])),
+                    ("file_mtime", integer()),
+                    ("dir_mtime", integer()),
+                    ("rsyncable", boolean()),
+                    ("remap_owner", owner_remap()),
+                    ("split_size", integer()),
+                ],
+            ),
+            Variant::new(
+                "docker",
+                &["image"],
+                vec![
+                    ("image", string()),
+                    ("base_image", string()),
+                    ("merge_policy", one_of(&["overwrite", "error", "skip"])),
+                    ("entrypoint", strings()),
+                    ("remap_owner", owner_remap()),
+                ],
+            ),
+            with_path(
+                "dockerfile",
+                vec![("base_image", string()), ("cmd", strings())],
+            ),
+            with_path(
+                "arch",
+                vec![
+                    ("compression", compression()),
+                    ("provides", strings()),
+                    ("replaces", strings()),
+                    ("conflicts", strings()),
+                    ("backup", strings()),
+                    ("signing_key", string()),
+                    ("gpg_home", string()),
+                ],
+            ),
+            with_path(
+                "deb",
+                vec![
+                    ("compression", compression()),
+                    ("control_compression", compression()),
+                    ("data_compression", compression()),
+                    ("rsyncable", boolean()),
+                    ("prerm", string()),
+                    ("postinst", string()),
+                    ("depends", json!({ "oneOf": [string(), strings()] })),
+                    ("pre_depends", json!({ "oneOf": [string(), strings()] })),
+                    ("breaks", json!({ "oneOf": [string(), strings()] })),
+                    ("split_debug", boolean()),
+                    ("format_version", string()),
+                    ("changelog", changelog()),
+                    ("conffiles", strings()),
+                ],
+            ),
+            with_path(
+                "rpm",
+                vec![
+                    ("spec", string()),
+                    ("release", string()),
+                    ("split_debug", boolean()),
+                    ("changelog", changelog()),
+                    ("config_files", strings()),
+                    ("doc_files", strings()),
+                    ("ghost_files", strings()),
+                ],
+            ),
+            with_path(
+                "ext4",
+                vec![("size_slack", integer()), ("template", string())],
+            ),
+            Variant::new(
+                "oci",
+                &["path", "architecture"],
+                vec![
+                    ("path", string()),
+                    ("architecture", string()),
+                    ("remap_owner", owner_remap()),
+                    ("reproducible", boolean()),
+                ],
+            ),
+            with_path("script_installer", vec![("post_extract", string())]),
+            with_path("manifest", vec![("format", one_of(&["json", "yaml"]))]),
+            with_path("sbom", vec![("format", one_of(&["cyclonedx", "spdx"]))]),
+            Variant::new(
+                "macos_pkg",
+                &["path", "identifier"],
+                vec![
+                    ("path", string()),
+                    ("identifier", string()),
+                    ("install_location", string()),
+                    ("preinstall", string()),
+                    ("postinstall", string()),
+                ],
+            ),
+            with_path(
+                "iso",
+                vec![
+                    ("volume_label", string()),
+                    ("rock_ridge", boolean()),
+                    ("joliet", boolean()),
+                ],
+            ),
+            with_path(
+                "cab",
+                vec![
+                    ("compression", one_of(&["none", "mszip"])),
+                    ("uncompressed", strings()),
+                ],
+            ),
+            with_path("passthrough", vec![("compression", compression())]),
+            Variant::new("plugin", &["plugin"], vec![("plugin", string())]),
+        ],
+    )
+}
+
+/// The schema for a whole `peckish.yaml`.
+pub fn config_schema() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "peckish.yaml",
+        "type": "object",
+        "properties": {
+            "version": integer(),
+            "chain": boolean(),
+            "overwrite": boolean(),
+            "defaults": {
+                "type": "object",
+                "properties": { "compression": compression() },
+            },
+            "metadata": {
+                "type": "object",
+                "properties": {
+                    "name": string(),
+                    "version": string(),
+                    "description": string(),
+                    "author": string(),
+                    "arch": string(),
+                    "license": string(),
+                },
+            },
+            "input": { "$ref": "#/definitions/input" },
+            "output": { "type": "array", "items": output() },
+            "injections": {
+                "type": "object",
+                "additionalProperties": { "$ref": "#/definitions/injection" },
+            },
+            "lint": {
+                "type": "object",
+                "properties": { "suppress": strings() },
+            },
+        },
+        "required": ["input", "output"],
+        "definitions": {
+            "input": input(),
+            "injection": injection(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::PeckishConfig;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    fn kinds(schema: &Value) -> Vec<String> {
+        let mut kinds = schema["properties"]["type"]["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|kind| kind.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds
+    }
+
+    /// The `type`s the config accepts, read out of the error for one it
+    /// doesn't.
+    fn accepted_kinds(config: &str) -> Vec<String> {
+        let err = PeckishConfig::parse(config).unwrap_err().to_string();
+        let expected = err
+            .split_once("expected one of ")
+            .unwrap_or_else(|| panic!("no variants in: {err}"))
+            .1;
+        let mut kinds = expected
+            .split(", ")
+            .map(|kind| {
+                kind.split('`')
+                    .nth(1)
+                    .unwrap_or_else(|| panic!("bad variant in: {err}"))
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds
+    }
+
+    #[test]
+    fn test_schema_types_match_the_config() {
+        let schema = config_schema();
+        let input = &schema["definitions"]["input"];
+        let output = &schema["properties"]["output"]["items"];
+        let injection = &schema["definitions"]["injection"];
+
+        assert!(kinds(input).contains(&"docker".to_string()));
+        assert!(kinds(output).contains(&"deb".to_string()));
+        assert!(kinds(injection).contains(&"move".to_string()));
+
+        let config = |input: &str, output: &str| {
+            format!(
+                "input:\n  name: \"in\"\n  type: \"{input}\"\n  path: \"./in.tar\"\n\noutput:\n  - name: \"out\"\n    type: \"{output}\"\n    path: \"./out.tar\"\n"
+            )
+        };
+        assert_eq!(accepted_kinds(&config("nope", "tarball")), kinds(input));
+        assert_eq!(accepted_kinds(&config("tarball", "nope")), kinds(output));
+        assert_eq!(
+            accepted_kinds(&format!(
+                "{}injections:\n  broken:\n    type: \"nope\"\n",
+                config("tarball", "tarball")
+            )),
+            kinds(injection)
+        );
+    }
+}
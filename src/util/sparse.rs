@@ -0,0 +1,125 @@
+//! Sparse file detection. Files with large zero-filled regions, like disk
+//! images, can be stored as a list of data regions instead, with the holes
+//! between them left implicit.
+
+/// Regions are aligned to tar's block size, since that's what sparse tar
+/// entries require.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Runs of zero blocks shorter than this are kept as data, since every region
+/// costs header space, and fragmenting a file into many tiny regions isn't
+/// worth it.
+pub const MIN_HOLE_SIZE: usize = 8 * BLOCK_SIZE;
+
+/// A region of a file that actually contains data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRegion {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Find the data regions of a file. Returns `None` if the file doesn't have
+/// any holes worth skipping, ie. it should be stored normally.
+///
+/// Every region but the last is block-aligned. If the file ends in a hole,
+/// the last region is an empty region at the end of the file, so that readers
+/// know the file's real size.
+pub fn data_regions(data: &[u8]) -> Option<Vec<DataRegion>> {
+    let mut regions = vec![];
+    let mut region_start = 0;
+    let mut hole_start = None;
+    let mut has_holes = false;
+
+    for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let offset = i * BLOCK_SIZE;
+        if block.iter().all(|b| *b == 0) {
+            hole_start.get_or_insert(offset);
+            continue;
+        }
+
+        if let Some(start) = hole_start.take() {
+            if offset - start >= MIN_HOLE_SIZE {
+                has_holes = true;
+                push_region(&mut regions, region_start, start);
+                region_start = offset;
+            }
+        }
+    }
+
+    match hole_start {
+        Some(start) if data.len() - start >= MIN_HOLE_SIZE => {
+            has_holes = true;
+            push_region(&mut regions, region_start, start);
+            regions.push(DataRegion {
+                offset: data.len() as u64,
+                length: 0,
+            });
+        }
+        _ => push_region(&mut regions, region_start, data.len()),
+    }
+
+    if has_holes {
+        Some(regions)
+    } else {
+        None
+    }
+}
+
+fn push_region(regions: &mut Vec<DataRegion>, start: usize, end: usize) {
+    if end > start {
+        regions.push(DataRegion {
+            offset: start as u64,
+            length: (end - start) as u64,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ctor::ctor]
+    fn init() {
+        crate::util::test_init();
+    }
+
+    #[test]
+    fn test_data_regions_finds_holes() {
+        let mut data = vec![0u8; 64 * BLOCK_SIZE];
+        data[0] = 1;
+        data[40 * BLOCK_SIZE + 1] = 1;
+        // A short run of zeroes that isn't worth a hole.
+        data[43 * BLOCK_SIZE] = 1;
+
+        let regions = data_regions(&data).unwrap();
+        assert_eq!(
+            regions,
+            vec![
+                DataRegion {
+                    offset: 0,
+                    length: BLOCK_SIZE as u64,
+                },
+                DataRegion {
+                    offset: 40 * BLOCK_SIZE as u64,
+                    length: 4 * BLOCK_SIZE as u64,
+                },
+                DataRegion {
+                    offset: data.len() as u64,
+                    length: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_regions_ignores_dense_files() {
+        let data = vec![1u8; 64 * BLOCK_SIZE];
+        assert!(data_regions(&data).is_none());
+
+        let mut data = vec![1u8; 64 * BLOCK_SIZE];
+        data[BLOCK_SIZE..2 * BLOCK_SIZE].fill(0);
+        assert!(data_regions(&data).is_none());
+
+        assert!(data_regions(&[]).is_none());
+    }
+}
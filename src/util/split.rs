@@ -0,0 +1,321 @@
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use eyre::{eyre, Result};
+use futures_util::future::{BoxFuture, FutureExt};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::*;
+
+use crate::util::sha256_digest;
+
+/// `foo.tar` -> `foo.tar.part<index>`
+pub fn part_path(path: &Path, index: usize) -> PathBuf {
+    with_suffix(path, &format!(".part{index}"))
+}
+
+/// `foo.tar` -> `foo.tar.parts`. The manifest lists a split file's parts in
+/// order, one `sha256  name` line per part, so `sha256sum -c` can check them
+/// too.
+pub fn manifest_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".parts")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    path.into()
+}
+
+/// Whether the file at `path` only exists as parts.
+pub fn is_split(path: &Path) -> bool {
+    !path.exists() && manifest_path(path).is_file()
+}
+
+/// The manifest and parts of a split file, as listed by its manifest. Files
+/// that aren't split have none.
+pub fn split_paths(path: &Path) -> Vec<PathBuf> {
+    let manifest = manifest_path(path);
+    let Ok(content) = std::fs::read_to_string(&manifest) else {
+        return vec![];
+    };
+
+    let mut paths = vec![manifest];
+    for (_, name) in parse_manifest(&content) {
+        match sibling(path, &name) {
+            Ok(part) => paths.push(part),
+            Err(e) => warn!("skipping part of {}: {e}", path.display()),
+        }
+    }
+    paths
+}
+
+/// The `(sha256, name)` of every part, in order.
+fn parse_manifest(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, name)| (hash.to_string(), name.to_string()))
+        .collect()
+}
+
+/// The part called `name`, next to the split file. Manifests can only name
+/// files in the same directory, so that a crafted one can't point at files
+/// elsewhere.
+fn sibling(path: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(eyre!("part name {name:?} is not a plain file name"));
+    }
+
+    Ok(path.with_file_name(name))
+}
+
+/// Writes a file as parts of at most `size` bytes, plus a manifest, without
+/// the whole file ever being on disk or in memory. Parts are created as
+/// they're needed, and the manifest is written on shutdown.
+pub struct SplitWriter {
+    path: PathBuf,
+    size: u64,
+    parts: usize,
+    written: u64,
+    hasher: Sha256,
+    manifest: String,
+    state: SplitState,
+}
+
+enum SplitState {
+    /// Before the first part, or between two of them.
+    Idle,
+    Opening(BoxFuture<'static, io::Result<BufWriter<File>>>),
+    Writing(BufWriter<File>),
+    Finishing(BoxFuture<'static, io::Result<()>>),
+    Done,
+}
+
+impl SplitWriter {
+    pub fn new(path: &Path, size: u64) -> Result<Self> {
+        if size == 0 {
+            return Err(eyre!("cannot split {} into 0 byte parts", path.display()));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size,
+            parts: 0,
+            written: 0,
+            hasher: Sha256::new(),
+            manifest: String::new(),
+            state: SplitState::Idle,
+        })
+    }
+
+    fn open_part(&mut self) {
+        let part = part_path(&self.path, self.parts);
+        debug!("writing part {}", part.display());
+        self.state = SplitState::Opening(
+            async move { File::create(part).await.map(BufWriter::new) }.boxed(),
+        );
+    }
+
+    fn finish_part(&mut self) {
+        let part = part_path(&self.path, self.parts);
+        self.manifest.push_str(&format!(
+            "{:x}  {}\n",
+            std::mem::take(&mut self.hasher).finalize(),
+            part.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        self.parts += 1;
+        self.written = 0;
+        self.state = SplitState::Idle;
+    }
+}
+
+impl AsyncWrite for SplitWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            match &mut this.state {
+                SplitState::Idle => this.open_part(),
+                SplitState::Opening(open) => {
+                    let part = ready!(open.as_mut().poll(cx))?;
+                    this.state = SplitState::Writing(part);
+                }
+                SplitState::Writing(part) if this.written == this.size => {
+                    ready!(Pin::new(part).poll_shutdown(cx))?;
+                    this.finish_part();
+                }
+                SplitState::Writing(part) => {
+                    let room = (this.size - this.written).min(buf.len() as u64) as usize;
+                    let wrote = ready!(Pin::new(part).poll_write(cx, &buf[..room]))?;
+                    this.hasher.update(&buf[..wrote]);
+                    this.written += wrote as u64;
+                    return Poll::Ready(Ok(wrote));
+                }
+                SplitState::Finishing(_) | SplitState::Done => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "cannot write to a split file after shutting it down",
+                    )));
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().state {
+            SplitState::Writing(part) => Pin::new(part).poll_flush(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                // Empty files still get one (empty) part.
+                SplitState::Idle if this.parts == 0 => this.open_part(),
+                SplitState::Idle => {
+                    let manifest = manifest_path(&this.path);
+                    let content = std::mem::take(&mut this.manifest);
+                    this.state = SplitState::Finishing(
+                        async move { tokio::fs::write(manifest, content).await }.boxed(),
+                    );
+                }
+                SplitState::Opening(open) => {
+                    let part = ready!(open.as_mut().poll(cx))?;
+                    this.state = SplitState::Writing(part);
+                }
+                SplitState::Writing(part) => {
+                    ready!(Pin::new(part).poll_shutdown(cx))?;
+                    this.finish_part();
+                }
+                SplitState::Finishing(write) => {
+                    ready!(write.as_mut().poll(cx))?;
+                    this.state = SplitState::Done;
+                }
+                SplitState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+/// Put a split file back together at `dest`, checking every part against the
+/// manifest.
+pub async fn reassemble(path: &Path, dest: &Path) -> Result<()> {
+    let manifest = manifest_path(path);
+    let content = tokio::fs::read_to_string(&manifest).await?;
+    let parts = parse_manifest(&content);
+    if parts.is_empty() {
+        return Err(eyre!(
+            "split manifest {} lists no parts",
+            manifest.display()
+        ));
+    }
+
+    let mut output = tokio::fs::File::create(dest).await?;
+    for (hash, name) in parts {
+        let part = sibling(path, &name)?;
+        let actual = sha256_digest(&part).await?;
+        if actual != hash {
+            return Err(eyre!(
+                "part {} is corrupt: expected sha256 {hash}, got {actual}",
+                part.display()
+            ));
+        }
+        tokio::io::copy(&mut tokio::fs::File::open(&part).await?, &mut output).await?;
+    }
+    output.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_split_writer_is_reassembled() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("data.bin");
+        let data = (0..2_500u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        // Writes that straddle parts are split between them.
+        let mut writer = SplitWriter::new(&path, 1_000)?;
+        for chunk in data.chunks(300) {
+            writer.write_all(chunk).await?;
+        }
+        writer.shutdown().await?;
+        assert!(is_split(&path));
+        let paths = split_paths(&path);
+        assert_eq!(
+            vec![
+                manifest_path(&path),
+                part_path(&path, 0),
+                part_path(&path, 1),
+                part_path(&path, 2),
+            ],
+            paths
+        );
+        assert_eq!(500, tokio::fs::metadata(part_path(&path, 2)).await?.len());
+
+        let dest = tmp.path_view().join("reassembled.bin");
+        reassemble(&path, &dest).await?;
+        assert_eq!(data, tokio::fs::read(&dest).await?);
+
+        // A damaged part is noticed rather than reassembled.
+        tokio::fs::write(part_path(&path, 1), b"oops").await?;
+        assert!(reassemble(&path, &dest).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_file_has_one_part() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        let path = tmp.path_view().join("empty.bin");
+        SplitWriter::new(&path, 1_000)?.shutdown().await?;
+
+        assert_eq!(
+            vec![manifest_path(&path), part_path(&path, 0)],
+            split_paths(&path)
+        );
+        let dest = tmp.path_view().join("reassembled.bin");
+        reassemble(&path, &dest).await?;
+        assert!(tokio::fs::read(&dest).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parts_outside_the_directory_are_rejected() -> Result<()> {
+        let tmp = TempDir::new().await?;
+        tokio::fs::create_dir_all(tmp.path_view().join("split")).await?;
+        tokio::fs::write(tmp.path_view().join("secret"), b"secret").await?;
+        let path = tmp.path_view().join("split/data.bin");
+        let hash = sha256_digest(&tmp.path_view().join("secret")).await?;
+        tokio::fs::write(manifest_path(&path), format!("{hash}  ../secret\n")).await?;
+
+        assert_eq!(vec![manifest_path(&path)], split_paths(&path));
+        let dest = tmp.path_view().join("reassembled.bin");
+        assert!(reassemble(&path, &dest).await.is_err());
+        assert!(sibling(&path, "/etc/passwd").is_err());
+        assert!(sibling(&path, "..").is_err());
+        assert!(sibling(&path, "data.bin.part0").is_ok());
+
+        Ok(())
+    }
+}